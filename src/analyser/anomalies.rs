@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+
+use chrono::{Duration, Local, NaiveDate};
+use chrono::offset::TimeZone;
+use clap::ArgMatches;
+use mysql::*;
+use mysql::prelude::*;
+
+use dystonse_curves::tree::{SerdeFormat, NodeData};
+
+use super::Analyser;
+use crate::types::{Anomaly, AnomalyReport, AnomalyExclusions, FlaggedVehicle, TimeSlot};
+
+use crate::{FnResult, Main, date_and_time_local};
+
+/// Days with less vehicles than this are neither checked nor used as history.
+const MIN_VEHICLES: usize = 3;
+
+/// Days are only checked if there are at least this many days of history for the same time slot.
+const MIN_HISTORY_DAYS: usize = 5;
+
+/// Lower bound for the median absolute deviation of the history (in seconds), so that
+/// routes with very regular delays don't produce huge scores for small deviations.
+const MIN_MAD: f32 = 15.0;
+
+/// Finds days on which the delays of a route deviate significantly from the previous weeks.
+pub struct AnomalyDetector<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches
+}
+
+/// Delays of all vehicles of a route within one time slot on one day.
+#[derive(Default)]
+struct DaySummary {
+    /// median delay of each vehicle
+    vehicle_delays: Vec<f32>,
+    vehicles: Vec<FlaggedVehicle>,
+}
+
+impl<'a> AnomalyDetector<'a> {
+    pub fn run_anomalies(&self) -> FnResult<()> {
+        let yesterday = Local::today().naive_local().pred();
+        let to = match self.args.value_of("to") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => yesterday,
+        };
+        let from = match self.args.value_of("from") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => to - Duration::days(6),
+        };
+        let history_days : i64 = self.args.value_of("history-days").unwrap().parse()?; // has a default value
+        let threshold : f32 = self.args.value_of("threshold").unwrap().parse()?; // has a default value
+
+        let route_ids : Vec<String> = match self.args.values_of("route-ids") {
+            Some(route_ids) => route_ids.map(String::from).collect(),
+            None => self.analyser.schedule.routes.keys().cloned().collect(),
+        };
+        println!("Looking for anomalies of {} routes from {} to {}, compared with the {} days before…", route_ids.len(), from, to, history_days);
+
+        let mut anomalies = Vec::new();
+        for route_id in &route_ids {
+            match self.find_anomalies_for_route(route_id, from, to, history_days, threshold) {
+                Ok(route_anomalies) => anomalies.extend(route_anomalies),
+                Err(e) => println!("Could not look for anomalies of route {}: {}", route_id, e)
+            }
+        }
+        anomalies.sort_by(|a, b| b.score.abs().partial_cmp(&a.score.abs()).unwrap_or(std::cmp::Ordering::Equal));
+
+        let report = AnomalyReport {
+            source: self.main.source.clone(),
+            from,
+            to,
+            history_days,
+            threshold,
+            anomalies,
+        };
+        self.write_csv(&report)?;
+        report.save_to_file(&self.main.dir, AnomalyReport::NAME, &SerdeFormat::Json)?;
+        println!("Found {} anomalies, written to {}/anomalies.json.", report.anomalies.len(), self.main.dir);
+
+        Ok(())
+    }
+
+    fn find_anomalies_for_route(&self, route_id: &str, from: NaiveDate, to: NaiveDate, history_days: i64, threshold: f32) -> FnResult<Vec<Anomaly>> {
+        let route = self.analyser.schedule.get_route(route_id)?;
+        let summaries = self.get_day_summaries(route_id, from - Duration::days(history_days), to)?;
+
+        let mut anomalies = Vec::new();
+        for ((date, ts_id), summary) in &summaries {
+            if *date < from || summary.vehicles.len() < MIN_VEHICLES {
+                continue;
+            }
+            let history_window = (*date - Duration::days(history_days), *date);
+            let mut history : Vec<f32> = summaries.iter()
+                .filter(|((d, t), s)| t == ts_id && *d >= history_window.0 && *d < history_window.1 && s.vehicles.len() >= MIN_VEHICLES)
+                .map(|(_, s)| median(&s.vehicle_delays))
+                .collect();
+            if history.len() < MIN_HISTORY_DAYS {
+                continue;
+            }
+
+            let history_median = median(&history);
+            for value in history.iter_mut() {
+                *value = (*value - history_median).abs();
+            }
+            let history_mad = median(&history);
+
+            let median_delay = median(&summary.vehicle_delays);
+            // 0.6745 is the 75th percentile of the standard normal distribution, which makes the score comparable to a z-score
+            let score = 0.6745 * (median_delay - history_median) / history_mad.max(MIN_MAD);
+            if score.abs() >= threshold {
+                anomalies.push(Anomaly {
+                    route_id: route_id.to_string(),
+                    route_short_name: route.short_name.clone(),
+                    date: *date,
+                    time_slot: TimeSlot::from_id(*ts_id).map(|ts| ts.description).unwrap_or_default().to_string(),
+                    median_delay,
+                    p90_delay: quantile(&summary.vehicle_delays, 0.9),
+                    history_median,
+                    history_mad,
+                    history_day_count: history.len(),
+                    score,
+                    vehicles: summary.vehicles.clone(),
+                });
+            }
+        }
+
+        if !anomalies.is_empty() {
+            println!("Found {} anomalies for route {}.", anomalies.len(), route.short_name);
+        }
+        Ok(anomalies)
+    }
+
+    /// Reads the records of the route and summarizes them for each day and time slot.
+    /// The time slot of a vehicle is determined by its start time.
+    fn get_day_summaries(&self, route_id: &str, from: NaiveDate, to: NaiveDate) -> FnResult<HashMap<(NaiveDate, u8), DaySummary>> {
+        let mut con = self.main.pool.get_conn()?;
+        let stmt = con.prep(
+            r"SELECT
+                trip_id,
+                trip_start_date,
+                trip_start_time,
+                delay_arrival,
+                delay_departure
+            FROM
+                records
+            WHERE
+                source = :source AND
+                route_id = :route_id AND
+                trip_start_date >= :from AND
+                trip_start_date <= :to AND
+                trip_start_time IS NOT NULL AND
+                (delay_arrival IS NOT NULL OR delay_departure IS NOT NULL)",
+        )?;
+
+        let mut result = con.exec_iter(
+            &stmt,
+            params! {
+                "source" => &self.main.source,
+                "route_id" => route_id,
+                "from" => from,
+                "to" => to,
+            },
+        )?;
+
+        let result_set = result.next_set().unwrap()?;
+
+        let mut delays_by_vehicle : HashMap<(String, NaiveDate, i64), Vec<f32>> = HashMap::new();
+        for row in result_set {
+            let row = row?;
+            let trip_id : String = row.get(0).unwrap();
+            let start_date : NaiveDate = row.get(1).unwrap();
+            let start_time : Duration = row.get(2).unwrap();
+            let delay : Option<i32> = row.get_opt::<i32, _>(3).unwrap().ok().or(row.get_opt::<i32, _>(4).unwrap().ok());
+            if let Some(delay) = delay {
+                delays_by_vehicle.entry((trip_id, start_date, start_time.num_seconds())).or_insert_with(Vec::new).push(delay as f32);
+            }
+        }
+
+        let mut summaries : HashMap<(NaiveDate, u8), DaySummary> = HashMap::new();
+        for ((trip_id, start_date, start_time), delays) in delays_by_vehicle {
+            let start = date_and_time_local(&Local.from_local_date(&start_date).unwrap(), start_time as i32);
+            let ts = TimeSlot::from_datetime(start);
+            let summary = summaries.entry((start_date, ts.id)).or_insert_with(DaySummary::default);
+            summary.vehicle_delays.push(median(&delays));
+            summary.vehicles.push(FlaggedVehicle { trip_id, start_date, start_time });
+        }
+        Ok(summaries)
+    }
+
+    fn write_csv(&self, report: &AnomalyReport) -> FnResult<()> {
+        let mut file = File::create(format!("{}/anomalies.csv", self.main.dir))?;
+        writeln!(file, "route_id;route_short_name;date;time_slot;vehicles;median_delay;p90_delay;history_median;history_mad;history_day_count;score")?;
+        for anomaly in &report.anomalies {
+            writeln!(
+                file,
+                "{};{};{};{};{};{:.0};{:.0};{:.0};{:.0};{};{:.2}",
+                anomaly.route_id,
+                anomaly.route_short_name,
+                anomaly.date,
+                anomaly.time_slot,
+                anomaly.vehicles.len(),
+                anomaly.median_delay,
+                anomaly.p90_delay,
+                anomaly.history_median,
+                anomaly.history_mad,
+                anomaly.history_day_count,
+                anomaly.score
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the vehicles that shall be left out of the curve computation, if requested with `--exclude-anomalies`.
+pub fn get_exclusions(main: &Main, args: &ArgMatches) -> FnResult<AnomalyExclusions> {
+    if args.is_present("exclude-anomalies") {
+        AnomalyExclusions::load(&main.dir)
+    } else {
+        Ok(AnomalyExclusions::none())
+    }
+}
+
+/// Returns the value at the given quantile (between 0 and 1) of the values, without interpolation.
+fn quantile(values: &Vec<f32>, q: f32) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut sorted = values.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let index = ((sorted.len() - 1) as f32 * q).round() as usize;
+    sorted[index]
+}
+
+fn median(values: &Vec<f32>) -> f32 {
+    quantile(values, 0.5)
+}
@@ -0,0 +1,423 @@
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use chrono::{Duration, Local, NaiveDate, NaiveDateTime};
+use chrono::offset::TimeZone;
+use clap::ArgMatches;
+use gtfs_structures::Gtfs;
+use mysql::*;
+use mysql::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use dystonse_curves::tree::{SerdeFormat, NodeData};
+
+use super::Analyser;
+use crate::types::{DelayStatistics, EventType, TimeSlot};
+
+use crate::{FnResult, Main, date_and_time_local};
+
+/// Reports how much realtime data there is for each route, route variant and time slot,
+/// and when the realtime feed was interrupted.
+pub struct CoverageReporter<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches
+}
+
+/// Coverage of one route variant in one time slot.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CoverageRow {
+    pub route_id: String,
+    pub route_short_name: String,
+    pub route_variant: u64,
+    pub time_slot: String,
+    /// number of vehicles (trips on a certain day) with at least one recorded delay
+    pub vehicles: usize,
+    pub records: usize,
+    /// number of stops of the route variant with at least one recorded delay
+    pub stops_with_data: usize,
+    pub stop_count: usize,
+    /// number of curve sets (arrival and departure) in all_curves.exp, i.e. stop pairs with more than 20 pairs of delays
+    pub curve_sets: Option<usize>,
+    pub possible_curve_sets: usize,
+}
+
+/// A time window in which scheduled trips were running, but no realtime data was recorded at all.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Gap {
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub minutes: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CoverageReport {
+    pub source: String,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub gap_minutes: i64,
+    pub rows: Vec<CoverageRow>,
+    pub gaps: Vec<Gap>,
+}
+
+/// Data which is collected for each route variant and time slot.
+#[derive(Default)]
+struct VariantCoverage {
+    vehicles: HashSet<(String, NaiveDate, i64)>,
+    records: usize,
+    stops: HashSet<u16>,
+}
+
+impl<'a> CoverageReporter<'a> {
+    pub fn run_coverage(&self) -> FnResult<()> {
+        let yesterday = Local::today().naive_local().pred();
+        let to = match self.args.value_of("to") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => yesterday,
+        };
+        let from = match self.args.value_of("from") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => to - Duration::days(27),
+        };
+        let gap_minutes : i64 = self.args.value_of("gap-minutes").unwrap().parse()?; // has a default value
+        let output_dir = match self.args.value_of("output") {
+            Some(dir) => dir.to_string(),
+            None => format!("{}/coverage", self.main.dir),
+        };
+
+        let statistics = match self.main.get_delay_statistics() {
+            Ok(statistics) => Some(statistics),
+            Err(e) => {
+                println!("Could not load delay statistics, so curve sets can't be counted: {}", e);
+                None
+            }
+        };
+
+        let route_ids : Vec<String> = match self.args.values_of("route-ids") {
+            Some(route_ids) => route_ids.map(String::from).collect(),
+            None => self.analyser.schedule.routes.keys().cloned().collect(),
+        };
+        println!("Computing coverage of {} routes from {} to {}…", route_ids.len(), from, to);
+
+        let mut rows = Vec::new();
+        for route_id in &route_ids {
+            match self.get_coverage_for_route(route_id, from, to, &statistics) {
+                Ok(route_rows) => rows.extend(route_rows),
+                Err(e) => println!("Could not compute coverage for route {}: {}", route_id, e)
+            }
+        }
+        // the sort is stable, so the time slots stay in their original order
+        rows.sort_by(|a, b| (&a.route_id, a.route_variant).cmp(&(&b.route_id, b.route_variant)));
+
+        println!("Looking for gaps in the realtime data…");
+        let gaps = self.get_gaps(from, to, gap_minutes)?;
+
+        let report = CoverageReport {
+            source: self.main.source.clone(),
+            from,
+            to,
+            gap_minutes,
+            rows,
+            gaps,
+        };
+
+        fs::create_dir_all(&output_dir)?;
+        self.write_csv(&report, &output_dir)?;
+        report.save_to_file(&output_dir, "coverage", &SerdeFormat::Json)?;
+        println!("Coverage report written to {}, found {} gaps.", output_dir, report.gaps.len());
+
+        Ok(())
+    }
+
+    fn get_coverage_for_route(&self, route_id: &str, from: NaiveDate, to: NaiveDate, statistics: &Option<Arc<DelayStatistics>>) -> FnResult<Vec<CoverageRow>> {
+        let schedule = &self.analyser.schedule;
+        let route = schedule.get_route(route_id)?;
+
+        // number of stops of each route variant, according to the schedule:
+        let mut stop_counts : HashMap<u64, usize> = HashMap::new();
+        for trip in schedule.trips.values().filter(|trip| trip.route_id == route_id) {
+            if let Some(Ok(route_variant)) = trip.route_variant.as_ref().map(|rv| rv.parse::<u64>()) {
+                stop_counts.insert(route_variant, trip.stop_times.len());
+            }
+        }
+
+        let mut con = self.main.pool.get_conn()?;
+        let stmt = con.prep(
+            r"SELECT
+                trip_id,
+                trip_start_date,
+                trip_start_time,
+                stop_sequence,
+                route_variant
+            FROM
+                records
+            WHERE
+                source = :source AND
+                route_id = :route_id AND
+                trip_start_date >= :from AND
+                trip_start_date <= :to AND
+                (delay_arrival IS NOT NULL OR delay_departure IS NOT NULL)",
+        )?;
+
+        let mut result = con.exec_iter(
+            &stmt,
+            params! {
+                "source" => &self.main.source,
+                "route_id" => route_id,
+                "from" => from,
+                "to" => to,
+            },
+        )?;
+
+        let result_set = result.next_set().unwrap()?;
+
+        let mut coverage : HashMap<(u64, u8), VariantCoverage> = HashMap::new();
+        for row in result_set {
+            let row = row?;
+            let trip_id : String = row.get(0).unwrap();
+            let start_date : NaiveDate = row.get(1).unwrap();
+            let start_time : Duration = row.get(2).unwrap();
+            let stop_sequence : u16 = row.get(3).unwrap();
+            let route_variant : u64 = row.get(4).unwrap();
+
+            // the time slot of the whole vehicle is determined by its start time
+            let start = date_and_time_local(&Local.from_local_date(&start_date).unwrap(), start_time.num_seconds() as i32);
+            for ts in &[TimeSlot::from_datetime(start), &TimeSlot::DEFAULT] {
+                let variant_coverage = coverage.entry((route_variant, ts.id)).or_insert_with(VariantCoverage::default);
+                variant_coverage.vehicles.insert((trip_id.clone(), start_date, start_time.num_seconds()));
+                variant_coverage.records += 1;
+                variant_coverage.stops.insert(stop_sequence);
+            }
+        }
+
+        let mut rows = Vec::new();
+        for (route_variant, stop_count) in stop_counts {
+            let variant_data = statistics.as_ref().map(|s| {
+                s.specific.get(route_id).and_then(|route_data| route_data.variants.get(&route_variant))
+            });
+            for ts in &TimeSlot::TIME_SLOTS_WITH_DEFAULT {
+                let empty = VariantCoverage::default();
+                let variant_coverage = coverage.get(&(route_variant, ts.id)).unwrap_or(&empty);
+                let curve_sets = variant_data.map(|variant_data| match variant_data {
+                    Some(variant_data) => EventType::TYPES.iter()
+                        .map(|et| variant_data.curve_sets[**et].keys().filter(|key| key.time_slot.id == ts.id).count())
+                        .sum::<usize>(),
+                    None => 0
+                });
+                rows.push(CoverageRow {
+                    route_id: route_id.to_string(),
+                    route_short_name: route.short_name.clone(),
+                    route_variant,
+                    time_slot: ts.description.to_string(),
+                    vehicles: variant_coverage.vehicles.len(),
+                    records: variant_coverage.records,
+                    stops_with_data: variant_coverage.stops.len(),
+                    stop_count,
+                    curve_sets,
+                    // one curve set per pair of stops and event type:
+                    possible_curve_sets: stop_count * stop_count.saturating_sub(1),
+                });
+            }
+        }
+
+        Ok(rows)
+    }
+
+    /// Finds all time windows of at least `gap_minutes` within which trips were scheduled, but no records were recorded.
+    fn get_gaps(&self, from: NaiveDate, to: NaiveDate, gap_minutes: i64) -> FnResult<Vec<Gap>> {
+        let mut con = self.main.pool.get_conn()?;
+        let times : Vec<NaiveDateTime> = con.exec(
+            r"SELECT DISTINCT
+                time_of_recording
+            FROM
+                records
+            WHERE
+                source = :source AND
+                time_of_recording >= :from AND
+                time_of_recording < :to
+            ORDER BY
+                time_of_recording",
+            params! {
+                "source" => &self.main.source,
+                "from" => from.and_hms(0, 0, 0),
+                "to" => to.succ().and_hms(0, 0, 0),
+            },
+        )?;
+
+        let service_periods = get_service_periods(&self.analyser.schedule, from, to);
+        Ok(find_gaps(times, (from.and_hms(0, 0, 0), to.succ().and_hms(0, 0, 0)), &service_periods, gap_minutes))
+    }
+
+    fn write_csv(&self, report: &CoverageReport, output_dir: &str) -> FnResult<()> {
+        let mut file = File::create(format!("{}/coverage.csv", output_dir))?;
+        writeln!(file, "route_id;route_short_name;route_variant;time_slot;vehicles;records;stops_with_data;stop_count;curve_sets;possible_curve_sets")?;
+        for row in &report.rows {
+            writeln!(
+                file,
+                "{};{};{};{};{};{};{};{};{};{}",
+                row.route_id,
+                row.route_short_name,
+                row.route_variant,
+                row.time_slot,
+                row.vehicles,
+                row.records,
+                row.stops_with_data,
+                row.stop_count,
+                row.curve_sets.map(|c| c.to_string()).unwrap_or_default(),
+                row.possible_curve_sets
+            )?;
+        }
+
+        let mut file = File::create(format!("{}/coverage_gaps.csv", output_dir))?;
+        writeln!(file, "from;to;minutes")?;
+        for gap in &report.gaps {
+            writeln!(file, "{};{};{}", gap.from, gap.to, gap.minutes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Merges overlapping intervals, and returns them in ascending order.
+fn merge_intervals<T: Ord + Copy>(mut intervals: Vec<(T, T)>) -> Vec<(T, T)> {
+    intervals.sort();
+    let mut merged : Vec<(T, T)> = Vec::new();
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = max(last.1, end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Returns the time windows in which scheduled trips are running between the start of `from` and the end of `to`,
+/// from the first departure to the last arrival of each trip.
+fn get_service_periods(schedule: &Gtfs, from: NaiveDate, to: NaiveDate) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    // the times of day (in seconds, which may exceed 24 hours) in which the trips of each service are running
+    let mut times_by_service : HashMap<&String, Vec<(u32, u32)>> = HashMap::new();
+    for trip in schedule.trips.values() {
+        let first = trip.stop_times.first().and_then(|st| st.departure_time.or(st.arrival_time));
+        let last = trip.stop_times.last().and_then(|st| st.arrival_time.or(st.departure_time));
+        if let (Some(first), Some(last)) = (first, last) {
+            times_by_service.entry(&trip.service_id).or_insert_with(Vec::new).push((first, last));
+        }
+    }
+
+    // trips of the previous day may still be running after midnight
+    let first_day = from.pred();
+    let mut periods = Vec::new();
+    for (service_id, times) in times_by_service {
+        let times = merge_intervals(times);
+        for offset in schedule.trip_days(service_id, first_day) {
+            let day = first_day + Duration::days(offset as i64);
+            if day > to {
+                continue;
+            }
+            let service_day = Local.from_local_date(&day).unwrap();
+            for (start, end) in &times {
+                periods.push((
+                    date_and_time_local(&service_day, *start as i32).naive_local(),
+                    date_and_time_local(&service_day, *end as i32).naive_local(),
+                ));
+            }
+        }
+    }
+    let range = (from.and_hms(0, 0, 0), to.succ().and_hms(0, 0, 0));
+    merge_intervals(periods).into_iter()
+        .map(|(start, end)| (max(start, range.0), min(end, range.1)))
+        .filter(|(start, end)| start < end)
+        .collect()
+}
+
+/// Finds the parts of the time windows between consecutive recordings that are at least `gap_minutes` long
+/// and in which scheduled trips are running. The borders of `range` count as recordings, so that gaps
+/// at the beginning and end are found, too.
+fn find_gaps(times: Vec<NaiveDateTime>, range: (NaiveDateTime, NaiveDateTime), service_periods: &[(NaiveDateTime, NaiveDateTime)], gap_minutes: i64) -> Vec<Gap> {
+    let mut bounded_times = vec![range.0];
+    bounded_times.extend(times);
+    bounded_times.push(range.1);
+
+    let mut gaps = Vec::new();
+    for window in bounded_times.windows(2) {
+        for (start, end) in service_periods {
+            let from = max(window[0], *start);
+            let to = min(window[1], *end);
+            let minutes = (to - from).num_minutes();
+            if to > from && minutes >= gap_minutes {
+                gaps.push(Gap { from, to, minutes });
+            }
+        }
+    }
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_structures::{CalendarDate, Exception, StopTime, Trip};
+
+    fn time(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 6, day).and_hms(hour, minute, 0)
+    }
+
+    fn trip(id: &str, first_departure: u32, last_arrival: u32) -> Trip {
+        Trip {
+            id: id.to_string(),
+            service_id: String::from("service"),
+            stop_times: vec![
+                StopTime { stop_sequence: 1, departure_time: Some(first_departure), ..Default::default() },
+                StopTime { stop_sequence: 2, arrival_time: Some(last_arrival), ..Default::default() },
+            ],
+            ..Default::default()
+        }
+    }
+
+    /// Trips from 6:00 to 23:00 and from 24:30 to 25:30, on June 8th only.
+    fn schedule() -> Gtfs {
+        let mut schedule = Gtfs::default();
+        for trip in vec![trip("day", 6 * 3600, 12 * 3600), trip("evening", 11 * 3600, 23 * 3600), trip("night", 88200, 91800)] {
+            schedule.trips.insert(trip.id.clone(), trip);
+        }
+        schedule.calendar_dates.insert(String::from("service"), vec![CalendarDate {
+            service_id: String::from("service"),
+            date: NaiveDate::from_ymd(2020, 6, 8),
+            exception_type: Exception::Added,
+        }]);
+        schedule
+    }
+
+    #[test]
+    fn test_service_periods() {
+        let schedule = schedule();
+        let day = NaiveDate::from_ymd(2020, 6, 8);
+        assert_eq!(get_service_periods(&schedule, day, day), vec![(time(8, 6, 0), time(8, 23, 0))]);
+        assert_eq!(get_service_periods(&schedule, day, day.succ()), vec![(time(8, 6, 0), time(8, 23, 0)), (time(9, 0, 30), time(9, 1, 30))]);
+        // the night trip runs after midnight, but belongs to the previous service day
+        assert_eq!(get_service_periods(&schedule, day.succ(), day.succ()), vec![(time(9, 0, 30), time(9, 1, 30))]);
+        assert!(get_service_periods(&schedule, day.pred(), day.pred()).is_empty());
+    }
+
+    #[test]
+    fn test_gaps_only_while_trips_are_running() {
+        let schedule = schedule();
+        let day = NaiveDate::from_ymd(2020, 6, 8);
+        let service_periods = get_service_periods(&schedule, day, day);
+        let range = (time(8, 0, 0), time(9, 0, 0));
+
+        // recordings every 10 minutes from 5:00 to 11:50 and from 14:00 to 22:50, and one in the night
+        let mut times = vec![time(8, 3, 0)];
+        times.extend((30..72).chain(84..138).map(|i| time(8, i / 6, (i % 6) * 10)));
+        let gaps = find_gaps(times, range, &service_periods, 30);
+        // the gaps in the night and after 23:00 are not reported, as no trips are running
+        let gaps : Vec<(NaiveDateTime, NaiveDateTime, i64)> = gaps.into_iter().map(|gap| (gap.from, gap.to, gap.minutes)).collect();
+        assert_eq!(gaps, vec![(time(8, 11, 50), time(8, 14, 0), 130)]);
+
+        // without any recordings, the whole service period is a gap
+        let gaps = find_gaps(Vec::new(), range, &service_periods, 30);
+        let gaps : Vec<(NaiveDateTime, NaiveDateTime, i64)> = gaps.into_iter().map(|gap| (gap.from, gap.to, gap.minutes)).collect();
+        assert_eq!(gaps, vec![(time(8, 6, 0), time(8, 23, 0), 1020)]);
+    }
+}
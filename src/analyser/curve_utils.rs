@@ -1,10 +1,18 @@
+use std::collections::HashMap;
+
+use clap::ArgMatches;
+use gtfs_structures::RouteType;
 use simple_error::bail;
 
 use dystonse_curves::irregular_dynamic::*;
 use dystonse_curves::Curve;
 
+use crate::types::{CurveData, DefaultCurves, EventType, RouteSection, SmoothingMethod};
 use crate::FnResult;
 
+/// Number of points at which smoothed curves are evaluated before they are simplified.
+const SMOOTHED_CURVE_POINTS : usize = 100;
+
 // This method determines whether there should be another marker between the ones already present at lower and upper.
 // Upper and lower are initial delay by seconds.
 pub fn recurse(initial_delay_curve: &IrregularDynamicCurve<f32, f32>, markers: &mut Vec<f32>, lower: f32, upper: f32, count: f32) {
@@ -41,7 +49,9 @@ pub fn recurse(initial_delay_curve: &IrregularDynamicCurve<f32, f32>, markers: &
     }
 }
 
-pub fn get_weight(delay: f32, focus: Option<f32>, min_delay: f32, max_delay: f32) -> f32 {
+/// Returns the weight of a delay value for a curve. `sample_weight` is the weight the value
+/// already has on its own, e.g. from recency weighting, or 1.0.
+pub fn get_weight(delay: f32, sample_weight: f32, focus: Option<f32>, min_delay: f32, max_delay: f32) -> f32 {
     // handling delay values outside of given bounds: always 0.
     if delay < min_delay || delay > max_delay {
         return 0.0;
@@ -51,14 +61,14 @@ pub fn get_weight(delay: f32, focus: Option<f32>, min_delay: f32, max_delay: f32
         // if focus is given, weight is 1 at the focus and goes down to zero 
         // towards the bounds given by min_delay and max_delay
         if delay == focus {
-            1.0
+            sample_weight
         } else if delay < focus {
-            (delay - min_delay) / (focus - min_delay)
+            sample_weight * (delay - min_delay) / (focus - min_delay)
         } else {
-            1.0 - ((delay - focus) / (max_delay - focus))
+            sample_weight * (1.0 - ((delay - focus) / (max_delay - focus)))
         }
     } else {
-        1.0
+        sample_weight
     }
 }
 
@@ -68,7 +78,7 @@ pub fn make_curve(values: &Vec<f32>, focus: Option<f32>) -> FnResult<(IrregularD
     let min_delay = *own_values.first().unwrap();
     let max_delay = *own_values.last().unwrap();
     
-    let pairs: Vec<(f32,f32)> = own_values.iter().map(|v| (*v, get_weight(*v, focus, min_delay, max_delay))).collect();
+    let pairs: Vec<(f32,f32)> = own_values.iter().map(|v| (*v, get_weight(*v, 1.0, focus, min_delay, max_delay))).collect();
 
     let sum_of_weights: f32 = pairs.iter().map(|(_v, w)| *w).sum();
 
@@ -91,4 +101,306 @@ pub fn make_curve(values: &Vec<f32>, focus: Option<f32>) -> FnResult<(IrregularD
     tups.last_mut().unwrap().y = 1.0;
 
     Ok((IrregularDynamicCurve::new(tups), sum_of_weights))
-}
\ No newline at end of file
+}
+
+/// Returns the smoothing method selected with `--smoothing`.
+pub fn get_smoothing_method(args: &ArgMatches) -> SmoothingMethod {
+    match args.value_of("smoothing") {
+        Some("kde") => SmoothingMethod::Kde,
+        Some("lognormal") => SmoothingMethod::LogNormal,
+        _ => SmoothingMethod::Empirical,
+    }
+}
+
+/// Like `make_curve`, but for values that have a weight on their own, given as `(value, weight)`,
+/// e.g. from the histograms of recency weighted samples. Unless the method is `Empirical`, the
+/// distribution of the values is smoothed, using the weights of the values (see `get_weight`) as well.
+pub fn make_weighted_curve(values: &[(f32, f32)], focus: Option<f32>, method: SmoothingMethod) -> FnResult<(IrregularDynamicCurve<f32, f32>, f32)> {
+    if values.is_empty() {
+        bail!("Can't make a curve without values.");
+    }
+
+    let mut own_values = values.to_vec();
+    own_values.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let min_delay = own_values.first().unwrap().0;
+    let max_delay = own_values.last().unwrap().0;
+
+    // Most delays are rounded to multiples of 12 seconds, so equal values are combined
+    // to keep the number of kernels small.
+    let mut pairs : Vec<(f32, f32)> = Vec::new();
+    for (v, sample_weight) in own_values {
+        let w = get_weight(v, sample_weight, focus, min_delay, max_delay);
+        match pairs.last_mut() {
+            Some((last_v, last_w)) if *last_v == v => *last_w += w,
+            _ => pairs.push((v, w)),
+        }
+    }
+    if method == SmoothingMethod::Empirical {
+        return empirical_points(&pairs);
+    }
+    pairs.retain(|(_v, w)| *w > 0.0);
+
+    let sum_of_weights: f32 = pairs.iter().map(|(_v, w)| *w).sum();
+    if pairs.len() < 2 {
+        bail!("Curve would be made from only {} distinct values, skipping.", pairs.len());
+    }
+
+    let mut tups = match method {
+        SmoothingMethod::Kde => kde_points(&pairs, sum_of_weights)?,
+        SmoothingMethod::LogNormal => log_normal_points(&pairs, sum_of_weights)?,
+        SmoothingMethod::Empirical => unreachable!(),
+    };
+
+    tups.first_mut().unwrap().y = 0.0;
+    tups.last_mut().unwrap().y = 1.0;
+
+    Ok((IrregularDynamicCurve::new(tups), sum_of_weights))
+}
+
+/// The empirical distribution of the sorted, distinct values with their weights:
+/// the y value at each value is the share of the weights below it.
+fn empirical_points(pairs: &[(f32, f32)]) -> FnResult<(IrregularDynamicCurve<f32, f32>, f32)> {
+    if pairs.len() < 2 {
+        bail!("Curve would have only {} points, skipping.", pairs.len());
+    }
+    let sum_of_weights: f32 = pairs.iter().map(|(_v, w)| *w).sum();
+    if sum_of_weights <= 0.0 {
+        bail!("Values have no weight, skipping.");
+    }
+
+    let mut tups = Vec::<Tup<f32, f32>>::with_capacity(pairs.len());
+    let mut cumulated = 0.0;
+    for (x, w) in pairs {
+        tups.push(Tup {x: *x, y: cumulated / sum_of_weights});
+        cumulated += w;
+    }
+
+    tups.first_mut().unwrap().y = 0.0;
+    tups.last_mut().unwrap().y = 1.0;
+
+    Ok((IrregularDynamicCurve::new(tups), sum_of_weights))
+}
+
+/// Gaussian kernel density estimation, with the bandwidth chosen by Silverman's rule of thumb.
+/// `pairs` are the sorted values with their weights.
+fn kde_points(pairs: &[(f32, f32)], sum_of_weights: f32) -> FnResult<Vec<Tup<f32, f32>>> {
+    let mean = pairs.iter().map(|(v, w)| v * w).sum::<f32>() / sum_of_weights;
+    let variance = pairs.iter().map(|(v, w)| w * (v - mean).powi(2)).sum::<f32>() / sum_of_weights;
+    let iqr = weighted_quantile(pairs, sum_of_weights, 0.75) - weighted_quantile(pairs, sum_of_weights, 0.25);
+    let spread = if iqr > 0.0 { variance.sqrt().min(iqr / 1.34) } else { variance.sqrt() };
+    // the weights are not all equal if there is a focus, so the effective number of values is used
+    let effective_count = sum_of_weights.powi(2) / pairs.iter().map(|(_v, w)| w * w).sum::<f32>();
+    let bandwidth = 0.9 * spread * effective_count.powf(-0.2);
+    if bandwidth.is_nan() || bandwidth <= 0.0 {
+        bail!("Values have no spread, can't estimate their density.");
+    }
+
+    let lower = pairs.first().unwrap().0 - 3.0 * bandwidth;
+    let upper = pairs.last().unwrap().0 + 3.0 * bandwidth;
+    Ok((0..SMOOTHED_CURVE_POINTS).map(|i| {
+        let x = lower + (upper - lower) * i as f32 / (SMOOTHED_CURVE_POINTS - 1) as f32;
+        let y = pairs.iter().map(|(v, w)| w * normal_cdf((x - v) / bandwidth)).sum::<f32>() / sum_of_weights;
+        Tup { x, y }
+    }).collect())
+}
+
+/// Fits a log-normal distribution to the values, using the weighted mean and standard deviation
+/// of their logarithms. As delays can be negative, the distribution is shifted to start a bit
+/// below the smallest value. `pairs` are the sorted values with their weights.
+fn log_normal_points(pairs: &[(f32, f32)], sum_of_weights: f32) -> FnResult<Vec<Tup<f32, f32>>> {
+    let min_delay = pairs.first().unwrap().0;
+    let max_delay = pairs.last().unwrap().0;
+    // leave at least the usual rounding step of 12 seconds below the smallest value
+    let shift = min_delay - f32::max(12.0, 0.1 * (max_delay - min_delay));
+
+    let mean = pairs.iter().map(|(v, w)| w * (v - shift).ln()).sum::<f32>() / sum_of_weights;
+    let variance = pairs.iter().map(|(v, w)| w * ((v - shift).ln() - mean).powi(2)).sum::<f32>() / sum_of_weights;
+    let deviation = variance.sqrt();
+    if deviation.is_nan() || deviation <= 0.0 {
+        bail!("Values have no spread, can't fit a distribution.");
+    }
+
+    // from four standard deviations below to four above the mean of the logarithms
+    Ok((0..SMOOTHED_CURVE_POINTS).map(|i| {
+        let z = -4.0 + 8.0 * i as f32 / (SMOOTHED_CURVE_POINTS - 1) as f32;
+        Tup { x: shift + (mean + deviation * z).exp(), y: normal_cdf(z) }
+    }).collect())
+}
+
+/// Returns the smallest of the sorted values at which the share `q` of the weights is reached.
+fn weighted_quantile(pairs: &[(f32, f32)], sum_of_weights: f32, q: f32) -> f32 {
+    let mut cumulated = 0.0;
+    for (v, w) in pairs {
+        cumulated += w;
+        if cumulated >= q * sum_of_weights {
+            return *v;
+        }
+    }
+    pairs.last().unwrap().0
+}
+
+/// Cumulative distribution function of the standard normal distribution,
+/// using the approximation of the error function by Abramowitz and Stegun (7.1.26).
+fn normal_cdf(z: f32) -> f32 {
+    let x = (z.abs() / std::f32::consts::SQRT_2) as f64;
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let polynomial = ((((1.061_405_429 * t - 1.453_152_027) * t + 1.421_413_741) * t - 0.284_496_736) * t + 0.254_829_592) * t;
+    let erf = (1.0 - polynomial * (-x * x).exp()) as f32;
+    if z >= 0.0 {
+        0.5 * (1.0 + erf)
+    } else {
+        0.5 * (1.0 - erf)
+    }
+}
+
+/// Mixes a curve with a parent curve, e.g. a default curve. The curve gets the weight
+/// `sample_size / (sample_size + prior_samples)`, so that the parent dominates for small samples.
+pub fn blend_curves(curve: &IrregularDynamicCurve<f32, f32>, sample_size: u32, parent: &IrregularDynamicCurve<f32, f32>, prior_samples: f32) -> IrregularDynamicCurve<f32, f32> {
+    let weight = sample_size as f32 / (sample_size as f32 + prior_samples);
+    mix_curves(curve, parent, weight)
+}
+
+/// Returns the mixture of two distributions, where the first one has the share `weight` (between 0 and 1).
+pub fn mix_curves(a: &IrregularDynamicCurve<f32, f32>, b: &IrregularDynamicCurve<f32, f32>, weight: f32) -> IrregularDynamicCurve<f32, f32> {
+    let mut xs = a.get_values_as_vectors().0;
+    xs.extend(b.get_values_as_vectors().0);
+    xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    xs.dedup();
+
+    let y_at_x = |c: &IrregularDynamicCurve<f32, f32>, x: f32| c.y_at_x(x).max(0.0).min(1.0);
+    let tups = xs.into_iter().map(|x| Tup { x, y: weight * y_at_x(a, x) + (1.0 - weight) * y_at_x(b, x) }).collect();
+    IrregularDynamicCurve::new(tups)
+}
+
+/// The default curves with which specific general delay curves are blended (see `blend_curves`).
+pub struct BlendParents {
+    /// The general delay curves don't depend on the time slot, so their parent is
+    /// the average of the default curves of all time slots.
+    curves: HashMap<(RouteType, RouteSection, EventType), IrregularDynamicCurve<f32, f32>>,
+    /// sample size at which a curve and its parent have the same weight
+    prior_samples: f32,
+}
+
+impl BlendParents {
+    pub fn new(default_curves: &DefaultCurves, prior_samples: f32) -> Self {
+        let mut curves_by_section : HashMap<(RouteType, RouteSection, EventType), Vec<&IrregularDynamicCurve<f32, f32>>> = HashMap::new();
+        for (key, curve_data) in &default_curves.all_default_curves {
+            curves_by_section.entry((key.route_type, key.route_section.clone(), key.event_type)).or_insert_with(Vec::new).push(&curve_data.curve);
+        }
+        BlendParents {
+            curves: curves_by_section.into_iter().map(|(key, curves)| (key, IrregularDynamicCurve::<f32, f32>::average(&curves))).collect(),
+            prior_samples,
+        }
+    }
+
+    pub fn blend(&self, curve_data: &mut CurveData, route_type: RouteType, route_section: RouteSection, event_type: EventType) {
+        if let Some(parent) = self.curves.get(&(route_type, route_section, event_type)) {
+            curve_data.curve = blend_curves(&curve_data.curve, curve_data.sample_size, parent, self.prior_samples);
+        }
+    }
+}
+
+/// Returns the index within `super_stop_ids` at which `sub_stop_ids` start, if all of
+/// `sub_stop_ids` appear there consecutively and in the same order.
+pub fn sub_trip_offset(super_stop_ids: &[String], sub_stop_ids: &[String]) -> Option<usize> {
+    if sub_stop_ids.is_empty() {
+        return None;
+    }
+    // from https://stackoverflow.com/a/35907071
+    super_stop_ids
+        .windows(sub_stop_ids.len())
+        .position(|window| window == sub_stop_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() <= tolerance, "expected {} ± {}, got {}", expected, tolerance, actual);
+    }
+
+    fn curve(points: &[(f32, f32)]) -> IrregularDynamicCurve<f32, f32> {
+        IrregularDynamicCurve::new(points.iter().map(|(x, y)| Tup { x: *x, y: *y }).collect())
+    }
+
+    fn assert_monotonic(tups: &[Tup<f32, f32>]) {
+        for (a, b) in tups.iter().tuple_windows() {
+            assert!(a.x < b.x && a.y <= b.y, "not monotonic: {:?}, {:?}", (a.x, a.y), (b.x, b.y));
+        }
+    }
+
+    #[test]
+    fn test_normal_cdf() {
+        assert_close(normal_cdf(0.0), 0.5, 1e-6);
+        assert_close(normal_cdf(1.96), 0.975, 1e-4);
+        assert_close(normal_cdf(-1.96), 0.025, 1e-4);
+        assert_close(normal_cdf(-8.0), 0.0, 1e-6);
+        assert_close(normal_cdf(8.0), 1.0, 1e-6);
+    }
+
+    #[test]
+    fn test_weighted_quantile() {
+        let pairs = [(-12.0, 1.0), (0.0, 1.0), (24.0, 2.0)];
+        assert_eq!(weighted_quantile(&pairs, 4.0, 0.0), -12.0);
+        assert_eq!(weighted_quantile(&pairs, 4.0, 0.25), -12.0);
+        assert_eq!(weighted_quantile(&pairs, 4.0, 0.5), 0.0);
+        assert_eq!(weighted_quantile(&pairs, 4.0, 0.75), 24.0);
+        assert_eq!(weighted_quantile(&pairs, 4.0, 1.0), 24.0);
+    }
+
+    #[test]
+    fn test_kde_points() {
+        // symmetric around 12, so the median of the density is there as well
+        let pairs = [(-24.0, 1.0), (0.0, 2.0), (12.0, 3.0), (24.0, 2.0), (48.0, 1.0)];
+        let tups = kde_points(&pairs, 9.0).unwrap();
+        assert_eq!(tups.len(), SMOOTHED_CURVE_POINTS);
+        assert_monotonic(&tups);
+        assert_close(tups.first().unwrap().y, 0.0, 0.01);
+        assert_close(tups.last().unwrap().y, 1.0, 0.01);
+        assert_close(IrregularDynamicCurve::new(tups).x_at_y(0.5), 12.0, 0.5);
+
+        assert!(kde_points(&[(12.0, 1.0)], 1.0).is_err());
+    }
+
+    #[test]
+    fn test_log_normal_points() {
+        // the distribution is shifted to start 12 s below the smallest value,
+        // and its median is at the geometric mean of the shifted values
+        let pairs = [(0.0, 1.0), (12.0, 1.0), (48.0, 1.0)];
+        let tups = log_normal_points(&pairs, 3.0).unwrap();
+        assert_eq!(tups.len(), SMOOTHED_CURVE_POINTS);
+        assert_monotonic(&tups);
+        assert!(tups.first().unwrap().x > -12.0);
+        let median = -12.0 + (12.0f32 * 24.0 * 60.0).cbrt();
+        assert_close(IrregularDynamicCurve::new(tups).x_at_y(0.5), median, 0.5);
+
+        assert!(log_normal_points(&[(12.0, 1.0)], 1.0).is_err());
+    }
+
+    #[test]
+    fn test_mix_curves() {
+        let a = curve(&[(0.0, 0.0), (60.0, 0.5), (120.0, 1.0)]);
+        let b = curve(&[(-60.0, 0.0), (30.0, 1.0)]);
+        let y_at_x = |c: &IrregularDynamicCurve<f32, f32>, x: f32| c.y_at_x(x).max(0.0).min(1.0);
+
+        // with the weight 0 or 1, the mixture is one of the curves
+        let only_a = mix_curves(&a, &b, 1.0);
+        let only_b = mix_curves(&a, &b, 0.0);
+        let half = mix_curves(&a, &b, 0.5);
+        let blended = blend_curves(&a, 30, &b, 10.0);
+        for x in (-90..=150).step_by(15) {
+            let x = x as f32;
+            assert_close(y_at_x(&only_a, x), y_at_x(&a, x), 1e-6);
+            assert_close(y_at_x(&only_b, x), y_at_x(&b, x), 1e-6);
+            assert_close(y_at_x(&half, x), 0.5 * (y_at_x(&a, x) + y_at_x(&b, x)), 1e-6);
+            assert_close(y_at_x(&blended, x), 0.75 * y_at_x(&a, x) + 0.25 * y_at_x(&b, x), 1e-6);
+        }
+        assert_eq!(only_a.get_values_as_vectors().0, vec![-60.0, 0.0, 30.0, 60.0, 120.0]);
+
+        // without prior samples, the parent has no influence, and without samples, the curve has none
+        assert_close(blend_curves(&a, 10, &b, 0.0).x_at_y(0.5), 60.0, 1e-4);
+        assert_close(blend_curves(&a, 0, &b, 10.0).x_at_y(0.5), -15.0, 1e-4);
+    }
+}
@@ -1,13 +1,16 @@
+use chrono::Local;
 use clap::ArgMatches;
+use simple_error::bail;
 
 use dystonse_curves::tree::{SerdeFormat, NodeData};
 
 use super::Analyser;
-use crate::types::DelayStatistics;
+use crate::types::{DelayStatistics, CurveSamples, DateRange, SegmentData};
 
 use crate::{ FnResult, Main };
 
 use std::collections::HashMap;
+use std::path::Path;
 
 use super::{SpecificCurveCreator, DefaultCurveCreator};
 
@@ -22,25 +25,276 @@ impl<'a> CurveCreator<'a> {
         let scc = SpecificCurveCreator {
             main: self.main,
             analyser: self.analyser,
-            args: self.args, 
+            args: self.args,
         };
-        
+
         let dcc = DefaultCurveCreator {
             main: self.main,
             analyser: self.analyser,
-            args: self.args, 
+            args: self.args,
+        };
+
+        // The samples are only complete if all routes were used, so they can only
+        // be stored (and be used for the next incremental run) in that case.
+        let store_samples = self.args.is_present("all") && !self.args.is_present("default-only");
+
+        let half_life = self.main.get_half_life()?;
+        let mut samples = if self.args.is_present("incremental") {
+            Self::load_samples(&self.analyser.main.dir, &self.main.source, half_life)?
+        } else {
+            CurveSamples::new(&self.main.source)
+        };
+        samples.half_life = half_life;
+
+        // Records of the current day are still incomplete, so stored samples only contain full days.
+        // The next incremental run continues with the current day then. Other runs use all records.
+        let until = if store_samples {
+            Some(Local::today().naive_local().pred())
+        } else {
+            None
+        };
+        let date_range = DateRange { after: samples.high_water_mark, until };
+        match (date_range.after, until) {
+            (Some(after), Some(until)) => println!("Reading records from {} to {}…", after.succ(), until),
+            (None, Some(until)) => println!("Reading records up to {}…", until),
+            _ => println!("Reading all records…"),
+        }
+
+        let specific_samples = if !self.args.is_present("default-only") {
+            scc.get_specific_samples(&date_range)?
+        } else {
+            HashMap::new()
         };
-        
+        let (general_samples, stop_samples) = dcc.get_default_samples(&date_range)?;
+        samples.merge_date_range(&date_range, specific_samples, general_samples, stop_samples);
+
+        // the default curves are built first, so that the specific curves can be blended with them
+        let general = dcc.build_default_curves(&samples.general, &samples.stops)?;
         let delay_stats = DelayStatistics {
-            specific: if !self.args.is_present("default-only") { 
-                scc.get_specific_curves()?
+            specific: if !self.args.is_present("default-only") {
+                scc.build_specific_curves(&samples.specific, Some(&general))?
             } else {
                 HashMap::new()
             },
-            general: dcc.get_default_curves()?
+            general,
+            segments: self.load_previous_segments()
         };
-       
+
         delay_stats.save_to_file(&self.analyser.main.dir, "all_curves", &SerdeFormat::MessagePack)?;
+
+        if store_samples {
+            Self::store_samples(&self.analyser.main.dir, &samples)?;
+        }
+        Ok(())
+    }
+
+    /// The segments are computed by `analyse segments`, not here, so they are carried over from the previous all_curves.exp.
+    fn load_previous_segments(&self) -> HashMap<String, HashMap<u64, SegmentData>> {
+        if !Path::new(&format!("{}/all_curves.exp", self.analyser.main.dir)).exists() {
+            return HashMap::new();
+        }
+        match DelayStatistics::load_from_file(&self.analyser.main.dir, "all_curves", &SerdeFormat::MessagePack) {
+            Ok(previous) => previous.segments,
+            Err(e) => {
+                println!("Could not load segments from previous all_curves.exp: {}", e);
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Loads the samples of the previous run from `curve_samples.exp` in `dir`, or starts with empty samples if there are none.
+    fn load_samples(dir: &str, source: &str, half_life: Option<f64>) -> FnResult<CurveSamples> {
+        let samples = match CurveSamples::load_from_file(dir, "curve_samples", &SerdeFormat::MessagePack) {
+            Ok(samples) => samples,
+            Err(e) => {
+                println!("Could not load curve_samples.exp ({}), starting from scratch.", e);
+                return Ok(CurveSamples::new(source));
+            }
+        };
+        if samples.source != source {
+            bail!("curve_samples.exp was created for source {}, not for {}.", samples.source, source);
+        }
+        if samples.half_life != half_life {
+            bail!("curve_samples.exp was created with a half-life of {:?} days, not {:?}. Run without --incremental to start from scratch.", samples.half_life, half_life);
+        }
+        Ok(samples)
+    }
+
+    /// Stores the samples in `curve_samples.exp` in `dir`, for the next run with `--incremental`.
+    fn store_samples(dir: &str, samples: &CurveSamples) -> FnResult<()> {
+        samples.save_to_file(dir, "curve_samples", &SerdeFormat::MessagePack)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone};
+    use gtfs_structures::{Stop, StopTime, Trip};
+    use std::sync::Arc;
+    use crate::analyser::trajectory::Trajectory;
+    use crate::types::{DbItem, EventPair, EventType, GtfsDateTime, RecencyWeighting, RouteSamples, RouteVariantSamples, StationGrouping, StopSamples};
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(2020, 6, day)
+    }
+
+    /// A trip with the stops s1 to s3, which runs on the mornings of June 1st to 7th.
+    fn trip() -> Trip {
+        let times = [(28320, 28320), (28500, 28560), (28680, 28680)];
+        Trip {
+            id: String::from("trip"),
+            stop_times: times.iter().enumerate().map(|(i, (arrival, departure))| StopTime {
+                stop: Arc::new(Stop { id: format!("s{}", i + 1), ..Default::default() }),
+                stop_sequence: i as u16 + 1,
+                arrival_time: Some(*arrival),
+                departure_time: Some(*departure),
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The vehicle of the given day, with delays that differ from day to day.
+    fn vehicle(trip: &Trip, day: u32) -> Trajectory {
+        let start = GtfsDateTime::new(Local.from_local_date(&date(day)).unwrap(), 28320);
+        let mut trajectory = Trajectory::new(trip, start);
+        for i in 0..3 {
+            let delay = 12 * (day as i32 % 3) + 36 * i as i32;
+            let item = DbItem {
+                delay: EventPair { arrival: if i > 0 { Some(delay) } else { None }, departure: if i < 2 { Some(delay) } else { None } },
+                trip_start_date: None,
+                trip_start_time: None,
+                trip_id: String::from("trip"),
+                stop_sequence: i as u16 + 1,
+                stop_id: format!("s{}", i + 1),
+                route_variant: 1,
+            };
+            trajectory.add(&item, &StationGrouping::none());
+        }
+        trajectory
+    }
+
+    /// Collects the samples of the vehicles within the date range, like `SpecificCurveCreator` does with the records from the database.
+    fn specific_samples(trip: &Trip, date_range: &DateRange, half_life: Option<f64>) -> HashMap<String, RouteSamples> {
+        let recency = RecencyWeighting::new(half_life, date_range);
+        let mut variant_samples = RouteVariantSamples::new();
+        for day in 1..=7 {
+            if date_range.after.map_or(false, |after| date(day) <= after) || date_range.until.map_or(false, |until| date(day) > until) {
+                continue;
+            }
+            vehicle(trip, day).add_samples(&mut variant_samples, false, &recency);
+        }
+
+        let mut route_samples = RouteSamples::new();
+        route_samples.variants.insert(1, variant_samples);
+        let mut specific = HashMap::new();
+        specific.insert(String::from("route"), route_samples);
+        specific
+    }
+
+    /// Runs like `compute-curves --incremental` for each of the given end dates, storing and loading the samples in `dir`.
+    fn run_incrementally(dir: &str, trip: &Trip, half_life: Option<f64>, until_days: &[u32]) -> FnResult<CurveSamples> {
+        for until in until_days {
+            let mut samples = CurveCreator::load_samples(dir, "test", half_life)?;
+            samples.half_life = half_life;
+            let date_range = DateRange { after: samples.high_water_mark, until: Some(date(*until)) };
+            samples.merge_date_range(&date_range, specific_samples(trip, &date_range, half_life), HashMap::new(), StopSamples::new());
+            CurveCreator::store_samples(dir, &samples)?;
+        }
+        CurveCreator::load_samples(dir, "test", half_life)
+    }
+
+    fn run_completely(trip: &Trip, half_life: Option<f64>, until: u32) -> CurveSamples {
+        let mut samples = CurveSamples::new("test");
+        samples.half_life = half_life;
+        let date_range = DateRange { after: None, until: Some(date(until)) };
+        samples.merge_date_range(&date_range, specific_samples(trip, &date_range, half_life), HashMap::new(), StopSamples::new());
+        samples
+    }
+
+    fn assert_same_weights<K: PartialEq + std::fmt::Debug>(a: Vec<(K, f64)>, b: Vec<(K, f64)>) {
+        assert_eq!(a.len(), b.len());
+        for ((value_a, weight_a), (value_b, weight_b)) in a.iter().zip(b.iter()) {
+            assert_eq!(value_a, value_b);
+            assert!((weight_a - weight_b).abs() < 1e-9, "weights of {:?} differ: {} and {}", value_a, weight_a, weight_b);
+        }
+    }
+
+    fn assert_same_samples(a: &CurveSamples, b: &CurveSamples) {
+        assert_eq!(a.high_water_mark, b.high_water_mark);
+        let variant_a = &a.specific["route"].variants[&1];
+        let variant_b = &b.specific["route"].variants[&1];
+        for et in &EventType::TYPES {
+            assert_eq!(variant_a.general_delay[**et].len(), variant_b.general_delay[**et].len());
+            for (stop_index, histogram) in &variant_a.general_delay[**et] {
+                let other = &variant_b.general_delay[**et][stop_index];
+                assert_same_weights(histogram.weighted_values(), other.weighted_values());
+                assert!((histogram.effective_size() - other.effective_size()).abs() < 1e-9);
+            }
+            assert_eq!(variant_a.curve_sets[**et].len(), variant_b.curve_sets[**et].len());
+            for (key, histogram) in &variant_a.curve_sets[**et] {
+                let other = &variant_b.curve_sets[**et][key];
+                assert_same_weights(histogram.weighted_values(), other.weighted_values());
+                assert!((histogram.effective_size() - other.effective_size()).abs() < 1e-9);
+            }
+        }
+    }
+
+    fn assert_incremental_equals_complete(half_life: Option<f64>, test_name: &str) -> FnResult<()> {
+        let dir_name = std::env::temp_dir().join(format!("dystonse_curve_samples_{}_{}", std::process::id(), test_name));
+        let dir_name = dir_name.to_str().unwrap();
+        std::fs::create_dir_all(dir_name)?;
+
+        let trip = trip();
+        let incremental = run_incrementally(dir_name, &trip, half_life, &[4, 7]);
+        std::fs::remove_dir_all(dir_name)?;
+
+        assert_same_samples(&run_completely(&trip, half_life, 7), &incremental?);
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_merging_date_ranges() -> FnResult<()> {
+        assert_incremental_equals_complete(None, "unweighted")?;
+
+        // departures from s1 on all seven days, with delays of 0, 12 and 24 seconds
+        let samples = run_completely(&trip(), None, 7);
+        let histogram = &samples.specific["route"].variants[&1].general_delay.departure[&0];
+        assert_eq!(histogram.weighted_values(), vec![(0, 2.0), (12, 3.0), (24, 2.0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decaying_before_merging() -> FnResult<()> {
+        // the stored samples are decayed by the days between the old and the new end of the date range,
+        // so that they get the same weights as if all records had been read again
+        assert_incremental_equals_complete(Some(2.0), "half_life_2")?;
+        assert_incremental_equals_complete(Some(30.0), "half_life_30")?;
+
+        // a delay of 0 at s1 was observed on day 3 and 6, i.e. 2 half-lives and half a half-life before day 7
+        let samples = run_completely(&trip(), Some(2.0), 7);
+        let histogram = &samples.specific["route"].variants[&1].general_delay.departure[&0];
+        assert!((histogram.weighted_values()[0].1 - (0.25 + 0.5f64.sqrt())).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_loading_samples_of_other_runs() -> FnResult<()> {
+        let dir_name = std::env::temp_dir().join(format!("dystonse_curve_samples_{}_other_runs", std::process::id()));
+        let dir_name = dir_name.to_str().unwrap();
+        std::fs::create_dir_all(dir_name)?;
+
+        let stored = run_incrementally(dir_name, &trip(), Some(2.0), &[4]);
+        let other_source = CurveCreator::load_samples(dir_name, "other", Some(2.0));
+        let other_half_life = CurveCreator::load_samples(dir_name, "test", None);
+        std::fs::remove_dir_all(dir_name)?;
+
+        // samples of other sources or with another half-life can't be continued
+        assert_eq!(stored?.high_water_mark, Some(date(4)));
+        assert!(other_source.is_err());
+        assert!(other_half_life.is_err());
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::{HashSet, HashMap};
 use std::u16;
 
-use crate::types::{TimeSlot, DbItem, RouteSection, DefaultCurves, EventType, EventPair, DefaultCurveKey, CurveData, PrecisionType};
+use crate::types::{TimeSlot, DbItem, AnomalyExclusions, RouteSection, DefaultCurves, EventType, EventPair, DefaultCurveKey, CurveData, PrecisionType, SmoothingMethod, DefaultSampleKey, DelayHistogram, DateRange, RecencyWeighting, StopCurveKey, StopSamples, StationGrouping, merge_histograms};
 
 use super::curve_utils::*;
 
@@ -17,8 +17,12 @@ use super::Analyser;
 
 use crate::{FnResult, Main};
 
-// curves based on less than this number of data will be discarded:
-const MIN_DATA_FOR_CURVE : usize = 10; 
+// curves based on less than this (effective) number of data will be discarded:
+const MIN_DATA_FOR_CURVE : f64 = 10.0; 
+
+// stop curves are only used if there are no specific curves, but they are built
+// from the data of several routes, so they need more data to be worthwhile:
+const MIN_DATA_FOR_STOP_CURVE : f64 = 50.0;
 
 /// Create default curves for predictions on routes for which we don't have realtime data
 /// Default curves are computed for delay_arrival and delay_departure 
@@ -26,10 +30,11 @@ const MIN_DATA_FOR_CURVE : usize = 10;
 /// The calculations are based on the routes for which we have historic realtime data, 
 /// but the curves are intended to be used for any prediction, identified by the criteria mentioned above.
 
-type Collection<'a> = EventPair
-<HashMap<(&'a RouteType, &'a RouteSection, &'a TimeSlot), 
+type Collection = EventPair
+<HashMap<(RouteType, RouteSection, TimeSlot), 
     Vec<CurveData>>>;
 
+type Samples = HashMap<DefaultSampleKey, DelayHistogram>;
 
 pub struct DefaultCurveCreator<'a> {
     pub main: &'a Main,
@@ -40,30 +45,24 @@ pub struct DefaultCurveCreator<'a> {
 impl<'a> DefaultCurveCreator<'a> {
 
     pub fn get_default_curves(&self) -> FnResult<DefaultCurves> {
-        let schedule = &self.analyser.schedule;
+        let (samples, stop_samples) = self.get_default_samples(&DateRange::ALL)?;
+        self.build_default_curves(&samples, &stop_samples)
+    }
 
-        let route_types = [
-            RouteType::Tramway,
-            RouteType::Subway,
-            RouteType::Rail,
-            RouteType::Bus,
-            RouteType::Ferry,
-            RouteType::CableCar,
-            RouteType::Gondola,
-            RouteType::Funicular,
-            RouteType::Coach,
-            RouteType::Air,
-            RouteType::Taxi
-            ];
-            
-        let route_sections = [
-            RouteSection::Beginning, 
-            RouteSection::Middle, 
-            RouteSection::End
-            ];
+    /// Reads the records within `date_range` from the database and collects the
+    /// delays for each route variant, route section, time slot and event type,
+    /// as well as for each station, time slot and event type.
+    /// The delays are weighted by their age if a half-life is set.
+    pub fn get_default_samples(&self, date_range: &DateRange) -> FnResult<(Samples, StopSamples)> {
+        let schedule = &self.analyser.schedule;
+        let exclusions = super::anomalies::get_exclusions(self.main, self.args)?;
+        let recency = RecencyWeighting::new(self.main.get_half_life()?, date_range);
+        // records without a start date can't be aged, so they keep the full weight
+        let weight = |item: &DbItem| item.trip_start_date.map_or(1.0, |date| recency.weight(date.naive_local()));
+        let stations = StationGrouping::for_stop_curves(schedule, self.args.is_present("group-stations"));
 
         //iterate over route types
-        let mut general_curves = route_types.par_iter().map(|rt| {
+        let samples = DefaultCurves::ROUTE_TYPES.par_iter().map(|rt| {
             println!("Starting with route type {:?}", rt);
 
             //find all routes for this type
@@ -78,10 +77,9 @@ impl<'a> DefaultCurveCreator<'a> {
             println!("Found {} route variants in {} {:?} routes", route_variants.len(), routes.len(), rt);
 
             //iterate over route variants
-            //for (ri, rv) in route_variants {
-
-            let collection_for_route_type: Collection = route_variants.par_iter().map(|(ri, rv)| {
-                let mut collection_for_route_variant = Self::empty_collection();
+            let samples_for_route_type: (Samples, StopSamples) = route_variants.par_iter().map(|(ri, rv)| {
+                let mut samples_for_route_variant = Samples::new();
+                let mut stop_samples_for_route_variant = StopSamples::new();
                  
                 //find one trip of this variant
                 let trip = schedule.trips.values().filter(
@@ -107,22 +105,17 @@ impl<'a> DefaultCurveCreator<'a> {
                 }
                 //...now the borders should be known.
 
-                // println!("For route variant {} with {} stops, the route sections are at {} and {}.",
-                //     rv, rv_stops.len(), max_beginning_stop, max_middle_stop);
-
                 // Get rt data from the database for all route sections in this route variant
                 // TODO: fix this, because it panics if anything went wrong in the database connection etc.!
-                let beginning_data = self.get_data_from_db(&ri, &rv, 0, max_beginning_stop).unwrap();
-                let middle_data = self.get_data_from_db(&ri, &rv, max_beginning_stop + 1, max_middle_stop).unwrap();
-                let end_data = self.get_data_from_db(&ri, &rv, max_middle_stop + 1, u16::MAX).unwrap();
+                let beginning_data = self.get_data_from_db(&ri, &rv, 0, max_beginning_stop, date_range, &exclusions).unwrap();
+                let middle_data = self.get_data_from_db(&ri, &rv, max_beginning_stop + 1, max_middle_stop, date_range, &exclusions).unwrap();
+                let end_data = self.get_data_from_db(&ri, &rv, max_middle_stop + 1, u16::MAX, date_range, &exclusions).unwrap();
 
                 // for each of these sections, separate the data into time slots
                 let beginning_data_by_timeslot = self.sort_dbitems_by_timeslot(beginning_data).unwrap();
                 let middle_data_by_timeslot = self.sort_dbitems_by_timeslot(middle_data).unwrap();
                 let end_data_by_timeslot = self.sort_dbitems_by_timeslot(end_data).unwrap();
 
-                // TODO: catch errors when beginning/middle/end data was empty!
-
                 // make a hashmap on one more meta level from what we have until here
                 let mut data_by_route_section_and_timeslot : 
                     HashMap<RouteSection, HashMap<&TimeSlot, Vec<DbItem>>> = HashMap::new();
@@ -131,44 +124,91 @@ impl<'a> DefaultCurveCreator<'a> {
                 data_by_route_section_and_timeslot.insert(RouteSection::Middle, middle_data_by_timeslot);
                 data_by_route_section_and_timeslot.insert(RouteSection::End, end_data_by_timeslot);
 
-                // for each time slot in each section, make two curves (delay for arrival and depature)
-                for rs in &route_sections {
+                // for each time slot in each section, collect the delays for arrival and depature
+                for rs in &RouteSection::SECTIONS {
                     for ts in &TimeSlot::TIME_SLOTS {
-                        // println!("Create curves for section {:?} and time slot {}.", rs, ts.description);
-
-                        // collect delays in vectors:
-                        let mut delays : EventPair<Vec<f32>> = EventPair { arrival: Vec::new(), departure: Vec::new() };
                         for e_t in &EventType::TYPES {
-                            delays[**e_t] = data_by_route_section_and_timeslot[rs][ts].iter()
-                                .filter_map(|item| item.delay[**e_t]).map(|i| i as f32).collect();
+                            let mut delays = DelayHistogram::new();
+                            for item in &data_by_route_section_and_timeslot[rs][ts] {
+                                if let Some(delay) = item.delay[**e_t] {
+                                    delays.add_weighted(delay, weight(item));
+                                }
+                            }
+                            if !delays.is_empty() {
+                                let key = DefaultSampleKey {
+                                    route_id: ri.clone(),
+                                    route_variant: rv.to_string(),
+                                    route_section: rs.clone(),
+                                    time_slot: (**ts).clone(),
+                                    event_type: **e_t
+                                };
+                                samples_for_route_variant.insert(key, delays);
+                            }
                         }
-                        for e_t in &EventType::TYPES {
-                            if delays[**e_t].len() >= MIN_DATA_FOR_CURVE {
-                                if let Ok((mut curve, _)) = make_curve(&delays[**e_t], None) {
-                                    curve.simplify(0.001);
-                                    // only create vectors that will have entries
-                                    let curve_data = CurveData {
-                                        curve,
-                                        precision_type: PrecisionType::Unknown,
-                                        sample_size: delays[**e_t].len() as u32,
+                        // the same delays, but sorted by station instead of route section:
+                        for item in &data_by_route_section_and_timeslot[rs][ts] {
+                            let stop_id = stations.station_id(&item.stop_id).to_string();
+                            for e_t in &EventType::TYPES {
+                                if let Some(delay) = item.delay[**e_t] {
+                                    let key = StopCurveKey {
+                                        stop_id: stop_id.clone(),
+                                        time_slot: (**ts).clone(),
+                                        event_type: **e_t
                                     };
-                                    collection_for_route_variant[**e_t].entry((rt, rs, *ts)).or_insert(Vec::new()).push(curve_data);
+                                    stop_samples_for_route_variant.entry(key).or_insert_with(DelayHistogram::new).add_weighted(delay, weight(item));
                                 }
-                            }   
+                            }
                         }
                     }
                 }
-                collection_for_route_variant
+                (samples_for_route_variant, stop_samples_for_route_variant)
             }).reduce(
-                || Self::empty_collection(),
-                |a, b| Self::merge_collections(a, b)
+                || (Samples::new(), StopSamples::new()),
+                Self::merge_samples
             );
-           collection_for_route_type
+            samples_for_route_type
         }).reduce(
-            || Self::empty_collection(),
-            |a, b| Self::merge_collections(a, b)
+            || (Samples::new(), StopSamples::new()),
+            Self::merge_samples
         );
 
+        Ok(samples)
+    }
+
+    fn merge_samples(mut a: (Samples, StopSamples), b: (Samples, StopSamples)) -> (Samples, StopSamples) {
+        merge_histograms(&mut a.0, b.0);
+        merge_histograms(&mut a.1, b.1);
+        a
+    }
+
+    /// Computes the default curves from previously collected samples.
+    pub fn build_default_curves(&self, samples: &Samples, stop_samples: &StopSamples) -> FnResult<DefaultCurves> {
+        let schedule = &self.analyser.schedule;
+        let smoothing = get_smoothing_method(self.args);
+
+        // make one curve for each route variant, route section, time slot and event type
+        let variant_curves : Vec<(EventType, (RouteType, RouteSection, TimeSlot), CurveData)> = samples.par_iter().filter_map(|(key, delays)| {
+            let effective_size = delays.effective_size();
+            if effective_size < MIN_DATA_FOR_CURVE {
+                return None;
+            }
+            // routes which are not part of the current schedule are skipped
+            let route = schedule.get_route(&key.route_id).ok()?;
+            let (mut curve, _) = make_weighted_curve(&Self::weighted_values(delays), None, smoothing).ok()?;
+            curve.simplify(0.001);
+            let curve_data = CurveData {
+                curve,
+                precision_type: PrecisionType::Unknown,
+                sample_size: effective_size.round() as u32,
+                smoothing,
+            };
+            Some((key.event_type, (route.route_type, key.route_section.clone(), key.time_slot.clone()), curve_data))
+        }).collect();
+
+        let mut general_curves = Self::empty_collection();
+        for (e_t, key, curve_data) in variant_curves {
+            general_curves[e_t].entry(key).or_insert(Vec::new()).push(curve_data);
+        }
 
         println!("Done with curves for each route variant, now computing average curves…");
 
@@ -185,11 +225,11 @@ impl<'a> DefaultCurveCreator<'a> {
         let mut fallback_general_curves : HashMap<(RouteType, EventType), Vec<CurveData>> = HashMap::new();
         let mut super_default_curves : Vec<CurveData> = Vec::new();
         // collect allll the curves in there
-        for rt in &route_types {
+        for rt in &DefaultCurves::ROUTE_TYPES {
             for et in &EventType::TYPES {
-                for rs in &route_sections {
+                for rs in &RouteSection::SECTIONS {
                     for ts in &TimeSlot::TIME_SLOTS {
-                        if let Some(curves) = general_curves[**et].get_mut(&(rt, rs, *ts)) {
+                        if let Some(curves) = general_curves[**et].get_mut(&(*rt, rs.clone(), (**ts).clone())) {
                             // put any curves found here into the broad defaults:
                             for c in curves.iter() {
                                 fallback_general_curves.entry((*rt, **et)).or_insert(Vec::new()).push(c.clone());
@@ -208,15 +248,15 @@ impl<'a> DefaultCurveCreator<'a> {
         super_general_curve_data.curve.simplify(0.001);
 
         // now back to the actual default curves...
-        for rt in &route_types {
-            for rs in &route_sections {
+        for rt in &DefaultCurves::ROUTE_TYPES {
+            for rs in &RouteSection::SECTIONS {
                 for ts in &TimeSlot::TIME_SLOTS {
                     println!("Create average curves for route type {:?}, route section {:?} and time slot {}", rt, rs, ts.description);
 
                     for e_t in &EventType::TYPES {
                         let key = DefaultCurveKey{route_type: *rt, route_section: rs.clone(), time_slot: (**ts).clone(), event_type: **e_t};
                         // curve vectors
-                        if let Some(curves) = general_curves[**e_t].get_mut(&(rt, rs, *ts)) {
+                        if let Some(curves) = general_curves[**e_t].get_mut(&(*rt, rs.clone(), (**ts).clone())) {
                             // interpolate them into one curve each and
                             // put curves into the final datastructure:
                             if curves.len() > 0 {
@@ -242,23 +282,52 @@ impl<'a> DefaultCurveCreator<'a> {
                 }
             }
         }
+        println!("Computing stop curves…");
+        dc.stop_curves = self.build_stop_curves(stop_samples, smoothing);
+        dc.grouped_stations = self.args.is_present("group-stations");
+        println!("Computed {} stop curves.", dc.stop_curves.len());
+
         println!("Done with everything but saving."); // Result: {:?}", dc.all_default_curves);
 
         Ok(dc)
     }
 
-    pub fn empty_collection() -> Collection<'a> {
-        //data structures to collect all default curves:
-        EventPair { arrival: HashMap::new(), departure: HashMap::new() }
-    }
+    /// Computes one curve for each station, time slot and event type with enough data,
+    /// plus one for the default time slot from all data of the station.
+    fn build_stop_curves(&self, stop_samples: &StopSamples, smoothing: SmoothingMethod) -> HashMap<StopCurveKey, CurveData> {
+        let mut all_samples = stop_samples.clone();
+        for (key, delays) in stop_samples {
+            let default_key = StopCurveKey {
+                stop_id: key.stop_id.clone(),
+                time_slot: TimeSlot::DEFAULT.clone(),
+                event_type: key.event_type
+            };
+            all_samples.entry(default_key).or_insert_with(DelayHistogram::new).merge(delays.clone());
+        }
 
-    pub fn merge_collections(mut c1: Collection<'a>, c2: Collection<'a>) -> Collection<'a> {
-        for e_t in &EventType::TYPES {
-            for (key, value) in c2[**e_t].clone() {
-                c1[**e_t].entry(key).or_insert(Vec::new()).extend(value);
+        all_samples.par_iter().filter_map(|(key, delays)| {
+            let effective_size = delays.effective_size();
+            if effective_size < MIN_DATA_FOR_STOP_CURVE {
+                return None;
             }
-        }
-        c1
+            let (mut curve, _) = make_weighted_curve(&Self::weighted_values(delays), None, smoothing).ok()?;
+            curve.simplify(0.001);
+            Some((key.clone(), CurveData {
+                curve,
+                precision_type: PrecisionType::StopSpecific,
+                sample_size: effective_size.round() as u32,
+                smoothing,
+            }))
+        }).collect()
+    }
+
+    fn weighted_values(delays: &DelayHistogram) -> Vec<(f32, f32)> {
+        delays.weighted_values().into_iter().map(|(d, w)| (d as f32, w as f32)).collect()
+    }
+
+    pub fn empty_collection() -> Collection {
+        //data structures to collect all default curves:
+        EventPair { arrival: HashMap::new(), departure: HashMap::new() }
     }
 
     pub fn run_default_curves(&self) -> FnResult<()> {
@@ -304,7 +373,7 @@ impl<'a> DefaultCurveCreator<'a> {
     }
 
     // picks all rows from the database for a given route section and variant
-    fn get_data_from_db(&self, ri: &str, rv: &str, min: u16, max: u16) -> FnResult<Vec<DbItem>> {
+    fn get_data_from_db(&self, ri: &str, rv: &str, min: u16, max: u16, date_range: &DateRange, exclusions: &AnomalyExclusions) -> FnResult<Vec<DbItem>> {
         let mut con = self.main.pool.get_conn()?;
         let stmt = con.prep(
             r"SELECT 
@@ -323,7 +392,9 @@ impl<'a> DefaultCurveCreator<'a> {
                 route_id = :route_id AND
                 route_variant=:route_variant AND
                 stop_sequence >= :lower_bound AND
-                stop_sequence <= :upper_bound",
+                stop_sequence <= :upper_bound AND
+                (:after IS NULL OR trip_start_date > :after) AND
+                (:until IS NULL OR trip_start_date <= :until)",
         )?;
 
         let mut result = con.exec_iter(
@@ -334,6 +405,8 @@ impl<'a> DefaultCurveCreator<'a> {
                 "route_variant" => rv,
                 "lower_bound" => min,
                 "upper_bound" => max,
+                "after" => date_range.after,
+                "until" => date_range.until,
             },
         )?;
 
@@ -344,6 +417,7 @@ impl<'a> DefaultCurveCreator<'a> {
                 let item: DbItem = from_row(row.unwrap());
                 item
             })
+            .filter(|item| !exclusions.is_excluded(item))
             .collect();
 
         return Ok(db_items);
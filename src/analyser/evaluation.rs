@@ -0,0 +1,569 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::prelude::*;
+
+use chrono::{Date, Duration, Local, NaiveDate};
+use chrono::offset::TimeZone;
+use clap::ArgMatches;
+use gtfs_structures::Trip;
+use mysql::*;
+use mysql::prelude::*;
+use plotters::prelude::RGBColor;
+use serde::{Serialize, Deserialize};
+
+use dystonse_curves::irregular_dynamic::*;
+use dystonse_curves::Curve;
+use dystonse_curves::tree::{SerdeFormat, NodeData};
+
+use super::Analyser;
+use super::line_chart::{LineChart, ChartLine, LegendPosition, plotters_color};
+use crate::predictor::Predictor;
+use crate::types::*;
+
+use crate::{FnResult, Main, date_and_time_local};
+
+/// Number of bins of the PIT histogram.
+const PIT_BINS: usize = 10;
+
+/// Bounds (in minutes) of the lead time buckets for recomputed predictions.
+pub const LEAD_TIME_BUCKETS: [i32; 6] = [0, 5, 15, 30, 60, 120];
+
+/// Compares predictions with the delays that were observed afterwards.
+pub struct Evaluator<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches
+}
+
+/// A single prediction together with the delay that was observed afterwards.
+struct EvaluatedPrediction {
+    curve: IrregularDynamicCurve<f32, f32>,
+    observed: f32,
+    precision_type: PrecisionType,
+    /// only known for recomputed predictions, as the predictions table does not store it
+    smoothing: Option<SmoothingMethod>,
+    origin_type: OriginType,
+    route_type: String,
+    time_slot: &'static str,
+    lead_time: String,
+}
+
+/// Collects the metrics for one group of predictions.
+#[derive(Default)]
+struct Accumulator {
+    count: usize,
+    pit_histogram: [usize; PIT_BINS],
+    covered: usize,
+    sharpness_sum: f64,
+    crps_sum: f64,
+    absolute_errors: Vec<f32>,
+}
+
+impl Accumulator {
+    fn add(&mut self, prediction: &EvaluatedPrediction) {
+        let curve = &prediction.curve;
+        let observed = prediction.observed;
+
+        let pit = curve.y_at_x(observed).max(0.0).min(1.0);
+        let bin = ((pit * PIT_BINS as f32) as usize).min(PIT_BINS - 1);
+        let lower = curve.x_at_y(0.05);
+        let upper = curve.x_at_y(0.95);
+
+        self.count += 1;
+        self.pit_histogram[bin] += 1;
+        if lower <= observed && observed <= upper {
+            self.covered += 1;
+        }
+        self.sharpness_sum += (upper - lower) as f64;
+        self.crps_sum += crps(curve, observed);
+        self.absolute_errors.push((curve.x_at_y(0.5) - observed).abs());
+    }
+
+    fn to_row(&mut self, dimension: &str, group: &str) -> EvaluationRow {
+        let count = self.count as f32;
+        self.absolute_errors.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        EvaluationRow {
+            dimension: dimension.to_string(),
+            group: group.trim().to_string(),
+            count: self.count,
+            pit_histogram: self.pit_histogram.iter().map(|c| *c as f32 / count).collect(),
+            coverage_5_95: self.covered as f32 / count,
+            mean_sharpness: (self.sharpness_sum / self.count as f64) as f32,
+            mean_crps: (self.crps_sum / self.count as f64) as f32,
+            median_absolute_error: self.absolute_errors[self.absolute_errors.len() / 2],
+        }
+    }
+}
+
+/// The metrics for one group of predictions. All values that refer to delays are in seconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EvaluationRow {
+    pub dimension: String,
+    pub group: String,
+    pub count: usize,
+    /// share of observations within each tenth of the predicted distribution, should be 0.1 everywhere
+    pub pit_histogram: Vec<f32>,
+    /// share of observations between the 5 % and 95 % quantiles, should be 0.9
+    pub coverage_5_95: f32,
+    /// mean width of the 5–95 % band
+    pub mean_sharpness: f32,
+    /// mean continuous ranked probability score
+    pub mean_crps: f32,
+    /// median of the absolute difference between the median prediction and the observation
+    pub median_absolute_error: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EvaluationReport {
+    pub source: String,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub recomputed: bool,
+    pub rows: Vec<EvaluationRow>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn run_evaluate(&self) -> FnResult<()> {
+        let yesterday = Local::today().naive_local().pred();
+        let to = match self.args.value_of("to") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => yesterday,
+        };
+        let from = match self.args.value_of("from") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => to - Duration::days(6),
+        };
+        let recomputed = self.args.is_present("recompute");
+        let output_dir = match self.args.value_of("output") {
+            Some(dir) => dir.to_string(),
+            None => format!("{}/evaluation", self.main.dir),
+        };
+
+        println!("Evaluating {} predictions for trips from {} to {}…", if recomputed { "recomputed" } else { "stored" }, from, to);
+        let predictions = if recomputed {
+            self.get_recomputed_predictions(from, to)?
+        } else {
+            self.get_stored_predictions(from, to)?
+        };
+        println!("Found {} predictions with observed delays.", predictions.len());
+
+        let mut groups : BTreeMap<(&str, String), Accumulator> = BTreeMap::new();
+        for prediction in &predictions {
+            let mut keys = vec![
+                ("all", String::from("all")),
+                ("precision_type", format!("{:?}", prediction.precision_type)),
+                ("origin_type", format!("{:?}", prediction.origin_type)),
+                ("route_type", prediction.route_type.clone()),
+                ("time_slot", prediction.time_slot.to_string()),
+                ("lead_time", prediction.lead_time.clone()),
+            ];
+            if let Some(smoothing) = prediction.smoothing {
+                keys.push(("smoothing", format!("{:?}", smoothing)));
+            }
+            for key in keys.iter() {
+                groups.entry(key.clone()).or_insert_with(Accumulator::default).add(prediction);
+            }
+        }
+
+        let report = EvaluationReport {
+            source: self.main.source.clone(),
+            from,
+            to,
+            recomputed,
+            rows: groups.iter_mut().map(|((dimension, group), acc)| acc.to_row(dimension, group)).collect(),
+        };
+
+        fs::create_dir_all(&output_dir)?;
+        self.write_csv(&report, &format!("{}/evaluation.csv", output_dir))?;
+        report.save_to_file(&output_dir, "evaluation", &SerdeFormat::Json)?;
+        if self.args.is_present("charts") {
+            self.draw_charts(&report, &output_dir)?;
+        }
+        println!("Evaluation written to {}.", output_dir);
+
+        Ok(())
+    }
+
+    fn write_csv(&self, report: &EvaluationReport, filename: &str) -> FnResult<()> {
+        let mut file = File::create(filename)?;
+        let pit_columns : Vec<String> = (0..PIT_BINS).map(|i| format!("pit_{}", i)).collect();
+        writeln!(file, "dimension;group;count;{};coverage_5_95;mean_sharpness;mean_crps;median_absolute_error", pit_columns.join(";"))?;
+        for row in &report.rows {
+            let pit_values : Vec<String> = row.pit_histogram.iter().map(|v| format!("{:.4}", v)).collect();
+            writeln!(
+                file,
+                "{};{};{};{};{:.4};{:.1};{:.1};{:.1}",
+                row.dimension,
+                row.group,
+                row.count,
+                pit_values.join(";"),
+                row.coverage_5_95,
+                row.mean_sharpness,
+                row.mean_crps,
+                row.median_absolute_error
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Draws one PIT histogram chart per dimension, with one line for each group.
+    fn draw_charts(&self, report: &EvaluationReport, output_dir: &str) -> FnResult<()> {
+        let mut rows_by_dimension : BTreeMap<&str, Vec<&EvaluationRow>> = BTreeMap::new();
+        for row in &report.rows {
+            rows_by_dimension.entry(&row.dimension).or_insert_with(Vec::new).push(row);
+        }
+
+        let x : Vec<f32> = (0..PIT_BINS).map(|i| (i as f32 + 0.5) / PIT_BINS as f32).collect();
+        for (dimension, rows) in rows_by_dimension {
+            let lines : Vec<(String, Vec<(f32, f32)>)> = rows.iter().map(|row| {
+                let y = row.pit_histogram.iter().map(|v| v * PIT_BINS as f32);
+                (format!("{} ({})", row.group, row.count), x.iter().cloned().zip(y).collect())
+            }).collect();
+            let max_y = lines.iter().flat_map(|(_, points)| points.iter().map(|(_x, y)| *y)).fold(2.0, f32::max);
+
+            let mut chart = LineChart::new(&format!("PIT-Histogramm nach {}", dimension), 0.0..1.0, 0.0..max_y * 1.1);
+            chart.legend_position = LegendPosition::UpperRight;
+            // a perfectly calibrated prediction has a flat PIT histogram:
+            chart.lines.push(ChartLine::new("ideal", RGBColor(0x88, 0x88, 0x88), vec![(0.0, 1.0), (1.0, 1.0)]).without_points());
+            let line_count = lines.len();
+            for (i, (caption, points)) in lines.into_iter().enumerate() {
+                let color = plotters_color(colorous::PLASMA.eval_rational(i, line_count + 2));
+                chart.lines.push(ChartLine::new(&caption, color, points));
+            }
+            chart.save_to_svg(&format!("{}/pit_{}.svg", output_dir, dimension), 1024, 768)?;
+        }
+        Ok(())
+    }
+
+    /// Joins the predictions that the importer stored with the delays that were recorded later on.
+    /// The predictions table does not store when a prediction was made, so the lead time is unknown.
+    fn get_stored_predictions(&self, from: NaiveDate, to: NaiveDate) -> FnResult<Vec<EvaluatedPrediction>> {
+        let schedule = &self.analyser.schedule;
+        let mut values : Vec<Value> = vec![self.main.source.clone().into(), from.into(), to.into()];
+        // only the predictions of the given routes, if any:
+        let route_condition = match self.get_route_ids() {
+            Some(route_ids) => {
+                let placeholders = vec!["?"; route_ids.len()].join(", ");
+                values.extend(route_ids.into_iter().map(Value::from));
+                format!("AND p.route_id IN ({})", placeholders)
+            },
+            None => String::new(),
+        };
+
+        let mut con = self.main.pool.get_conn()?;
+        let query = format!(
+            r"SELECT
+                p.route_id,
+                p.trip_id,
+                p.trip_start_date,
+                p.stop_sequence,
+                p.event_type,
+                p.precision_type,
+                p.origin_type,
+                p.prediction_curve,
+                r.delay_arrival,
+                r.delay_departure
+            FROM
+                predictions AS p
+            JOIN
+                records AS r
+            ON
+                r.source = p.source AND
+                r.trip_id = p.trip_id AND
+                r.trip_start_date = p.trip_start_date AND
+                r.trip_start_time = p.trip_start_time AND
+                r.stop_sequence = p.stop_sequence
+            WHERE
+                p.source = ? AND
+                p.trip_start_date >= ? AND
+                p.trip_start_date <= ?
+                {}",
+            route_condition
+        );
+
+        let mut result = con.exec_iter(query, Params::Positional(values))?;
+
+        let result_set = result.next_set().unwrap()?;
+
+        let mut predictions = Vec::new();
+        for row in result_set {
+            let row = row?;
+            let route_id : String = row.get(0).unwrap();
+            let trip_id : String = row.get(1).unwrap();
+            let start_date : NaiveDate = row.get(2).unwrap();
+            let stop_sequence : u16 = row.get(3).unwrap();
+            let event_type = EventType::from_int(row.get(4).unwrap());
+            let observed = match row.get_opt::<i32, _>(if event_type == EventType::Arrival { 8 } else { 9 }).unwrap() {
+                Ok(delay) => delay,
+                Err(_) => continue, // no delay was recorded for this event
+            };
+
+            let trip = match schedule.get_trip(&trip_id) {
+                Ok(trip) => trip,
+                Err(_) => continue,
+            };
+            let time_slot = match Self::get_time_slot(trip, stop_sequence, event_type, &Local.from_local_date(&start_date).unwrap()) {
+                Some(time_slot) => time_slot,
+                None => continue,
+            };
+
+            predictions.push(EvaluatedPrediction {
+                curve: IrregularDynamicCurve::<f32, f32>::deserialize_compact(row.get(7).unwrap()),
+                observed: observed as f32,
+                precision_type: PrecisionType::from_int(row.get(5).unwrap()),
+                smoothing: None,
+                origin_type: OriginType::from_int(row.get(6).unwrap()),
+                route_type: self.get_route_type(&route_id),
+                time_slot: time_slot.description,
+                lead_time: String::from("unknown"),
+            });
+        }
+
+        Ok(predictions)
+    }
+
+    /// Uses the current curves to make predictions for past trips, as if the observed delays
+    /// at previous stops had been reported in realtime, and compares them with the observed delays.
+    /// For each observed event, there is one prediction without realtime data and one for each
+    /// lead time bucket, based on the latest stop that fits into that bucket.
+    fn get_recomputed_predictions(&self, from: NaiveDate, to: NaiveDate) -> FnResult<Vec<EvaluatedPrediction>> {
+        let predictor = Predictor::with_schedule(self.main, self.args, self.analyser.schedule.clone())?;
+        let route_ids = match self.get_route_ids() {
+            Some(route_ids) => route_ids,
+            None => self.analyser.schedule.routes.keys().cloned().collect(),
+        };
+
+        let mut predictions = Vec::new();
+        for route_id in &route_ids {
+            let items = self.get_records_for_route(route_id, from, to)?;
+            let mut items_by_vehicle : HashMap<VehicleIdentifier, Vec<DbItem>> = HashMap::new();
+            for item in items {
+                if let (Some(start_date), Some(start_time)) = (item.trip_start_date, item.trip_start_time) {
+                    let vehicle_id = VehicleIdentifier {
+                        trip_id: item.trip_id.clone(),
+                        start: GtfsDateTime::new(start_date, start_time.num_seconds() as i32),
+                    };
+                    items_by_vehicle.entry(vehicle_id).or_insert_with(Vec::new).push(item);
+                }
+            }
+
+            let route_type = self.get_route_type(route_id);
+            for (vehicle_id, mut items) in items_by_vehicle {
+                let trip = match self.analyser.schedule.get_trip(&vehicle_id.trip_id) {
+                    Ok(trip) => trip,
+                    Err(_) => continue,
+                };
+                items.sort_by_key(|item| item.stop_sequence);
+                let start_date_time = vehicle_id.start.date_time();
+
+                for target in &items {
+                    for et in &EventType::TYPES {
+                        let observed = match target.delay[**et] {
+                            Some(delay) => delay as f32,
+                            None => continue,
+                        };
+                        let target_time = match Self::get_scheduled_time(trip, target.stop_sequence, **et) {
+                            Some(time) => time,
+                            None => continue,
+                        };
+                        let time_slot = match Self::get_time_slot(trip, target.stop_sequence, **et, &vehicle_id.start.service_day()) {
+                            Some(time_slot) => time_slot,
+                            None => continue,
+                        };
+
+                        // for each lead time bucket, find the latest previous stop with an observed departure
+                        let mut bases : BTreeMap<usize, PredictionBasis> = BTreeMap::new();
+                        for start in items.iter().take_while(|item| item.stop_sequence < target.stop_sequence) {
+                            let (delay, start_time) = match (start.delay.departure, Self::get_scheduled_time(trip, start.stop_sequence, EventType::Departure)) {
+                                (Some(delay), Some(time)) => (delay, time),
+                                _ => continue,
+                            };
+                            // the prediction is made as soon as the vehicle departs, so the lead time includes the delay
+                            let lead_time = target_time - start_time - delay;
+                            if lead_time < 0 {
+                                continue;
+                            }
+                            let bucket = LEAD_TIME_BUCKETS.iter().rposition(|b| lead_time >= b * 60).unwrap();
+                            bases.insert(bucket, PredictionBasis { stop_sequence: start.stop_sequence, delay_departure: Some(delay as i64) });
+                        }
+
+                        let mut requests : Vec<(Option<PredictionBasis>, OriginType, String)> = vec![(None, OriginType::Schedule, String::from("no realtime"))];
+                        for (bucket, basis) in bases {
+                            requests.push((Some(basis), OriginType::Realtime, Self::lead_time_label(bucket)));
+                        }
+
+                        for (basis, origin_type, lead_time) in requests {
+                            let curve_data = match predictor.predict(route_id, &vehicle_id.trip_id, &basis, target.stop_sequence, **et, start_date_time) {
+                                Ok(PredictionResult::CurveData(curve_data)) => curve_data,
+                                _ => continue,
+                            };
+                            predictions.push(EvaluatedPrediction {
+                                curve: curve_data.curve,
+                                observed,
+                                precision_type: curve_data.precision_type,
+                                smoothing: Some(curve_data.smoothing),
+                                origin_type,
+                                route_type: route_type.clone(),
+                                time_slot: time_slot.description,
+                                lead_time,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(predictions)
+    }
+
+    fn get_records_for_route(&self, route_id: &str, from: NaiveDate, to: NaiveDate) -> FnResult<Vec<DbItem>> {
+        let mut con = self.main.pool.get_conn()?;
+        let stmt = con.prep(
+            r"SELECT
+                delay_arrival,
+                delay_departure,
+                trip_start_date,
+                trip_start_time,
+                trip_id,
+                stop_id,
+                stop_sequence,
+                route_variant
+            FROM
+                records
+            WHERE
+                source=:source AND
+                route_id=:route_id AND
+                trip_start_date >= :from AND
+                trip_start_date <= :to",
+        )?;
+
+        let mut result = con.exec_iter(
+            &stmt,
+            params! {
+                "source" => &self.main.source,
+                "route_id" => route_id,
+                "from" => from,
+                "to" => to,
+            },
+        )?;
+
+        let result_set = result.next_set().unwrap()?;
+
+        let db_items: Vec<_> = result_set
+            .map(|row| {
+                let item: DbItem = from_row(row.unwrap());
+                item
+            })
+            .collect();
+
+        Ok(db_items)
+    }
+
+    fn get_route_ids(&self) -> Option<Vec<String>> {
+        self.args.values_of("route-ids").map(|values| values.map(|s| s.to_string()).collect())
+    }
+
+    fn get_route_type(&self, route_id: &str) -> String {
+        match self.analyser.schedule.get_route(route_id) {
+            Ok(route) => format!("{:?}", route.route_type),
+            Err(_) => String::from("unknown"),
+        }
+    }
+
+    pub fn get_scheduled_time(trip: &Trip, stop_sequence: u16, event_type: EventType) -> Option<i32> {
+        let stop_time = trip.get_stop_time_by_sequence(stop_sequence).ok()?;
+        event_type.get_time_from_stop_time(stop_time)
+    }
+
+    fn get_time_slot(trip: &Trip, stop_sequence: u16, event_type: EventType, service_day: &Date<Local>) -> Option<&'static TimeSlot> {
+        let time = Self::get_scheduled_time(trip, stop_sequence, event_type)?;
+        Some(TimeSlot::from_datetime(date_and_time_local(service_day, time)))
+    }
+
+    /// Labels are padded so that they are sorted in the right order.
+    pub fn lead_time_label(bucket: usize) -> String {
+        if bucket + 1 < LEAD_TIME_BUCKETS.len() {
+            format!("{:>3}-{:>3} min", LEAD_TIME_BUCKETS[bucket], LEAD_TIME_BUCKETS[bucket + 1])
+        } else {
+            format!(" >{} min", LEAD_TIME_BUCKETS[bucket])
+        }
+    }
+}
+
+/// Continuous ranked probability score of the predicted distribution `curve` for the observed
+/// value, i.e. the integral of (F(x) - H(x - observed))², where F is the cumulative distribution
+/// function given by the curve and H is the Heaviside step function. Lower is better, and for a
+/// deterministic prediction, it equals the absolute error.
+fn crps(curve: &IrregularDynamicCurve<f32, f32>, observed: f32) -> f64 {
+    // integral over [x0, x1] of (F - c)², where F is linear from y0 to y1:
+    fn segment(x0: f64, x1: f64, y0: f64, y1: f64, c: f64) -> f64 {
+        let (a, b) = (y0 - c, y1 - c);
+        (x1 - x0) * (a * a + a * b + b * b) / 3.0
+    }
+
+    let (xs, ys) = curve.get_values_as_vectors();
+    let observed = observed as f64;
+    let first = xs[0] as f64;
+    let last = xs[xs.len() - 1] as f64;
+
+    // outside of the curve, F is 0 on the left and 1 on the right:
+    let mut sum = 0.0;
+    if observed < first {
+        sum += first - observed;
+    }
+    if observed > last {
+        sum += observed - last;
+    }
+
+    for i in 0..xs.len() - 1 {
+        let (x0, x1) = (xs[i] as f64, xs[i + 1] as f64);
+        let (y0, y1) = (ys[i] as f64, ys[i + 1] as f64);
+        if observed <= x0 {
+            sum += segment(x0, x1, y0, y1, 1.0);
+        } else if observed >= x1 {
+            sum += segment(x0, x1, y0, y1, 0.0);
+        } else {
+            let y_observed = y0 + (y1 - y0) * (observed - x0) / (x1 - x0);
+            sum += segment(x0, observed, y0, y_observed, 0.0);
+            sum += segment(observed, x1, y_observed, y1, 1.0);
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-3, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn test_crps_of_step() {
+        // a (nearly) deterministic prediction scores its absolute error
+        let step = IrregularDynamicCurve::new(vec![Tup { x: 10.0, y: 0.0 }, Tup { x: 10.0001, y: 1.0 }]);
+        assert_close(crps(&step, 10.0), 0.0);
+        assert_close(crps(&step, 40.0), 30.0);
+        assert_close(crps(&step, -20.0), 30.0);
+    }
+
+    #[test]
+    fn test_crps_of_uniform_distribution() {
+        // for U(0, w), the CRPS at y within [0, w] is w * (u³ + (1 - u)³) / 3 with u = y / w
+        let uniform = IrregularDynamicCurve::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 100.0, y: 1.0 }]);
+        assert_close(crps(&uniform, 50.0), 100.0 * (0.125 + 0.125) / 3.0);
+        assert_close(crps(&uniform, 20.0), 100.0 * (0.008 + 0.512) / 3.0);
+        // outside of the curve, the distance to its end is added
+        assert_close(crps(&uniform, 150.0), 50.0 + 100.0 / 3.0);
+        assert_close(crps(&uniform, -10.0), 10.0 + 100.0 / 3.0);
+    }
+
+    #[test]
+    fn test_crps_with_kink() {
+        // F rises to 0.5 at 0 and to 1 at 100, observed at 0:
+        // ∫(-100..0) (0.5 u)² + ∫(0..100) (0.5 (1 - u))², with u the relative position, each 100 * 0.25 / 3
+        let curve = IrregularDynamicCurve::new(vec![Tup { x: -100.0, y: 0.0 }, Tup { x: 0.0, y: 0.5 }, Tup { x: 100.0, y: 1.0 }]);
+        assert_close(crps(&curve, 0.0), 2.0 * 100.0 * 0.25 / 3.0);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{BufWriter, prelude::*};
+
+use clap::ArgMatches;
+use simple_error::bail;
+
+use dystonse_curves::irregular_dynamic::*;
+use dystonse_curves::Curve;
+
+use super::Analyser;
+use crate::types::*;
+
+use crate::{FnResult, Main};
+
+/// Writes all curves of the delay statistics as a flat table, with one row per curve.
+pub struct Exporter<'a> {
+    pub main: &'a Main,
+    #[allow(dead_code)]
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches
+}
+
+/// Identifies the curve that a row describes. Fields that don't apply to a kind of curve stay empty.
+#[derive(Default, Clone)]
+struct RowKey {
+    kind: &'static str,
+    route_id: String,
+    route_variant: String,
+    start_stop_index: String,
+    start_stop_id: String,
+    end_stop_index: String,
+    end_stop_id: String,
+    time_slot: String,
+    event_type: String,
+    route_type: String,
+    route_section: String,
+    initial_delay: String,
+}
+
+struct TableWriter {
+    file: BufWriter<File>,
+    /// in percent, as given on the command line
+    quantiles: Vec<(String, f32)>,
+    row_count: usize,
+}
+
+impl TableWriter {
+    fn write_header(&mut self) -> FnResult<()> {
+        let quantile_columns : Vec<String> = self.quantiles.iter().map(|(name, _)| format!("q{}", name)).collect();
+        writeln!(
+            self.file,
+            "kind;route_id;route_variant;start_stop_index;start_stop_id;end_stop_index;end_stop_id;time_slot;event_type;route_type;route_section;initial_delay;precision_type;sample_size;{}",
+            quantile_columns.join(";")
+        )?;
+        Ok(())
+    }
+
+    fn write_row(&mut self, key: &RowKey, curve: &IrregularDynamicCurve<f32, f32>, precision_type: &PrecisionType, sample_size: u32) -> FnResult<()> {
+        let quantile_values : Vec<String> = self.quantiles.iter().map(|(_, q)| format!("{:.0}", curve.x_at_y(*q))).collect();
+        writeln!(
+            self.file,
+            "{};{};{};{};{};{};{};{};{};{};{};{};{:?};{};{}",
+            key.kind,
+            key.route_id,
+            key.route_variant,
+            key.start_stop_index,
+            key.start_stop_id,
+            key.end_stop_index,
+            key.end_stop_id,
+            key.time_slot,
+            key.event_type,
+            key.route_type,
+            key.route_section,
+            key.initial_delay,
+            precision_type,
+            sample_size,
+            quantile_values.join(";")
+        )?;
+        self.row_count += 1;
+        Ok(())
+    }
+
+    fn write_curve_data(&mut self, key: &RowKey, curve_data: &CurveData) -> FnResult<()> {
+        self.write_row(key, &curve_data.curve, &curve_data.precision_type, curve_data.sample_size)
+    }
+
+    /// Writes one row for each curve of the curve set, with its initial delay.
+    fn write_curve_set_data(&mut self, key: &RowKey, curve_set_data: &CurveSetData) -> FnResult<()> {
+        for (initial_delay, curve) in curve_set_data.curve_set.curves.iter() {
+            let curve_key = RowKey { initial_delay: format!("{:.0}", initial_delay), ..key.clone() };
+            self.write_row(&curve_key, curve, &curve_set_data.precision_type, curve_set_data.sample_size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the entries of the map, sorted by the given key, so that the output is deterministic.
+fn sorted_by<'m, K: Eq + Hash, V, S: Ord, F: Fn(&K) -> S>(map: &'m HashMap<K, V>, sort_key: F) -> Vec<(&'m K, &'m V)> {
+    let mut entries : Vec<(&K, &V)> = map.iter().collect();
+    entries.sort_by_key(|(k, _v)| sort_key(k));
+    entries
+}
+
+fn stop_id(stop_ids: &Vec<String>, index: u32) -> String {
+    stop_ids.get(index as usize).cloned().unwrap_or_default()
+}
+
+impl<'a> Exporter<'a> {
+    pub fn run_export(&self) -> FnResult<()> {
+        let mut quantiles = Vec::new();
+        for value in self.args.value_of("quantiles").unwrap().split(',') { // has a default value
+            let percent : f32 = value.trim().parse()?;
+            if percent < 0.0 || percent > 100.0 {
+                bail!("Quantiles must be between 0 and 100, but got {}.", percent);
+            }
+            quantiles.push((value.trim().to_string(), percent / 100.0));
+        }
+        let file_name = match self.args.value_of("output") {
+            Some(file_name) => file_name.to_string(),
+            None => format!("{}/curves_export.csv", self.main.dir),
+        };
+        let route_ids : Option<Vec<String>> = self.args.values_of("route-ids").map(|route_ids| route_ids.map(String::from).collect());
+
+        let statistics = self.main.get_delay_statistics()?;
+
+        let mut writer = TableWriter {
+            file: BufWriter::new(File::create(&file_name)?),
+            quantiles,
+            row_count: 0,
+        };
+        writer.write_header()?;
+
+        for (route_id, route_data) in sorted_by(&statistics.specific, |k| k.clone()) {
+            if let Some(route_ids) = &route_ids {
+                if !route_ids.contains(route_id) {
+                    continue;
+                }
+            }
+            Self::export_route(&mut writer, route_id, route_data)?;
+        }
+        for (route_id, variants) in sorted_by(&statistics.segments, |k| k.clone()) {
+            if let Some(route_ids) = &route_ids {
+                if !route_ids.contains(route_id) {
+                    continue;
+                }
+            }
+            Self::export_segments(&mut writer, route_id, variants)?;
+        }
+        if route_ids.is_none() {
+            Self::export_default_curves(&mut writer, &statistics.general)?;
+        }
+
+        writer.file.flush()?;
+        println!("Wrote {} rows to {}.", writer.row_count, file_name);
+        Ok(())
+    }
+
+    fn export_route(writer: &mut TableWriter, route_id: &str, route_data: &RouteData) -> FnResult<()> {
+        for (route_variant, variant_data) in sorted_by(&route_data.variants, |k| *k) {
+            let variant_key = RowKey {
+                route_id: route_id.to_string(),
+                route_variant: route_variant.to_string(),
+                ..RowKey::default()
+            };
+            for et in &EventType::TYPES {
+                for (stop_index, curve_data) in sorted_by(&variant_data.general_delay[**et], |k| *k) {
+                    let key = RowKey {
+                        kind: "general_delay",
+                        end_stop_index: stop_index.to_string(),
+                        end_stop_id: stop_id(&variant_data.stop_ids, *stop_index),
+                        event_type: format!("{:?}", et),
+                        ..variant_key.clone()
+                    };
+                    writer.write_curve_data(&key, curve_data)?;
+                }
+                for (csk, curve_set_data) in sorted_by(&variant_data.curve_sets[**et], |k| (k.start_stop_index, k.end_stop_index, k.time_slot.id)) {
+                    let key = RowKey {
+                        kind: "curve_set",
+                        start_stop_index: csk.start_stop_index.to_string(),
+                        start_stop_id: stop_id(&variant_data.stop_ids, csk.start_stop_index),
+                        end_stop_index: csk.end_stop_index.to_string(),
+                        end_stop_id: stop_id(&variant_data.stop_ids, csk.end_stop_index),
+                        time_slot: csk.time_slot.get_description().to_string(),
+                        event_type: format!("{:?}", et),
+                        ..variant_key.clone()
+                    };
+                    writer.write_curve_set_data(&key, curve_set_data)?;
+                }
+            }
+        }
+        for (time_slot, curve_set_data) in sorted_by(&route_data.layover_recovery, |k| k.id) {
+            let key = RowKey {
+                kind: "layover_recovery",
+                route_id: route_id.to_string(),
+                time_slot: time_slot.get_description().to_string(),
+                event_type: format!("{:?}", EventType::Departure),
+                ..RowKey::default()
+            };
+            writer.write_curve_set_data(&key, curve_set_data)?;
+        }
+        Ok(())
+    }
+
+    fn export_segments(writer: &mut TableWriter, route_id: &str, variants: &HashMap<u64, SegmentData>) -> FnResult<()> {
+        for (route_variant, segment_data) in sorted_by(variants, |k| *k) {
+            for (kind, map, offset) in &[("segment_run", &segment_data.run_times, 1), ("segment_dwell", &segment_data.dwell_times, 0)] {
+                for (sk, segment_curve_data) in sorted_by(map, |k| (k.stop_index, k.time_slot.id)) {
+                    let key = RowKey {
+                        kind: *kind,
+                        route_id: route_id.to_string(),
+                        route_variant: route_variant.to_string(),
+                        start_stop_index: sk.stop_index.to_string(),
+                        start_stop_id: stop_id(&segment_data.stop_ids, sk.stop_index),
+                        end_stop_index: (sk.stop_index + offset).to_string(),
+                        end_stop_id: stop_id(&segment_data.stop_ids, sk.stop_index + offset),
+                        time_slot: sk.time_slot.get_description().to_string(),
+                        ..RowKey::default()
+                    };
+                    writer.write_curve_data(&key, &segment_curve_data.curve_data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn export_default_curves(writer: &mut TableWriter, default_curves: &DefaultCurves) -> FnResult<()> {
+        let sorted_defaults = sorted_by(&default_curves.all_default_curves, |k| {
+            (format!("{:?}", k.route_type), format!("{:?}", k.route_section), k.time_slot.id, k.event_type.to_int())
+        });
+        for (dck, curve_data) in sorted_defaults {
+            let key = RowKey {
+                kind: "default",
+                route_type: format!("{:?}", dck.route_type),
+                route_section: format!("{:?}", dck.route_section),
+                time_slot: dck.time_slot.get_description().to_string(),
+                event_type: format!("{:?}", dck.event_type),
+                ..RowKey::default()
+            };
+            writer.write_curve_data(&key, curve_data)?;
+        }
+
+        for (sck, curve_data) in sorted_by(&default_curves.stop_curves, |k| (k.stop_id.clone(), k.time_slot.id, k.event_type.to_int())) {
+            let key = RowKey {
+                kind: "stop",
+                end_stop_id: sck.stop_id.clone(),
+                time_slot: sck.time_slot.get_description().to_string(),
+                event_type: format!("{:?}", sck.event_type),
+                ..RowKey::default()
+            };
+            writer.write_curve_data(&key, curve_data)?;
+        }
+        Ok(())
+    }
+}
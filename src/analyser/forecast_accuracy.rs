@@ -0,0 +1,327 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{Duration, Local, NaiveDate, NaiveDateTime};
+use chrono::offset::TimeZone;
+use clap::ArgMatches;
+use itertools::Itertools;
+use mysql::*;
+use mysql::prelude::*;
+
+use dystonse_curves::Curve;
+use dystonse_curves::tree::{SerdeFormat, NodeData};
+
+use super::Analyser;
+use super::curve_utils::make_weighted_curve;
+use super::evaluation::{Evaluator, LEAD_TIME_BUCKETS};
+use crate::predictor::Predictor;
+use crate::types::*;
+
+use crate::{FnResult, Main, date_and_time_local};
+
+/// Compares the provider's prognoses from the `records_history` table with the final delays
+/// from the `records` table, and with the curves that could have been predicted at the same time.
+pub struct ForecastAccuracyAnalyser<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches
+}
+
+/// One row of the records history, i.e. a prognosis that differed from the previous one for its stop.
+struct Prognosis {
+    stop_sequence: u16,
+    /// unix timestamp
+    time_of_recording: i64,
+    delay: EventPair<Option<i32>>,
+}
+
+/// The latest prognosis for an event within a lead time bucket,
+/// with the basis from which a curve could have been predicted at the same time.
+struct Candidate {
+    prognosis: i32,
+    basis: Option<PredictionBasis>,
+}
+
+/// Collects the errors for one event type and lead time bucket.
+#[derive(Default)]
+struct Accumulator {
+    /// prognosis minus final delay
+    provider_errors: Vec<f32>,
+    /// absolute difference between the median of the curve and the final delay
+    curve_errors: Vec<f32>,
+}
+
+impl Accumulator {
+    fn to_row(&self, event_type: EventType, bucket: usize) -> ForecastAccuracyRow {
+        let count = self.provider_errors.len();
+        let mean = |values: &mut dyn Iterator<Item = f32>| values.sum::<f32>() / count as f32;
+
+        // the distribution of the final delay relative to the prognosis, for blending.
+        // Many prognoses are exact, so the residuals have many duplicates, especially 0.
+        let residuals : Vec<(f32, f32)> = self.provider_errors.iter().map(|e| (-e, 1.0)).collect();
+        let provider_errors = make_weighted_curve(&residuals, None, SmoothingMethod::Empirical).ok().map(|(mut curve, _sum)| {
+            curve.simplify(0.001);
+            curve
+        });
+
+        ForecastAccuracyRow {
+            event_type,
+            min_lead_time: LEAD_TIME_BUCKETS[bucket],
+            lead_time: Evaluator::lead_time_label(bucket).trim().to_string(),
+            count,
+            provider_mean_absolute_error: mean(&mut self.provider_errors.iter().map(|e| e.abs())),
+            provider_bias: mean(&mut self.provider_errors.iter().cloned()),
+            provider_within_60s: self.provider_errors.iter().filter(|e| e.abs() <= 60.0).count() as f32 / count as f32,
+            curve_count: self.curve_errors.len(),
+            curve_mean_absolute_error: if self.curve_errors.is_empty() {
+                None
+            } else {
+                Some(self.curve_errors.iter().sum::<f32>() / self.curve_errors.len() as f32)
+            },
+            provider_errors,
+        }
+    }
+}
+
+impl<'a> ForecastAccuracyAnalyser<'a> {
+    pub fn run_forecast_accuracy(&self) -> FnResult<()> {
+        let yesterday = Local::today().naive_local().pred();
+        let to = match self.args.value_of("to") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => yesterday,
+        };
+        let from = match self.args.value_of("from") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => to - Duration::days(6),
+        };
+        let route_ids : Option<Vec<String>> = self.args.values_of("route-ids").map(|values| values.map(|s| s.to_string()).collect());
+        let predictor = Predictor::with_schedule(self.main, self.args, self.analyser.schedule.clone())?;
+
+        println!("Comparing the prognoses for trips from {} to {} with the final delays…", from, to);
+        let mut con = self.main.pool.get_conn()?;
+        let stmt = con.prep(
+            r"SELECT
+                h.route_id,
+                h.trip_id,
+                h.trip_start_date,
+                h.trip_start_time,
+                h.stop_sequence,
+                h.time_of_recording,
+                h.delay_arrival,
+                h.delay_departure,
+                r.delay_arrival,
+                r.delay_departure
+            FROM
+                records_history AS h
+            JOIN
+                records AS r
+            ON
+                r.source = h.source AND
+                r.trip_id = h.trip_id AND
+                r.trip_start_date = h.trip_start_date AND
+                r.trip_start_time = h.trip_start_time AND
+                r.stop_sequence = h.stop_sequence
+            WHERE
+                h.source = :source AND
+                h.trip_start_date >= :from AND
+                h.trip_start_date <= :to
+            ORDER BY
+                h.trip_id,
+                h.trip_start_date,
+                h.trip_start_time,
+                h.time_of_recording",
+        )?;
+
+        let mut result = con.exec_iter(
+            &stmt,
+            params! {
+                "source" => &self.main.source,
+                "from" => from,
+                "to" => to,
+            },
+        )?;
+
+        let result_set = result.next_set().unwrap()?;
+
+        // The rows arrive sorted by vehicle, so each vehicle is evaluated as soon as all its rows are read.
+        let mut accumulators : BTreeMap<(EventType, usize), Accumulator> = BTreeMap::new();
+        let mut vehicle : Option<(String, VehicleIdentifier)> = None;
+        let mut prognoses : Vec<Prognosis> = Vec::new();
+        let mut final_delays : HashMap<u16, EventPair<Option<i32>>> = HashMap::new();
+        let mut vehicle_count = 0;
+        for row in result_set {
+            let row = row?;
+            let route_id : String = row.get(0).unwrap();
+            if let Some(route_ids) = &route_ids {
+                if !route_ids.contains(&route_id) {
+                    continue;
+                }
+            }
+            let start_date : NaiveDate = row.get(2).unwrap();
+            let start_time : Duration = row.get(3).unwrap();
+            let vehicle_id = VehicleIdentifier {
+                trip_id: row.get(1).unwrap(),
+                start: GtfsDateTime::new(Local.from_local_date(&start_date).unwrap(), start_time.num_seconds() as i32),
+            };
+
+            if vehicle.as_ref().map_or(true, |(_route_id, id)| *id != vehicle_id) {
+                if let Some((route_id, id)) = &vehicle {
+                    self.evaluate_vehicle(&predictor, route_id, id, &prognoses, &final_delays, &mut accumulators);
+                    vehicle_count += 1;
+                }
+                vehicle = Some((route_id, vehicle_id));
+                prognoses.clear();
+                final_delays.clear();
+            }
+
+            let stop_sequence : u16 = row.get(4).unwrap();
+            let time_of_recording : NaiveDateTime = row.get(5).unwrap();
+            prognoses.push(Prognosis {
+                stop_sequence,
+                time_of_recording: Local.from_local_datetime(&time_of_recording).unwrap().timestamp(),
+                delay: EventPair {
+                    arrival: row.get_opt::<i32, _>(6).unwrap().ok(),
+                    departure: row.get_opt::<i32, _>(7).unwrap().ok(),
+                },
+            });
+            final_delays.insert(stop_sequence, EventPair {
+                arrival: row.get_opt::<i32, _>(8).unwrap().ok(),
+                departure: row.get_opt::<i32, _>(9).unwrap().ok(),
+            });
+        }
+        if let Some((route_id, id)) = &vehicle {
+            self.evaluate_vehicle(&predictor, route_id, id, &prognoses, &final_delays, &mut accumulators);
+            vehicle_count += 1;
+        }
+        println!("Evaluated the prognoses for {} vehicles.", vehicle_count);
+
+        let accuracy = ForecastAccuracy {
+            source: self.main.source.clone(),
+            from,
+            to,
+            rows: accumulators.iter().map(|((event_type, bucket), acc)| acc.to_row(*event_type, *bucket)).collect(),
+        };
+
+        for row in &accuracy.rows {
+            let curves = match row.curve_mean_absolute_error {
+                Some(error) => format!("{:.0} s", error),
+                None => String::from("unknown"),
+            };
+            println!(
+                "{:?}, {}: {} prognoses, mean absolute error of the provider {:.0} s (bias {:.0} s, {:.0} % within one minute), of the curves {}.",
+                row.event_type,
+                row.lead_time,
+                row.count,
+                row.provider_mean_absolute_error,
+                row.provider_bias,
+                row.provider_within_60s * 100.0,
+                curves
+            );
+        }
+
+        accuracy.save_to_file(&self.main.dir, ForecastAccuracy::NAME, &SerdeFormat::Json)?;
+        println!("Forecast accuracy written to {}/{}.json.", self.main.dir, ForecastAccuracy::NAME);
+
+        Ok(())
+    }
+
+    /// Replays the prognoses of one vehicle in the order in which they were recorded. For each event and lead
+    /// time bucket, the latest prognosis within that bucket is compared with the final delay, and so is the median
+    /// of the curve that could have been predicted at that time, from the latest departure that already happened.
+    fn evaluate_vehicle(
+        &self,
+        predictor: &Predictor,
+        route_id: &str,
+        vehicle_id: &VehicleIdentifier,
+        prognoses: &[Prognosis],
+        final_delays: &HashMap<u16, EventPair<Option<i32>>>,
+        accumulators: &mut BTreeMap<(EventType, usize), Accumulator>
+    ) {
+        let trip = match self.analyser.schedule.get_trip(&vehicle_id.trip_id) {
+            Ok(trip) => trip,
+            Err(_) => return,
+        };
+        let service_day = vehicle_id.start.service_day();
+        let event_time = |stop_sequence: u16, event_type: EventType, delay: i32| -> Option<i64> {
+            let time = Evaluator::get_scheduled_time(trip, stop_sequence, event_type)?;
+            Some(date_and_time_local(&service_day, time).timestamp() + delay as i64)
+        };
+
+        // the latest prognosis for each stop, as known at the time of recording
+        let mut current : BTreeMap<u16, EventPair<Option<i32>>> = BTreeMap::new();
+        let mut candidates : HashMap<(u16, EventType, usize), Candidate> = HashMap::new();
+        for (time_of_recording, recorded) in &prognoses.iter().group_by(|prognosis| prognosis.time_of_recording) {
+            for prognosis in recorded {
+                current.insert(prognosis.stop_sequence, prognosis.delay.clone());
+            }
+
+            let basis = current.iter().rev()
+                .filter_map(|(stop_sequence, delay)| {
+                    let departure = delay.departure?;
+                    if event_time(*stop_sequence, EventType::Departure, departure)? <= time_of_recording {
+                        Some(PredictionBasis { stop_sequence: *stop_sequence, delay_departure: Some(departure as i64) })
+                    } else {
+                        None
+                    }
+                })
+                .next();
+
+            for (stop_sequence, delay) in &current {
+                if basis.as_ref().map_or(false, |basis| *stop_sequence <= basis.stop_sequence) {
+                    continue;
+                }
+                for et in &EventType::TYPES {
+                    let prognosis = match delay[**et] {
+                        Some(prognosis) => prognosis,
+                        None => continue,
+                    };
+                    let lead_time = match event_time(*stop_sequence, **et, prognosis) {
+                        Some(time) => time - time_of_recording,
+                        None => continue,
+                    };
+                    if lead_time < 0 {
+                        continue;
+                    }
+                    let bucket = LEAD_TIME_BUCKETS.iter().rposition(|b| lead_time >= *b as i64 * 60).unwrap();
+                    candidates.insert((*stop_sequence, **et, bucket), Candidate { prognosis, basis: basis.clone() });
+                }
+            }
+        }
+
+        for ((stop_sequence, et, bucket), candidate) in candidates {
+            let final_delay = match final_delays.get(&stop_sequence).and_then(|delay| delay[et]) {
+                Some(delay) => delay,
+                None => continue,
+            };
+            let acc = accumulators.entry((et, bucket)).or_insert_with(Accumulator::default);
+            acc.provider_errors.push((candidate.prognosis - final_delay) as f32);
+            if let Ok(PredictionResult::CurveData(curve_data)) = predictor.predict(route_id, &vehicle_id.trip_id, &candidate.basis, stop_sequence, et, vehicle_id.start.date_time()) {
+                acc.curve_errors.push((curve_data.curve.x_at_y(0.5) - final_delay as f32).abs());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_row() {
+        let accumulator = Accumulator {
+            provider_errors: vec![0.0, 0.0, 12.0, -60.0, 0.0, 120.0],
+            curve_errors: vec![30.0, 60.0],
+        };
+        let row = accumulator.to_row(EventType::Arrival, 1);
+        assert_eq!(row.count, 6);
+        assert_eq!(row.provider_mean_absolute_error, 32.0);
+        assert_eq!(row.provider_bias, 12.0);
+        assert_eq!(row.provider_within_60s, 5.0 / 6.0);
+        assert_eq!(row.curve_count, 2);
+        assert_eq!(row.curve_mean_absolute_error, Some(45.0));
+
+        // each residual is a point of the curve, with the share of the prognoses below it
+        let (xs, ys) = row.provider_errors.unwrap().get_values_as_vectors();
+        assert_eq!(xs, vec![-120.0, -12.0, 0.0, 60.0]);
+        assert_eq!(ys, vec![0.0, 1.0 / 6.0, 2.0 / 6.0, 1.0]);
+    }
+}
@@ -0,0 +1,15 @@
+/// Escapes text so that it can be used within html elements and attribute values.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Returns the beginning of a self-contained html page, up to and including the opening body tag.
+pub fn page_start(title: &str, style: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n",
+        escape_html(title),
+        style
+    )
+}
+
+pub const PAGE_END : &str = "</body>\n</html>\n";
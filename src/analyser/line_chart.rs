@@ -0,0 +1,125 @@
+use std::ops::Range;
+
+use plotters::prelude::*;
+
+use crate::FnResult;
+
+/// A single line within a `LineChart`. A line without points only adds its caption
+/// to the legend, which can be used for headings within the legend.
+pub struct ChartLine {
+    pub caption: String,
+    pub color: RGBColor,
+    pub points: Vec<(f32, f32)>,
+    pub stroke_width: u32,
+    /// if true, a small circle is drawn at each point
+    pub with_points: bool,
+}
+
+impl ChartLine {
+    pub fn new(caption: &str, color: RGBColor, points: Vec<(f32, f32)>) -> Self {
+        ChartLine {
+            caption: String::from(caption),
+            color,
+            points,
+            stroke_width: 1,
+            with_points: true,
+        }
+    }
+
+    pub fn heading(caption: &str) -> Self {
+        Self::new(caption, WHITE, Vec::new())
+    }
+
+    pub fn with_stroke_width(mut self, stroke_width: u32) -> Self {
+        self.stroke_width = stroke_width;
+        self
+    }
+
+    pub fn without_points(mut self) -> Self {
+        self.with_points = false;
+        self
+    }
+}
+
+/// Where the legend is placed within the chart.
+pub enum LegendPosition {
+    LowerRight,
+    UpperRight,
+}
+
+/// Simple line chart with a grid and a legend, which is rendered to svg without external tools.
+pub struct LineChart {
+    pub title: String,
+    pub x_range: Range<f32>,
+    pub y_range: Range<f32>,
+    /// appended to each label of the y axis, e.g. " %"
+    pub y_label_suffix: String,
+    pub legend_position: LegendPosition,
+    pub lines: Vec<ChartLine>,
+}
+
+impl LineChart {
+    pub fn new(title: &str, x_range: Range<f32>, y_range: Range<f32>) -> Self {
+        LineChart {
+            title: String::from(title),
+            x_range,
+            y_range,
+            y_label_suffix: String::new(),
+            legend_position: LegendPosition::LowerRight,
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn save_to_svg(&self, file_name: &str, width: u32, height: u32) -> FnResult<()> {
+        let root = SVGBackend::new(file_name, (width, height)).into_drawing_area();
+        root.fill(&WHITE)?;
+
+        let mut chart = ChartBuilder::on(&root)
+            .caption(&self.title, ("sans-serif", 16).into_font())
+            .margin(15)
+            .x_label_area_size(30)
+            .y_label_area_size(50)
+            .build_ranged(self.x_range.clone(), self.y_range.clone())?;
+
+        chart
+            .configure_mesh()
+            .line_style_1(&RGBColor(0xAA, 0xAA, 0xAA))
+            .line_style_2(&RGBColor(0xEE, 0xEE, 0xEE))
+            .y_label_formatter(&|y| format!("{:.0}{}", y, self.y_label_suffix))
+            .draw()?;
+
+        for line in &self.lines {
+            let color = line.color;
+            let style = ShapeStyle::from(&color).stroke_width(line.stroke_width);
+            // plotters does not clip at the borders of the chart, so we leave out points outside of the x range
+            let points : Vec<(f32, f32)> = line.points.iter().filter(|(x, _y)| self.x_range.contains(x)).cloned().collect();
+
+            chart
+                .draw_series(LineSeries::new(points.clone(), style.clone()))?
+                .label(&line.caption)
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], style.clone()));
+
+            if line.with_points {
+                chart.draw_series(points.iter().map(|p| Circle::new(*p, 2, color.filled())))?;
+            }
+        }
+
+        let position = match self.legend_position {
+            LegendPosition::LowerRight => SeriesLabelPosition::LowerRight,
+            LegendPosition::UpperRight => SeriesLabelPosition::UpperRight,
+        };
+        chart
+            .configure_series_labels()
+            .position(position)
+            .background_style(&WHITE.mix(0.8))
+            .border_style(&BLACK)
+            .draw()?;
+
+        Ok(())
+    }
+}
+
+/// Converts a color of a colorous gradient, so that it can be used with plotters.
+pub fn plotters_color(color: colorous::Color) -> RGBColor {
+    RGBColor(color.r, color.g, color.b)
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fs;
+
+use clap::ArgMatches;
+use gtfs_structures::{RouteType, Trip};
+use plotters::coord::Shift;
+use plotters::prelude::*;
+
+use dystonse_curves::Curve;
+
+use super::Analyser;
+use super::line_chart::plotters_color;
+use crate::types::{CurveSetData, CurveSetKey, DelayStatistics, EventType, RouteVariantData, SegmentData, SegmentKey, TimeSlot};
+
+use crate::{FnResult, Main};
+
+/// Number of initial delays (quantiles of the departure delay at the start stop)
+/// over which the delay increase of a curve set is averaged.
+const INITIAL_DELAY_QUANTILES: usize = 10;
+
+/// Delay increases (in seconds) at the ends of the color scale.
+const MIN_INCREASE: f32 = -60.0;
+const MAX_INCREASE: f32 = 180.0;
+
+/// Draws maps of the network, with route segments and stops colored by the delay increase.
+pub struct MapDrawer<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches
+}
+
+/// Mean of values, weighted by their sample sizes.
+#[derive(Default)]
+struct WeightedMean {
+    sum: f64,
+    weight: f64,
+}
+
+impl WeightedMean {
+    fn add(&mut self, value: f32, sample_size: u32) {
+        self.sum += value as f64 * sample_size as f64;
+        self.weight += sample_size as f64;
+    }
+
+    fn mean(&self) -> Option<f32> {
+        if self.weight > 0.0 {
+            Some((self.sum / self.weight) as f32)
+        } else {
+            None
+        }
+    }
+}
+
+/// The connection between two consecutive stops, as (longitude, latitude) pairs.
+struct SegmentFeature {
+    points: Vec<(f64, f64)>,
+    increase: WeightedMean,
+}
+
+struct StopFeature {
+    point: (f64, f64),
+    increase: WeightedMean,
+}
+
+/// Everything that is drawn onto one map.
+#[derive(Default)]
+struct MapData {
+    /// by ids of the start and end stop, so that segments shared by several routes are drawn once
+    segments: HashMap<(String, String), SegmentFeature>,
+    stops: HashMap<String, StopFeature>,
+}
+
+impl<'a> MapDrawer<'a> {
+    pub fn run_map(&self) -> FnResult<()> {
+        let quantile = match self.args.value_of("quantile").unwrap() { // has a default value
+            "p90" => 0.9,
+            _ => 0.5,
+        };
+        let format = self.args.value_of("format").unwrap(); // has a default value
+        let width : u32 = self.args.value_of("width").unwrap().parse()?; // has a default value
+        let output_dir = match self.args.value_of("output") {
+            Some(dir) => dir.to_string(),
+            None => format!("{}/maps", self.main.dir),
+        };
+        let route_ids : Option<Vec<String>> = self.args.values_of("route-ids").map(|route_ids| route_ids.map(String::from).collect());
+
+        let statistics = self.main.get_delay_statistics()?;
+        let maps = self.collect_map_data(&statistics, &route_ids, quantile);
+
+        fs::create_dir_all(&output_dir)?;
+        let mut keys : Vec<&(RouteType, u8)> = maps.keys().collect();
+        keys.sort_by_key(|(rt, ts_id)| (format!("{:?}", rt), *ts_id));
+        for key in keys {
+            let (route_type, ts_id) = key;
+            let time_slot = TimeSlot::from_id(*ts_id).unwrap(); // the ids were taken from TIME_SLOTS_WITH_DEFAULT
+            let file_name = format!("{}/map_{:?}_{}.{}", output_dir, route_type, time_slot.description.replace(' ', "_"), format);
+            let title = format!("{:?}, {}: {} delay increase", route_type, time_slot.description, if quantile > 0.5 { "90th percentile of the" } else { "median" });
+            let map_data = &maps[key];
+            let height = Self::get_height(map_data, width);
+            if format == "png" {
+                self.draw_map(BitMapBackend::new(&file_name, (width, height)).into_drawing_area(), map_data, &title)?;
+            } else {
+                self.draw_map(SVGBackend::new(&file_name, (width, height)).into_drawing_area(), map_data, &title)?;
+            }
+            println!("Drew {} segments and {} stops to {}.", map_data.segments.len(), map_data.stops.len(), file_name);
+        }
+        Ok(())
+    }
+
+    /// Collects the delay increases of all route variants, for each route type and time slot.
+    fn collect_map_data(&self, statistics: &DelayStatistics, route_ids: &Option<Vec<String>>, quantile: f32) -> HashMap<(RouteType, u8), MapData> {
+        let schedule = &self.analyser.schedule;
+        let mut maps : HashMap<(RouteType, u8), MapData> = HashMap::new();
+
+        for (route_id, route_data) in &statistics.specific {
+            if let Some(route_ids) = route_ids {
+                if !route_ids.contains(route_id) {
+                    continue;
+                }
+            }
+            let route = match schedule.get_route(route_id) {
+                Ok(route) => route,
+                Err(_) => continue,
+            };
+            for (route_variant, data) in &route_data.variants {
+                let variant_as_string = Some(format!("{}", route_variant));
+                let trip = match schedule.trips.values().find(|trip| trip.route_id == *route_id && trip.route_variant == variant_as_string) {
+                    Some(trip) => trip,
+                    None => {
+                        println!("Could not find trip for route_variant {}.", route_variant);
+                        continue;
+                    }
+                };
+                let segment_data = statistics.segments.get(route_id).and_then(|variants| variants.get(route_variant));
+                let geometries = self.get_segment_geometries(trip);
+
+                for ts in &TimeSlot::TIME_SLOTS_WITH_DEFAULT {
+                    let map_data = maps.entry((route.route_type, ts.id)).or_insert_with(MapData::default);
+                    for (i, points) in geometries.iter().enumerate() {
+                        let i = i as u32;
+                        let (from, to) = (&trip.stop_times[i as usize].stop, &trip.stop_times[i as usize + 1].stop);
+                        if let Some(points) = points {
+                            if let Some((increase, sample_size)) = Self::get_segment_increase(data, segment_data, i, ts, quantile) {
+                                map_data.segments
+                                    .entry((from.id.clone(), to.id.clone()))
+                                    .or_insert_with(|| SegmentFeature { points: points.clone(), increase: WeightedMean::default() })
+                                    .increase.add(increase, sample_size);
+                            }
+                        }
+                        // there is no dwell time at the last stop
+                        let is_last_stop = i as usize + 2 == trip.stop_times.len();
+                        if let (Some(lon), Some(lat), false) = (to.longitude, to.latitude, is_last_stop) {
+                            if let Some((increase, sample_size)) = Self::get_stop_increase(data, segment_data, i + 1, ts, quantile) {
+                                map_data.stops
+                                    .entry(to.id.clone())
+                                    .or_insert_with(|| StopFeature { point: (lon, lat), increase: WeightedMean::default() })
+                                    .increase.add(increase, sample_size);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // don't draw maps without any data
+        maps.retain(|_key, map_data| !map_data.segments.is_empty() || !map_data.stops.is_empty());
+        maps
+    }
+
+    /// Returns the line between each pair of consecutive stops. The shape of the trip
+    /// is used if it exists, otherwise the stops are connected by straight lines.
+    /// Segments between stops without coordinates are None.
+    fn get_segment_geometries(&self, trip: &Trip) -> Vec<Option<Vec<(f64, f64)>>> {
+        let stops : Vec<Option<(f64, f64)>> = trip.stop_times.iter().map(|st| match (st.stop.longitude, st.stop.latitude) {
+            (Some(lon), Some(lat)) => Some((lon, lat)),
+            _ => None
+        }).collect();
+
+        let mut shape_points : Vec<(f64, f64)> = Vec::new();
+        if let Some(shape) = trip.shape_id.as_ref().and_then(|shape_id| self.analyser.schedule.shapes.get(shape_id)) {
+            let mut sorted_shape : Vec<_> = shape.iter().collect();
+            sorted_shape.sort_by_key(|point| point.sequence);
+            shape_points = sorted_shape.iter().map(|point| (point.longitude, point.latitude)).collect();
+        }
+
+        // index of the shape point that is closest to each stop, searching only forward along the shape
+        let mut shape_indices : Vec<Option<usize>> = Vec::new();
+        let mut search_start = 0;
+        for stop in &stops {
+            let index = match stop {
+                Some(stop) if search_start < shape_points.len() => {
+                    let (offset, _) = shape_points[search_start..].iter().enumerate()
+                        .map(|(offset, point)| (offset, (point.0 - stop.0).powi(2) + (point.1 - stop.1).powi(2)))
+                        .fold((0, std::f64::MAX), |best, candidate| if candidate.1 < best.1 { candidate } else { best });
+                    search_start += offset;
+                    Some(search_start)
+                },
+                _ => None
+            };
+            shape_indices.push(index);
+        }
+
+        let mut geometries = Vec::new();
+        for i in 0..stops.len().saturating_sub(1) {
+            let geometry = match (stops[i], stops[i + 1]) {
+                (Some(from), Some(to)) => {
+                    let mut points = match (shape_indices[i], shape_indices[i + 1]) {
+                        (Some(from_index), Some(to_index)) if to_index > from_index => shape_points[from_index..=to_index].to_vec(),
+                        _ => Vec::new()
+                    };
+                    // the segment starts and ends exactly at the stops, even if the shape passes them at a distance
+                    points.insert(0, from);
+                    points.push(to);
+                    Some(points)
+                },
+                _ => None
+            };
+            geometries.push(geometry);
+        }
+        geometries
+    }
+
+    /// Returns the increase of the delay from the departure at the stop with index `stop_index`
+    /// to the arrival at the next stop, together with the sample size. Falls back to the run times of
+    /// the segments if there is no curve set.
+    fn get_segment_increase(data: &RouteVariantData, segment_data: Option<&SegmentData>, stop_index: u32, ts: &TimeSlot, quantile: f32) -> Option<(f32, u32)> {
+        if let Some(increase) = Self::get_curve_set_increase(data, EventType::Arrival, stop_index, ts, quantile) {
+            return Some(increase);
+        }
+        let key = SegmentKey { stop_index, time_slot: ts.clone() };
+        segment_data.and_then(|sd| sd.run_times.get(&key)).map(|run_time| {
+            (run_time.curve_data.curve.x_at_y(quantile) - run_time.scheduled_duration, run_time.curve_data.sample_size)
+        })
+    }
+
+    /// Returns the increase of the delay from the arrival to the departure at the stop with index `stop_index`,
+    /// which is computed from the curve sets that start at the previous stop. Falls back to the dwell times
+    /// of the segments if there are no such curve sets.
+    fn get_stop_increase(data: &RouteVariantData, segment_data: Option<&SegmentData>, stop_index: u32, ts: &TimeSlot, quantile: f32) -> Option<(f32, u32)> {
+        if stop_index > 0 {
+            let arrival = Self::get_curve_set_increase(data, EventType::Arrival, stop_index - 1, ts, quantile);
+            let departure = Self::get_curve_set_increase(data, EventType::Departure, stop_index - 1, ts, quantile);
+            if let (Some((arrival, arrival_samples)), Some((departure, departure_samples))) = (arrival, departure) {
+                return Some((departure - arrival, u32::min(arrival_samples, departure_samples)));
+            }
+        }
+        let key = SegmentKey { stop_index, time_slot: ts.clone() };
+        segment_data.and_then(|sd| sd.dwell_times.get(&key)).map(|dwell_time| {
+            (dwell_time.curve_data.curve.x_at_y(quantile) - dwell_time.scheduled_duration, dwell_time.curve_data.sample_size)
+        })
+    }
+
+    /// Returns the quantile of the delay at the stop after `start_stop_index`, minus the initial delay,
+    /// averaged over the distribution of the departure delay at the start stop.
+    fn get_curve_set_increase(data: &RouteVariantData, et: EventType, start_stop_index: u32, ts: &TimeSlot, quantile: f32) -> Option<(f32, u32)> {
+        let key = CurveSetKey {
+            start_stop_index,
+            end_stop_index: start_stop_index + 1,
+            time_slot: ts.clone()
+        };
+        let curve_set_data : &CurveSetData = data.curve_sets[et].get(&key)?;
+        if curve_set_data.curve_set.curves.is_empty() {
+            return None;
+        }
+        let increases : Vec<f32> = match data.general_delay.departure.get(&start_stop_index) {
+            Some(start) => (0..INITIAL_DELAY_QUANTILES).map(|i| {
+                let initial_delay = start.curve.x_at_y((i as f32 + 0.5) / INITIAL_DELAY_QUANTILES as f32);
+                curve_set_data.curve_set.curve_at_x_with_continuation(initial_delay).x_at_y(quantile) - initial_delay
+            }).collect(),
+            None => curve_set_data.curve_set.curves.iter().map(|(initial_delay, curve)| curve.x_at_y(quantile) - initial_delay).collect()
+        };
+        Some((increases.iter().sum::<f32>() / increases.len() as f32, curve_set_data.sample_size))
+    }
+
+    /// Returns the bounding box of all features, as (min_lon, max_lon, min_lat, max_lat).
+    fn get_bounds(map_data: &MapData) -> (f64, f64, f64, f64) {
+        let points = map_data.segments.values().flat_map(|segment| segment.points.iter())
+            .chain(map_data.stops.values().map(|stop| &stop.point));
+        points.fold(
+            (std::f64::MAX, std::f64::MIN, std::f64::MAX, std::f64::MIN),
+            |(min_lon, max_lon, min_lat, max_lat), (lon, lat)| (min_lon.min(*lon), max_lon.max(*lon), min_lat.min(*lat), max_lat.max(*lat))
+        )
+    }
+
+    /// Computes the height of the image, so that the map is not distorted.
+    fn get_height(map_data: &MapData, width: u32) -> u32 {
+        let (min_lon, max_lon, min_lat, max_lat) = Self::get_bounds(map_data);
+        let lon_scale = ((min_lat + max_lat) / 2.0).to_radians().cos();
+        let aspect = (max_lat - min_lat) / ((max_lon - min_lon) * lon_scale);
+        if aspect.is_finite() && aspect > 0.0 {
+            (width as f64 * aspect).max(200.0).min(width as f64 * 4.0) as u32 + 60 // room for the title
+        } else {
+            width
+        }
+    }
+
+    fn draw_map<DB: DrawingBackend>(&self, root: DrawingArea<DB, Shift>, map_data: &MapData, title: &str) -> FnResult<()> where DB::ErrorType: 'static {
+        root.fill(&WHITE)?;
+        let (min_lon, max_lon, min_lat, max_lat) = Self::get_bounds(map_data);
+        let margin = (max_lon - min_lon).max(max_lat - min_lat) * 0.02 + 0.001;
+
+        let mut chart = ChartBuilder::on(&root)
+            .caption(title, ("sans-serif", 20).into_font())
+            .margin(10)
+            .build_ranged(min_lon - margin..max_lon + margin, min_lat - margin..max_lat + margin)?;
+
+        let mut segments : Vec<&SegmentFeature> = map_data.segments.values().collect();
+        // draw the segments with the biggest increase last, so they are not hidden by others
+        segments.sort_by(|a, b| a.increase.mean().partial_cmp(&b.increase.mean()).unwrap_or(std::cmp::Ordering::Equal));
+        chart.draw_series(segments.iter().filter_map(|segment| {
+            let increase = segment.increase.mean()?;
+            Some(PathElement::new(segment.points.clone(), ShapeStyle::from(&increase_color(increase)).stroke_width(3)))
+        }))?;
+        chart.draw_series(map_data.stops.values().filter_map(|stop| {
+            let increase = stop.increase.mean()?;
+            Some(Circle::new(stop.point, 4, increase_color(increase).filled()))
+        }))?;
+
+        // legend with some steps of the color scale
+        let steps = [MIN_INCREASE, 0.0, 30.0, 60.0, 120.0, MAX_INCREASE];
+        for (i, step) in steps.iter().enumerate() {
+            let prefix = if i == 0 { "≤ " } else if i == steps.len() - 1 { "≥ " } else { "" };
+            let color = increase_color(*step);
+            chart
+                .draw_series(std::iter::empty::<Circle<(f64, f64), i32>>())?
+                .label(format!("{}{} s", prefix, step))
+                .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], color.filled()));
+        }
+        chart
+            .configure_series_labels()
+            .position(SeriesLabelPosition::UpperRight)
+            .background_style(&WHITE.mix(0.8))
+            .border_style(&BLACK)
+            .draw()?;
+
+        Ok(())
+    }
+}
+
+/// Maps delay increases from `MIN_INCREASE` (green) to `MAX_INCREASE` (red) onto a color scale.
+fn increase_color(increase: f32) -> RGBColor {
+    let t = ((increase - MIN_INCREASE) / (MAX_INCREASE - MIN_INCREASE)).max(0.0).min(1.0);
+    plotters_color(colorous::RED_YELLOW_GREEN.eval_continuous(1.0 - t as f64))
+}
@@ -1,9 +1,20 @@
+mod anomalies;
 mod count;
-mod curve_utils;
+mod coverage;
+pub mod curve_utils;
 mod curve_visualisation;
 pub mod specific_curves;
 pub mod default_curves;
 pub mod curves;
+mod evaluation;
+mod export;
+mod forecast_accuracy;
+mod html;
+mod line_chart;
+mod map;
+mod report;
+mod segments;
+mod trajectory;
 
 #[cfg(feature = "visual-schedule")]
 mod visual_schedule;
@@ -13,11 +24,19 @@ use clap::{App, Arg, ArgMatches};
 use gtfs_structures::Gtfs;
 use regex::Regex;
 
+use anomalies::AnomalyDetector;
 use count::*;
+use coverage::CoverageReporter;
 use specific_curves::SpecificCurveCreator;
 use default_curves::DefaultCurveCreator;
 use curves::CurveCreator;
 use curve_visualisation::CurveDrawer;
+use evaluation::Evaluator;
+use export::Exporter;
+use forecast_accuracy::ForecastAccuracyAnalyser;
+use map::MapDrawer;
+use report::ReportCreator;
+use segments::SegmentCreator;
 
 #[cfg(feature = "visual-schedule")]
 use visual_schedule::*;
@@ -47,6 +66,38 @@ impl<'a> Analyser<'a> {
                     .takes_value(true)
                 )
             )
+            .subcommand(App::new("coverage")
+                .about("Reports how much realtime data there is for each route, route variant and time slot, and when no realtime data was recorded at all.")
+                .arg(Arg::new("from")
+                    .long("from")
+                    .about("First trip start date (YYYY-MM-DD) to include. Defaults to 27 days before --to.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("to")
+                    .long("to")
+                    .about("Last trip start date (YYYY-MM-DD) to include. Defaults to yesterday.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, only the selected routes are reported. Otherwise, all routes of the schedule are reported.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                ).arg(Arg::new("gap-minutes")
+                    .long("gap-minutes")
+                    .about("Minimum length of a time window without any realtime data to be reported as a gap.")
+                    .value_name("MINUTES")
+                    .takes_value(true)
+                    .default_value("30")
+                ).arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .about("Directory for the report. Defaults to the 'coverage' subdirectory of --dir.")
+                    .value_name("DIR")
+                    .takes_value(true)
+                )
+            )
             .subcommand(App::new("compute-specific-curves")
                 .about("Generates curve data for specific routes from realtime data out of the database")
                 .arg(Arg::new("route-ids")
@@ -60,10 +111,47 @@ impl<'a> Analyser<'a> {
                     .long("all")
                     .about("If provided, curves will be computed for each route of the schedule.")
                     .conflicts_with("route-ids")
+                ).arg(Arg::new("separate-variants")
+                    .short('s')
+                    .long("separate-variants")
+                    .about("If provided, the curves of each route variant are computed only from its own records. Otherwise, route variants whose stops are a part of the stops of another route variant share their records and curves.")
+                ).arg(Arg::new("group-stations")
+                    .short('g')
+                    .long("group-stations")
+                    .about("If provided, stops of the same station (e.g. several platforms) are treated as one, so that records at another platform are used for the scheduled stop.")
+                ).arg(Arg::new("exclude-projections")
+                    .short('e')
+                    .long("exclude-projections")
+                    .about("If provided, delays that were projected onto stops without records are not used for the curves.")
+                ).arg(Arg::new("exclude-anomalies")
+                    .short('x')
+                    .long("exclude-anomalies")
+                    .about("If provided, the records of vehicles that were flagged in the anomalies.json file of --dir (see 'analyse anomalies') are left out.")
+                ).arg(Arg::new("smoothing")
+                    .long("smoothing")
+                    .about("How the delay curves are made from the samples: as empirical distribution, by kernel density estimation, or as fitted log-normal distribution. The method is stored with each curve.")
+                    .value_name("METHOD")
+                    .possible_values(&["empirical", "kde", "lognormal"])
+                    .default_value("empirical")
                 )
             )
             .subcommand(App::new("compute-default-curves")
                 .about("Generates default curve data from realtime data out of the database")
+                .arg(Arg::new("exclude-anomalies")
+                    .short('x')
+                    .long("exclude-anomalies")
+                    .about("If provided, the records of vehicles that were flagged in the anomalies.json file of --dir (see 'analyse anomalies') are left out.")
+                ).arg(Arg::new("smoothing")
+                    .long("smoothing")
+                    .about("How the delay curves are made from the samples: as empirical distribution, by kernel density estimation, or as fitted log-normal distribution. The method is stored with each curve.")
+                    .value_name("METHOD")
+                    .possible_values(&["empirical", "kde", "lognormal"])
+                    .default_value("empirical")
+                ).arg(Arg::new("group-stations")
+                    .short('g')
+                    .long("group-stations")
+                    .about("If provided, the stop curves are computed for stations of stops with the same name that are close by, not only for stops with the same parent station.")
+                )
             )
             .subcommand(App::new("compute-curves")
                 .about("Generates default and specific curve data from realtime data out of the database")
@@ -83,6 +171,223 @@ impl<'a> Analyser<'a> {
                     .long("default-only")
                     .about("If provided, only default curves will be generated, but the output format is still the same.")
                     .conflicts_with("route-ids")
+                ).arg(Arg::new("incremental")
+                    .short('i')
+                    .long("incremental")
+                    .about("If provided, only records that are newer than those of the last run will be read from the database. They are merged into the samples stored in curve_samples.exp, from which all curves are recomputed.")
+                    .requires("all")
+                    .conflicts_with("default-only")
+                ).arg(Arg::new("separate-variants")
+                    .short('s')
+                    .long("separate-variants")
+                    .about("If provided, the curves of each route variant are computed only from its own records. Otherwise, route variants whose stops are a part of the stops of another route variant share their records and curves.")
+                    .conflicts_with("default-only")
+                ).arg(Arg::new("group-stations")
+                    .short('g')
+                    .long("group-stations")
+                    .about("If provided, stops of the same station (e.g. several platforms) are treated as one, so that records at another platform are used for the scheduled stop. This also applies to the stop curves.")
+                ).arg(Arg::new("exclude-projections")
+                    .short('e')
+                    .long("exclude-projections")
+                    .about("If provided, delays that were projected onto stops without records are not used for the curves.")
+                    .conflicts_with("default-only")
+                ).arg(Arg::new("exclude-anomalies")
+                    .short('x')
+                    .long("exclude-anomalies")
+                    .about("If provided, the records of vehicles that were flagged in the anomalies.json file of --dir (see 'analyse anomalies') are left out. Not available with --incremental, as the stored samples would still contain the flagged vehicles.")
+                    .conflicts_with("incremental")
+                ).arg(Arg::new("smoothing")
+                    .long("smoothing")
+                    .about("How the delay curves are made from the samples: as empirical distribution, by kernel density estimation, or as fitted log-normal distribution. The method is stored with each curve.")
+                    .value_name("METHOD")
+                    .possible_values(&["empirical", "kde", "lognormal"])
+                    .default_value("empirical")
+                ).arg(Arg::new("blend")
+                    .long("blend")
+                    .about("If provided, the general delay curves of each stop are blended with the default curve of its route section. A curve from the given number of samples gets the same weight as the default curve, curves from more samples get more weight.")
+                    .value_name("SAMPLES")
+                    .takes_value(true)
+                    .conflicts_with("default-only")
+                )
+            )
+            .subcommand(App::new("anomalies")
+                .about("Finds days on which the delays of a route differ significantly from the previous weeks within the same time slot.")
+                .arg(Arg::new("from")
+                    .long("from")
+                    .about("First trip start date (YYYY-MM-DD) to check. Defaults to 6 days before --to.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("to")
+                    .long("to")
+                    .about("Last trip start date (YYYY-MM-DD) to check. Defaults to yesterday.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("history-days")
+                    .long("history-days")
+                    .about("Number of days before each checked day that are used as history.")
+                    .value_name("DAYS")
+                    .takes_value(true)
+                    .default_value("56")
+                ).arg(Arg::new("threshold")
+                    .long("threshold")
+                    .about("Minimum absolute score for a day to be reported as an anomaly.")
+                    .value_name("SCORE")
+                    .takes_value(true)
+                    .default_value("3.5")
+                ).arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, only the selected routes are checked. Otherwise, all routes of the schedule are checked.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                )
+            )
+            .subcommand(App::new("evaluate")
+                .about("Compares predictions with the delays that were observed afterwards, and reports how well they are calibrated.")
+                .arg(Arg::new("from")
+                    .long("from")
+                    .about("First trip start date (YYYY-MM-DD) to evaluate. Defaults to six days before --to.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("to")
+                    .long("to")
+                    .about("Last trip start date (YYYY-MM-DD) to evaluate. Defaults to yesterday.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("recompute")
+                    .long("recompute")
+                    .about("If provided, predictions are recomputed from the current curves for several lead times, instead of using the predictions stored by the importer.")
+                ).arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, only predictions for the selected routes are evaluated.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                ).arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .about("Directory for the report. Defaults to the 'evaluation' subdirectory of --dir.")
+                    .value_name("DIR")
+                    .takes_value(true)
+                ).arg(Arg::new("charts")
+                    .long("charts")
+                    .about("If provided, PIT histograms are drawn as SVG files.")
+                )
+            )
+            .subcommand(App::new("forecast-accuracy")
+                .about("Compares the prognoses of the provider, recorded with 'import --record-history', with the final delays and with the curves.")
+                .long_about(
+                    "Compares the prognoses of the provider, recorded with 'import --record-history', with the final delays \
+                    and with the medians of the curves that could have been predicted at the same time. The errors are reported \
+                    by event type and lead time, and written to forecast_accuracy.json in --dir, where 'import --blend-provider' reads them."
+                )
+                .arg(Arg::new("from")
+                    .long("from")
+                    .about("First trip start date (YYYY-MM-DD) to analyse. Defaults to six days before --to.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("to")
+                    .long("to")
+                    .about("Last trip start date (YYYY-MM-DD) to analyse. Defaults to yesterday.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, only prognoses for the selected routes are analysed.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                )
+            )
+            .subcommand(App::new("export")
+                .about("Writes all curves from the delay statistics into a CSV file, with selected quantiles of each curve.")
+                .arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .about("Name of the CSV file. Defaults to curves_export.csv in --dir.")
+                    .value_name("FILE")
+                    .takes_value(true)
+                ).arg(Arg::new("quantiles")
+                    .short('q')
+                    .long("quantiles")
+                    .about("Comma-separated list of quantiles (in percent) that are written for each curve.")
+                    .value_name("PERCENTS")
+                    .takes_value(true)
+                    .default_value("5,25,50,75,95")
+                ).arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, only the curves of the selected routes are exported, and no default curves.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                )
+            )
+            .subcommand(App::new("map")
+                .about("Draws maps of the network with route segments and stops colored by the delay increase, one for each route type and time slot.")
+                .arg(Arg::new("quantile")
+                    .short('q')
+                    .long("quantile")
+                    .about("Which quantile of the delay increase is used for the colors.")
+                    .value_name("QUANTILE")
+                    .possible_values(&["median", "p90"])
+                    .default_value("median")
+                ).arg(Arg::new("format")
+                    .short('f')
+                    .long("format")
+                    .about("File format of the maps.")
+                    .value_name("FORMAT")
+                    .possible_values(&["svg", "png"])
+                    .default_value("svg")
+                ).arg(Arg::new("width")
+                    .short('w')
+                    .long("width")
+                    .about("Width of the maps in pixels. The height is chosen to fit the network.")
+                    .value_name("PIXELS")
+                    .default_value("2000")
+                ).arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, only the selected routes are drawn.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                ).arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .about("Directory for the maps. Defaults to the 'maps' subdirectory of --dir.")
+                    .value_name("DIR")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(App::new("report")
+                .about("Writes an HTML report about the punctuality of the selected routes, based on the delay statistics.")
+                .arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("The routes for which a report is written, one file per route.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                    .required(true)
+                ).arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .about("Directory for the reports. Defaults to the 'reports' subdirectory of --dir.")
+                    .value_name("DIR")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(App::new("segments")
+                .about("Computes the distributions of run times between consecutive stops and of dwell times at stops, and compares them with the schedule.")
+                .arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, segments will be computed for each route variant of each of the selected routes.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                ).arg(Arg::new("all")
+                    .short('a')
+                    .long("all")
+                    .about("If provided, segments will be computed for each route of the schedule.")
+                    .conflicts_with("route-ids")
                 )
             )
             .subcommand(App::new("draw-curves")
@@ -93,12 +398,11 @@ impl<'a> Analyser<'a> {
                     .about("If provided, curves will be drawn for each route variant of each of the selected routes.")
                     .value_name("ROUTE_ID")
                     .multiple(true)
-                // TODO implement the "all" mode
-                // ).arg(Arg::new("all")
-                //     .short('a')
-                //     .long("all")
-                //     .about("If provided, curves will be drawn for each route of the schedule.")
-                //     .conflicts_with("route-ids")
+                ).arg(Arg::new("all")
+                    .short('a')
+                    .long("all")
+                    .about("If provided, curves will be drawn for each route that has curve data.")
+                    .conflicts_with("route-ids")
                 )
             );
 
@@ -124,6 +428,20 @@ impl<'a> Analyser<'a> {
                         .long("all")
                         .about("If provided, graphical schedules will be created for each route of the schedule.")
                         .conflicts_with("route-ids")
+                    ).arg(Arg::new("date")
+                        .short('d')
+                        .long("date")
+                        .about("If provided, only the trips and records of this date (YYYY-MM-DD) are drawn, together with the predicted ranges of their delays.")
+                        .value_name("DATE")
+                        .takes_value(true)
+                    ).arg(Arg::new("recompute")
+                        .long("recompute")
+                        .about("If provided, the predictions are computed from the current curves, instead of using the predictions stored by the importer.")
+                        .requires("date")
+                    ).arg(Arg::new("realtime-only")
+                        .long("realtime-only")
+                        .about("If provided, only predictions that are based on realtime data are drawn.")
+                        .requires("date")
                     )
                 );
             }
@@ -143,6 +461,14 @@ impl<'a> Analyser<'a> {
     pub fn run(&mut self) -> FnResult<()> {
         match self.args.clone().subcommand() {
             ("count", Some(_sub_args)) => run_count(&self),
+            ("coverage", Some(sub_args)) => {
+                let reporter = CoverageReporter {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                reporter.run_coverage()
+            },
             #[cfg(feature = "visual-schedule")]
             ("graph", Some(sub_args)) => {
                 let mut vsc = VisualScheduleCreator { 
@@ -176,6 +502,62 @@ impl<'a> Analyser<'a> {
                 };
                 cc.run_curves()
             },
+            ("anomalies", Some(sub_args)) => {
+                let ad = AnomalyDetector {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                ad.run_anomalies()
+            },
+            ("evaluate", Some(sub_args)) => {
+                let evaluator = Evaluator {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                evaluator.run_evaluate()
+            },
+            ("forecast-accuracy", Some(sub_args)) => {
+                let fa = ForecastAccuracyAnalyser {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                fa.run_forecast_accuracy()
+            },
+            ("export", Some(sub_args)) => {
+                let exporter = Exporter {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                exporter.run_export()
+            },
+            ("map", Some(sub_args)) => {
+                let md = MapDrawer {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                md.run_map()
+            },
+            ("report", Some(sub_args)) => {
+                let rc = ReportCreator {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                rc.run_report()
+            },
+            ("segments", Some(sub_args)) => {
+                let sc = SegmentCreator {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                sc.run_segments()
+            },
             ("draw-curves", Some(sub_args)) => {
                 let cd = CurveDrawer {
                     main: self.main,
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+
+use chrono::Local;
+use clap::ArgMatches;
+use gtfs_structures::{Route, Trip};
+use simple_error::bail;
+
+use dystonse_curves::irregular_dynamic::*;
+use dystonse_curves::Curve;
+
+use super::Analyser;
+use super::html::{escape_html, page_start, PAGE_END};
+use crate::types::{DelayStatistics, DefaultCurveKey, EventType, RouteSection, RouteVariantData, TimeSlot};
+
+use crate::{FnResult, Main};
+
+/// Number of initial delays (quantiles of the departure delay at the start stop)
+/// that are used to combine the curves of a curve set into a single curve.
+const INITIAL_DELAY_QUANTILES: usize = 10;
+
+/// Number of curve sets that are listed as most affected by the initial delay.
+const MOST_AFFECTED_COUNT: usize = 10;
+
+const STYLE : &str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 2px 6px; text-align: right; }
+th { background: #eee; }
+td.name { text-align: left; }
+svg { display: block; margin-bottom: 2em; }";
+
+/// Writes an html report about the punctuality of routes, based on the delay statistics.
+pub struct ReportCreator<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches
+}
+
+/// Arrival delay at one stop in one time slot.
+struct CellStats {
+    median: f32,
+    p90: f32,
+    sample_size: u32,
+}
+
+/// A curve set whose curves differ a lot depending on the initial delay.
+struct AffectedCurveSet {
+    start_stop_index: u32,
+    end_stop_index: u32,
+    time_slot: TimeSlot,
+    min_initial_delay: f32,
+    max_initial_delay: f32,
+    min_median: f32,
+    max_median: f32,
+    sample_size: u32,
+}
+
+impl AffectedCurveSet {
+    fn effect(&self) -> f32 {
+        self.max_median - self.min_median
+    }
+}
+
+impl<'a> ReportCreator<'a> {
+    pub fn run_report(&self) -> FnResult<()> {
+        let output_dir = match self.args.value_of("output") {
+            Some(dir) => dir.to_string(),
+            None => format!("{}/reports", self.main.dir),
+        };
+        fs::create_dir_all(&output_dir)?;
+
+        let statistics = self.main.get_delay_statistics()?;
+        for route_id in self.args.values_of("route-ids").unwrap() { // is required
+            match self.create_report_for_route(route_id, &statistics) {
+                Ok(html) => {
+                    let file_name = format!("{}/route_{}.html", output_dir, route_id);
+                    fs::write(&file_name, html)?;
+                    println!("Wrote report for route {} to {}.", route_id, file_name);
+                },
+                Err(e) => println!("Could not create report for route {}: {}", route_id, e)
+            }
+        }
+        Ok(())
+    }
+
+    fn create_report_for_route(&self, route_id: &str, statistics: &DelayStatistics) -> FnResult<String> {
+        let schedule = &self.analyser.schedule;
+        let route = schedule.get_route(route_id)?;
+        let route_data = match statistics.specific.get(route_id) {
+            Some(route_data) => route_data,
+            None => bail!("No curve data for route {}.", route_id)
+        };
+        let agency_name = schedule
+            .agencies
+            .iter()
+            .find(|agency| agency.id == route.agency_id)
+            .map(|agency| agency.name.clone())
+            .unwrap_or_default();
+
+        let title = format!("Reliability report for route {}", route.short_name);
+        let mut html = page_start(&title, STYLE);
+        writeln!(html, "<h1>{}</h1>", escape_html(&title))?;
+        writeln!(
+            html,
+            "<p>{} ({:?}, route id {}), source {}. Generated on {} from the delay statistics in {}.</p>",
+            escape_html(&agency_name),
+            route.route_type,
+            escape_html(route_id),
+            escape_html(&self.main.source),
+            Local::now().format("%Y-%m-%d %H:%M"),
+            escape_html(&self.main.dir)
+        )?;
+        writeln!(html, "<p>All delays are arrival delays, shown in minutes in the heatmaps and in seconds elsewhere. For each stop and time slot, the curves of the curve set from the first stop with data \
+            are combined over the distribution of the departure delay at that stop. The column \"{}\" contains all time slots.</p>", TimeSlot::DEFAULT.description)?;
+
+        let mut route_variants : Vec<&u64> = route_data.variants.keys().collect();
+        route_variants.sort();
+        for route_variant in route_variants {
+            let variant_as_string = Some(format!("{}", route_variant));
+            let trip = match schedule.trips.values().find(|trip| trip.route_id == route_id && trip.route_variant == variant_as_string) {
+                Some(trip) => trip,
+                None => {
+                    println!("Could not find trip for route_variant {}.", route_variant);
+                    continue;
+                }
+            };
+            self.write_variant(&mut html, route, *route_variant, trip, &route_data.variants[route_variant], statistics)?;
+        }
+
+        html.push_str(PAGE_END);
+        Ok(html)
+    }
+
+    fn write_variant(&self, html: &mut String, route: &Route, route_variant: u64, trip: &Trip, data: &RouteVariantData, statistics: &DelayStatistics) -> FnResult<()> {
+        let stop_names : Vec<String> = data.stop_ids.iter().map(|stop_id| match self.analyser.schedule.get_stop(stop_id) {
+            Ok(stop) => stop.name.clone(),
+            Err(_) => stop_id.clone()
+        }).collect();
+        let headsign = trip.trip_headsign.clone().unwrap_or_else(|| stop_names.last().cloned().unwrap_or_default());
+
+        writeln!(html, "<h2>Route variant {} to {}</h2>", route_variant, escape_html(&headsign))?;
+
+        let mut cells : HashMap<(u32, u8), CellStats> = HashMap::new();
+        for stop_index in 0..data.stop_ids.len() as u32 {
+            for ts in &TimeSlot::TIME_SLOTS_WITH_DEFAULT {
+                if let Some(cell) = Self::get_cell_stats(data, stop_index, ts) {
+                    cells.insert((stop_index, ts.id), cell);
+                }
+            }
+        }
+
+        writeln!(html, "<h3>Median delay per stop and time slot</h3>")?;
+        Self::write_heatmap(html, &stop_names, &cells, |cell| cell.median)?;
+        writeln!(html, "<h3>90th percentile of the delay per stop and time slot</h3>")?;
+        Self::write_heatmap(html, &stop_names, &cells, |cell| cell.p90)?;
+
+        writeln!(html, "<h3>Delay growth along the route</h3>")?;
+        Self::write_growth_chart(html, &stop_names, &cells)?;
+
+        writeln!(html, "<h3>Sample sizes</h3>")?;
+        Self::write_sample_sizes(html, &stop_names, data, &cells)?;
+
+        writeln!(html, "<h3>Curve sets most affected by the initial delay</h3>")?;
+        Self::write_most_affected(html, &stop_names, data)?;
+
+        writeln!(html, "<h3>Comparison with the default curves for {:?}</h3>", route.route_type)?;
+        Self::write_default_comparison(html, route, trip, data.stop_ids.len(), &cells, statistics)?;
+
+        Ok(())
+    }
+
+    /// Combines the arrival curve sets that end at `stop_index` into a single curve, weighted by
+    /// the departure delay at the start stop. The curve set from the first possible start stop is used.
+    /// Without curve sets, the general delay of the route variant is used for the default time slot.
+    fn get_cell_stats(data: &RouteVariantData, stop_index: u32, ts: &TimeSlot) -> Option<CellStats> {
+        let curve_set = data.curve_sets.arrival.iter()
+            .filter(|(key, _)| key.end_stop_index == stop_index && key.time_slot.id == ts.id)
+            .min_by_key(|(key, _)| key.start_stop_index);
+
+        let (curve, sample_size) = match curve_set {
+            Some((key, curve_set_data)) if !curve_set_data.curve_set.curves.is_empty() => {
+                let curves : Vec<IrregularDynamicCurve<f32, f32>> = match data.general_delay.departure.get(&key.start_stop_index) {
+                    Some(start) => (0..INITIAL_DELAY_QUANTILES).map(|i| {
+                        let initial_delay = start.curve.x_at_y((i as f32 + 0.5) / INITIAL_DELAY_QUANTILES as f32);
+                        curve_set_data.curve_set.curve_at_x_with_continuation(initial_delay)
+                    }).collect(),
+                    None => curve_set_data.curve_set.curves.iter().map(|(_focus, curve)| curve.clone()).collect()
+                };
+                let curve_refs : Vec<&IrregularDynamicCurve<f32, f32>> = curves.iter().collect();
+                (IrregularDynamicCurve::<f32, f32>::average(&curve_refs), curve_set_data.sample_size)
+            },
+            _ if ts.id == TimeSlot::DEFAULT.id => {
+                let curve_data = data.general_delay.arrival.get(&stop_index).or(data.general_delay.departure.get(&stop_index))?;
+                (curve_data.curve.clone(), curve_data.sample_size)
+            },
+            _ => return None
+        };
+
+        Some(CellStats {
+            median: curve.x_at_y(0.5),
+            p90: curve.x_at_y(0.9),
+            sample_size,
+        })
+    }
+
+    fn write_heatmap<F: Fn(&CellStats) -> f32>(html: &mut String, stop_names: &Vec<String>, cells: &HashMap<(u32, u8), CellStats>, value: F) -> FnResult<()> {
+        let label_width = 300;
+        let header_height = 160;
+        let cell_width = 60;
+        let cell_height = 20;
+        let time_slots = &TimeSlot::TIME_SLOTS_WITH_DEFAULT;
+        let width = label_width + cell_width * time_slots.len();
+        let height = header_height + cell_height * stop_names.len();
+
+        writeln!(html, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-size=\"11\">", width, height)?;
+        for (column, ts) in time_slots.iter().enumerate() {
+            let x = label_width + column * cell_width + cell_width / 2;
+            writeln!(
+                html,
+                "<text x=\"{}\" y=\"{}\" transform=\"rotate(-60 {} {})\">{}</text>",
+                x, header_height - 5, x, header_height - 5, escape_html(ts.description)
+            )?;
+        }
+        for (row, name) in stop_names.iter().enumerate() {
+            let y = header_height + row * cell_height;
+            writeln!(html, "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">#{} {}</text>", label_width - 5, y + cell_height - 6, row, escape_html(name))?;
+            for (column, ts) in time_slots.iter().enumerate() {
+                let x = label_width + column * cell_width;
+                match cells.get(&(row as u32, ts.id)) {
+                    Some(cell) => {
+                        let delay = value(cell);
+                        writeln!(
+                            html,
+                            "<g><title>{}s, {} samples</title><rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\" stroke=\"white\"/>\
+                            <text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{:.1}</text></g>",
+                            delay as i32, cell.sample_size,
+                            x, y, cell_width, cell_height, delay_color(delay),
+                            x + cell_width / 2, y + cell_height - 6, delay / 60.0
+                        )?;
+                    },
+                    None => {
+                        writeln!(html, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#f4f4f4\" stroke=\"white\"/>", x, y, cell_width, cell_height)?;
+                    }
+                }
+            }
+        }
+        writeln!(html, "</svg>")?;
+        Ok(())
+    }
+
+    /// Draws the median and the 90th percentile of the default time slot along the route.
+    fn write_growth_chart(html: &mut String, stop_names: &Vec<String>, cells: &HashMap<(u32, u8), CellStats>) -> FnResult<()> {
+        let points : Vec<(usize, &CellStats)> = (0..stop_names.len())
+            .filter_map(|i| cells.get(&(i as u32, TimeSlot::DEFAULT.id)).map(|cell| (i, cell)))
+            .collect();
+        if points.is_empty() {
+            writeln!(html, "<p>No data.</p>")?;
+            return Ok(());
+        }
+
+        let (width, height, margin) = (900.0, 300.0, 50.0);
+        let min_y = points.iter().map(|(_, cell)| cell.median).fold(-60.0, f32::min);
+        let max_y = points.iter().map(|(_, cell)| cell.p90).fold(300.0, f32::max);
+        let max_x = usize::max(stop_names.len() - 1, 1) as f32;
+        let to_x = |i: usize| margin + i as f32 / max_x * (width - 2.0 * margin);
+        let to_y = |delay: f32| height - margin - (delay - min_y) / (max_y - min_y) * (height - 2.0 * margin);
+
+        writeln!(html, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-size=\"11\">", width, height)?;
+        // horizontal lines for each full minute (or each 5 minutes for long routes)
+        let step = if max_y - min_y > 1200.0 { 300 } else { 60 };
+        let mut tick = (min_y as i32 / step) * step;
+        while tick as f32 <= max_y {
+            let y = to_y(tick as f32);
+            let color = if tick == 0 { "#888" } else { "#ddd" };
+            writeln!(html, "<line x1=\"{}\" y1=\"{:.1}\" x2=\"{}\" y2=\"{:.1}\" stroke=\"{}\"/>", margin, y, width - margin, y, color)?;
+            writeln!(html, "<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">{} min</text>", margin - 5.0, y + 4.0, tick / 60)?;
+            tick += step;
+        }
+        for (caption, color, value) in &[("median", "#0d0887", true), ("90th percentile", "#cc4778", false)] {
+            let coordinates : Vec<String> = points.iter()
+                .map(|(i, cell)| format!("{:.1},{:.1}", to_x(*i), to_y(if *value { cell.median } else { cell.p90 })))
+                .collect();
+            writeln!(html, "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"2\" points=\"{}\"><title>{}</title></polyline>", color, coordinates.join(" "), caption)?;
+            for (i, cell) in &points {
+                let delay = if *value { cell.median } else { cell.p90 };
+                writeln!(
+                    html,
+                    "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"{}\"><title>#{} {}: {} {}s</title></circle>",
+                    to_x(*i), to_y(delay), color, i, escape_html(&stop_names[*i]), caption, delay as i32
+                )?;
+            }
+        }
+        writeln!(html, "<text x=\"{}\" y=\"{}\" fill=\"#0d0887\">median</text>", margin, height - 15.0)?;
+        writeln!(html, "<text x=\"{}\" y=\"{}\" fill=\"#cc4778\">90th percentile</text>", margin + 80.0, height - 15.0)?;
+        writeln!(html, "</svg>")?;
+        Ok(())
+    }
+
+    fn write_sample_sizes(html: &mut String, stop_names: &Vec<String>, data: &RouteVariantData, cells: &HashMap<(u32, u8), CellStats>) -> FnResult<()> {
+        writeln!(html, "<table>\n<tr><th>#</th><th>Stop</th><th>Arrivals</th><th>Departures</th><th>Curve sets ending here</th><th>Samples (all time slots)</th></tr>")?;
+        for (i, name) in stop_names.iter().enumerate() {
+            let i = i as u32;
+            let sample_size = |et: EventType| data.general_delay[et].get(&i).map(|curve_data| curve_data.sample_size.to_string()).unwrap_or_default();
+            let curve_set_count = data.curve_sets.arrival.keys().filter(|key| key.end_stop_index == i).count();
+            writeln!(
+                html,
+                "<tr><td>{}</td><td class=\"name\">{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                i,
+                escape_html(name),
+                sample_size(EventType::Arrival),
+                sample_size(EventType::Departure),
+                curve_set_count,
+                cells.get(&(i, TimeSlot::DEFAULT.id)).map(|cell| cell.sample_size.to_string()).unwrap_or_default()
+            )?;
+        }
+        writeln!(html, "</table>")?;
+        Ok(())
+    }
+
+    /// Lists the curve sets for which the median arrival delay differs most between the
+    /// lowest and the highest initial delay.
+    fn write_most_affected(html: &mut String, stop_names: &Vec<String>, data: &RouteVariantData) -> FnResult<()> {
+        let mut affected : Vec<AffectedCurveSet> = data.curve_sets.arrival.iter().filter_map(|(key, curve_set_data)| {
+            let curves = &curve_set_data.curve_set.curves;
+            if curves.len() < 2 {
+                return None;
+            }
+            let (min_initial_delay, min_curve) = curves.first().unwrap(); // there are at least two curves
+            let (max_initial_delay, max_curve) = curves.last().unwrap();
+            Some(AffectedCurveSet {
+                start_stop_index: key.start_stop_index,
+                end_stop_index: key.end_stop_index,
+                time_slot: key.time_slot.clone(),
+                min_initial_delay: *min_initial_delay,
+                max_initial_delay: *max_initial_delay,
+                min_median: min_curve.x_at_y(0.5),
+                max_median: max_curve.x_at_y(0.5),
+                sample_size: curve_set_data.sample_size,
+            })
+        }).collect();
+        if affected.is_empty() {
+            writeln!(html, "<p>No curve sets.</p>")?;
+            return Ok(());
+        }
+        affected.sort_by(|a, b| b.effect().partial_cmp(&a.effect()).unwrap_or(std::cmp::Ordering::Equal));
+
+        writeln!(html, "<p>The share of the initial delay that is passed on is the difference of the medians, divided by the difference of the initial delays.</p>")?;
+        writeln!(html, "<table>\n<tr><th>From</th><th>To</th><th>Time slot</th><th>Initial delay</th><th>Median at lowest</th><th>Median at highest</th><th>Difference</th><th>Passed on</th><th>Samples</th></tr>")?;
+        let stop_name = |i: u32| stop_names.get(i as usize).cloned().unwrap_or_default();
+        for a in affected.iter().take(MOST_AFFECTED_COUNT) {
+            let initial_range = a.max_initial_delay - a.min_initial_delay;
+            writeln!(
+                html,
+                "<tr><td class=\"name\">#{} {}</td><td class=\"name\">#{} {}</td><td class=\"name\">{}</td><td>{}s to {}s</td><td>{}s</td><td>{}s</td><td>{}s</td><td>{}</td><td>{}</td></tr>",
+                a.start_stop_index, escape_html(&stop_name(a.start_stop_index)),
+                a.end_stop_index, escape_html(&stop_name(a.end_stop_index)),
+                escape_html(a.time_slot.get_description()),
+                a.min_initial_delay as i32, a.max_initial_delay as i32,
+                a.min_median as i32, a.max_median as i32, a.effect() as i32,
+                if initial_range > 0.0 { format!("{:.0} %", a.effect() / initial_range * 100.0) } else { String::new() },
+                a.sample_size
+            )?;
+        }
+        writeln!(html, "</table>")?;
+        Ok(())
+    }
+
+    /// Compares the arrival delays of the route, averaged over the stops of each route section,
+    /// with the default curves of its route type.
+    fn write_default_comparison(html: &mut String, route: &Route, trip: &Trip, stop_count: usize, cells: &HashMap<(u32, u8), CellStats>, statistics: &DelayStatistics) -> FnResult<()> {
+        writeln!(html, "<table>\n<tr><th>Time slot</th><th>Route section</th><th>Median (route)</th><th>Median (default)</th><th>90th percentile (route)</th><th>90th percentile (default)</th></tr>")?;
+        let mut row_count = 0;
+        for ts in &TimeSlot::TIME_SLOTS_WITH_DEFAULT {
+            for rs in &RouteSection::SECTIONS {
+                let section_cells : Vec<&CellStats> = (0..stop_count)
+                    .filter(|i| RouteSection::get_route_section_by_stop_index(trip, *i).ok().as_ref() == Some(rs))
+                    .filter_map(|i| cells.get(&(i as u32, ts.id)))
+                    .collect();
+                let key = DefaultCurveKey {
+                    route_type: route.route_type,
+                    route_section: rs.clone(),
+                    time_slot: (*ts).clone(),
+                    event_type: EventType::Arrival
+                };
+                let default_curve_data = match statistics.general.all_default_curves.get(&key) {
+                    Some(curve_data) if !section_cells.is_empty() => curve_data,
+                    _ => continue
+                };
+                let mean = |f: fn(&CellStats) -> f32| section_cells.iter().map(|cell| f(cell)).sum::<f32>() / section_cells.len() as f32;
+                writeln!(
+                    html,
+                    "<tr><td class=\"name\">{}</td><td class=\"name\">{:?}</td><td>{}s</td><td>{}s</td><td>{}s</td><td>{}s</td></tr>",
+                    escape_html(ts.description),
+                    rs,
+                    mean(|cell| cell.median) as i32,
+                    default_curve_data.curve.x_at_y(0.5) as i32,
+                    mean(|cell| cell.p90) as i32,
+                    default_curve_data.curve.x_at_y(0.9) as i32
+                )?;
+                row_count += 1;
+            }
+        }
+        writeln!(html, "</table>")?;
+        if row_count == 0 {
+            writeln!(html, "<p>No default curves for this route type.</p>")?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps delays from -2 minutes (green) to 10 minutes (red) onto a color scale.
+fn delay_color(delay: f32) -> String {
+    let t = ((delay + 120.0) / 720.0).max(0.0).min(1.0);
+    format!("#{:x}", colorous::RED_YELLOW_GREEN.eval_continuous(1.0 - t as f64))
+}
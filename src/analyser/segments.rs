@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use clap::ArgMatches;
+use gtfs_structures::Trip;
+use mysql::*;
+use mysql::prelude::*;
+use simple_error::bail;
+
+use dystonse_curves::Curve;
+use dystonse_curves::tree::{SerdeFormat, NodeData};
+
+use super::Analyser;
+use super::curve_utils::make_curve;
+use crate::types::{
+    DbItem, DelayStatistics, EventType, GetByEventType, GtfsDateTime, VehicleIdentifier,
+    SegmentData, SegmentKey, SegmentCurveData, CurveData, PrecisionType, SmoothingMethod, TimeSlot
+};
+use crate::{FnResult, Main};
+
+// segments with less observations than this don't get a curve (same as for the general_delay curves):
+const MIN_DATA_FOR_SEGMENT : usize = 20;
+
+// observations which deviate from the schedule by this many seconds or more are ignored:
+const MAX_DEVIATION : i32 = 3000;
+
+/// Pairs of (actual duration, scheduled duration) in seconds.
+type DurationSamples = HashMap<SegmentKey, Vec<(i32, i32)>>;
+
+/// Computes the distributions of run times between consecutive stops and of dwell times at stops.
+pub struct SegmentCreator<'a> {
+    pub main: &'a Main,
+    pub analyser:&'a Analyser<'a>,
+    pub args: &'a ArgMatches
+}
+
+impl<'a> SegmentCreator<'a> {
+    pub fn run_segments(&self) -> FnResult<()> {
+        let route_ids : Vec<String> = if let Some(route_ids) = self.args.values_of("route-ids") {
+            route_ids.map(String::from).collect()
+        } else if self.args.is_present("all") {
+            self.analyser.schedule.routes.keys().cloned().collect()
+        } else {
+            bail!("Either --route-ids or --all must be given.");
+        };
+        println!("Handling {} route ids…", route_ids.len());
+
+        let mut segments = HashMap::new();
+        for route_id in &route_ids {
+            match self.compute_segments_for_route(route_id) {
+                Ok(variants) => {
+                    if !variants.is_empty() {
+                        segments.insert(route_id.clone(), variants);
+                    }
+                },
+                Err(e) => println!("Could not compute segments for route {}: {}", route_id, e)
+            }
+        }
+
+        self.write_csv(&segments)?;
+        self.save_segments(segments)
+    }
+
+    /// Stores the segments in all_curves.exp, next to the curves that are already there.
+    fn save_segments(&self, segments: HashMap<String, HashMap<u64, SegmentData>>) -> FnResult<()> {
+        let dir = &self.main.dir;
+        let mut statistics = if Path::new(&format!("{}/all_curves.exp", dir)).exists() {
+            DelayStatistics::load_from_file(dir, "all_curves", &SerdeFormat::MessagePack)?
+        } else {
+            DelayStatistics::new()
+        };
+        // with --all, segments of routes that are not part of the schedule anymore are dropped
+        if self.args.is_present("all") {
+            statistics.segments.clear();
+        }
+        statistics.segments.extend(segments);
+        statistics.save_to_file(dir, "all_curves", &SerdeFormat::MessagePack)?;
+        println!("Saved segments of {} routes to all_curves.exp.", statistics.segments.len());
+        Ok(())
+    }
+
+    fn compute_segments_for_route(&self, route_id: &str) -> FnResult<HashMap<u64, SegmentData>> {
+        let schedule = &self.analyser.schedule;
+        let db_items = self.get_data_from_db(route_id)?;
+
+        // sort the items by route variant and vehicle, and within those by stop_sequence:
+        let mut items_by_vehicle : HashMap<u64, HashMap<VehicleIdentifier, HashMap<u16, &DbItem>>> = HashMap::new();
+        for item in &db_items {
+            if let (Some(start_date), Some(start_time)) = (item.trip_start_date, item.trip_start_time) {
+                let v_id = VehicleIdentifier {
+                    trip_id: item.trip_id.clone(),
+                    start: GtfsDateTime::new(start_date, start_time.num_seconds() as i32)
+                };
+                items_by_vehicle.entry(item.route_variant).or_insert_with(HashMap::new)
+                    .entry(v_id).or_insert_with(HashMap::new)
+                    .insert(item.stop_sequence, item);
+            }
+        }
+
+        let mut variants = HashMap::new();
+        for (route_variant, vehicles) in items_by_vehicle {
+            let variant_as_string = Some(format!("{}", route_variant));
+            let variant_trip = match schedule.trips.values().find(|trip| trip.route_id == route_id && trip.route_variant == variant_as_string) {
+                Some(trip) => trip,
+                None => {
+                    println!("Could not find trip for route_variant {}.", route_variant);
+                    continue;
+                }
+            };
+
+            let mut run_samples = DurationSamples::new();
+            let mut dwell_samples = DurationSamples::new();
+            for (v_id, items) in &vehicles {
+                if let Ok(trip) = schedule.get_trip(&v_id.trip_id) {
+                    Self::collect_samples_for_vehicle(trip, items, &mut run_samples, &mut dwell_samples);
+                }
+            }
+
+            let segment_data = SegmentData {
+                stop_ids: variant_trip.stop_times.iter().map(|st| st.stop.id.clone()).collect(),
+                run_times: Self::build_curves(&run_samples),
+                dwell_times: Self::build_curves(&dwell_samples),
+            };
+            if !segment_data.run_times.is_empty() || !segment_data.dwell_times.is_empty() {
+                variants.insert(route_variant, segment_data);
+            }
+        }
+
+        println!("Computed segments for {} variants of route {}.", variants.len(), route_id);
+        Ok(variants)
+    }
+
+    // picks all rows from the database for a given route
+    fn get_data_from_db(&self, route_id: &str) -> FnResult<Vec<DbItem>> {
+        let mut con = self.main.pool.get_conn()?;
+        let stmt = con.prep(
+            r"SELECT
+                delay_arrival,
+                delay_departure,
+                trip_start_date,
+                trip_start_time,
+                trip_id,
+                stop_id,
+                stop_sequence,
+                route_variant
+            FROM
+                records
+            WHERE
+                source=:source AND
+                route_id=:route_id",
+        )?;
+
+        let mut result = con.exec_iter(
+            &stmt,
+            params! {
+                "source" => &self.main.source,
+                "route_id" => route_id,
+            },
+        )?;
+
+        let result_set = result.next_set().unwrap()?;
+
+        let db_items: Vec<_> = result_set
+            .map(|row| {
+                let item: DbItem = from_row(row.unwrap());
+                item
+            })
+            .collect();
+
+        Ok(db_items)
+    }
+
+    fn collect_samples_for_vehicle(trip: &Trip, items: &HashMap<u16, &DbItem>, run_samples: &mut DurationSamples, dwell_samples: &mut DurationSamples) {
+        let stop_count = trip.stop_times.len();
+        for (i, st) in trip.stop_times.iter().enumerate() {
+            let item = match items.get(&st.stop_sequence) {
+                Some(item) => item,
+                None => continue
+            };
+
+            // dwell times are meaningless at the first and last stop
+            if i > 0 && i < stop_count - 1 {
+                if let (Some(arr), Some(dep), Some(t_arr), Some(t_dep)) = (item.delay.arrival, item.delay.departure, st.get_time(EventType::Arrival), st.get_time(EventType::Departure)) {
+                    let scheduled = t_dep as i32 - t_arr as i32;
+                    if (dep - arr).abs() < MAX_DEVIATION && scheduled + dep - arr >= 0 {
+                        if let Some(dt) = item.get_datetime_from_trip(trip, EventType::Arrival) {
+                            Self::add_sample(dwell_samples, i, dt, scheduled + dep - arr, scheduled);
+                        }
+                    }
+                }
+            }
+
+            if i < stop_count - 1 {
+                let next_st = &trip.stop_times[i + 1];
+                let next_item = match items.get(&next_st.stop_sequence) {
+                    Some(next_item) => next_item,
+                    None => continue
+                };
+                if let (Some(dep), Some(arr), Some(t_dep), Some(t_arr)) = (item.delay.departure, next_item.delay.arrival, st.get_time(EventType::Departure), next_st.get_time(EventType::Arrival)) {
+                    let scheduled = t_arr as i32 - t_dep as i32;
+                    if (arr - dep).abs() < MAX_DEVIATION && scheduled + arr - dep >= 0 {
+                        if let Some(dt) = item.get_datetime_from_trip(trip, EventType::Departure) {
+                            Self::add_sample(run_samples, i, dt, scheduled + arr - dep, scheduled);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds the sample to its own time slot and to the default time slot.
+    fn add_sample(samples: &mut DurationSamples, stop_index: usize, dt: DateTime<Local>, actual: i32, scheduled: i32) {
+        for ts in &[TimeSlot::from_datetime(dt), &TimeSlot::DEFAULT] {
+            let key = SegmentKey {
+                stop_index: stop_index as u32,
+                time_slot: (*ts).clone()
+            };
+            samples.entry(key).or_insert_with(Vec::new).push((actual, scheduled));
+        }
+    }
+
+    fn build_curves(samples: &DurationSamples) -> HashMap<SegmentKey, SegmentCurveData> {
+        let mut curves = HashMap::new();
+        for (key, pairs) in samples {
+            if pairs.len() < MIN_DATA_FOR_SEGMENT {
+                continue;
+            }
+            let values : Vec<f32> = pairs.iter().map(|(actual, _scheduled)| *actual as f32).collect();
+            let mut curve = match make_curve(&values, None) {
+                Ok((curve, _sum)) => curve,
+                Err(_) => continue
+            };
+            curve.simplify(0.01);
+            let within_count = pairs.iter().filter(|(actual, scheduled)| actual <= scheduled).count();
+            let scheduled_sum : i64 = pairs.iter().map(|(_actual, scheduled)| *scheduled as i64).sum();
+            curves.insert(key.clone(), SegmentCurveData {
+                curve_data: CurveData {
+                    curve,
+                    precision_type: PrecisionType::SemiSpecific,
+                    sample_size: pairs.len() as u32,
+                    smoothing: SmoothingMethod::Empirical
+                },
+                scheduled_duration: scheduled_sum as f32 / pairs.len() as f32,
+                within_schedule: within_count as f32 / pairs.len() as f32,
+            });
+        }
+        curves
+    }
+
+    /// Writes one row per segment and time slot into segments.csv.
+    fn write_csv(&self, segments: &HashMap<String, HashMap<u64, SegmentData>>) -> FnResult<()> {
+        let file_name = format!("{}/segments.csv", self.main.dir);
+        let mut file = File::create(&file_name)?;
+        writeln!(file, "route_id;route_variant;segment_type;stop_index;from_stop_id;to_stop_id;time_slot;sample_size;scheduled_duration;p05;p50;p95;median_slack;within_schedule")?;
+
+        let mut route_ids : Vec<&String> = segments.keys().collect();
+        route_ids.sort();
+        for route_id in route_ids {
+            let mut route_variants : Vec<&u64> = segments[route_id].keys().collect();
+            route_variants.sort();
+            for route_variant in route_variants {
+                let segment_data = &segments[route_id][route_variant];
+                for (segment_type, map, offset) in &[("run", &segment_data.run_times, 1), ("dwell", &segment_data.dwell_times, 0)] {
+                    let mut keys : Vec<&SegmentKey> = map.keys().collect();
+                    keys.sort_by_key(|key| (key.stop_index, key.time_slot.id));
+                    for key in keys {
+                        let data = &map[key];
+                        let stop_id = |index: u32| segment_data.stop_ids.get(index as usize).map(|s| s.as_str()).unwrap_or("");
+                        let curve = &data.curve_data.curve;
+                        writeln!(file, "{};{};{};{};{};{};{};{};{:.0};{:.0};{:.0};{:.0};{:.0};{:.3}",
+                            route_id,
+                            route_variant,
+                            segment_type,
+                            key.stop_index,
+                            stop_id(key.stop_index),
+                            stop_id(key.stop_index + offset),
+                            key.time_slot.get_description(),
+                            data.curve_data.sample_size,
+                            data.scheduled_duration,
+                            curve.x_at_y(0.05),
+                            curve.x_at_y(0.5),
+                            curve.x_at_y(0.95),
+                            data.scheduled_duration - curve.x_at_y(0.5),
+                            data.within_schedule
+                        )?;
+                    }
+                }
+            }
+        }
+        println!("Wrote {}.", file_name);
+        Ok(())
+    }
+}
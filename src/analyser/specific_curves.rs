@@ -1,10 +1,11 @@
 use clap::ArgMatches;
 use gtfs_structures::Trip;
 use itertools::Itertools;
+use rayon::prelude::*;
 use mysql::*;
 use mysql::prelude::*;
 use simple_error::bail;
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, NaiveDate};
 
 use dystonse_curves::irregular_dynamic::*;
 use dystonse_curves::{Curve, curve_set::CurveSet};
@@ -12,11 +13,39 @@ use dystonse_curves::tree::{SerdeFormat, NodeData};
 
 use super::Analyser;
 use super::curve_utils::*;
+use super::trajectory::*;
 use crate::types::*;
 
 use crate::{ FnResult, Main, OrError };
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// how many previous trips are looked up in one query, to stay far below the placeholder limit of the database
+const LAYOVER_QUERY_BATCH_SIZE: usize = 1000;
+
+/// Route variants whose curves are computed from their pooled samples.
+struct VariantGroup<'a> {
+    /// the variant with the most stops, on whose stop indices the samples are pooled
+    master: &'a Trip,
+    /// all variants of the group (including the master), with the index of their first stop within the master
+    members: Vec<(u64, &'a Trip, usize)>,
+}
+
+/// How the curves are made from the samples, chosen for each run.
+struct CurveOptions {
+    smoothing: SmoothingMethod,
+    /// the default curves to blend the general delay curves with, if requested with `--blend`
+    blend_parents: Option<BlendParents>,
+}
+
+/// Departure of a vehicle from the first stop of its trip, whose previous trip in the block is known.
+struct LayoverDeparture {
+    service_day: NaiveDate,
+    delay: i32,
+    time: DateTime<Local>,
+    previous_trip_id: String,
+}
 
 pub struct SpecificCurveCreator<'a> {
     pub main: &'a Main,
@@ -27,27 +56,88 @@ pub struct SpecificCurveCreator<'a> {
 impl<'a> SpecificCurveCreator<'a> {
 
     pub fn get_specific_curves(&self) -> FnResult<HashMap<String, RouteData>> {
-        let mut map = HashMap::new();
-        if let Some(route_ids) = self.args.values_of("route-ids") {
-            println!("Handling {} route ids…", route_ids.len());
-            for route_id in route_ids {
-                let route_data = self.create_curves_for_route(&String::from(route_id))?;
-                map.insert(String::from(route_id), route_data);
-            }
+        let samples = self.get_specific_samples(&DateRange::ALL)?;
+        self.build_specific_curves(&samples, None)
+    }
+
+    /// Reads the records within `date_range` from the database and collects the
+    /// samples for the selected routes, without computing any curves yet.
+    /// The samples are weighted by their age if a half-life is set.
+    pub fn get_specific_samples(&self, date_range: &DateRange) -> FnResult<HashMap<String, RouteSamples>> {
+        let block_index = BlockIndex::new(&self.analyser.schedule);
+        let exclusions = super::anomalies::get_exclusions(self.main, self.args)?;
+        let stations = self.get_station_grouping();
+        let recency = RecencyWeighting::new(self.main.get_half_life()?, date_range);
+        let route_ids : Vec<String> = if let Some(route_ids) = self.args.values_of("route-ids") {
+            route_ids.map(String::from).collect()
         } else if self.args.is_present("all") {
-            let route_ids = self.analyser.schedule.routes.keys();
-            println!("Handling {} route ids…", route_ids.len());
-            for route_id in route_ids {
-                let route_data = self.create_curves_for_route(&String::from(route_id))?;
-                map.insert(String::from(route_id), route_data);
-            }
+            self.analyser.schedule.routes.keys().cloned().collect()
         } else {
             println!("I've got no route!");
+            Vec::new()
+        };
+        println!("Handling {} route ids…", route_ids.len());
+
+        // Routes are processed in parallel, each one reading its records one route variant at a time.
+        let done_counter = AtomicUsize::new(0);
+        let total_count = route_ids.len();
+        let results : Vec<(&String, std::result::Result<RouteSamples, String>)> = route_ids
+            .par_iter()
+            .map(|route_id| {
+                let result = self.collect_samples_for_route(route_id, &block_index, date_range, &exclusions, &stations, &recency)
+                    .map_err(|e| e.to_string());
+                let done = 1 + done_counter.fetch_add(1, Ordering::SeqCst);
+                println!("Status: collected samples for {} of {} routes.", done, total_count);
+                (route_id, result)
+            })
+            .collect();
+
+        let mut map = HashMap::new();
+        let mut error_count = 0;
+        for (route_id, result) in results {
+            match result {
+                Ok(route_samples) => {
+                    map.insert(route_id.clone(), route_samples);
+                },
+                Err(e) => {
+                    eprintln!("Error while collecting samples for route {}: {}", route_id, e);
+                    error_count += 1;
+                }
+            }
         }
-        
+        if error_count > 0 {
+            bail!("Could not collect samples for {} of {} routes.", error_count, total_count);
+        }
+
         Ok(map)
     }
 
+    /// Computes the curves for all routes from previously collected samples.
+    /// If `default_curves` are given, the general delay curves are blended with them as requested with `--blend`.
+    pub fn build_specific_curves(&self, samples: &HashMap<String, RouteSamples>, default_curves: Option<&DefaultCurves>) -> FnResult<HashMap<String, RouteData>> {
+        let stations = self.get_station_grouping();
+        let blend_parents = match (default_curves, self.args.value_of("blend")) {
+            (Some(default_curves), Some(prior_samples)) => Some(BlendParents::new(default_curves, prior_samples.parse()?)),
+            _ => None
+        };
+        let options = CurveOptions {
+            smoothing: get_smoothing_method(self.args),
+            blend_parents,
+        };
+        Ok(samples.iter().map(|(route_id, route_samples)| {
+            (route_id.clone(), self.create_curves_for_route(route_id, route_samples, &stations, &options))
+        }).collect())
+    }
+
+    /// Stops of the same station (e.g. several platforms) are only treated as one if requested with `--group-stations`.
+    fn get_station_grouping(&self) -> StationGrouping {
+        if self.args.is_present("group-stations") {
+            StationGrouping::new(&self.analyser.schedule)
+        } else {
+            StationGrouping::none()
+        }
+    }
+
     pub fn run_specific_curves(&self) -> FnResult<()> {
         let map = self.get_specific_curves()?;
         
@@ -55,7 +145,187 @@ impl<'a> SpecificCurveCreator<'a> {
         Ok(())
     }
 
-    fn create_curves_for_route(&self, route_id: &String)  -> FnResult<RouteData> {
+    fn create_curves_for_route(&self, route_id: &String, route_samples: &RouteSamples, stations: &StationGrouping, options: &CurveOptions) -> RouteData {
+        let schedule = &self.analyser.schedule;
+        let mut route_data = RouteData::new(route_id);
+
+        if self.args.is_present("separate-variants") {
+            for (route_variant, variant_samples) in &route_samples.variants {
+                let variant_as_string = Some(format!("{}", route_variant));
+                let trip = schedule.trips.values().filter(|trip| trip.route_id == *route_id && trip.route_variant == variant_as_string).next();
+                match trip {
+                    None => println!("Could not find trip for route_variant {}.", route_variant),
+                    Some(trip) => {
+                        let variant_data = self.create_curves_for_route_variant(variant_samples, trip, options);
+                        route_data.variants.insert(*route_variant, variant_data);
+                    }
+                }
+            }
+        } else {
+            let groups = self.group_route_variants(route_id, stations);
+            for route_variant in route_samples.variants.keys() {
+                if !groups.iter().any(|group| group.members.iter().any(|(rv, _trip, _offset)| rv == route_variant)) {
+                    println!("Could not find trip for route_variant {}.", route_variant);
+                }
+            }
+
+            for group in &groups {
+                // pool the samples of all variants of the group, using the stop indices of the master variant
+                let mut pooled_samples = RouteVariantSamples::new();
+                let mut has_samples = false;
+                for (route_variant, _trip, offset) in &group.members {
+                    if let Some(variant_samples) = route_samples.variants.get(route_variant) {
+                        pooled_samples.merge(Self::shift_samples(variant_samples, *offset));
+                        has_samples = true;
+                    }
+                }
+                if !has_samples {
+                    continue;
+                }
+
+                let master_data = self.create_curves_for_route_variant(&pooled_samples, group.master, options);
+                for (route_variant, trip, offset) in &group.members {
+                    route_data.variants.insert(*route_variant, Self::extract_variant_data(&master_data, trip, *offset));
+                }
+            }
+        }
+
+        for (ts, pairs) in &route_samples.layover_recovery {
+            // Don't generate statistics if we have too few pairs.
+            if pairs.effective_size() > 20.0 {
+                if let Ok(curve_set_data) = Self::generate_curves_for_stop_pair(pairs, options.smoothing) {
+                    route_data.layover_recovery.insert(ts.clone(), curve_set_data);
+                }
+            }
+        }
+
+        route_data
+    }
+
+    /// Groups the route variants of the route, so that the stations of each variant are a contiguous part
+    /// of the stations of the master variant of its group, in the same order. Reversed variants are not
+    /// grouped (unlike in the visual schedules), as they run in the opposite direction.
+    fn group_route_variants(&self, route_id: &str, stations: &StationGrouping) -> Vec<VariantGroup> {
+        let schedule = &self.analyser.schedule;
+
+        // one trip for each route variant, sorted by the number of stops, descending
+        let mut variants : Vec<(u64, &Trip)> = Vec::new();
+        for trip in schedule.trips.values().filter(|trip| trip.route_id == route_id) {
+            if let Some(route_variant) = trip.route_variant.as_ref().and_then(|rv| rv.parse::<u64>().ok()) {
+                if !variants.iter().any(|(rv, _trip)| *rv == route_variant) {
+                    variants.push((route_variant, trip));
+                }
+            }
+        }
+        variants.sort_by_key(|(route_variant, trip)| (std::cmp::Reverse(trip.stop_times.len()), *route_variant));
+
+        let stop_ids = |trip: &Trip| -> Vec<String> { trip.stop_times.iter().map(|st| stations.station_id(&st.stop.id).to_string()).collect() };
+
+        let mut groups = Vec::new();
+        while !variants.is_empty() {
+            let master = variants[0].1;
+            let master_stop_ids = stop_ids(master);
+            let mut members = Vec::new();
+            variants.retain(|(route_variant, trip)| {
+                match sub_trip_offset(&master_stop_ids, &stop_ids(*trip)) {
+                    Some(offset) => {
+                        members.push((*route_variant, *trip, offset));
+                        false
+                    },
+                    None => true
+                }
+            });
+            if members.len() > 1 {
+                println!("Merging {} route variants of route {} into master variant {}.", members.len(), route_id, master.route_variant.as_ref().unwrap());
+            }
+            groups.push(VariantGroup { master, members });
+        }
+        groups
+    }
+
+    /// Returns a copy of the samples with all stop indices increased by `offset`.
+    fn shift_samples(samples: &RouteVariantSamples, offset: usize) -> RouteVariantSamples {
+        let offset = offset as u32;
+        let mut shifted = RouteVariantSamples::new();
+        for et in &EventType::TYPES {
+            shifted.general_delay[**et] = samples.general_delay[**et].iter()
+                .map(|(stop_index, delays)| (stop_index + offset, delays.clone()))
+                .collect();
+            shifted.curve_sets[**et] = samples.curve_sets[**et].iter()
+                .map(|(key, pairs)| {
+                    let shifted_key = CurveSetKey {
+                        start_stop_index: key.start_stop_index + offset,
+                        end_stop_index: key.end_stop_index + offset,
+                        time_slot: key.time_slot.clone()
+                    };
+                    (shifted_key, pairs.clone())
+                })
+                .collect();
+        }
+        shifted
+    }
+
+    /// Takes the curves of the stops of `trip` from the curves of the master variant of its group,
+    /// where the first stop of `trip` has the index `offset`.
+    fn extract_variant_data(master_data: &RouteVariantData, trip: &Trip, offset: usize) -> RouteVariantData {
+        let offset = offset as u32;
+        let stop_count = trip.stop_times.len() as u32;
+        let contains = |stop_index: u32| stop_index >= offset && stop_index < offset + stop_count;
+
+        let mut variant_data = RouteVariantData::new();
+        variant_data.stop_ids = trip.stop_times.iter().map(|st| st.stop.id.clone()).collect();
+        for et in &EventType::TYPES {
+            for (stop_index, curve_data) in &master_data.general_delay[**et] {
+                if contains(*stop_index) {
+                    variant_data.general_delay[**et].insert(stop_index - offset, curve_data.clone());
+                }
+            }
+            for (key, curve_set_data) in &master_data.curve_sets[**et] {
+                if contains(key.start_stop_index) && contains(key.end_stop_index) {
+                    let variant_key = CurveSetKey {
+                        start_stop_index: key.start_stop_index - offset,
+                        end_stop_index: key.end_stop_index - offset,
+                        time_slot: key.time_slot.clone()
+                    };
+                    variant_data.curve_sets[**et].insert(variant_key, curve_set_data.clone());
+                }
+            }
+        }
+        variant_data
+    }
+
+    fn create_curves_for_route_variant(&self, variant_samples: &RouteVariantSamples, trip: &Trip, options: &CurveOptions) -> RouteVariantData {
+        let mut route_variant_data = RouteVariantData::new();
+        route_variant_data.stop_ids = trip.stop_times.iter().map(|st| st.stop.id.clone()).collect();
+        let route_type = self.analyser.schedule.get_route(&trip.route_id).map(|route| route.route_type).ok();
+
+        for et in &EventType::TYPES {
+            // this is where the general_delay curves are created
+            for (stop_index, delays) in &variant_samples.general_delay[**et] {
+                if let Ok(mut res) = Self::generate_delay_curve_data(delays, options.smoothing) {
+                    if let (Some(blend_parents), Some(route_type)) = (&options.blend_parents, route_type) {
+                        let route_section = RouteSection::get_route_section_by_stop_index(trip, *stop_index as usize);
+                        if let Ok(route_section) = route_section {
+                            blend_parents.blend(&mut res, route_type, route_section, **et);
+                        }
+                    }
+                    route_variant_data.general_delay[**et].insert(*stop_index, res);
+                }
+            }
+
+            for (key, pairs) in &variant_samples.curve_sets[**et] {
+                // Don't generate statistics if we have too few pairs.
+                if pairs.effective_size() > 20.0 {
+                    if let Ok(actual_data) = Self::generate_curves_for_stop_pair(pairs, options.smoothing) {
+                        route_variant_data.curve_sets[**et].insert(key.clone(), actual_data);
+                    }
+                }
+            }
+        }
+        route_variant_data
+    }
+
+    fn collect_samples_for_route(&self, route_id: &String, block_index: &BlockIndex, date_range: &DateRange, exclusions: &AnomalyExclusions, stations: &StationGrouping, recency: &RecencyWeighting) -> FnResult<RouteSamples> {
         let schedule = &self.analyser.schedule;
         let route = schedule.get_route(route_id)?;
         let agencies_count = schedule.agencies.len();
@@ -73,9 +343,10 @@ impl<'a> SpecificCurveCreator<'a> {
             schedule.agencies[0].name.clone()
         };
 
-        println!("Working on route {} of agency {}.", route.short_name, agency_name);
+        println!("Working on route {} of agency {}.", route.short_name, agency_name);
 
-        let mut route_data = RouteData::new(route_id);
+        let mut route_samples = RouteSamples::new();
+        let mut layover_departures = Vec::new();
 
         let mut con = self.main.pool.get_conn()?;
         let stmt = con.prep(
@@ -92,292 +363,231 @@ impl<'a> SpecificCurveCreator<'a> {
                 records 
             WHERE 
                 source=:source AND 
-                route_id=:routeid
+                route_id=:routeid AND
+                trip_start_date IS NOT NULL AND
+                trip_start_time IS NOT NULL AND
+                (:after IS NULL OR trip_start_date > :after) AND
+                (:until IS NULL OR trip_start_date <= :until)
             ORDER BY 
+                route_variant,
                 trip_start_date,
-                trip_id",
+                trip_start_time,
+                trip_id,
+                stop_sequence",
         )?;
 
         let mut result = con.exec_iter(
             &stmt,
             params! {
                 "source" => &self.main.source,
-                "routeid" => route_id
+                "routeid" => route_id,
+                "after" => date_range.after,
+                "until" => date_range.until
             },
         )?;
 
         let result_set = result.next_set().unwrap()?;
 
-        let db_items: Vec<_> = result_set
-            .map(|row| {
-                let item: DbItem = from_row(row.unwrap());
-                item
-            })
-            .collect();
-
-        let route_variants : Vec<_> = db_items.iter().map(|item| &item.route_variant).unique().collect();
-        println!("For route {} there are {} variants: {:?}", route_id, route_variants.len(), route_variants);
-
-        for route_variant in route_variants {
-            let variant_as_string = Some(format!("{}", route_variant));
-            let trip = schedule.trips.values().filter(|trip| trip.route_id == *route.id && trip.route_variant == variant_as_string).next();
+        // The records arrive sorted by route variant and vehicle, so the records of each vehicle are
+        // grouped into its trajectory as they come in, and only one route variant is held in memory.
+        let mut route_variant : Option<u64> = None;
+        let mut trajectories : Vec<Trajectory> = Vec::new();
+        for row in result_set {
+            let item : DbItem = from_row(row?);
+            if exclusions.is_excluded(&item) {
+                continue;
+            }
+            let start = match (item.trip_start_date, item.trip_start_time) {
+                (Some(start_date), Some(start_time)) => GtfsDateTime::new(start_date, start_time.num_seconds() as i32),
+                _ => continue
+            };
 
-            match trip {
-                None => {
-                    println!("Could not find trip for route_variant {}.", route_variant);
-                },
-                Some(trip) => {
-                    let rows_matching_variant : Vec<_> = db_items.iter().filter(|item| item.route_variant == *route_variant).collect();
-
-                    println!("trying to compute projection of missing delays…");
-                    // try to do projections
-                    match self.compute_projections_for_route_variant(&rows_matching_variant) {
-                        Ok(rows_matching_variant_with_projection) => {
-                            println!("projection successful for route_variant {}.", route_variant);
-
-                            // convert vec into vec of references:
-                            let rows_matching_variant_with_projection_refs = rows_matching_variant_with_projection.iter().collect();
-
-                            let variant_data = self.create_curves_for_route_variant(&rows_matching_variant_with_projection_refs, trip)?;
-                            route_data.variants.insert(*route_variant, variant_data);
-                        },
-                        Err(e) => { // if making projections failed, proceed as usual
-                            println!("projection failed for route_variant {}. Now using only the data we already had before. Reason: {}", route_variant, e);
-                            let variant_data = self.create_curves_for_route_variant(&rows_matching_variant, trip)?;
-                            route_data.variants.insert(*route_variant, variant_data);
-                        }
-                    }
+            if route_variant != Some(item.route_variant) {
+                if let Some(finished_variant) = route_variant {
+                    self.finish_route_variant(finished_variant, &mut trajectories, &mut route_samples, &mut layover_departures, block_index, recency);
                 }
+                route_variant = Some(item.route_variant);
             }
-        }
-
-        Ok(route_data)
-    }
-
-    // project the delay at the previous stop onto each following stop where we have no data
-    fn compute_projections_for_route_variant(&self, rows_from_db: &Vec<&DbItem>) -> FnResult<Vec<DbItem>> {
 
-        let route_variant = rows_from_db[0].route_variant;
-        
-        let mut resulting_rows : Vec<DbItem> = Vec::new();
-
-        // first step: sort the items by vehicle id
-        let mut rows_by_vehicle : HashMap<VehicleIdentifier, Vec<&DbItem>> = HashMap::new();
-
-        for item in rows_from_db {
-            let trip_id = item.trip_id.clone();
-            if let Some(start_date) = item.trip_start_date {
-                if let Some(start_time) = item.trip_start_time {
-                    let start = GtfsDateTime::new(start_date, start_time.num_seconds() as i32);
-                    let v_id = VehicleIdentifier{
-                        trip_id,
-                        start
-                    };
-                    // sort the item into the corresponding vec for its vehicle id
-                    let vec = rows_by_vehicle.entry(v_id).or_insert_with(|| Vec::new());
-                    vec.push(item);
-                } else {
-                    eprintln!("No trip_start_time found in DbItem, this should not happen!");
+            let is_same_vehicle = trajectories.last().map_or(false, |trajectory| trajectory.is_vehicle(&item.trip_id, &start));
+            if !is_same_vehicle {
+                match schedule.get_trip(&item.trip_id) {
+                    Ok(trip) => trajectories.push(Trajectory::new(trip, start)),
+                    Err(_) => continue
                 }
-            } else {
-                eprintln!("No trip_start_date found in DbItem, this should not happen!");
             }
+            trajectories.last_mut().unwrap().add(&item, stations);
+        }
+        if let Some(finished_variant) = route_variant {
+            self.finish_route_variant(finished_variant, &mut trajectories, &mut route_samples, &mut layover_departures, block_index, recency);
         }
 
-        // second step: for each vehicle id, fill in the gaps
-
-        for (v_id, vec) in rows_by_vehicle {
-            
-            // find out which stops this trip is supposed to have
-            let stop_times = if let Ok(trip) = self.analyser.schedule.get_trip(&v_id.trip_id) {
-                &trip.stop_times
-            } else {
-                //TODO: maybe use eprintln and continue instead of bail?
-                bail!("no stop times found in schedule for trip {}", v_id.trip_id);
-            };
-
-            let items_iter = vec.iter();
-            let mut st_iter = stop_times.iter();
-
-            let mut delay_found = false;
-
-            'item_loop: for item in items_iter {
-
-                // remember the delays:
-                let delay_arr = item.delay.arrival;
-                let delay_dep = item.delay.departure;
-
-                'stop_time_loop: loop {
+        println!("For route {} there are {} variants: {:?}", route_id, route_samples.variants.len(), route_samples.variants.keys().collect::<Vec<_>>());
 
-                    if let Some(st) = st_iter.next() {
+        match self.collect_layover_recovery_samples(&layover_departures, recency) {
+            Ok(layover_recovery) => route_samples.layover_recovery = layover_recovery,
+            Err(e) => println!("Could not collect layover recovery samples for route {}: {}", route_id, e)
+        }
 
-                        if item.stop_sequence == st.stop_sequence {
+        Ok(route_samples)
+    }
 
-                            resulting_rows.push((**item).clone());
+    /// Fills the gaps in the trajectories of a route variant and adds them to its samples,
+    /// after which the trajectories are dropped. The departures from the first stop are kept
+    /// for the layover recovery.
+    fn finish_route_variant(
+        &self,
+        route_variant: u64,
+        trajectories: &mut Vec<Trajectory>,
+        route_samples: &mut RouteSamples,
+        layover_departures: &mut Vec<LayoverDeparture>,
+        block_index: &BlockIndex,
+        recency: &RecencyWeighting
+    ) {
+        // projected delays are left out if requested with `--exclude-projections`
+        let include_synthetic = !self.args.is_present("exclude-projections");
+
+        let drift = Drift::learn(trajectories);
+        let mut variant_samples = RouteVariantSamples::new();
+        for trajectory in trajectories.iter_mut() {
+            trajectory.fill_gaps(&drift);
+            trajectory.add_samples(&mut variant_samples, include_synthetic, recency);
+            if let Some(departure) = self.get_layover_departure(trajectory, block_index) {
+                layover_departures.push(departure);
+            }
+        }
+        println!("Collected samples of {} vehicles for route variant {}.", trajectories.len(), route_variant);
 
-                            delay_found = true;
+        route_samples.variants.insert(route_variant, variant_samples);
+        trajectories.clear();
+    }
 
-                            
-                        } else if item.stop_sequence > st.stop_sequence {
+    /// Returns the departure of the vehicle from the first stop, if the previous trip in its block is known.
+    fn get_layover_departure(&self, trajectory: &Trajectory, block_index: &BlockIndex) -> Option<LayoverDeparture> {
+        let delay = trajectory.delays.first()?.departure?;
+        let time = trajectory.time(0, EventType::Departure)?;
+        let service_day = trajectory.start.service_day().naive_local();
+        let previous_trip = block_index.get_previous_trip(&self.analyser.schedule, trajectory.trip, service_day)?;
+        Some(LayoverDeparture {
+            service_day,
+            delay,
+            time,
+            previous_trip_id: previous_trip.id.clone(),
+        })
+    }
 
-                            if delay_found {
-                                eprintln!("ERROR: stop_sequence of dbitem is bigger than stop_sequence from schedule. This should not happen after delay was found once!");
-                            } 
-                            continue 'stop_time_loop;
+    /// Learns how much of the delay of the previous trip in a vehicle's block survives the turnaround.
+    /// Each pair consists of the arrival delay at the last stop of the previous trip and the departure
+    /// delay at the first stop of a trip of this route.
+    fn collect_layover_recovery_samples(&self, departures: &Vec<LayoverDeparture>, recency: &RecencyWeighting) -> FnResult<HashMap<TimeSlot, PairHistogram>> {
+        let schedule = &self.analyser.schedule;
 
-                        } else if item.stop_sequence < st.stop_sequence {
+        if departures.is_empty() {
+            return Ok(HashMap::new());
+        }
 
-                            // we have found a gap and have to fill it in now:
+        // look up the arrival delays at the last stop of the previous trips, for many trips per query:
+        let mut last_stop_sequences : Vec<(&String, u16)> = Vec::new();
+        for previous_trip_id in departures.iter().map(|departure| &departure.previous_trip_id).unique() {
+            let previous_trip = schedule.get_trip(previous_trip_id)?;
+            let last_stop_sequence = previous_trip.stop_times.last().or_error("Trip without stop times")?.stop_sequence;
+            last_stop_sequences.push((previous_trip_id, last_stop_sequence));
+        }
+        // only the service days of the departures are needed
+        let first_day = departures.iter().map(|departure| departure.service_day).min().unwrap();
+        let last_day = departures.iter().map(|departure| departure.service_day).max().unwrap();
 
-                            //TODO: if delay_arrival was None before, we should probably use delay_departure for projecting the next arrival
-                            let new_item = DbItem{
-                                delay : EventPair { arrival: delay_arr, departure: delay_dep },
-                                trip_start_date : Some(v_id.start.service_day()),
-                                trip_start_time : Some(v_id.start.duration()),
-                                trip_id : v_id.trip_id.clone(),
-                                stop_sequence : st.stop_sequence,
-                                stop_id : st.stop.id.clone(),
-                                route_variant : route_variant
-                            };
+        let mut con = self.main.pool.get_conn()?;
+        let mut previous_arrivals : HashMap<(String, NaiveDate), i32> = HashMap::new();
+        for batch in last_stop_sequences.chunks(LAYOVER_QUERY_BATCH_SIZE) {
+            let placeholders = vec!["(?, ?)"; batch.len()].join(", ");
+            let query = format!(
+                r"SELECT 
+                    trip_id,
+                    trip_start_date,
+                    delay_arrival
+                FROM 
+                    records 
+                WHERE 
+                    source=? AND 
+                    trip_start_date BETWEEN ? AND ? AND
+                    (trip_id, stop_sequence) IN ({}) AND
+                    delay_arrival IS NOT NULL",
+                placeholders
+            );
+            let mut values : Vec<Value> = vec![self.main.source.clone().into(), first_day.into(), last_day.into()];
+            for (trip_id, stop_sequence) in batch {
+                values.push(Value::from(trip_id.as_str()));
+                values.push(Value::from(*stop_sequence));
+            }
 
-                            resulting_rows.push(new_item);
-                        }
-                    } else {
-                        break 'item_loop;
-                    }
-                }
+            let rows : Vec<(String, NaiveDate, i32)> = con.exec(query, Params::Positional(values))?;
+            for (trip_id, date, delay) in rows {
+                previous_arrivals.insert((trip_id, date), delay);
             }
         }
-        Ok(resulting_rows)
-    }
 
-    fn create_curves_for_route_variant(
-        &self, 
-        rows_matching_variant: &Vec<&DbItem>, 
-        trip: &Trip
-    ) -> FnResult<RouteVariantData> {
-        let mut route_variant_data = RouteVariantData::new();
-        route_variant_data.stop_ids = trip.stop_times.iter().map(|st| st.stop.id.clone()).collect();
-
-        // threshold of delay (in seconds) that will be considered. 
-        // Every stop with more than t or less then -t delay will be ignored.
-        let t = 3000; 
-        
-        for et in &EventType::TYPES {
-            let item_times: Vec<(&DbItem, DateTime<Local>)> = rows_matching_variant.iter().filter_map(|item| { 
-                if let Some(datetime) = item.get_datetime_from_trip(trip, **et) {
-                    Some((*item, datetime))
-                } else {
-                    None
-                }
-            }).collect();
-            for ts in &TimeSlot::TIME_SLOTS_WITH_DEFAULT {
-           
-                let rows_matching_time_slot : Vec<&DbItem> = item_times.iter().filter_map(|(item, datetime)| if ts.matches(*datetime) { Some(*item)} else {None} ).collect();
-
-                // Iterate over all start stations
-                for (i_s, st_s) in trip.stop_times.iter().enumerate() {
-                    // Locally select the rows which match the start station
-                    let rows_matching_start : Vec<&DbItem> = rows_matching_time_slot.iter().filter(|item| item.stop_id == st_s.stop.id).map(|i| *i).collect();
-
-                    // this is where the general_delay curves are created
-                    if let Ok(res) = self.generate_delay_curve_data(&rows_matching_start, **et) {
-                        route_variant_data.general_delay[**et].insert(i_s as u32, res);
-                    }
-                     
-                    // Iterate over end stations, and only use the ones after the start station
-                    for (i_e, st_e) in trip.stop_times.iter().enumerate() {
-                        if i_e > i_s {
-                            // Locally select rows that are matching the end station
-                            let rows_matching_end : Vec<_> = rows_matching_time_slot.iter().filter(|item| item.stop_id == st_e.stop.id).collect();
-                            
-                            // now rows_matching_start and rows_matching_end are disjunctive sets which can be joined by their vehicle
-                            // which is given by (date, trip_id).
-                            // TODO: also match start_time? 
-                            // TODO: use VehicleIdentifier from PerScheduleImporter (should be moved to types)
-
-                            let vec_size = usize::min(rows_matching_start.len(), rows_matching_end.len());
-
-                            let mut matching_pairs : EventPair<Vec<(f32, f32)>> = EventPair{
-                                arrival: Vec::<(f32, f32)>::with_capacity(vec_size), 
-                                departure: Vec::<(f32, f32)>::with_capacity(vec_size)
-                            };
-                            for row_s in &rows_matching_start {
-                                for row_e in &rows_matching_end {
-                                    if row_s.trip_start_date == row_e.trip_start_date && 
-                                    row_s.trip_start_time == row_e.trip_start_time && 
-                                            row_s.trip_id == row_e.trip_id {
-                                        // Only use rows where delay is not None
-                                        // TODO filter those out at the DB level or in the above filter expressions
-                                        if let Some(d_s) = row_s.delay.departure {
-                                            if let Some(d_e) = row_e.delay[**et] {
-                                                // Filter out rows with too much positive or negative delay
-                                                if d_s < t && d_s > -t && d_e < t && d_e > -t {
-                                                    // Now we round the delays to multiples of 12. Much of the data that we get from the agencies
-                                                    // tends to be rounded that way, and mixing up rounded and non-rounded data leads to all
-                                                    // kinds of problems.
-                                                    let rounded_d_s = (d_s / 12) * 12;
-                                                    let rounded_d_e = (d_e / 12) * 12;
-                                                    matching_pairs[**et].push((rounded_d_s as f32, rounded_d_e as f32));
-                                                }
-                                            }
-                                        }
-                                        break;
-                                    }
-                                }
-                            }
-                            // For the start station i_s and the end station i_e we now have a collection of matching
-                            // pairs of observations, i.e. each pair means:
-                            // "The vehicle which had p.0 delay at i_s arrived with p.1 delay at i_e."
-
-                            // println!("Stop #{} and #{} have {} and {} rows each, with {} matching", i_s, i_e, rows_matching_start.len(), rows_matching_end.len(), matching_pairs.len());
-                            
-                            
-                            // Don't generate statistics if we have too few pairs.
-                            if matching_pairs[**et].len() > 20 {
-                                let stop_pair_data = self.generate_curves_for_stop_pair(&matching_pairs[**et]);
-                                if let Ok(actual_data) = stop_pair_data {
-                                    let key = CurveSetKey {
-                                        start_stop_index: i_s as u32, 
-                                        end_stop_index: i_e as u32, 
-                                        time_slot: (**ts).clone()
-                                    };
-                                    route_variant_data.curve_sets[**et].insert(key, actual_data);
-                                }
-                            }
-                        }
+        let mut layover_recovery = HashMap::new();
+        for ts in &TimeSlot::TIME_SLOTS_WITH_DEFAULT {
+            let mut pairs = PairHistogram::new();
+            let matching_pairs = departures.iter()
+                .filter(|departure| ts.matches(departure.time))
+                .filter_map(|departure| {
+                    let d_s = *previous_arrivals.get(&(departure.previous_trip_id.clone(), departure.service_day))?;
+                    let d_e = departure.delay;
+                    if d_s < MAX_DELAY && d_s > -MAX_DELAY && d_e < MAX_DELAY && d_e > -MAX_DELAY {
+                        // round to multiples of 12, like the samples of the curve sets
+                        Some((((d_s / 12) * 12, (d_e / 12) * 12), recency.weight(departure.service_day)))
+                    } else {
+                        None
                     }
-                }
+                });
+            for (pair, weight) in matching_pairs {
+                pairs.add_weighted(pair, weight);
+            }
+            if !pairs.is_empty() {
+                layover_recovery.insert((**ts).clone(), pairs);
             }
         }
-        Ok(route_variant_data)
-    }
 
-    fn generate_delay_curve_data(&self, items: &Vec<&DbItem>, event_type: EventType) -> FnResult<CurveData> {
-        let values: Vec<f32> = items.iter().filter_map(|r| r.delay[event_type]).map(|t| t as f32).collect();
+        Ok(layover_recovery)
+    }
 
-        if values.len() < 20 {
+    /// Makes the curve of the delays at one stop. With recency weighting, the sample size
+    /// is the effective number of samples, which is smaller than the number of records.
+    fn generate_delay_curve_data(delays: &DelayHistogram, smoothing: SmoothingMethod) -> FnResult<CurveData> {
+        let effective_size = delays.effective_size();
+        if effective_size < 20.0 {
             bail!("Less than 20 data rows.");
         }
-        let mut curve = make_curve(&values, None)?.0;
+        let values : Vec<(f32, f32)> = delays.weighted_values().into_iter().map(|(d, w)| (d as f32, w as f32)).collect();
+        let mut curve = make_weighted_curve(&values, None, smoothing)?.0;
         curve.simplify(0.01);
         Ok(CurveData {
             curve,
             precision_type: PrecisionType::SemiSpecific,
-            sample_size: values.len() as u32
+            sample_size: effective_size.round() as u32,
+            smoothing
         })
     }
 
-    fn generate_curves_for_stop_pair(&self, pairs: &Vec<(f32, f32)>) -> FnResult<CurveSetData> {
-        // Clone the pairs so that we may sort them. We sort them by delay at the start station
-        // because we will group them by that criterion.
-        let mut own_pairs = pairs.clone();
-        own_pairs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-        let count = own_pairs.len();
+    fn generate_curves_for_stop_pair(pairs: &PairHistogram, smoothing: SmoothingMethod) -> FnResult<CurveSetData> {
+        // The pairs come sorted by delay at the start station, because we will group them by that criterion.
+        // Each distinct pair comes with the sum of its weights, and the cumulated share of the weights
+        // up to and including each pair is used to find the pairs between two markers.
+        let own_pairs : Vec<((f32, f32), f32)> = pairs.weighted_values().into_iter()
+            .map(|((d_s, d_e), w)| ((d_s as f32, d_e as f32), w as f32))
+            .collect();
+        let total_weight : f32 = own_pairs.iter().map(|(_pair, w)| *w).sum();
+        let cumulated_shares : Vec<f32> = own_pairs.iter()
+            .scan(0.0, |cumulated, (_pair, w)| { *cumulated += w; Some(*cumulated / total_weight) })
+            .collect();
+        let count = pairs.effective_size() as f32;
 
         // Try to make a curve out of initial delays. This curve is different from the actual
         // output curve(s), but is needed as a intermediate result to compute the markers.
-        let (initial_curve, _sum) = make_curve(&own_pairs.iter().map(|(s,_e)| *s).collect(), None).or_error("Could not make curve.")?;
+        let initial_values : Vec<(f32, f32)> = own_pairs.iter().map(|((s, _e), w)| (*s, *w)).collect();
+        let (initial_curve, _sum) = make_weighted_curve(&initial_values, None, SmoothingMethod::Empirical).or_error("Could not make curve.")?;
         // We build a list of "markers", which are x-coordinates / initial delays for which we 
         // will build a curve. That curve will consist of rows with "similar" delays.
         // All the "middle" will be inserted in-order by the recurse function. 
@@ -386,23 +596,27 @@ impl<'a> SpecificCurveCreator<'a> {
         let mut markers = Vec::<f32>::new();
         markers.push(initial_curve.min_x());
         markers.push(initial_curve.min_x());
-        recurse(&initial_curve, &mut markers, initial_curve.min_x(), initial_curve.max_x(), count as f32);
+        recurse(&initial_curve, &mut markers, initial_curve.min_x(), initial_curve.max_x(), count);
         markers.push(initial_curve.max_x());
         markers.push(initial_curve.max_x());
         
-        let mut sample_size: u32 = 0;
+        let mut sample_size: f32 = 0.0;
         let mut curve_set = CurveSet::<f32, IrregularDynamicCurve<f32, f32>>::new();
         // Now generate and draw one or more actual result curves.
         // Each curve will focus on the mid marker, and include all the data points from
         // the min to the max marker.
         // Remember that we added the absolute min and absolute max markers twice.
         for (lower, mid, upper) in markers.iter().tuple_windows() {
-            let min_index = (count as f32 * initial_curve.y_at_x(*lower)) as usize;
-            let max_index = (count as f32 * initial_curve.y_at_x(*upper)) as usize;
-            let slice : Vec<f32> = own_pairs[min_index .. max_index].iter().map(|(_s,e)| *e).collect();
-            sample_size += slice.len() as u32;
+            // the small tolerance makes sure that the last pair is included at the upper end
+            let index_at_x = |x: f32| cumulated_shares.iter().take_while(|share| **share <= initial_curve.y_at_x(x) + 1e-6).count();
+            let min_index = index_at_x(*lower);
+            let max_index = index_at_x(*upper).max(min_index);
+            let slice : Vec<(f32, f32)> = own_pairs[min_index .. max_index].iter().map(|((_s, e), w)| (*e, *w)).collect();
+            let slice_weight : f32 = slice.iter().map(|(_e, w)| *w).sum();
+            // the effective number of samples in the slice, assuming that the weights are spread evenly
+            sample_size += count * slice_weight / total_weight;
             if slice.len() > 1 {
-                if let Ok((mut curve, _sum)) = make_curve(&slice,  Some(*mid)) {
+                if let Ok((mut curve, _sum)) = make_weighted_curve(&slice, Some(*mid), smoothing) {
                     curve.simplify(0.001);
                     if curve.max_x() <  curve.min_x() + 13.0 {
                         continue;
@@ -417,11 +631,103 @@ impl<'a> SpecificCurveCreator<'a> {
             bail!("Curve set would consist of 0 curves.");
         }
 
-        sample_size /= curve_set.curves.len() as u32;
+        sample_size /= curve_set.curves.len() as f32;
         return Ok(CurveSetData {
             curve_set,
-            sample_size, //average amount of samples per curve
-            precision_type: PrecisionType::Specific
+            sample_size: sample_size.round() as u32, //average effective amount of samples per curve
+            precision_type: PrecisionType::Specific,
+            smoothing
         });
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_structures::{Stop, StopTime};
+    use std::sync::Arc;
+
+    fn trip(stop_ids: &[&str]) -> Trip {
+        Trip {
+            stop_times: stop_ids.iter().enumerate().map(|(i, id)| StopTime {
+                stop: Arc::new(Stop { id: id.to_string(), ..Default::default() }),
+                stop_sequence: i as u16 + 1,
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// 40 samples for each stop of `trip`, with a delay that grows by 12 s per stop,
+    /// and 40 pairs of delays from the first to the last stop.
+    fn samples(trip: &Trip) -> RouteVariantSamples {
+        let mut samples = RouteVariantSamples::new();
+        let last = trip.stop_times.len() as u32 - 1;
+        for i in 0..40 {
+            let initial = (i % 5) * 12;
+            for stop_index in 0..=last {
+                let delay = initial + stop_index as i32 * 12 + (i % 7) * 12;
+                samples.general_delay.arrival.entry(stop_index).or_insert_with(DelayHistogram::new).add_weighted(delay, 1.0);
+            }
+            let key = CurveSetKey { start_stop_index: 0, end_stop_index: last, time_slot: TimeSlot::DEFAULT.clone() };
+            samples.curve_sets.arrival.entry(key).or_insert_with(PairHistogram::new).add_weighted((initial, initial + (i % 7) * 24), 1.0);
+        }
+        samples
+    }
+
+    /// Makes the curves like `create_curves_for_route_variant` does, without blending.
+    fn curves(samples: &RouteVariantSamples, trip: &Trip) -> RouteVariantData {
+        let mut data = RouteVariantData::new();
+        data.stop_ids = trip.stop_times.iter().map(|st| st.stop.id.clone()).collect();
+        for (stop_index, delays) in &samples.general_delay.arrival {
+            data.general_delay.arrival.insert(*stop_index, SpecificCurveCreator::generate_delay_curve_data(delays, SmoothingMethod::Empirical).unwrap());
+        }
+        for (key, pairs) in &samples.curve_sets.arrival {
+            data.curve_sets.arrival.insert(key.clone(), SpecificCurveCreator::generate_curves_for_stop_pair(pairs, SmoothingMethod::Empirical).unwrap());
+        }
+        data
+    }
+
+    #[test]
+    fn test_short_turn_round_trip() {
+        let master_trip = trip(&["a", "b", "c", "d", "e"]);
+        let short_trip = trip(&["b", "c", "d"]);
+        let stop_ids = |trip: &Trip| -> Vec<String> { trip.stop_times.iter().map(|st| st.stop.id.clone()).collect() };
+        let offset = sub_trip_offset(&stop_ids(&master_trip), &stop_ids(&short_trip)).unwrap();
+        assert_eq!(offset, 1);
+        assert_eq!(sub_trip_offset(&stop_ids(&master_trip), &stop_ids(&trip(&["b", "d"]))), None);
+
+        // the short variant's samples, shifted to the stop indices of the master variant
+        let short_samples = samples(&short_trip);
+        let shifted = SpecificCurveCreator::shift_samples(&short_samples, offset);
+        let mut shifted_indices : Vec<u32> = shifted.general_delay.arrival.keys().cloned().collect();
+        shifted_indices.sort();
+        assert_eq!(shifted_indices, vec![1, 2, 3]);
+        let shifted_key = shifted.curve_sets.arrival.keys().next().unwrap();
+        assert_eq!((shifted_key.start_stop_index, shifted_key.end_stop_index), (1, 3));
+
+        // curves of the master variant from the shifted samples, so that they are comparable,
+        // plus curves for the stops and pairs which the short variant doesn't serve.
+        let mut master_data = curves(&shifted, &master_trip);
+        for stop_index in &[0, 4] {
+            let curve_data = master_data.general_delay.arrival[&2].clone();
+            master_data.general_delay.arrival.insert(*stop_index, curve_data);
+        }
+        let outer_key = CurveSetKey { start_stop_index: 0, end_stop_index: 4, time_slot: TimeSlot::DEFAULT.clone() };
+        let curve_set_data = master_data.curve_sets.arrival.values().next().unwrap().clone();
+        master_data.curve_sets.arrival.insert(outer_key, curve_set_data);
+        let extracted = SpecificCurveCreator::extract_variant_data(&master_data, &short_trip, offset);
+        let direct = curves(&short_samples, &short_trip);
+
+        assert_eq!(extracted.stop_ids, vec!["b", "c", "d"]);
+        assert_eq!(extracted.general_delay.arrival.len(), 3);
+        for (stop_index, curve_data) in &direct.general_delay.arrival {
+            let extracted_curve_data = &extracted.general_delay.arrival[stop_index];
+            assert_eq!(extracted_curve_data.sample_size, curve_data.sample_size);
+            assert_eq!(extracted_curve_data.curve.x_at_y(0.5), curve_data.curve.x_at_y(0.5));
+        }
+        assert_eq!(extracted.curve_sets.arrival.len(), 1);
+        let key = CurveSetKey { start_stop_index: 0, end_stop_index: 2, time_slot: TimeSlot::DEFAULT.clone() };
+        assert_eq!(extracted.curve_sets.arrival[&key].sample_size, direct.curve_sets.arrival[&key].sample_size);
+    }
+}
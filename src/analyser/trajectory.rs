@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Local};
+use gtfs_structures::{StopTime, Trip};
+use itertools::Itertools;
+
+use crate::types::*;
+use crate::date_and_time_local;
+
+/// Threshold of delay (in seconds) that will be considered for the curve sets.
+/// Every stop with more than this or less then its negative delay will be ignored.
+pub const MAX_DELAY : i32 = 3000;
+
+/// Segments and stops need at least this many observations to learn their drift.
+const MIN_DRIFT_SAMPLES : usize = 10;
+
+/// The records of one vehicle, i.e. of one trip starting at one time, along the stops of its trip.
+pub struct Trajectory<'a> {
+    pub trip: &'a Trip,
+    pub start: GtfsDateTime,
+    /// delays at the stops, by the index of the stop within the trip
+    pub delays: Vec<EventPair<Option<i32>>>,
+    /// true for the stops without a record, whose delays were projected from the records at other stops
+    pub synthetic: Vec<bool>,
+}
+
+impl<'a> Trajectory<'a> {
+    pub fn new(trip: &'a Trip, start: GtfsDateTime) -> Self {
+        let stop_count = trip.stop_times.len();
+        Trajectory {
+            trip,
+            start,
+            delays: vec![EventPair { arrival: None, departure: None }; stop_count],
+            synthetic: vec![false; stop_count],
+        }
+    }
+
+    pub fn is_vehicle(&self, trip_id: &str, start: &GtfsDateTime) -> bool {
+        self.trip.id == trip_id && self.start == *start
+    }
+
+    /// Adds the delays of a record to the stop with its stop_sequence, or else to the first
+    /// stop of the same station. Records of stops that are not part of the trip are ignored.
+    pub fn add(&mut self, item: &DbItem, stations: &StationGrouping) {
+        let stop_times = &self.trip.stop_times;
+        let index = stop_times.iter()
+            .position(|st| st.stop_sequence == item.stop_sequence && stations.same_station(&st.stop.id, &item.stop_id))
+            .or_else(|| stop_times.iter().position(|st| stations.same_station(&st.stop.id, &item.stop_id)));
+        if let Some(index) = index {
+            let delays = &mut self.delays[index];
+            delays.arrival = item.delay.arrival.or(delays.arrival);
+            delays.departure = item.delay.departure.or(delays.departure);
+        }
+    }
+
+    /// Returns the scheduled time of the event at the stop with the given index.
+    pub fn time(&self, index: usize, et: EventType) -> Option<DateTime<Local>> {
+        let seconds = self.trip.stop_times[index].get_time(et)?;
+        Some(date_and_time_local(&self.start.service_day(), seconds as i32))
+    }
+
+    fn has_record(&self, index: usize) -> bool {
+        self.delays[index].arrival.is_some() || self.delays[index].departure.is_some()
+    }
+
+    /// Fills the stops without records between two stops with records.
+    /// The delays are interpolated by scheduled time between the last delay before and the first
+    /// delay after the gap, following the typical changes of the delay (drift) along the route
+    /// variant where they are known. Projected stops are marked as `synthetic`.
+    pub fn fill_gaps(&mut self, drift: &Drift) {
+        let observed : Vec<usize> = (0..self.delays.len()).filter(|i| self.has_record(*i)).collect();
+        for (start, end) in observed.into_iter().tuple_windows() {
+            if end > start + 1 {
+                self.project_gap(start, end, drift);
+            }
+        }
+    }
+
+    /// Projects the delays at the stops between the stops with the indices `start` and `end`.
+    fn project_gap(&mut self, start: usize, end: usize, drift: &Drift) {
+        let trip = self.trip;
+        let stop_times = &trip.stop_times;
+
+        // the gap begins with the departure from the stop before and ends with the arrival
+        // at the stop after it, each using the other event type if there is no delay for it
+        let (start_delay, end_delay) = match (self.delays[start].departure.or(self.delays[start].arrival), self.delays[end].arrival.or(self.delays[end].departure)) {
+            (Some(start_delay), Some(end_delay)) => (start_delay as f32, end_delay as f32),
+            _ => return
+        };
+        let (start_time, end_time) = match (stop_times[start].departure_time.or(stop_times[start].arrival_time), stop_times[end].arrival_time.or(stop_times[end].departure_time)) {
+            (Some(start_time), Some(end_time)) => (start_time as f32, end_time as f32),
+            _ => return
+        };
+
+        // the part of the change of the delay that is not explained by the drift
+        // is distributed along the gap in proportion to the scheduled time
+        let total_drift : f32 = (start + 1..=end)
+            .map(|i| drift.run_to(&stop_times[i]) + if i < end { drift.dwell_at(&stop_times[i]) } else { 0.0 })
+            .sum();
+        let residual = end_delay - start_delay - total_drift;
+        let project = |time: Option<u32>, cumulated_drift: f32| -> Option<i32> {
+            let share = if end_time > start_time {
+                ((time? as f32 - start_time) / (end_time - start_time)).max(0.0).min(1.0)
+            } else {
+                0.5
+            };
+            Some((start_delay + cumulated_drift + share * residual).round() as i32)
+        };
+
+        let mut cumulated_drift = 0.0;
+        for i in start + 1..end {
+            let st = &stop_times[i];
+            cumulated_drift += drift.run_to(st);
+            let arrival = project(st.arrival_time, cumulated_drift);
+            cumulated_drift += drift.dwell_at(st);
+            let departure = project(st.departure_time, cumulated_drift);
+
+            self.delays[i] = EventPair { arrival, departure };
+            self.synthetic[i] = true;
+        }
+    }
+
+    /// Adds the delays of this vehicle to the samples of its route variant, in a single pass over its stops:
+    /// the delay at each stop to the general delays, and the departure delay at each stop together with
+    /// the delay at each later stop to the curve sets. All samples get the recency weight of the service day.
+    pub fn add_samples(&self, samples: &mut RouteVariantSamples, include_synthetic: bool, recency: &RecencyWeighting) {
+        let weight = recency.weight(self.start.service_day().naive_local());
+        let usable = |i: usize| include_synthetic || !self.synthetic[i];
+        for et in &EventType::TYPES {
+            // the time slots of the scheduled events at each stop
+            let time_slots : Vec<Option<&TimeSlot>> = (0..self.delays.len())
+                .map(|i| self.time(i, **et).map(TimeSlot::from_datetime))
+                .collect();
+
+            for i_e in 0..self.delays.len() {
+                let (d_e, ts_e) = match (self.delays[i_e][**et], time_slots[i_e]) {
+                    (Some(d_e), Some(ts_e)) if usable(i_e) => (d_e, ts_e),
+                    _ => continue
+                };
+                samples.general_delay[**et].entry(i_e as u32).or_insert_with(DelayHistogram::new).add_weighted(d_e, weight);
+
+                if d_e >= MAX_DELAY || d_e <= -MAX_DELAY {
+                    continue;
+                }
+                for i_s in 0..i_e {
+                    let (d_s, ts_s) = match (self.delays[i_s].departure, time_slots[i_s]) {
+                        (Some(d_s), Some(ts_s)) if usable(i_s) => (d_s, ts_s),
+                        _ => continue
+                    };
+                    if d_s >= MAX_DELAY || d_s <= -MAX_DELAY {
+                        continue;
+                    }
+                    // Now we round the delays to multiples of 12. Much of the data that we get from the agencies
+                    // tends to be rounded that way, and mixing up rounded and non-rounded data leads to all
+                    // kinds of problems.
+                    let pair = ((d_s / 12) * 12, (d_e / 12) * 12);
+
+                    // "The vehicle which had pair.0 delay at i_s arrived with pair.1 delay at i_e."
+                    // This counts for a time slot only if both events are within it.
+                    Self::add_pair(samples, **et, i_s, i_e, &TimeSlot::DEFAULT, pair, weight);
+                    if ts_s.id == ts_e.id {
+                        Self::add_pair(samples, **et, i_s, i_e, ts_s, pair, weight);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_pair(samples: &mut RouteVariantSamples, et: EventType, i_s: usize, i_e: usize, ts: &TimeSlot, pair: (i32, i32), weight: f64) {
+        let key = CurveSetKey {
+            start_stop_index: i_s as u32,
+            end_stop_index: i_e as u32,
+            time_slot: ts.clone()
+        };
+        samples.curve_sets[et].entry(key).or_insert_with(PairHistogram::new).add_weighted(pair, weight);
+    }
+}
+
+/// Typical change of the delay (median, in seconds) along a route variant, learned from
+/// vehicles with records at consecutive stops.
+pub struct Drift {
+    /// from the departure at the previous stop to the arrival at the stop with this stop_sequence
+    run: HashMap<u16, f32>,
+    /// from the arrival to the departure at the stop with this stop_sequence
+    dwell: HashMap<u16, f32>,
+}
+
+impl Drift {
+    /// Learns the drift from the recorded delays of the vehicles.
+    pub fn learn(trajectories: &[Trajectory]) -> Self {
+        let mut run_samples : HashMap<u16, Vec<f32>> = HashMap::new();
+        let mut dwell_samples : HashMap<u16, Vec<f32>> = HashMap::new();
+
+        for trajectory in trajectories {
+            let stop_times = &trajectory.trip.stop_times;
+            for (i, delays) in trajectory.delays.iter().enumerate() {
+                if let (Some(arrival), Some(departure)) = (delays.arrival, delays.departure) {
+                    dwell_samples.entry(stop_times[i].stop_sequence).or_insert_with(Vec::new).push((departure - arrival) as f32);
+                }
+                // only use records at directly consecutive stops
+                if i > 0 {
+                    if let (Some(departure), Some(arrival)) = (trajectory.delays[i - 1].departure, delays.arrival) {
+                        run_samples.entry(stop_times[i].stop_sequence).or_insert_with(Vec::new).push((arrival - departure) as f32);
+                    }
+                }
+            }
+        }
+
+        Drift {
+            run: Self::medians(run_samples),
+            dwell: Self::medians(dwell_samples),
+        }
+    }
+
+    fn medians(samples: HashMap<u16, Vec<f32>>) -> HashMap<u16, f32> {
+        samples.into_iter()
+            .filter(|(_stop_sequence, values)| values.len() >= MIN_DRIFT_SAMPLES)
+            .map(|(stop_sequence, mut values)| {
+                values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                (stop_sequence, values[values.len() / 2])
+            })
+            .collect()
+    }
+
+    /// Drift from the departure at the previous stop to the arrival at `stop_time`, or 0 if unknown.
+    fn run_to(&self, stop_time: &StopTime) -> f32 {
+        *self.run.get(&stop_time.stop_sequence).unwrap_or(&0.0)
+    }
+
+    /// Drift from the arrival to the departure at `stop_time`, or 0 if unknown.
+    fn dwell_at(&self, stop_time: &StopTime) -> f32 {
+        *self.dwell.get(&stop_time.stop_sequence).unwrap_or(&0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone};
+    use gtfs_structures::Stop;
+    use std::sync::Arc;
+
+    /// A trip on Monday morning, whose last stop is in the next time slot (from 8h).
+    /// The stops s1 to s4 have the stop_sequences 1 to 4.
+    fn trip() -> Trip {
+        let times = [(28320, 28320), (28500, 28560), (28680, 28740), (28920, 28920)];
+        Trip {
+            id: String::from("trip"),
+            stop_times: times.iter().enumerate().map(|(i, (arrival, departure))| StopTime {
+                stop: Arc::new(Stop { id: format!("s{}", i + 1), ..Default::default() }),
+                stop_sequence: i as u16 + 1,
+                arrival_time: Some(*arrival),
+                departure_time: Some(*departure),
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn item(stop_sequence: u16, stop_id: &str, arrival: Option<i32>, departure: Option<i32>) -> DbItem {
+        DbItem {
+            delay: EventPair { arrival, departure },
+            trip_start_date: None,
+            trip_start_time: None,
+            trip_id: String::from("trip"),
+            stop_sequence,
+            stop_id: stop_id.to_string(),
+            route_variant: 1,
+        }
+    }
+
+    fn vehicle<'a>(trip: &'a Trip, items: &[DbItem]) -> Trajectory<'a> {
+        let start = GtfsDateTime::new(Local.from_local_date(&NaiveDate::from_ymd(2020, 6, 8)).unwrap(), 28320);
+        let mut trajectory = Trajectory::new(trip, start);
+        for item in items {
+            trajectory.add(item, &StationGrouping::none());
+        }
+        trajectory
+    }
+
+    fn delays(trajectory: &Trajectory) -> Vec<(Option<i32>, Option<i32>)> {
+        trajectory.delays.iter().map(|d| (d.arrival, d.departure)).collect()
+    }
+
+    fn collect_samples(trajectory: &Trajectory, include_synthetic: bool) -> RouteVariantSamples {
+        let mut samples = RouteVariantSamples::new();
+        let recency = RecencyWeighting::new(None, &DateRange { after: None, until: None });
+        trajectory.add_samples(&mut samples, include_synthetic, &recency);
+        samples
+    }
+
+    fn pairs(samples: &RouteVariantSamples, et: EventType, i_s: u32, i_e: u32, ts: &TimeSlot) -> Vec<((i32, i32), f64)> {
+        let key = CurveSetKey { start_stop_index: i_s, end_stop_index: i_e, time_slot: ts.clone() };
+        samples.curve_sets[et][&key].weighted_values()
+    }
+
+    #[test]
+    fn test_add() {
+        let trip = trip();
+        let trajectory = vehicle(&trip, &[
+            item(1, "s1", None, Some(60)),
+            // a record with an unknown stop_sequence is assigned by its stop_id
+            item(9, "s4", Some(120), None),
+            item(3, "unknown", Some(30), Some(30)),
+        ]);
+        assert!(trajectory.is_vehicle("trip", &trajectory.start));
+        assert_eq!(delays(&trajectory), vec![(None, Some(60)), (None, None), (None, None), (Some(120), None)]);
+    }
+
+    #[test]
+    fn test_fill_gaps_without_drift() {
+        let trip = trip();
+        let mut trajectory = vehicle(&trip, &[item(1, "s1", None, Some(60)), item(4, "s4", Some(120), None)]);
+        trajectory.fill_gaps(&Drift::learn(&[]));
+
+        // the change of 60 s within 600 s of scheduled time is interpolated
+        assert_eq!(delays(&trajectory), vec![(None, Some(60)), (Some(78), Some(84)), (Some(96), Some(102)), (Some(120), None)]);
+        assert_eq!(trajectory.synthetic, vec![false, true, true, false]);
+    }
+
+    #[test]
+    fn test_fill_gaps_with_drift() {
+        let trip = trip();
+        let full_trajectories : Vec<Trajectory> = (0..10).map(|d| vehicle(&trip, &[
+            item(1, "s1", None, Some(d)),
+            item(2, "s2", Some(d + 30), Some(d + 40)),
+            item(3, "s3", Some(d + 50), Some(d + 55)),
+            item(4, "s4", Some(d + 75), None),
+        ])).collect();
+        let gap = [item(1, "s1", None, Some(0)), item(4, "s4", Some(97), None)];
+
+        // with less than 10 vehicles, the drift is unknown
+        let mut trajectory = vehicle(&trip, &gap);
+        trajectory.fill_gaps(&Drift::learn(&full_trajectories[1..]));
+        assert_eq!(delays(&trajectory), vec![(None, Some(0)), (Some(29), Some(39)), (Some(58), Some(68)), (Some(97), None)]);
+
+        // the drift explains 75 s of the change of 97 s, the remaining 22 s are interpolated
+        let mut trajectory = vehicle(&trip, &gap);
+        trajectory.fill_gaps(&Drift::learn(&full_trajectories));
+        assert_eq!(delays(&trajectory), vec![(None, Some(0)), (Some(37), Some(49)), (Some(63), Some(70)), (Some(97), None)]);
+    }
+
+    #[test]
+    fn test_add_samples() {
+        let trip = trip();
+        let mut trajectory = vehicle(&trip, &[item(1, "s1", None, Some(60)), item(4, "s4", Some(120), None)]);
+        trajectory.fill_gaps(&Drift::learn(&[]));
+
+        // the time slots of the first and the last stop differ, so their pair only counts for the default time slot
+        let samples = collect_samples(&trajectory, false);
+        assert_eq!(samples.general_delay.arrival.keys().collect::<Vec<_>>(), vec![&3]);
+        assert_eq!(samples.general_delay.arrival[&3].weighted_values(), vec![(120, 1.0)]);
+        assert_eq!(samples.general_delay.departure.keys().collect::<Vec<_>>(), vec![&0]);
+        assert_eq!(samples.general_delay.departure[&0].weighted_values(), vec![(60, 1.0)]);
+        assert_eq!(samples.curve_sets.arrival.len(), 1);
+        assert_eq!(pairs(&samples, EventType::Arrival, 0, 3, &TimeSlot::DEFAULT), vec![((60, 120), 1.0)]);
+        assert!(samples.curve_sets.departure.is_empty());
+
+        // with the synthetic stops, the delays are rounded to multiples of 12 for the pairs
+        let samples = collect_samples(&trajectory, true);
+        assert_eq!(samples.general_delay.arrival.len(), 3);
+        assert_eq!(samples.general_delay.arrival[&1].weighted_values(), vec![(78, 1.0)]);
+        assert_eq!(samples.general_delay.departure[&2].weighted_values(), vec![(102, 1.0)]);
+        assert_eq!(pairs(&samples, EventType::Arrival, 0, 1, &TimeSlot::DEFAULT), vec![((60, 72), 1.0)]);
+        assert_eq!(pairs(&samples, EventType::Arrival, 0, 1, &TimeSlot::WORKDAY_MORNING_RUSH), vec![((60, 72), 1.0)]);
+        assert_eq!(pairs(&samples, EventType::Arrival, 2, 3, &TimeSlot::DEFAULT), vec![((96, 120), 1.0)]);
+        assert_eq!(pairs(&samples, EventType::Departure, 1, 2, &TimeSlot::WORKDAY_MORNING_RUSH), vec![((84, 96), 1.0)]);
+        // 3 pairs of arrivals within the morning rush, 6 in the default time slot
+        assert_eq!(samples.curve_sets.arrival.len(), 9);
+        // 3 pairs of departures, as there is no departure at the last stop
+        assert_eq!(samples.curve_sets.departure.len(), 6);
+    }
+}
@@ -1,5 +1,6 @@
 use clap::ArgMatches;
-use chrono::{Datelike, NaiveDate, Weekday};
+use chrono::{Datelike, Local, NaiveDate, Weekday};
+use chrono::offset::TimeZone;
 use gtfs_structures::{Gtfs, Trip};
 use itertools::Itertools;
 use mysql::*;
@@ -10,12 +11,16 @@ use plotters::style::text_anchor::*;
 use rand::Rng;
 use rayon::prelude::*;
 
+use dystonse_curves::irregular_dynamic::*;
+use dystonse_curves::Curve;
+
 use super::Analyser;
 
-use crate::FnResult;
-use crate::Main;
+use crate::{FnResult, Main, date_and_time_local};
+use crate::predictor::Predictor;
+use crate::types::{EventType, OriginType, PredictionBasis, PredictionResult, StationGrouping};
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -40,6 +45,19 @@ impl FromRow for VsDbItem {
 }
 
 
+/// Quantiles of the predicted delay that are drawn as bands: the outer band
+/// spans from the first to the last, the inner band from the second to the third.
+const BAND_QUANTILES : [f32; 4] = [0.05, 0.25, 0.75, 0.95];
+
+/// Predictions that are drawn on top of the visual schedules of a route.
+struct PredictionOverlay {
+    /// only the trips and records of this date are drawn
+    date: NaiveDate,
+    /// for each trip_id and stop_sequence, the event type of the prediction
+    /// and the delays (in seconds) at the `BAND_QUANTILES`
+    delays: HashMap<String, BTreeMap<u16, (EventType, [f32; 4])>>,
+}
+
 pub struct VisualScheduleCreator<'a> {
     pub main: &'a Main,
     pub analyser:&'a Analyser<'a>,
@@ -49,14 +67,28 @@ pub struct VisualScheduleCreator<'a> {
 impl<'a> VisualScheduleCreator<'a> {
     pub fn run_visual_schedule(&mut self) -> FnResult<()> {
         let schedule = &self.analyser.schedule;
+        // stops of the same station (e.g. several platforms) are drawn as one
+        let stations = StationGrouping::new(schedule);
+        let date = match self.args.value_of("date") {
+            Some(date) => Some(NaiveDate::parse_from_str(date, "%Y-%m-%d")?),
+            None => None
+        };
+        // with `--recompute`, the curves are loaded once and shared by all routes
+        let predictor = if self.args.is_present("recompute") && date.is_some() {
+            Some(Predictor::with_schedule(self.main, self.args, schedule.clone())?)
+        } else {
+            None
+        };
         if let Some(route_ids) = self.args.values_of("route-ids") {
             println!("Handling {} route ids…", route_ids.len());
             for route_id in route_ids {
-                self.create_visual_schedule_for_route(&String::from(route_id))?;
+                self.create_visual_schedule_for_route(&String::from(route_id), &stations, predictor.as_ref(), date)?;
             }
         }
         if let Some(shape_ids) = self.args.values_of("shape-ids") {
             println!("Handling {} shape ids…", shape_ids.len());
+            // without a route, there are no records and predictions, but the trips are still filtered by date
+            let overlay = date.map(|date| PredictionOverlay { date, delays: HashMap::new() });
             for shape_id in shape_ids {
                 self.create_visual_schedule_for_shapes(
                     &String::from(shape_id),
@@ -64,6 +96,8 @@ impl<'a> VisualScheduleCreator<'a> {
                     &Vec::new(),
                     "unknown",
                     "unknown",
+                    &stations,
+                    overlay.as_ref(),
                 )?;
             }
         }
@@ -93,7 +127,7 @@ impl<'a> VisualScheduleCreator<'a> {
 
             let (count, success) = route_ids
                 .par_iter()
-                .map(|id| match self.create_visual_schedule_for_route(&id) {
+                .map(|id| match self.create_visual_schedule_for_route(&id, &stations, predictor.as_ref(), date) {
                     Ok(()) => {
                         let curr_suc = 1 + success_counter.fetch_add(1, Ordering::SeqCst);
                         let curr_err = error_counter.load(Ordering::SeqCst);
@@ -127,7 +161,7 @@ impl<'a> VisualScheduleCreator<'a> {
         Ok(())
     }
 
-    fn create_visual_schedule_for_route(&self, route_id: &String) -> FnResult<()> {
+    fn create_visual_schedule_for_route(&self, route_id: &String, stations: &StationGrouping, predictor: Option<&Predictor>, date: Option<NaiveDate>) -> FnResult<()> {
         let schedule = &self.analyser.schedule;
         let mut con = self.main.pool.get_conn()?;
         let stmt = con.prep(
@@ -142,7 +176,8 @@ impl<'a> VisualScheduleCreator<'a> {
                 records 
             WHERE 
                 source=:source AND 
-                route_id=:routeid
+                route_id=:routeid AND
+                (:date IS NULL OR trip_start_date = :date)
             ORDER BY 
                 trip_start_date,
                 trip_id",
@@ -152,7 +187,8 @@ impl<'a> VisualScheduleCreator<'a> {
             &stmt,
             params! {
                 "source" => &self.main.source,
-                "routeid" => route_id
+                "routeid" => route_id,
+                "date" => date
             },
         )?;
 
@@ -174,6 +210,14 @@ impl<'a> VisualScheduleCreator<'a> {
             return Ok(());
         }
 
+        let overlay = match date {
+            Some(date) => Some(PredictionOverlay {
+                date,
+                delays: self.get_predicted_delays(route_id, date, &db_items, stations, predictor)?,
+            }),
+            None => None
+        };
+
         // collect trips and route variants for this route
         let all_trips = &schedule.trips;
 
@@ -187,7 +231,7 @@ impl<'a> VisualScheduleCreator<'a> {
             .filter_map(|trip| trip.route_variant.clone())
             .collect();
 
-        // for each distinct route_variant_id, find a trip, and from there, the list of stop_ids
+        // for each distinct route_variant_id, find a trip, and from there, the list of station ids
         let mut stop_ids_by_route_variant_id: Vec<(&String, Vec<String>)> = route_variant_ids
             .iter()
             .map(|route_variant_id| {
@@ -203,7 +247,7 @@ impl<'a> VisualScheduleCreator<'a> {
                 let stop_ids: Vec<String> = trip
                     .stop_times
                     .iter()
-                    .map(|stop_time| stop_time.stop.id.clone())
+                    .map(|stop_time| stations.station_id(&stop_time.stop.id).to_string())
                     .collect();
                 (route_variant_id, stop_ids)
             })
@@ -255,6 +299,8 @@ impl<'a> VisualScheduleCreator<'a> {
                 &db_items,
                 &agency_name,
                 &route_name,
+                stations,
+                overlay.as_ref(),
             )?;
 
             stop_ids_by_route_variant_id.retain(|(route_variant_id, _stop_ids)| {
@@ -268,12 +314,136 @@ impl<'a> VisualScheduleCreator<'a> {
         Ok(())
     }
 
+    /// Collects the predictions for the trips of the route on `date`, either from the predictions table
+    /// or, with `--recompute`, from the current curves of `predictor`.
+    fn get_predicted_delays(
+        &self,
+        route_id: &str,
+        date: NaiveDate,
+        db_items: &Vec<VsDbItem>,
+        stations: &StationGrouping,
+        predictor: Option<&Predictor>,
+    ) -> FnResult<HashMap<String, BTreeMap<u16, (EventType, [f32; 4])>>> {
+        let realtime_only = self.args.is_present("realtime-only");
+        let predictions = match predictor {
+            Some(predictor) => self.recompute_predictions(predictor, route_id, date, db_items, stations, realtime_only)?,
+            None => self.get_stored_predictions(route_id, date, realtime_only)?
+        };
+        println!("Found {} predictions for route id {} on {}.", predictions.len(), route_id, date);
+
+        // the arrival is drawn if there is a prediction for it, otherwise the departure (e.g. at the first stop)
+        let mut delays : HashMap<String, BTreeMap<u16, (EventType, [f32; 4])>> = HashMap::new();
+        for (trip_id, stop_sequence, event_type, curve) in predictions {
+            let stops = delays.entry(trip_id).or_insert_with(BTreeMap::new);
+            if event_type == EventType::Arrival || !stops.contains_key(&stop_sequence) {
+                let mut quantiles = [0.0; 4];
+                for (i, q) in BAND_QUANTILES.iter().enumerate() {
+                    quantiles[i] = curve.x_at_y(*q);
+                }
+                stops.insert(stop_sequence, (event_type, quantiles));
+            }
+        }
+        Ok(delays)
+    }
+
+    fn get_stored_predictions(
+        &self,
+        route_id: &str,
+        date: NaiveDate,
+        realtime_only: bool,
+    ) -> FnResult<Vec<(String, u16, EventType, IrregularDynamicCurve<f32, f32>)>> {
+        let mut con = self.main.pool.get_conn()?;
+        let stmt = con.prep(
+            r"SELECT 
+                trip_id,
+                stop_sequence,
+                event_type,
+                origin_type,
+                prediction_curve
+            FROM 
+                predictions 
+            WHERE 
+                source=:source AND 
+                route_id=:routeid AND
+                trip_start_date=:date",
+        )?;
+
+        let rows : Vec<Row> = con.exec(
+            &stmt,
+            params! {
+                "source" => &self.main.source,
+                "routeid" => route_id,
+                "date" => date
+            },
+        )?;
+
+        let mut predictions = Vec::new();
+        for row in rows {
+            if realtime_only && OriginType::from_int(row.get(3).unwrap()) != OriginType::Realtime {
+                continue;
+            }
+            predictions.push((
+                row.get(0).unwrap(),
+                row.get(1).unwrap(),
+                EventType::from_int(row.get(2).unwrap()),
+                IrregularDynamicCurve::<f32, f32>::deserialize_compact(row.get(4).unwrap()),
+            ));
+        }
+        Ok(predictions)
+    }
+
+    /// Makes predictions for all trips of the route on `date` from the current curves. For the stops
+    /// after the first stop with a recorded departure delay, that delay is used as initial delay,
+    /// as if it had been reported in realtime. All other predictions are based on the schedule only.
+    fn recompute_predictions(
+        &self,
+        predictor: &Predictor,
+        route_id: &str,
+        date: NaiveDate,
+        db_items: &Vec<VsDbItem>,
+        stations: &StationGrouping,
+        realtime_only: bool,
+    ) -> FnResult<Vec<(String, u16, EventType, IrregularDynamicCurve<f32, f32>)>> {
+        let schedule = &self.analyser.schedule;
+
+        let mut items_by_trip : HashMap<&str, Vec<&VsDbItem>> = HashMap::new();
+        for item in db_items.iter().filter(|item| item.date == Some(date)) {
+            items_by_trip.entry(item.trip_id.as_str()).or_insert_with(Vec::new).push(item);
+        }
+
+        let mut predictions = Vec::new();
+        let trips = schedule.trips.values().filter(|trip| trip.route_id == route_id && schedule.trip_days(&trip.service_id, date).contains(&0));
+        for trip in trips {
+            let start_time = match trip.stop_times.first().and_then(|st| st.departure_time) {
+                Some(start_time) => start_time,
+                None => continue
+            };
+            let start_date_time = date_and_time_local(&Local.from_local_date(&date).unwrap(), start_time as i32);
+
+            let items = items_by_trip.get(trip.id.as_str());
+            let basis = trip.stop_times.iter().find_map(|st| {
+                let item = items?.iter().find(|item| stations.same_station(&item.stop_id, &st.stop.id))?;
+                Some(PredictionBasis { stop_sequence: st.stop_sequence, delay_departure: Some(item.delay_departure? as i64) })
+            });
+
+            for st in &trip.stop_times {
+                let start = match &basis {
+                    Some(basis) if basis.stop_sequence < st.stop_sequence => Some(basis.clone()),
+                    _ if realtime_only => continue,
+                    _ => None
+                };
+                for et in &EventType::TYPES {
+                    if let Ok(PredictionResult::CurveData(curve_data)) = predictor.predict(route_id, &trip.id, &start, st.stop_sequence, **et, start_date_time) {
+                        predictions.push((trip.id.clone(), st.stop_sequence, **et, curve_data.curve));
+                    }
+                }
+            }
+        }
+        Ok(predictions)
+    }
+
     fn is_sub_trip(&self, super_stop_ids: &Vec<String>, sub_stop_ids: &Vec<String>) -> bool {
-        // from https://stackoverflow.com/a/35907071
-        super_stop_ids
-            .windows(sub_stop_ids.len())
-            .position(|window| *window == sub_stop_ids[..])
-            .is_some()
+        super::curve_utils::sub_trip_offset(super_stop_ids, sub_stop_ids).is_some()
     }
 
     fn create_visual_schedule_for_route_variants(
@@ -283,6 +453,8 @@ impl<'a> VisualScheduleCreator<'a> {
         db_items: &Vec<VsDbItem>,
         agency_name: &str,
         route_name: &str,
+        stations: &StationGrouping,
+        overlay: Option<&PredictionOverlay>,
     ) -> FnResult<()> {
         let schedule = &self.analyser.schedule;
         let all_trips = &schedule.trips;
@@ -315,10 +487,11 @@ impl<'a> VisualScheduleCreator<'a> {
         let path = &format!("data/img/agency_{}/route_{}", agency_name, route_name);
         fs::create_dir_all(path)?;
 
+        let date_suffix = Self::date_suffix(overlay);
         let filename = if route_variant_ids.len() > 1 {
-            format!("{}/variant_{}_and_{}_others.png", path, primary_route_variant_id, route_variant_ids.len() - 1)
+            format!("{}/variant_{}_and_{}_others{}.png", path, primary_route_variant_id, route_variant_ids.len() - 1, date_suffix)
         } else {
-            format!("{}/variant_{}.png", path, primary_route_variant_id)
+            format!("{}/variant_{}{}.png", path, primary_route_variant_id, date_suffix)
         };
 
         self.create_visual_schedule_for_trips(
@@ -326,6 +499,8 @@ impl<'a> VisualScheduleCreator<'a> {
             trips,
             &filename,
             db_items,
+            stations,
+            overlay,
         )
     }
 
@@ -336,6 +511,8 @@ impl<'a> VisualScheduleCreator<'a> {
         db_items: &Vec<VsDbItem>,
         agency_name: &str,
         route_name: &str,
+        stations: &StationGrouping,
+        overlay: Option<&PredictionOverlay>,
     ) -> FnResult<()> {
         let schedule = &self.analyser.schedule;
         let all_trips = &schedule.trips;
@@ -362,8 +539,10 @@ impl<'a> VisualScheduleCreator<'a> {
         self.create_visual_schedule_for_trips(
             primary_trip,
             trips,
-            &format!("{}/shape_{}.png", path, primary_shape_id),
+            &format!("{}/shape_{}{}.png", path, primary_shape_id, Self::date_suffix(overlay)),
             db_items,
+            stations,
+            overlay,
         )
     }
 
@@ -373,6 +552,8 @@ impl<'a> VisualScheduleCreator<'a> {
         trips: Vec<&Trip>,
         name: &str,
         db_items: &Vec<VsDbItem>,
+        stations: &StationGrouping,
+        overlay: Option<&PredictionOverlay>,
     ) -> FnResult<()> {
         let schedule = &self.analyser.schedule;
         let mut creator = GraphCreator::new(
@@ -382,12 +563,22 @@ impl<'a> VisualScheduleCreator<'a> {
             schedule,
             self.main,
             db_items,
+            stations,
+            overlay,
         );
 
         creator.create()?;
 
         Ok(())
     }
+
+    /// Images with predictions get the date in their name, so that they don't replace the images of all dates.
+    fn date_suffix(overlay: Option<&PredictionOverlay>) -> String {
+        match overlay {
+            Some(overlay) => format!("_{}", overlay.date),
+            None => String::new()
+        }
+    }
 }
 
 struct GraphCreator<'a> {
@@ -396,9 +587,12 @@ struct GraphCreator<'a> {
     trips: Vec<&'a Trip>,
     schedule: &'a Gtfs,
     _main: &'a Main,
-    relevant_stop_ids: Vec<String>,
+    /// station ids of the stops of the primary trip, one for each position on the station axis
+    relevant_station_ids: Vec<String>,
     relevant_stop_names: Vec<String>,
     db_items: &'a Vec<VsDbItem>,
+    stations: &'a StationGrouping,
+    overlay: Option<&'a PredictionOverlay>,
 }
 
 impl<'a> GraphCreator<'a> {
@@ -409,6 +603,8 @@ impl<'a> GraphCreator<'a> {
         schedule: &'a Gtfs,
         main: &'a Main,
         db_items: &'a Vec<VsDbItem>,
+        stations: &'a StationGrouping,
+        overlay: Option<&'a PredictionOverlay>,
     ) -> GraphCreator<'a> {
         GraphCreator {
             primary_trip,
@@ -416,9 +612,11 @@ impl<'a> GraphCreator<'a> {
             name,
             _main: main,
             schedule,
-            relevant_stop_ids: Vec::new(),
+            relevant_station_ids: Vec::new(),
             relevant_stop_names: Vec::new(),
             db_items,
+            stations,
+            overlay,
         }
     }
 
@@ -429,11 +627,12 @@ impl<'a> GraphCreator<'a> {
             self.name
         );
 
-        self.relevant_stop_ids = self
+        let stations = self.stations;
+        self.relevant_station_ids = self
             .primary_trip
             .stop_times
             .iter()
-            .map(|stop_time| stop_time.stop.id.clone())
+            .map(|stop_time| stations.station_id(&stop_time.stop.id).to_string())
             .collect();
         self.relevant_stop_names = self
             .primary_trip
@@ -441,7 +640,7 @@ impl<'a> GraphCreator<'a> {
             .iter()
             .map(|stop_time| stop_time.stop.name.clone())
             .collect();
-        let stop_count = self.relevant_stop_ids.len();
+        let stop_count = self.relevant_station_ids.len();
 
         let data_for_current_trips: Vec<&VsDbItem> = self
             .db_items
@@ -463,6 +662,23 @@ impl<'a> GraphCreator<'a> {
             .filter_map(|it| it.date)
             .unique();
 
+        // with a date, only the trips that run on that date are drawn, together with their predictions
+        let drawn_trips : Vec<&Trip> = match self.overlay {
+            Some(overlay) => self.trips.iter()
+                .filter(|trip| self.schedule.trip_days(&trip.service_id, overlay.date).contains(&0))
+                .cloned()
+                .collect(),
+            None => self.trips.clone()
+        };
+        let mut prediction_bands = Vec::new();
+        if let Some(overlay) = self.overlay {
+            for trip in &drawn_trips {
+                if let Some(stops) = overlay.delays.get(&trip.id) {
+                    prediction_bands.extend(self.make_prediction_bands(trip, stops));
+                }
+            }
+        }
+
         let mut actual_trip_shapes = Vec::new();
 
         let color_weekday = LinSrgba::new(0.0, 0.5, 0.0, 0.3);
@@ -486,9 +702,9 @@ impl<'a> GraphCreator<'a> {
 
             // group the data by trip_id
             for (_trip_id, items) in &data_of_the_day.group_by(|it| it.trip_id.clone()) {
-                // for each trip_id, sort by the stop_id's position in the list of relevant_stop_ids
+                // for each trip_id, sort by the position of the stop's station in the list of relevant_station_ids
                 let sorted_items = items
-                    .sorted_by_key(|it| self.relevant_stop_ids.iter().position(|id| *id == it.stop_id));
+                    .sorted_by_key(|it| self.station_position(&it.stop_id));
 
                 let path_for_trip = PathElement::new(
                     sorted_items
@@ -537,11 +753,13 @@ impl<'a> GraphCreator<'a> {
             .y_labels(45)
             .draw()?;
 
+        // DRAW PREDICTIONS
+        graphic_schedule.draw_series(prediction_bands)?;
         // DRAW REALTIME DATA
         graphic_schedule.draw_series(actual_trip_shapes)?;
         // DRAW SCHEDULE DATA
         graphic_schedule
-            .draw_series(self.trips.iter().map(|trip| self.make_trip_drawable(trip)))?;
+            .draw_series(drawn_trips.iter().map(|trip| self.make_trip_drawable(trip)))?;
         Ok(())
     }
 
@@ -558,19 +776,65 @@ impl<'a> GraphCreator<'a> {
     }
 
     fn make_coordinate(&self, stop_id: &str, time: Option<u32>) -> Option<(f64, f64)> {
-        if let Some(mut time) = time {
-            if let Some(x) = self.relevant_stop_ids.iter().position(|id| *id == stop_id) {
+        if let Some(time) = time {
+            if let Some(x) = self.station_position(stop_id) {
                 let r = rand::thread_rng().gen_range(-30, 30) as f64;
-                if time < 3600 * 3 {
-                    time += 3600 * 24;
-                }
-                return Some((x as f64, (time as f64 + r) / 3600.0_f64));
+                return Some((x as f64, Self::time_to_y(time as f64) + r / 3600.0_f64));
             }
         }
 
         None
     }
 
+    /// Converts seconds since midnight into hours. Times in the early morning
+    /// are drawn after the end of the day, as they belong to the previous service day.
+    fn time_to_y(mut time: f64) -> f64 {
+        if time < 3600.0 * 3.0 {
+            time += 3600.0 * 24.0;
+        }
+        time / 3600.0
+    }
+
+    /// Returns the areas between the outer and between the inner `BAND_QUANTILES` of the predicted
+    /// delays, for each run of consecutive stops of the trip that have predictions.
+    fn make_prediction_bands(&self, trip: &Trip, stops: &BTreeMap<u16, (EventType, [f32; 4])>) -> Vec<Polygon<(f64, f64)>> {
+        let outer_color = LinSrgba::new(0.0, 0.0, 0.8, 0.15);
+        let inner_color = LinSrgba::new(0.0, 0.0, 0.8, 0.3);
+
+        let mut bands = Vec::new();
+        // for each stop of the current run, the coordinates of each quantile
+        let mut run : Vec<[(f64, f64); 4]> = Vec::new();
+        for stop_time in &trip.stop_times {
+            let coordinates = stops.get(&stop_time.stop_sequence).and_then(|(event_type, delays)| {
+                let time = event_type.get_time_from_stop_time(stop_time)?;
+                let x = self.station_position(&stop_time.stop.id)? as f64;
+                let mut coordinates = [(0.0, 0.0); 4];
+                for (i, delay) in delays.iter().enumerate() {
+                    coordinates[i] = (x, Self::time_to_y(time as f64 + *delay as f64));
+                }
+                Some(coordinates)
+            });
+            match coordinates {
+                Some(coordinates) => run.push(coordinates),
+                None => Self::close_prediction_bands(&mut run, &mut bands, &outer_color, &inner_color)
+            }
+        }
+        Self::close_prediction_bands(&mut run, &mut bands, &outer_color, &inner_color);
+        bands
+    }
+
+    fn close_prediction_bands(run: &mut Vec<[(f64, f64); 4]>, bands: &mut Vec<Polygon<(f64, f64)>>, outer_color: &LinSrgba, inner_color: &LinSrgba) {
+        if run.len() > 1 {
+            for (lower, upper, color) in &[(0, 3, outer_color), (1, 2, inner_color)] {
+                // along the upper quantile, and back along the lower one
+                let mut points : Vec<(f64, f64)> = run.iter().map(|coordinates| coordinates[*upper]).collect();
+                points.extend(run.iter().rev().map(|coordinates| coordinates[*lower]));
+                bands.push(Polygon::new(points, ShapeStyle::from(*color).filled()));
+            }
+        }
+        run.clear();
+    }
+
     fn make_coordinate_from_item(&self, item: &VsDbItem) -> Option<(f64, f64)> {
         if item.delay_arrival.is_none() || item.delay_departure.is_none() {
             return None;
@@ -583,7 +847,7 @@ impl<'a> GraphCreator<'a> {
 
         let trip = self.schedule.get_trip(&item.trip_id).unwrap();
         // TODO there must be a prettier way to handle those cases:
-        let a = trip.stop_times.iter().filter(|stop_time| stop_time.stop.id == item.stop_id).next();
+        let a = trip.stop_times.iter().filter(|stop_time| self.stations.same_station(&stop_time.stop.id, &item.stop_id)).next();
         if a.is_none() {
             return None;
         }
@@ -599,6 +863,12 @@ impl<'a> GraphCreator<'a> {
         )
     }
 
+    /// Returns the position of the station of the stop on the station axis.
+    fn station_position(&self, stop_id: &str) -> Option<usize> {
+        let station_id = self.stations.station_id(stop_id);
+        self.relevant_station_ids.iter().position(|id| id == station_id)
+    }
+
     fn make_time_string(&self, t: f64) -> String {
         let hour = t.floor() as i32;
         let minute = ((t - t.floor()) * 60.0).floor() as i32;
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts how often values that are missing from the realtime data had to be resolved from the schedule.
+///
+/// These counters are collected in all modes and printed in verbose mode. This struct is
+/// thread safe, so that PerScheduleImporter can update it while processing entities in parallel.
+pub struct ResolutionStatistics {
+    pub trip_updates: AtomicUsize,
+    pub trip_updates_resolved: AtomicUsize,
+    pub stop_time_updates: AtomicUsize,
+    pub stop_sequence_mismatches: AtomicUsize,
+    pub resolved_route_ids: AtomicUsize,
+    pub resolved_start_dates: AtomicUsize,
+    pub resolved_start_times: AtomicUsize,
+    pub resolved_stop_sequences: AtomicUsize,
+    pub resolved_stop_ids: AtomicUsize,
+}
+
+impl ResolutionStatistics {
+    pub fn new() -> Self {
+        ResolutionStatistics {
+            trip_updates: AtomicUsize::new(0),
+            trip_updates_resolved: AtomicUsize::new(0),
+            stop_time_updates: AtomicUsize::new(0),
+            stop_sequence_mismatches: AtomicUsize::new(0),
+            resolved_route_ids: AtomicUsize::new(0),
+            resolved_start_dates: AtomicUsize::new(0),
+            resolved_start_times: AtomicUsize::new(0),
+            resolved_stop_sequences: AtomicUsize::new(0),
+            resolved_stop_ids: AtomicUsize::new(0),
+        }
+    }
+
+    /// Increments one of the counters.
+    pub fn count(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Prints a human-readable report of all counters.
+    pub fn print_report(&self) {
+        let trip_updates = get(&self.trip_updates);
+        let stop_time_updates = get(&self.stop_time_updates);
+
+        println!("Trip updates              : {}", trip_updates);
+        println!("  trip_id found in schedule: {} ({:.1} %)", get(&self.trip_updates_resolved), percent(get(&self.trip_updates_resolved), trip_updates));
+        println!("  route_id resolved        : {} ({:.1} %)", get(&self.resolved_route_ids), percent(get(&self.resolved_route_ids), trip_updates));
+        println!("  start_date resolved      : {} ({:.1} %)", get(&self.resolved_start_dates), percent(get(&self.resolved_start_dates), trip_updates));
+        println!("  start_time resolved      : {} ({:.1} %)", get(&self.resolved_start_times), percent(get(&self.resolved_start_times), trip_updates));
+        println!("Stop time updates         : {}", stop_time_updates);
+        println!("  stop_sequence mismatches : {} ({:.1} %)", get(&self.stop_sequence_mismatches), percent(get(&self.stop_sequence_mismatches), stop_time_updates));
+        println!("  stop_sequence resolved   : {} ({:.1} %)", get(&self.resolved_stop_sequences), percent(get(&self.resolved_stop_sequences), stop_time_updates));
+        println!("  stop_id resolved         : {} ({:.1} %)", get(&self.resolved_stop_ids), percent(get(&self.resolved_stop_ids), stop_time_updates));
+    }
+}
+
+/// Counts how well realtime data matches its schedule, in addition to the `ResolutionStatistics`.
+///
+/// This struct is thread safe, so that PerScheduleImporter can update it
+/// while processing entities in parallel.
+pub struct MatchStatistics {
+    pub start_time_differences: AtomicUsize,
+    pub events_with_delay: AtomicUsize,
+    pub events_without_delay: AtomicUsize,
+    /// number of reported delays, by delay in whole minutes (rounded towards negative infinity)
+    delay_histogram: Mutex<BTreeMap<i64, usize>>,
+}
+
+impl MatchStatistics {
+    pub fn new() -> Self {
+        MatchStatistics {
+            start_time_differences: AtomicUsize::new(0),
+            events_with_delay: AtomicUsize::new(0),
+            events_without_delay: AtomicUsize::new(0),
+            delay_histogram: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn add_delay(&self, delay: i64) {
+        ResolutionStatistics::count(&self.events_with_delay);
+        let minutes = (delay as f64 / 60.0).floor() as i64;
+        let mut histogram = self.delay_histogram.lock().unwrap();
+        *histogram.entry(minutes).or_insert(0) += 1;
+    }
+
+    /// Prints a human-readable report of all counters, including those of `resolution`, and the delay histogram.
+    pub fn print_report(&self, resolution: &ResolutionStatistics) {
+        let trip_updates = get(&resolution.trip_updates);
+        let events = get(&self.events_with_delay) + get(&self.events_without_delay);
+
+        resolution.print_report();
+        println!("Start time differs        : {} ({:.1} % of trip updates)", get(&self.start_time_differences), percent(get(&self.start_time_differences), trip_updates));
+        println!("Events                    : {}", events);
+        println!("  without delay            : {} ({:.1} %)", get(&self.events_without_delay), percent(get(&self.events_without_delay), events));
+
+        let histogram = self.delay_histogram.lock().unwrap();
+        let total: usize = histogram.values().sum();
+        if total > 0 {
+            println!("Histogram of reported delays (in minutes):");
+            for (minutes, count) in histogram.iter() {
+                println!("  {:>4} … {:>4}: {:>8} ({:.1} %)", minutes, minutes + 1, count, percent(*count, total));
+            }
+        }
+    }
+}
+
+fn get(counter: &AtomicUsize) -> usize {
+    counter.load(Ordering::Relaxed)
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 { 0.0 } else { part as f64 * 100.0 / total as f64 }
+}
@@ -1,6 +1,7 @@
 mod per_schedule_importer;
 mod scheduled_predictions_importer;
 mod batched_statements;
+mod match_statistics;
 
 use simple_error::bail;
 use clap::{App, Arg, ArgMatches, ArgGroup};
@@ -16,8 +17,9 @@ use std::sync::Mutex;
 use std::collections::HashMap;
 use std::sync::Arc;
 use batched_statements::BatchedStatements;
+use match_statistics::{MatchStatistics, ResolutionStatistics};
 
-use crate::{Main, FileCache, FnResult, read_dir_simple, date_from_filename, OrError};
+use crate::{Main, ScheduleCache, FnResult, read_dir_simple, date_from_filename, OrError};
 use crate::types::{PredictionBasis, VehicleIdentifier};
 
 use per_schedule_importer::PerScheduleImporter;
@@ -40,7 +42,12 @@ pub struct Importer<'a>  {
     perform_cleanup: bool,
     last_ping_time_mutex: Mutex<Option<DateTime<Local>>>,
     current_prediction_basis: Mutex<HashMap<VehicleIdentifier, PredictionBasis>>, //used in per_schedule_importer, but declared here for persistence
+    current_block_basis: Mutex<HashMap<VehicleIdentifier, i64>>, //used in per_schedule_importer, but declared here for persistence
+    last_prognoses: Mutex<HashMap<(VehicleIdentifier, u32), (Option<i64>, Option<i64>)>>, //used in per_schedule_importer, but declared here for persistence
     timeout_until: Mutex<Option<DateTime<Local>>>, //used in scheduled_predictions_importer, but declared here for persistence
+    resolution_statistics: ResolutionStatistics, //used in per_schedule_importer, but declared here for persistence
+    match_statistics: Option<MatchStatistics>, //only for check mode; used in per_schedule_importer, but declared here for persistence
+    dry_run: bool,
 }
 
 
@@ -74,9 +81,29 @@ impl<'a> Importer<'a>  {
                 .long("cleanup")
                 .takes_value(false)
             )
+            .arg(Arg::new("record-history")
+                .about("Indicates that each distinct prognosis shall be recorded in the records_history table, too.")
+                .long_about(
+                    "Indicates that each distinct prognosis shall be recorded in the records_history table, too. \
+                    The records table only keeps the latest prognosis for each stop, whereas the history keeps \
+                    all prognoses that differ from the previous one, with their time of recording and lead time."
+                )
+                .long("record-history")
+                .takes_value(false)
+                .requires("record")
+            )
+            .arg(Arg::new("blend-provider")
+                .about("Indicates that the predictions shall be blended with the provider's own prognoses, as far as they were accurate before.")
+                .long_about(
+                    "Indicates that the predictions shall be blended with the provider's own prognoses, as far as they were accurate before. \
+                    This needs the file forecast_accuracy.json in --dir, which is written by `analyse forecast-accuracy`."
+                )
+                .long("blend-provider")
+                .takes_value(false)
+                .requires("predict")
+            )
             .group(ArgGroup::new("processing")
                 .args(&["record", "predict", "cleanup"])
-                .required(true)
                 .multiple(true)
             )
             .subcommand(App::new("automatic")
@@ -103,6 +130,19 @@ impl<'a> Importer<'a>  {
                     )
                 )
             )
+            .subcommand(App::new("check")
+                .about("Parses realtime files and matches them against the schedule without writing anything to the database, then prints a report about the match quality.")
+                .long_about("Parses realtime files and matches them against the schedule without writing anything to the database, then prints a report about the match quality. \
+                    The flags --record, --predict and --cleanup are ignored. \
+                    If the global --schedule argument is not given, the schedule for each realtime file is chosen by the date in its file name.")
+                .arg(Arg::new("rt")
+                    .index(1)
+                    .multiple(true)
+                    .required_unless("help")
+                    .value_name("PBs")
+                    .about("One or more files with real time data, as .pb or .zip")
+                )
+            )
             .subcommand(App::new("manual")
                 .about("Imports all specified realtime files using one specified schedule. Paths to schedule and realtime files have to be given as arguments.")
                 .arg(Arg::new("schedule")
@@ -130,13 +170,27 @@ impl<'a> Importer<'a>  {
             perform_cleanup: args.is_present("cleanup"),
             last_ping_time_mutex: Mutex::new(None),
             current_prediction_basis: Mutex::new(HashMap::new()),
+            current_block_basis: Mutex::new(HashMap::new()),
+            last_prognoses: Mutex::new(HashMap::new()),
             timeout_until: Mutex::new(None),
+            resolution_statistics: ResolutionStatistics::new(),
+            match_statistics: if args.subcommand_name() == Some("check") { Some(MatchStatistics::new()) } else { None },
+            dry_run: false,
         }
     }
 
     /// Runs the actions that are selected via the command line args
     pub fn run(&mut self) -> FnResult<()> {
-        match self.args.clone().subcommand() {
+        let args = self.args.clone();
+        let any_processing = args.is_present("record") || args.is_present("predict") || args.is_present("cleanup");
+        if args.subcommand_name() != Some("check") && !any_processing {
+            bail!("At least one of --record, --predict or --cleanup has to be provided.");
+        }
+        match args.subcommand() {
+            ("check", Some(sub_args)) => {
+                self.dry_run = true;
+                self.run_check(sub_args)
+            }
             ("automatic", Some(_sub_args)) => {
                 self.set_dir_paths()?;
                 self.run_as_non_manual(true)
@@ -170,6 +224,37 @@ impl<'a> Importer<'a>  {
         Ok(())
     }
 
+    /// Handle check mode: process realtime files without writing to the database, then print statistics
+    fn run_check(&self, args: &ArgMatches) -> FnResult<()> {
+        let gtfs_realtime_filenames: Vec<String> = args.values_of("rt").unwrap().map(|s| String::from(s)).collect(); // already validated by clap
+
+        // group the realtime files by the schedule that was valid when they were recorded:
+        let mut realtime_files_by_schedule : HashMap<String, Vec<String>> = HashMap::new();
+        for rt_filename in gtfs_realtime_filenames {
+            let schedule_filename = match date_from_filename(&rt_filename) {
+                Ok(date) => self.main.get_schedule_filename_for_date(date.naive_local())?,
+                Err(_) => self.main.get_schedule_filename()?,
+            };
+            realtime_files_by_schedule.entry(schedule_filename).or_insert_with(Vec::new).push(rt_filename);
+        }
+
+        for (schedule_filename, rt_filenames) in &realtime_files_by_schedule {
+            let schedule = ScheduleCache::get_cached_simple(&self.main.gtfs_cache, schedule_filename)?;
+            let short_filename = &schedule_filename[schedule_filename.rfind('/').map_or(0, |i| i + 1) ..];
+            let imp = PerScheduleImporter::new(schedule, &self, self.verbose, short_filename)?;
+            for rt_filename in rt_filenames {
+                if let Err(e) = imp.handle_realtime_file(rt_filename) {
+                    eprintln!("Error while reading {}: {}", rt_filename, e);
+                }
+            }
+        }
+
+        if let Some(statistics) = &self.match_statistics {
+            statistics.print_report(&self.resolution_statistics);
+        }
+        Ok(())
+    }
+
     /// Handle cleanup command
     fn run_cleanup(&self) -> FnResult<()> {
         let min = Local::now() - *MAX_ESTIMATED_TRIP_DURATION;
@@ -219,6 +304,14 @@ impl<'a> Importer<'a>  {
                 println!("Deleted {} entries from prediction basis cache", to_remove.len());
             }
         }
+        { // block for mutex
+            let mut cbb = self.current_block_basis.lock().unwrap();
+            let count_before = cbb.len();
+            cbb.retain(|key, _delay| key.start.date_time() >= min);
+            if self.verbose {
+                println!("Deleted {} entries from block basis cache", count_before - cbb.len());
+            }
+        }
         Ok(())
     }
 
@@ -469,7 +562,7 @@ impl<'a> Importer<'a>  {
             println!("Parsing schedule…");
         }
 
-        let schedule = match FileCache::get_cached_simple(&self.main.gtfs_cache, gtfs_schedule_filename) {
+        let schedule = match ScheduleCache::get_cached_simple(&self.main.gtfs_cache, gtfs_schedule_filename) {
             Ok(schedule) => schedule,
             Err(e) => {
                 match &self.fail_dir {
@@ -537,6 +630,8 @@ impl<'a> Importer<'a>  {
         // TODO possibly make an error file per failed file to capture the error in place
         if self.verbose {
             println!("Finished importing file: {}", &gtfs_realtime_filename);
+            println!("Values resolved from the schedule since the start of the import:");
+            self.resolution_statistics.print_report();
         } else {
             println!("{}", &gtfs_realtime_filename);
         }
@@ -547,7 +642,7 @@ impl<'a> Importer<'a>  {
         Ok(())
     }
 
-    fn move_file_to_dir(filename: &str, dir: &String) -> FnResult<()> {
+    pub fn move_file_to_dir(filename: &str, dir: &String) -> FnResult<()> {
         let mut target_path = PathBuf::from(dir);
         target_path.push(Path::new(&filename).file_name().unwrap()); // assume that the filename does not end in `..` because we got it from a directory listing
         std::fs::rename(filename, target_path)?;
@@ -1,4 +1,5 @@
 use chrono::{Duration, Local, DateTime};
+use chrono::offset::TimeZone;
 use gtfs_rt::FeedMessage as GtfsRealtimeMessage;
 use gtfs_structures::{Gtfs, StopTime};
 use gtfs_structures::Trip as ScheduleTrip;
@@ -8,15 +9,17 @@ use simple_error::bail;
 use std::fs::File;
 use std::io::prelude::*;
 use mysql::prelude::*;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
 use rayon::prelude::*;
 
 use super::batched_statements::BatchedStatements;
-use super::{Importer, VehicleIdentifier, get_predictions_statements};
+use super::{Importer, VehicleIdentifier, MatchStatistics, ResolutionStatistics, get_predictions_statements, MAX_ESTIMATED_TRIP_DURATION};
 use crate::types::PredictionResult;
 
 use crate::{FnResult, OrError, date_and_time_local};
-use crate::types::{EventType, GetByEventType, PredictionBasis, CurveData, OriginType, GtfsDateTime};
+use crate::types::{EventType, GetByEventType, PredictionBasis, CurveData, OriginType, GtfsDateTime, BlockIndex, RouteSection, ForecastAccuracy};
 use crate::predictor::Predictor;
 use dystonse_curves::Curve;
 
@@ -26,12 +29,26 @@ pub struct PerScheduleImporter<'a> {
     verbose: bool,
     filename: &'a str,
     record_statements: Option<BatchedStatements>,
+    history_statements: Option<BatchedStatements>,
     predictions_statements: Option<BatchedStatements>,
     perform_record: bool,
+    perform_record_history: bool,
     perform_predict: bool,
     predictor: Option<Predictor<'a>>,
+    block_index: Option<BlockIndex>,
 }
 
+/// Minimum delay (in seconds) near the end of a trip that will be carried over
+/// to the following trips of the vehicle's block.
+const BLOCK_PROPAGATION_MIN_DELAY: f32 = 120.0;
+
+/// Maximum number of following trips of a block for which delays are predicted.
+const BLOCK_PROPAGATION_MAX_TRIPS: usize = 3;
+
+/// The provider's prognoses of the delays (in seconds) within one trip update, together with their lead
+/// times (the seconds from the time of recording to the estimated event), by stop_sequence and event type.
+type ProviderPrognoses = HashMap<(u16, EventType), (i64, i64)>;
+
 /// For an event (which may be an arrival or a departure), this struct
 /// contains the three possible times, where (logically) estimate = schedule + delay.
 /// No checkts are performed though.
@@ -68,19 +85,29 @@ impl<'a> PerScheduleImporter<'a> {
             verbose,
             filename,
             record_statements: None,
+            history_statements: None,
             predictions_statements: None,
-            perform_record: importer.args.is_present("record"),
-            perform_predict: importer.args.is_present("predict"),
+            perform_record: importer.args.is_present("record") && !importer.dry_run,
+            perform_record_history: importer.args.is_present("record-history") && !importer.dry_run,
+            perform_predict: importer.args.is_present("predict") && !importer.dry_run,
             predictor: None,
+            block_index: None,
         };
 
         if instance.perform_record {
             instance.init_record_statements()?;
         }
+        if instance.perform_record_history {
+            instance.init_history_statements()?;
+        }
         if instance.perform_predict {
-            match Predictor::new(importer.main, &importer.main.args) {
-                Ok(predictor) => { 
+            match Predictor::with_schedule(importer.main, &importer.main.args, gtfs_schedule.clone()) {
+                Ok(mut predictor) => { 
+                    if importer.args.is_present("blend-provider") {
+                        predictor.forecast_accuracy = Some(ForecastAccuracy::load(&importer.main.dir)?);
+                    }
                     instance.predictor = Some(predictor); 
+                    instance.block_index = Some(BlockIndex::new(&gtfs_schedule));
                     instance.init_predictions_statements()?;
                 }
                 Err(e) => {
@@ -142,6 +169,12 @@ impl<'a> PerScheduleImporter<'a> {
         if self.perform_record {
             self.record_statements.as_ref().unwrap().write_to_database()?;
         }
+        if self.perform_record_history {
+            self.history_statements.as_ref().unwrap().write_to_database()?;
+            // the prognoses for trips that have surely ended won't change anymore
+            let min = Local.timestamp(time_of_recording as i64, 0) - *MAX_ESTIMATED_TRIP_DURATION;
+            self.importer.last_prognoses.lock().unwrap().retain(|(vehicle_id, _stop_sequence), _prognosis| vehicle_id.start.date_time() >= min);
+        }
         if self.perform_predict {
             self.predictions_statements.as_ref().unwrap().write_to_database()?;
         }
@@ -153,30 +186,62 @@ impl<'a> PerScheduleImporter<'a> {
         trip_update: &gtfs_rt::TripUpdate,
         time_of_recording: u64,
     ) -> FnResult<()> {
+        let statistics = &self.importer.resolution_statistics;
+        ResolutionStatistics::count(&statistics.trip_updates);
+
         let realtime_trip = &trip_update.trip;
-        let route_id = &realtime_trip.route_id.as_ref().or_error("Trip needs route_id")?;
         let trip_id = &realtime_trip.trip_id.as_ref().or_error("Trip needs id")?;
-        let realtime_trip_start = GtfsDateTime::from_trip_descriptor(realtime_trip)?;
      
         let schedule_trip = self.gtfs_schedule.get_trip(&trip_id)
             .or_error(&format!("Did not find trip {} in schedule. Skipping.", trip_id))?;
+        ResolutionStatistics::count(&statistics.trip_updates_resolved);
+
+        // route_id, start_date and start_time may be omitted according to the GTFS-RT spec, 
+        // in that case we take them from the schedule:
+        let route_id = Self::resolve_route_id(statistics, realtime_trip, schedule_trip);
+        let realtime_trip_start = Self::resolve_trip_start(&self.gtfs_schedule, statistics, realtime_trip, schedule_trip, time_of_recording)?;
 
         let schedule_start_time = Duration::seconds(schedule_trip.stop_times[0].departure_time.unwrap() as i64);
         let time_difference = realtime_trip_start.duration() - schedule_start_time;
         if !time_difference.is_zero() {
-            eprintln!("Trip {} has a difference of {} seconds between scheduled start times in schedule data and realtime data.", trip_id, time_difference);
+            self.count_match(|s| &s.start_time_differences);
+            if self.verbose {
+                eprintln!("Trip {} has a difference of {} seconds between scheduled start times in schedule data and realtime data.", trip_id, time_difference);
+            }
         }
 
-        let mut prediction_done = false;
+        let mut last_stop_index : Option<usize> = None;
+        let mut resolved_stops : Vec<(String, u32, &gtfs_rt::trip_update::StopTimeUpdate)> = Vec::new();
         for stop_time_update in &trip_update.stop_time_update {
-            
+            ResolutionStatistics::count(&statistics.stop_time_updates);
+            match Self::resolve_stop(statistics, stop_time_update, schedule_trip, &mut last_stop_index) {
+                Ok((stop_id, stop_sequence)) => resolved_stops.push((stop_id, stop_sequence, stop_time_update)),
+                Err(e) => println!("Error with stop_time_update: {}", e)
+            };
+        }
+        let resolved_updates : Vec<(u16, &gtfs_rt::trip_update::StopTimeUpdate)> = resolved_stops.iter()
+            .map(|(_stop_id, stop_sequence, stop_time_update)| (*stop_sequence as u16, *stop_time_update))
+            .collect();
+
+        // the prognoses for all stops of the update are needed before the first prediction is made
+        let provider_prognoses = if self.predictor.as_ref().map_or(false, |predictor| predictor.forecast_accuracy.is_some()) {
+            self.get_provider_prognoses(&resolved_updates, &realtime_trip_start, schedule_trip, time_of_recording)
+        } else {
+            ProviderPrognoses::new()
+        };
+
+        let mut prediction_done = false;
+        for (stop_id, stop_sequence, stop_time_update) in resolved_stops {
             let res = self.process_stop_time_update(
                 stop_time_update,
+                stop_id,
+                stop_sequence,
                 &realtime_trip_start,
                 schedule_trip,
                 &trip_id,
                 &route_id,
                 time_of_recording,
+                &provider_prognoses,
                 &mut prediction_done
             );
             if let Err(e) = res {
@@ -186,6 +251,11 @@ impl<'a> PerScheduleImporter<'a> {
         if self.perform_predict && !prediction_done {
             println!("At the end, still no prediction.");
         }
+        if self.perform_predict {
+            if let Err(e) = self.propagate_delay_to_block(&resolved_updates, &realtime_trip_start, schedule_trip, &route_id, time_of_recording) {
+                println!("Error while propagating delay to block: {}", e);
+            }
+        }
 
         Ok(())
     }
@@ -193,26 +263,26 @@ impl<'a> PerScheduleImporter<'a> {
     fn process_stop_time_update(
         &self,
         stop_time_update: &gtfs_rt::trip_update::StopTimeUpdate,
+        stop_id: String,
+        stop_sequence: u32,
         start_gtfs_time: &GtfsDateTime,
         schedule_trip: &gtfs_structures::Trip,
         trip_id: &String,
         route_id: &String,
         time_of_recording: u64,
+        provider_prognoses: &ProviderPrognoses,
         prediction_done: &mut bool
     ) -> FnResult<()> {
         let start_date_time = start_gtfs_time.date_time();
 
-        // params into local variables
-        let stop_id : String = stop_time_update.stop_id.as_ref().or_error("no stop_id")?.clone();
-        let stop_sequence = stop_time_update.stop_sequence.or_error("no stop_sequence")?;
-        let arrival = PerScheduleImporter::get_event_times(
+        let arrival = self.get_event_times(
             stop_time_update.arrival.as_ref(),
             start_date_time,
             EventType::Arrival,
             &schedule_trip,
             stop_sequence,
         );
-        let departure = PerScheduleImporter::get_event_times(
+        let departure = self.get_event_times(
             stop_time_update.departure.as_ref(),
             start_date_time,
             EventType::Departure,
@@ -242,6 +312,38 @@ impl<'a> PerScheduleImporter<'a> {
             }))?;
         }
 
+        // write each prognosis into the history, unless it is the same as before
+        if self.perform_record_history {
+            let key = (VehicleIdentifier { trip_id: trip_id.clone(), start: start_gtfs_time.clone() }, stop_sequence);
+            let prognosis = (arrival.delay, departure.delay);
+            let is_new = { //block for mutex
+                let mut last_prognoses = self.importer.last_prognoses.lock().unwrap();
+                last_prognoses.insert(key, prognosis) != Some(prognosis)
+            };
+            if is_new {
+                // lead time until the estimated arrival, or the departure if there is no arrival
+                let lead_time = match arrival.delay {
+                    Some(delay) => Self::get_lead_time(start_gtfs_time, schedule_trip, stop_sequence as u16, EventType::Arrival, delay, time_of_recording),
+                    None => departure.delay.and_then(|delay| Self::get_lead_time(start_gtfs_time, schedule_trip, stop_sequence as u16, EventType::Departure, delay, time_of_recording))
+                };
+                self.history_statements.as_ref().unwrap().add_parameter_set(Params::from(params! {
+                    "source" => &self.importer.main.source,
+                    "route_id" => &route_id,
+                    "route_variant" => &schedule_trip.route_variant.as_ref().or_error("no route variant")?,
+                    "trip_id" => &trip_id,
+                    "trip_start_date" => start_gtfs_time.service_day().naive_local(),
+                    "trip_start_time" => start_gtfs_time.duration(),
+                    stop_sequence,
+                    "stop_id" => &stop_id,
+                    time_of_recording,
+                    lead_time,
+                    "delay_arrival" => arrival.delay,
+                    "delay_departure" => departure.delay,
+                    "schedule_file_name" => self.filename
+                }))?;
+            }
+        }
+
         // predictions:
 
         if self.perform_predict && !*prediction_done {
@@ -296,7 +398,9 @@ impl<'a> PerScheduleImporter<'a> {
                                 &vehicle_id,
                                 basis.clone(),
                                 stop_time,
-                                **event_type
+                                **event_type,
+                                OriginType::Realtime,
+                                provider_prognoses.get(&(stop_time.stop_sequence, **event_type)).cloned()
                             ) {
                                 Ok(()) => actual_success = true,
                                 Err(e) => println!("Prediction error: {}", e)
@@ -324,6 +428,8 @@ impl<'a> PerScheduleImporter<'a> {
         actual_begin: PredictionBasis,
         scheduled_end: &StopTime,
         event_type: EventType,
+        origin_type: OriginType,
+        provider_prognosis: Option<(i64, i64)>,
     ) -> FnResult<()> {
         let arrival_prediction = self.predictor.as_ref().unwrap().predict(
             &route_id,
@@ -335,12 +441,30 @@ impl<'a> PerScheduleImporter<'a> {
         // TODO in the previous line, we used to compute a broken date time from the schedued_end
         // now we use the start, which has a valid date, but behaviour might change anyway.
             
-        let curve_data : CurveData = match arrival_prediction {
+        let mut curve_data : CurveData = match arrival_prediction {
             PredictionResult::CurveData(curve_data) => curve_data,
             _ => bail!("Result of unexpected type, can't write to DB!")
         };
 
-        let scheduled_event_time = event_type.get_time_from_stop_time(scheduled_end).unwrap();
+        if let Some((delay, lead_time)) = provider_prognosis {
+            if let Some(blended) = self.predictor.as_ref().unwrap().blend_with_provider(&curve_data, event_type, delay as f32, lead_time) {
+                curve_data = blended;
+            }
+        }
+
+        self.write_prediction(route_id, vehicle_id, scheduled_end, event_type, &curve_data, origin_type)
+    }
+
+    fn write_prediction(
+        &self,
+        route_id: &String,
+        vehicle_id: &VehicleIdentifier,
+        scheduled_end: &StopTime,
+        event_type: EventType,
+        curve_data: &CurveData,
+        origin_type: OriginType,
+    ) -> FnResult<()> {
+        let scheduled_event_time = event_type.get_time_from_stop_time(scheduled_end).or_error("No scheduled time for event")?;
 
         let prediction_min = date_and_time_local(&vehicle_id.start.date(), scheduled_event_time + curve_data.curve.min_x() as i32);
         let prediction_max = date_and_time_local(&vehicle_id.start.date(), scheduled_event_time + curve_data.curve.max_x() as i32);
@@ -357,7 +481,7 @@ impl<'a> PerScheduleImporter<'a> {
             "trip_start_time" => vehicle_id.start.duration(),
             "stop_sequence" => scheduled_end.stop_sequence,
             "precision_type" => curve_data.precision_type.to_int(),
-            "origin_type" => OriginType::Realtime.to_int(),
+            "origin_type" => origin_type.to_int(),
             "sample_size" => curve_data.sample_size,
             "prediction_curve" => curve_data.curve.serialize_compact_limited(120),
             "schedule_file_name" => self.filename
@@ -365,7 +489,294 @@ impl<'a> PerScheduleImporter<'a> {
         Ok(())
     }
 
+    /// If the vehicle is running late near the end of its trip, the delay is carried over to the
+    /// following trips of its block, using the layover recovery curves learned by the analyser.
+    fn propagate_delay_to_block(
+        &self,
+        stop_time_updates: &Vec<(u16, &gtfs_rt::trip_update::StopTimeUpdate)>,
+        start_gtfs_time: &GtfsDateTime,
+        schedule_trip: &ScheduleTrip,
+        route_id: &String,
+        time_of_recording: u64,
+    ) -> FnResult<()> {
+        let predictor = self.predictor.as_ref().unwrap();
+        let block_index = self.block_index.as_ref().unwrap();
+
+        if schedule_trip.block_id.is_none() {
+            return Ok(());
+        }
+
+        let (stop_sequence, delay) = match Self::get_block_propagation_basis(stop_time_updates, start_gtfs_time, schedule_trip, time_of_recording)? {
+            Some(basis) => basis,
+            None => return Ok(())
+        };
+
+        let vehicle_id = VehicleIdentifier {
+            trip_id: schedule_trip.id.clone(),
+            start: start_gtfs_time.clone(),
+        };
+        { //block for mutex
+            let mut cbb = self.importer.current_block_basis.lock().unwrap();
+            if cbb.get(&vehicle_id) == Some(&delay) {
+                return Ok(());
+            }
+            cbb.insert(vehicle_id, delay);
+        }
+
+        let basis = PredictionBasis { stop_sequence, delay_departure: Some(delay) };
+        let mut previous_delay = self.predict_final_delay(route_id, &schedule_trip.id, start_gtfs_time, schedule_trip, &basis)
+            .unwrap_or(delay as f32);
+
+        let service_day = start_gtfs_time.service_day();
+        let following_trips = block_index.get_following_trips(&self.gtfs_schedule, schedule_trip, service_day.naive_local());
+
+        for next_trip in following_trips.iter().take(BLOCK_PROPAGATION_MAX_TRIPS) {
+            if previous_delay.abs() < BLOCK_PROPAGATION_MIN_DELAY {
+                break;
+            }
+            let first_stop_time = &next_trip.stop_times[0];
+            let next_vehicle_id = VehicleIdentifier {
+                trip_id: next_trip.id.clone(),
+                start: GtfsDateTime::new(service_day, first_stop_time.departure_time.or_error("First stop without departure time")? as i32),
+            };
+
+            // predictions based on the vehicle's own realtime data are always better:
+            if self.importer.current_prediction_basis.lock().unwrap().contains_key(&next_vehicle_id) {
+                break;
+            }
+
+            let layover = predictor.predict_layover(&next_trip.route_id, previous_delay, next_vehicle_id.start.date_time())?;
+            self.write_prediction(&next_trip.route_id, &next_vehicle_id, first_stop_time, EventType::Departure, &layover, OriginType::Block)?;
+
+            let next_basis = PredictionBasis {
+                stop_sequence: first_stop_time.stop_sequence,
+                delay_departure: Some(layover.curve.x_at_y(0.5) as i64),
+            };
+            for stop_time in next_trip.stop_times.iter().skip(1) {
+                for event_type in &EventType::TYPES {
+                    if let Err(e) = self.make_prediction(&next_trip.route_id, &next_vehicle_id, next_basis.clone(), stop_time, **event_type, OriginType::Block, None) {
+                        println!("Block prediction error: {}", e);
+                    }
+                }
+            }
+
+            // the expected delay at the end of this trip is the basis for the next one
+            previous_delay = self.predict_final_delay(&next_trip.route_id, &next_trip.id, &next_vehicle_id.start, next_trip, &next_basis)
+                .unwrap_or(next_basis.delay_departure.unwrap() as f32);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the median of the predicted arrival delay at the last stop of the trip.
+    fn predict_final_delay(
+        &self,
+        route_id: &str,
+        trip_id: &str,
+        start_gtfs_time: &GtfsDateTime,
+        schedule_trip: &ScheduleTrip,
+        basis: &PredictionBasis,
+    ) -> FnResult<f32> {
+        let last_stop_time = schedule_trip.stop_times.last().or_error("Trip without stop times")?;
+        if last_stop_time.stop_sequence == basis.stop_sequence {
+            return Ok(basis.delay_departure.or_error("No delay in prediction basis")? as f32);
+        }
+        match self.predictor.as_ref().unwrap().predict(
+            route_id,
+            trip_id,
+            &Some(basis.clone()),
+            last_stop_time.stop_sequence,
+            EventType::Arrival,
+            start_gtfs_time.date_time()
+        )? {
+            PredictionResult::CurveData(curve_data) => Ok(curve_data.curve.x_at_y(0.5)),
+            _ => bail!("Result of unexpected type, can't compute final delay!")
+        }
+    }
+
+    /// Returns the route_id of the trip, taken from the schedule if it is missing.
+    fn resolve_route_id<'b>(
+        statistics: &ResolutionStatistics,
+        realtime_trip: &'b gtfs_rt::TripDescriptor,
+        schedule_trip: &'b ScheduleTrip,
+    ) -> &'b String {
+        match &realtime_trip.route_id {
+            Some(route_id) => route_id,
+            None => {
+                ResolutionStatistics::count(&statistics.resolved_route_ids);
+                &schedule_trip.route_id
+            }
+        }
+    }
+
+    /// Returns the start of the trip, taking missing start_date or start_time from the schedule.
+    fn resolve_trip_start(
+        schedule: &Gtfs,
+        statistics: &ResolutionStatistics,
+        realtime_trip: &gtfs_rt::TripDescriptor,
+        schedule_trip: &ScheduleTrip,
+        time_of_recording: u64,
+    ) -> FnResult<GtfsDateTime> {
+
+        let time = match &realtime_trip.start_time {
+            Some(start_time) => GtfsDateTime::parse_time(start_time)?,
+            None => {
+                ResolutionStatistics::count(&statistics.resolved_start_times);
+                schedule_trip.stop_times[0].departure_time.or_error("Trip has no departure time at its first stop")? as i32
+            }
+        };
+
+        let service_day = match &realtime_trip.start_date {
+            Some(start_date) => GtfsDateTime::parse_date(start_date)?,
+            None => {
+                // Use the service day on which the trip runs, that is the closest to the time of recording.
+                // We need to look at the previous day as well, because trips may start after midnight.
+                let recording_time = Local.timestamp(time_of_recording as i64, 0);
+                let service_day = (-1..=1)
+                    .map(|offset| recording_time.date() + Duration::days(offset))
+                    .filter(|day| schedule.trip_days(&schedule_trip.service_id, day.naive_local()).contains(&0))
+                    .min_by_key(|day| (GtfsDateTime::new(*day, time).date_time() - recording_time).num_seconds().abs())
+                    .or_error("Could not find a service day near the time of recording on which the trip runs")?;
+                ResolutionStatistics::count(&statistics.resolved_start_dates);
+                service_day
+            }
+        };
+
+        Ok(GtfsDateTime::new(service_day, time))
+    }
+
+    /// Returns stop_id and stop_sequence of the stop time update, where a missing value 
+    /// is taken from the schedule. If only the stop_id is given, the next occurrence of 
+    /// the stop after the previous update is used, so that loop trips which visit a stop
+    /// twice are handled correctly.
+    fn resolve_stop(
+        statistics: &ResolutionStatistics,
+        stop_time_update: &gtfs_rt::trip_update::StopTimeUpdate,
+        schedule_trip: &ScheduleTrip,
+        last_stop_index: &mut Option<usize>,
+    ) -> FnResult<(String, u32)> {
+
+        let stop_index = match (stop_time_update.stop_sequence, &stop_time_update.stop_id) {
+            (Some(stop_sequence), Some(stop_id)) => {
+                match schedule_trip.get_stop_index_by_stop_sequence(stop_sequence as u16) {
+                    Ok(index) => *last_stop_index = Some(index),
+                    Err(_) => ResolutionStatistics::count(&statistics.stop_sequence_mismatches),
+                }
+                return Ok((stop_id.clone(), stop_sequence));
+            },
+            (Some(stop_sequence), None) => {
+                let index = match schedule_trip.get_stop_index_by_stop_sequence(stop_sequence as u16) {
+                    Ok(index) => index,
+                    Err(_) => {
+                        ResolutionStatistics::count(&statistics.stop_sequence_mismatches);
+                        bail!("No stop_id, and stop_sequence {} does not exist in trip {}.", stop_sequence, schedule_trip.id);
+                    }
+                };
+                ResolutionStatistics::count(&statistics.resolved_stop_ids);
+                index
+            },
+            (None, Some(stop_id)) => {
+                let first_unvisited = last_stop_index.map_or(0, |i| i + 1);
+                let index = schedule_trip.stop_times.iter().enumerate()
+                    .skip(first_unvisited)
+                    .find(|(_i, st)| &st.stop.id == stop_id)
+                    .map(|(i, _st)| i)
+                    .or_error(&format!("No stop_sequence, and stop {} is not visited by trip {} after stop index {}.", stop_id, schedule_trip.id, first_unvisited))?;
+                ResolutionStatistics::count(&statistics.resolved_stop_sequences);
+                index
+            },
+            (None, None) => bail!("Stop time update has neither stop_id nor stop_sequence."),
+        };
+
+        *last_stop_index = Some(stop_index);
+        let stop_time = &schedule_trip.stop_times[stop_index];
+        Ok((stop_time.stop.id.clone(), stop_time.stop_sequence as u32))
+    }
+
+    /// Collects the provider's prognoses for all stops of a trip update, see `ProviderPrognoses`.
+    fn get_provider_prognoses(
+        &self,
+        stop_time_updates: &Vec<(u16, &gtfs_rt::trip_update::StopTimeUpdate)>,
+        start_gtfs_time: &GtfsDateTime,
+        schedule_trip: &ScheduleTrip,
+        time_of_recording: u64,
+    ) -> ProviderPrognoses {
+        let mut prognoses = ProviderPrognoses::new();
+        for (stop_sequence, stop_time_update) in stop_time_updates {
+            for event_type in &EventType::TYPES {
+                let event = if **event_type == EventType::Arrival { &stop_time_update.arrival } else { &stop_time_update.departure };
+                if let Some(delay) = event.as_ref().and_then(|e| e.delay) {
+                    if let Some(lead_time) = Self::get_lead_time(start_gtfs_time, schedule_trip, *stop_sequence, **event_type, delay as i64, time_of_recording) {
+                        prognoses.insert((*stop_sequence, **event_type), (delay as i64, lead_time));
+                    }
+                }
+            }
+        }
+        prognoses
+    }
+
+    /// Finds the delay that can be carried over to the following trips of the block, with the stop_sequence at which it
+    /// was observed. This is the delay of the most recent event that already happened, preferring departures over
+    /// arrivals, if it is large and was observed near the end of the trip. Updates for later stops are only forecasts.
+    fn get_block_propagation_basis(
+        stop_time_updates: &Vec<(u16, &gtfs_rt::trip_update::StopTimeUpdate)>,
+        start_gtfs_time: &GtfsDateTime,
+        schedule_trip: &ScheduleTrip,
+        time_of_recording: u64,
+    ) -> FnResult<Option<(u16, i64)>> {
+        let mut latest : Option<(u16, i64)> = None;
+        for (stop_sequence, stop_time_update) in stop_time_updates {
+            let happened = |event: &Option<gtfs_rt::trip_update::StopTimeEvent>, event_type: EventType| -> Option<i64> {
+                let delay = event.as_ref()?.delay? as i64;
+                let lead_time = Self::get_lead_time(start_gtfs_time, schedule_trip, *stop_sequence, event_type, delay, time_of_recording)?;
+                if lead_time <= 0 { Some(delay) } else { None }
+            };
+            let delay = happened(&stop_time_update.departure, EventType::Departure)
+                .or_else(|| happened(&stop_time_update.arrival, EventType::Arrival));
+            if let Some(delay) = delay {
+                if latest.map_or(true, |(s, _d)| s < *stop_sequence) {
+                    latest = Some((*stop_sequence, delay));
+                }
+            }
+        }
+        let (stop_sequence, delay) = match latest {
+            Some(latest) => latest,
+            None => return Ok(None)
+        };
+
+        // only carry over large delays which were observed near the end of the trip:
+        let stop_index = schedule_trip.get_stop_index_by_stop_sequence(stop_sequence)?;
+        if RouteSection::get_route_section_by_stop_index(schedule_trip, stop_index)? != RouteSection::End
+            || (delay as f32).abs() < BLOCK_PROPAGATION_MIN_DELAY {
+            return Ok(None);
+        }
+        Ok(Some((stop_sequence, delay)))
+    }
+
+    /// Increments one of the match statistics, which are only collected for `import check`.
+    fn count_match(&self, counter: fn(&MatchStatistics) -> &AtomicUsize) {
+        if let Some(statistics) = &self.importer.match_statistics {
+            ResolutionStatistics::count(counter(statistics));
+        }
+    }
+
+    /// Returns the seconds from the time of recording to the event, estimated from the scheduled time and the delay.
+    /// The lead time is negative if the event already happened.
+    fn get_lead_time(
+        start_gtfs_time: &GtfsDateTime,
+        schedule_trip: &ScheduleTrip,
+        stop_sequence: u16,
+        event_type: EventType,
+        delay: i64,
+        time_of_recording: u64,
+    ) -> Option<i64> {
+        let time = schedule_trip.get_stop_time_by_sequence(stop_sequence).ok()?.get_time(event_type)?;
+        let estimate = date_and_time_local(&start_gtfs_time.service_day(), time as i32).timestamp() + delay;
+        Some(estimate - time_of_recording as i64)
+    }
+
     fn get_event_times(
+        &self,
         event: Option<&gtfs_rt::trip_update::StopTimeEvent>,
         start_date_time: DateTime<Local>,
         event_type: EventType,
@@ -374,9 +785,15 @@ impl<'a> PerScheduleImporter<'a> {
     ) -> EventTimes {
         let delay = if let Some(event) = event {
             if let Some(delay) = event.delay {
+                if let Some(statistics) = &self.importer.match_statistics {
+                    statistics.add_delay(delay as i64);
+                }
                 delay as i64
             } else {
-                eprintln!("Stop time update {:?} without delay. Skipping.", event_type);
+                self.count_match(|s| &s.events_without_delay);
+                if self.verbose {
+                    eprintln!("Stop time update {:?} without delay. Skipping.", event_type);
+                }
                 return EventTimes::empty();
             }
         } else {
@@ -455,8 +872,267 @@ impl<'a> PerScheduleImporter<'a> {
         Ok(())
     }
 
+    fn init_history_statements(&mut self) -> FnResult<()> {
+        let mut conn = self.importer.main.pool.get_conn()?;
+        let insert_statement = conn.prep(r"INSERT IGNORE INTO `records_history` (
+            `source`, 
+            `route_id`,
+            `route_variant`,
+            `trip_id`,
+            `trip_start_date`,
+            `trip_start_time`,
+            `stop_sequence`,
+            `stop_id`,
+            `time_of_recording`,
+            `lead_time`,
+            `delay_arrival`,
+            `delay_departure`,
+            `schedule_file_name`
+        ) VALUES ( 
+            :source,
+            :route_id,
+            :route_variant,
+            :trip_id,
+            :trip_start_date,
+            :trip_start_time,
+            :stop_sequence,
+            :stop_id,
+            FROM_UNIXTIME(:time_of_recording),
+            :lead_time,
+            :delay_arrival,
+            :delay_departure, 
+            :schedule_file_name
+        );")
+        .expect("Could not prepare insert statement"); // Should never happen because of hard-coded statement string
+
+        self.history_statements = Some(BatchedStatements::new("records_history", conn, vec![insert_statement]));
+        Ok(())
+    }
+
     fn init_predictions_statements(&mut self) -> FnResult<()> {
         self.predictions_statements = Some(get_predictions_statements(self.importer.main.pool.clone())?);
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use gtfs_rt::TripDescriptor;
+    use gtfs_rt::trip_update::{StopTimeEvent, StopTimeUpdate};
+    use gtfs_structures::{CalendarDate, Exception, Stop};
+    use std::sync::atomic::Ordering;
+
+    /// A trip with six stops, 10 minutes apart, with one minute for each stop, starting at 1:00.
+    fn schedule_trip() -> ScheduleTrip {
+        ScheduleTrip {
+            id: String::from("trip"),
+            block_id: Some(String::from("block")),
+            stop_times: (0..6).map(|i| StopTime {
+                stop_sequence: i + 1,
+                arrival_time: Some(3600 + 600 * i as u32),
+                departure_time: Some(3660 + 600 * i as u32),
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn event(delay: Option<i32>) -> Option<StopTimeEvent> {
+        delay.map(|delay| StopTimeEvent { delay: Some(delay), ..Default::default() })
+    }
+
+    fn update(arrival_delay: Option<i32>, departure_delay: Option<i32>) -> StopTimeUpdate {
+        StopTimeUpdate {
+            arrival: event(arrival_delay),
+            departure: event(departure_delay),
+            ..Default::default()
+        }
+    }
+
+    fn start() -> GtfsDateTime {
+        GtfsDateTime::new(Local.ymd(2020, 6, 8), 3660)
+    }
+
+    /// Finds the basis for block propagation, for updates with the given delays at all stops, at the given time of the day
+    fn basis(delays: &Vec<(Option<i32>, Option<i32>)>, time_of_day: i32) -> Option<(u16, i64)> {
+        let updates : Vec<StopTimeUpdate> = delays.iter().map(|(arrival, departure)| update(*arrival, *departure)).collect();
+        let updates : Vec<(u16, &StopTimeUpdate)> = updates.iter().enumerate().map(|(i, u)| (i as u16 + 1, u)).collect();
+        let time_of_recording = date_and_time_local(&start().service_day(), time_of_day).timestamp() as u64;
+        PerScheduleImporter::get_block_propagation_basis(&updates, &start(), &schedule_trip(), time_of_recording).unwrap()
+    }
+
+    #[test]
+    fn test_block_propagation_basis() {
+        let delays = vec![(Some(300), Some(300)); 6];
+        // the departure from stop 5 happened at 1:46, the arrival at stop 6 is expected at 1:55
+        assert_eq!(basis(&delays, 6500), Some((5, 300)));
+        // the latest departure that happened is from stop 3, which is not near the end of the trip
+        assert_eq!(basis(&delays, 5200), None);
+        // nothing happened yet
+        assert_eq!(basis(&delays, 3000), None);
+        // the arrival at the last stop happened
+        assert_eq!(basis(&delays, 7000), Some((6, 300)));
+    }
+
+    #[test]
+    fn test_block_propagation_basis_small_delay() {
+        let delays = vec![(Some(60), Some(60)); 6];
+        assert_eq!(basis(&delays, 6500), None);
+        let delays = vec![(Some(-180), Some(-180)); 6];
+        assert_eq!(basis(&delays, 6300), Some((5, -180)));
+    }
+
+    #[test]
+    fn test_block_propagation_basis_prefers_departures() {
+        let mut delays = vec![(Some(300), Some(300)); 6];
+        delays[4] = (Some(300), Some(200));
+        assert_eq!(basis(&delays, 6500), Some((5, 200)));
+        // without a departure delay, the arrival delay is used
+        delays[4] = (Some(300), None);
+        assert_eq!(basis(&delays, 6500), Some((5, 300)));
+        // no updates at all
+        assert_eq!(basis(&Vec::new(), 6500), None);
+    }
+
+    fn get(counter: &AtomicUsize) -> usize {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// A loop trip which serves stop "a" twice: a, b, a, c with stop_sequences 10, 20, 30, 40.
+    fn loop_trip() -> ScheduleTrip {
+        ScheduleTrip {
+            id: String::from("loop"),
+            stop_times: ["a", "b", "a", "c"].iter().enumerate().map(|(i, stop_id)| StopTime {
+                stop_sequence: 10 * (i as u16 + 1),
+                stop: Arc::new(Stop { id: stop_id.to_string(), ..Default::default() }),
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn stop_update(stop_sequence: Option<u32>, stop_id: Option<&str>) -> StopTimeUpdate {
+        StopTimeUpdate {
+            stop_sequence,
+            stop_id: stop_id.map(|id| id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve_stop_in_loop_trip() {
+        let statistics = ResolutionStatistics::new();
+        let trip = loop_trip();
+        let mut last_stop_index = None;
+        let mut resolve = |stop_id| PerScheduleImporter::resolve_stop(&statistics, &stop_update(None, Some(stop_id)), &trip, &mut last_stop_index).ok();
+
+        // each update refers to the next occurrence of its stop after the previous update
+        assert_eq!(resolve("a"), Some((String::from("a"), 10)));
+        assert_eq!(resolve("b"), Some((String::from("b"), 20)));
+        assert_eq!(resolve("a"), Some((String::from("a"), 30)));
+        // there is no third occurrence of "a"
+        assert_eq!(resolve("a"), None);
+        assert_eq!(resolve("c"), Some((String::from("c"), 40)));
+        assert_eq!(get(&statistics.resolved_stop_sequences), 4);
+    }
+
+    #[test]
+    fn test_resolve_stop_after_given_stop_sequence() {
+        let statistics = ResolutionStatistics::new();
+        let trip = loop_trip();
+
+        // an update with both values sets the position within the trip, even if it skips stops
+        let mut last_stop_index = None;
+        assert_eq!(PerScheduleImporter::resolve_stop(&statistics, &stop_update(Some(20), Some("b")), &trip, &mut last_stop_index).ok(), Some((String::from("b"), 20)));
+        assert_eq!(last_stop_index, Some(1));
+        assert_eq!(PerScheduleImporter::resolve_stop(&statistics, &stop_update(None, Some("a")), &trip, &mut last_stop_index).ok(), Some((String::from("a"), 30)));
+
+        // a missing stop_id is taken from the schedule
+        let mut last_stop_index = None;
+        assert_eq!(PerScheduleImporter::resolve_stop(&statistics, &stop_update(Some(30), None), &trip, &mut last_stop_index).ok(), Some((String::from("a"), 30)));
+        assert_eq!(last_stop_index, Some(2));
+        assert_eq!(get(&statistics.resolved_stop_ids), 1);
+
+        // unknown stop_sequences are counted as mismatches, and can only be used together with a stop_id
+        let mut last_stop_index = None;
+        assert_eq!(PerScheduleImporter::resolve_stop(&statistics, &stop_update(Some(25), Some("b")), &trip, &mut last_stop_index).ok(), Some((String::from("b"), 25)));
+        assert_eq!(last_stop_index, None);
+        assert!(PerScheduleImporter::resolve_stop(&statistics, &stop_update(Some(25), None), &trip, &mut last_stop_index).is_err());
+        assert!(PerScheduleImporter::resolve_stop(&statistics, &stop_update(None, None), &trip, &mut last_stop_index).is_err());
+        assert_eq!(get(&statistics.stop_sequence_mismatches), 2);
+        assert_eq!(get(&statistics.resolved_stop_sequences), 1);
+    }
+
+    /// A schedule with one trip on route "route", which starts at 25:00 and runs on June 8th and 9th.
+    fn night_schedule() -> Gtfs {
+        let mut schedule = Gtfs::default();
+        let trip = ScheduleTrip {
+            id: String::from("night"),
+            route_id: String::from("route"),
+            service_id: String::from("service"),
+            stop_times: vec![StopTime { stop_sequence: 1, departure_time: Some(90000), ..Default::default() }],
+            ..Default::default()
+        };
+        schedule.trips.insert(trip.id.clone(), trip);
+        schedule.calendar_dates.insert(String::from("service"), [8, 9].iter().map(|day| CalendarDate {
+            service_id: String::from("service"),
+            date: NaiveDate::from_ymd(2020, 6, *day),
+            exception_type: Exception::Added,
+        }).collect());
+        schedule
+    }
+
+    fn recorded_at(day: u32, hour: u32, minute: u32) -> u64 {
+        Local.ymd(2020, 6, day).and_hms(hour, minute, 0).timestamp() as u64
+    }
+
+    #[test]
+    fn test_resolve_trip_without_start_and_route() {
+        let schedule = night_schedule();
+        let statistics = ResolutionStatistics::new();
+        let schedule_trip = schedule.get_trip("night").unwrap();
+        let realtime_trip = TripDescriptor { trip_id: Some(String::from("night")), ..Default::default() };
+
+        assert_eq!(PerScheduleImporter::resolve_route_id(&statistics, &realtime_trip, schedule_trip), "route");
+
+        // shortly before 1:00 on June 9th, the trip of the service day June 8th is the closest one
+        let start = PerScheduleImporter::resolve_trip_start(&schedule, &statistics, &realtime_trip, schedule_trip, recorded_at(9, 0, 50)).unwrap();
+        assert_eq!(start.service_day(), Local.ymd(2020, 6, 8));
+        assert_eq!(start.date_time(), Local.ymd(2020, 6, 9).and_hms(1, 0, 0));
+
+        // in the evening of June 9th, it's the trip of the service day June 9th
+        let start = PerScheduleImporter::resolve_trip_start(&schedule, &statistics, &realtime_trip, schedule_trip, recorded_at(9, 23, 0)).unwrap();
+        assert_eq!(start.service_day(), Local.ymd(2020, 6, 9));
+
+        // the trip doesn't run near June 20th
+        assert!(PerScheduleImporter::resolve_trip_start(&schedule, &statistics, &realtime_trip, schedule_trip, recorded_at(20, 12, 0)).is_err());
+
+        assert_eq!(get(&statistics.resolved_route_ids), 1);
+        assert_eq!(get(&statistics.resolved_start_dates), 2);
+        assert_eq!(get(&statistics.resolved_start_times), 3);
+    }
+
+    #[test]
+    fn test_resolve_trip_with_start_and_route() {
+        let schedule = night_schedule();
+        let statistics = ResolutionStatistics::new();
+        let schedule_trip = schedule.get_trip("night").unwrap();
+        let realtime_trip = TripDescriptor {
+            trip_id: Some(String::from("night")),
+            route_id: Some(String::from("other_route")),
+            start_date: Some(String::from("20200609")),
+            start_time: Some(String::from("25:05:00")),
+            ..Default::default()
+        };
+
+        assert_eq!(PerScheduleImporter::resolve_route_id(&statistics, &realtime_trip, schedule_trip), "other_route");
+        // the given values are used even if they don't match the schedule or the time of recording
+        let start = PerScheduleImporter::resolve_trip_start(&schedule, &statistics, &realtime_trip, schedule_trip, recorded_at(20, 12, 0)).unwrap();
+        assert_eq!(start.date_time(), Local.ymd(2020, 6, 10).and_hms(1, 5, 0));
+
+        assert_eq!(get(&statistics.resolved_route_ids), 0);
+        assert_eq!(get(&statistics.resolved_start_dates), 0);
+        assert_eq!(get(&statistics.resolved_start_times), 0);
+    }
+}
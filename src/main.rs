@@ -2,6 +2,8 @@ mod importer;
 mod analyser;
 mod predictor;
 mod types;
+mod schedule_cache;
+mod validator;
 
 #[cfg(feature = "monitor")]
 mod monitor;
@@ -27,12 +29,14 @@ use std::time::{Instant};
 use importer::Importer;
 use analyser::Analyser;
 use predictor::Predictor;
+use validator::Validator;
 
 #[cfg(feature = "monitor")]
 use monitor::Monitor;
 
 use gtfs_structures::Gtfs;
-use types::DelayStatistics;
+use types::{DelayStatistics, HolidayCalendar};
+use schedule_cache::ScheduleCache;
 
 use std::fmt::Debug;
 
@@ -47,7 +51,7 @@ pub struct Main {
     source: String,
     dir: String,
     //file caches using Mutexes so main doesn't have to be mutable:
-    gtfs_cache: Mutex<FileCache<Gtfs>>,
+    gtfs_cache: Mutex<ScheduleCache>,
     all_statistics_cache: Mutex<FileCache<DelayStatistics>>,
     default_statistics_cache: Mutex<FileCache<DelayStatistics>>,
 }
@@ -126,6 +130,7 @@ fn parse_args() -> ArgMatches {
         .subcommand(Importer::get_subcommand())
         .subcommand(Analyser::get_subcommand())
         .subcommand(Predictor::get_subcommand())            
+        .subcommand(Validator::get_subcommand())
         .arg(Arg::new("verbose")
             .short('v')
             .long("verbose")
@@ -184,6 +189,51 @@ fn parse_args() -> ArgMatches {
             .about("The path of the GTFS schedule that is used to look up any static GTFS data.")
             .takes_value(true)
             .value_name("GTFS_SCHEDULE")
+        ).arg(Arg::new("schedule-cache-entries")
+            .long("schedule-cache-entries")
+            .env("SCHEDULE_CACHE_ENTRIES")
+            .about("Maximum number of GTFS schedules that are kept in memory at the same time.")
+            .takes_value(true)
+            .value_name("COUNT")
+            .default_value("3")
+        ).arg(Arg::new("schedule-cache-memory")
+            .long("schedule-cache-memory")
+            .env("SCHEDULE_CACHE_MEMORY")
+            .about("Approximate memory limit (in megabytes) for the GTFS schedules that are kept in memory.")
+            .long_about(
+                "Approximate memory limit (in megabytes) for the GTFS schedules that are kept in memory. \
+                The memory usage of a schedule is only estimated. The most recently used schedule is always \
+                kept, even if it exceeds the limit on its own."
+            )
+            .takes_value(true)
+            .value_name("MEGABYTES")
+            .default_value("4096")
+        ).arg(Arg::new("public-holidays")
+            .long("public-holidays")
+            .env("PUBLIC_HOLIDAYS")
+            .about("ICS or CSV file with the public holidays of the source. Public holidays are treated like sundays.")
+            .takes_value(true)
+            .value_name("FILE")
+        ).arg(Arg::new("school-holidays")
+            .long("school-holidays")
+            .env("SCHOOL_HOLIDAYS")
+            .about("ICS or CSV file with the school holidays of the source. Workdays during school holidays get their own time slot.")
+            .takes_value(true)
+            .value_name("FILE")
+        ).arg(Arg::new("holidays-from-schedule")
+            .long("holidays-from-schedule")
+            .about("Also treat days on which most regular services are removed via the schedule's calendar_dates as public holidays.")
+        ).arg(Arg::new("half-life")
+            .long("half-life")
+            .env("CURVE_HALF_LIFE")
+            .about("Half-life (in days) of the weight of the records when computing curves. By default, all records have the same weight.")
+            .long_about(
+                "Half-life (in days) of the weight of the records when computing curves. \
+                A record that is this many days older than the newest ones counts half as much. \
+                By default, all records have the same weight, no matter how old they are."
+            )
+            .takes_value(true)
+            .value_name("DAYS")
         );
 
         #[cfg(feature = "monitor")]
@@ -207,6 +257,8 @@ impl Main {
         let verbose = args.is_present("verbose");
         let source = String::from(args.value_of("source").unwrap()); // already validated by clap
         let dir = String::from(args.value_of("dir").unwrap()); // already validated by clap
+        let cache_entries: usize = args.value_of("schedule-cache-entries").unwrap().parse()?; // already validated by clap
+        let cache_memory: usize = args.value_of("schedule-cache-memory").unwrap().parse()?; // already validated by clap
 
         if verbose {
             println!("Connecting to database…");
@@ -221,7 +273,7 @@ impl Main {
             pool: Arc::new(pool),
             source,
             dir,
-            gtfs_cache: Mutex::new(FileCache::<Gtfs>::new()),
+            gtfs_cache: Mutex::new(ScheduleCache::new(cache_entries, cache_memory * 1024 * 1024)),
             all_statistics_cache: Mutex::new(FileCache::<DelayStatistics>::new()),
             default_statistics_cache: Mutex::new(FileCache::<DelayStatistics>::new()),
         })
@@ -229,6 +281,7 @@ impl Main {
 
     /// Runs the actions that are selected via the command line args
     fn run(self: Arc<Self>) -> FnResult<()> {
+        self.load_holidays()?;
         match self.args.clone().subcommand() {
             ("import", Some(sub_args)) => {
                 let mut importer = Importer::new(&self, sub_args);
@@ -242,6 +295,10 @@ impl Main {
                 let mut predictor = Predictor::new(&self, sub_args)?;
                 predictor.run()
             },
+            ("schedule", Some(sub_args)) => {
+                let validator = Validator::new(&self, sub_args);
+                validator.run()
+            },
             #[cfg(feature = "monitor")]
             ("monitor", Some(sub_args)) => {
                 Monitor::run(self.clone(), sub_args)
@@ -250,6 +307,28 @@ impl Main {
         }
     }
 
+    /// Sets up the holiday calendar which is used whenever dates are mapped to time slots.
+    fn load_holidays(&self) -> FnResult<()> {
+        let mut calendar = HolidayCalendar::new();
+        if let Some(filename) = self.args.value_of("public-holidays") {
+            calendar.add_public_holidays_from_file(filename)?;
+        }
+        if let Some(filename) = self.args.value_of("school-holidays") {
+            calendar.add_school_holidays_from_file(filename)?;
+        }
+        if self.args.is_present("holidays-from-schedule") {
+            let count = calendar.add_public_holidays_from_schedule(&self.get_schedule()?);
+            if self.verbose {
+                println!("Found {} additional public holidays in the schedule's calendar_dates.", count);
+            }
+        }
+        if self.verbose {
+            println!("Using {} public holidays and {} school holiday dates.", calendar.public_holiday_count(), calendar.school_holiday_count());
+        }
+        HolidayCalendar::set_global(calendar)?;
+        Ok(())
+    }
+
     /// Opens a connection to a database and returns the resulting connection pool.
     /// Takes configuration values from DB_PASSWORD, DB_USER, DB_HOST, DB_PORT and DB_DATABASE
     /// environment variables. For all values except DB_PASSWORD a default is provided.
@@ -269,10 +348,39 @@ impl Main {
         Ok(pool)
     }
 
+    /// Returns the half-life (in days) for the recency weighting of the records, if set with `--half-life`.
+    pub fn get_half_life(&self) -> FnResult<Option<f64>> {
+        match self.args.value_of("half-life") {
+            Some(half_life) => {
+                let half_life : f64 = half_life.parse()?;
+                if half_life.is_nan() || half_life <= 0.0 {
+                    bail!("The half-life must be a positive number of days.");
+                }
+                Ok(Some(half_life))
+            },
+            None => Ok(None)
+        }
+    }
+
     // returns the schedule (from args or auto-lookup)
     pub fn get_schedule(&self) -> FnResult<Arc<Gtfs>> {
         let filename = self.get_schedule_filename()?;
-        FileCache::get_cached_simple(&self.gtfs_cache, &filename)
+        ScheduleCache::get_cached_simple(&self.gtfs_cache, &filename)
+    }
+
+    // returns the schedule which is valid on the given service date (from args or auto-lookup)
+    pub fn get_schedule_for_date(&self, date: NaiveDate) -> FnResult<Arc<Gtfs>> {
+        let filename = self.get_schedule_filename_for_date(date)?;
+        ScheduleCache::get_cached_simple(&self.gtfs_cache, &filename)
+    }
+
+    pub fn get_schedule_filename_for_date(&self, date: NaiveDate) -> FnResult<String> {
+        // a schedule given as arg is used for all dates:
+        if let Some(filename) = self.args.value_of("schedule") {
+            return Ok(filename.to_string());
+        }
+        let schedule_dir = format!("{}/schedule", self.dir);
+        ScheduleCache::filename_for_date_simple(&self.gtfs_cache, &schedule_dir, date)
     }
 
     pub fn get_schedule_filename(&self) -> FnResult<String> {
@@ -302,6 +410,7 @@ impl Main {
                 let merged_statistics = DelayStatistics {
                     specific: all_statistics.as_ref().specific.clone(),
                     general: default_statistics.as_ref().general.clone(),
+                    segments: all_statistics.as_ref().segments.clone(),
                 };
                 println!("Using merged delay statistics.");
                 return Ok(Arc::new(merged_statistics));
@@ -184,6 +184,8 @@ impl JourneyData {
         let mut journey_iter = journey.iter();
         let timestring = journey_iter.next().unwrap(); 
         self.start_date_time = Local.datetime_from_str(timestring, "%d.%m.%y %H:%M")?;
+        // use the schedule that is valid at the start of the journey:
+        self.schedule = self.monitor.main.get_schedule_for_date(self.start_date_time.naive_local().date())?;
 
         let mut prev_component: Option<JourneyComponent> = None;
         let mut expect_stop = true;
@@ -1,7 +1,7 @@
 mod journey_data;
 mod time_curve;
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use crate::{FnResult, Main, date_and_time_local, OrError};
 use chrono::{Date, DateTime, Local, Duration, Timelike};
@@ -424,7 +424,7 @@ fn generate_error_page(code: StatusCode, message: &str) -> FnResult<Response<Bod
 }
 
 fn generate_stop_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, stop_data: &StopData) -> FnResult<Response<Body>> {
-    let schedule = monitor.main.get_schedule()?;
+    let schedule = journey_data.schedule.clone();
 
     let mut response = Response::new(Body::empty());
     let mut departures : Vec<DbPrediction> = Vec::new();
@@ -688,7 +688,7 @@ fn generate_breadcrumbs(mut w: &mut Vec<u8>, journey_data: &JourneyData) -> FnRe
 }
 
 fn generate_trip_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, trip_data: &TripData) -> FnResult<Response<Body>> {
-    let schedule = monitor.main.get_schedule()?;
+    let schedule = journey_data.schedule.clone();
 
     let mut response = Response::new(Body::empty());
     let trip = schedule.get_trip(&trip_data.vehicle_id.trip_id)?;
@@ -1037,6 +1037,7 @@ fn get_source_area(db_prediction: Option<&DbPrediction>) -> String {
             (OriginType::Realtime, PrecisionType::Specific) => ("E","Aktuelle Echtzeitdaten"),
             (OriginType::Realtime, PrecisionType::FallbackSpecific) => ("E","Aktuelle Echtzeitdaten"),
             (OriginType::Realtime, _) => ("U","Ungenutzte Echtzeitdaten"),
+            (OriginType::Block, _)    => ("V","Echtzeitdaten der vorherigen Fahrt dieses Fahrzeugs"),
             (OriginType::Schedule, _) => ("P","Fahrplandaten"),
             (OriginType::Unknown, _)  => ("?","Unbekannte Datenquelle")
         };
@@ -1045,6 +1046,7 @@ fn get_source_area(db_prediction: Option<&DbPrediction>) -> String {
             PrecisionType::Specific           => ("S+", "Spezifische Prognose für diese Linie, Haltestelle und Tageszeit"),
             PrecisionType::FallbackSpecific   => ("S" , "Spezifische Prognose für diese Linie und Haltestelle"),
             PrecisionType::SemiSpecific       => ("S-", "Spezifische Prognose für diese Linie und Haltestelle, jedoch ohne Echtzeitdaten zu nutzen"),
+            PrecisionType::StopSpecific       => ("H" , "Prognose für diese Haltestelle und Tageszeit, aus den Daten aller Linien"),
             PrecisionType::General            => ("G+", "Generelle Prognose für Fahrzeugart, Tageszeit und Routenabschnitt"),
             PrecisionType::FallbackGeneral    => ("G" , "Generelle Prognose für Fahrzeugart"),
             PrecisionType::SuperGeneral       => ("G-", "Standardprognose, sehr ungenau"),
@@ -1057,6 +1059,7 @@ fn get_source_area(db_prediction: Option<&DbPrediction>) -> String {
             (_,"S+") => "b",
             (_,"S") => "b",
             (_,"S-") => "b",
+            (_,"H") => "c",
             (_,"G+") => "c",
             (_,"G") => "d",
             (_,"G-") => "d",
@@ -1262,7 +1265,7 @@ fn generate_png_data_url(time_curve: &TimeCurve, min_time: DateTime<Local>, max_
 }
 
 fn generate_info_page(monitor: &Arc<Monitor>, journey: &JourneyData) -> FnResult<Response<Body>> {
-    let schedule = monitor.main.get_schedule()?;
+    let schedule = journey.schedule.clone();
 
     let mut response = Response::new(Body::empty());
     println!("generate_info_page");
@@ -1304,6 +1307,10 @@ fn generate_info_page(monitor: &Arc<Monitor>, journey: &JourneyData) -> FnResult
                         let curve_set_keys = route_variant_data.curve_sets[**et].keys();
                         let general_keys = route_variant_data.general_delay[**et].keys();
                         writeln!(&mut w, "            <h3>Daten ({:?}) für die Linien-Variante: {} Curve Sets, {} General Curves</h3>", **et, curve_set_keys.len(), general_keys.len())?;
+                        let smoothing_methods : BTreeSet<String> = route_variant_data.general_delay[**et].values().map(|cd| format!("{:?}", cd.smoothing))
+                            .chain(route_variant_data.curve_sets[**et].values().map(|csd| format!("{:?}", csd.smoothing)))
+                            .collect();
+                        writeln!(&mut w, "            <p>Glättung der Kurven: {}</p>", smoothing_methods.into_iter().collect::<Vec<_>>().join(", "))?;
                         for ts in TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter() {
                             
 
@@ -1,4 +1,4 @@
-use crate::types::{EventType, TimeSlot, RouteSection, PredictionResult, DelayStatistics};
+use crate::types::{EventType, TimeSlot, RouteSection, PredictionResult, DelayStatistics, ForecastAccuracy};
 
 use chrono::{DateTime, Local, NaiveDateTime};
 use chrono::offset::TimeZone;
@@ -6,13 +6,17 @@ use clap::{App, Arg, ArgMatches};
 use gtfs_structures::{Gtfs, Trip};
 use std::str::FromStr;
 
+use dystonse_curves::irregular_dynamic::*;
+use dystonse_curves::Curve;
+
 use simple_error::bail;
 
 use crate::{Main, FnResult, OrError};
+use crate::analyser::curve_utils::mix_curves;
 
 use std::sync::Arc;
 
-use crate::types::{PredictionBasis, DefaultCurveKey, PrecisionType, CurveData, CurveSetKey};
+use crate::types::{PredictionBasis, DefaultCurveKey, StopCurveKey, PrecisionType, CurveData, CurveSetKey, StationGrouping};
 
 mod real_time;
 
@@ -22,8 +26,15 @@ pub struct Predictor<'a> {
     pub args: &'a ArgMatches,
     pub schedule: Arc<Gtfs>,
     pub delay_statistics: Arc<DelayStatistics>,
+    /// if set, predictions can be blended with the provider's own prognoses (see `blend_with_provider`)
+    pub forecast_accuracy: Option<ForecastAccuracy>,
+    /// the grouping of stops into stations with which the stop curves were built
+    stations: StationGrouping,
 }
 
+/// Minimum number of evaluated prognoses for a lead time bucket, before the provider's prognoses are blended in.
+const MIN_PROGNOSES_FOR_BLEND: usize = 50;
+
 impl<'a> Predictor<'a> {
     pub fn get_subcommand() -> App<'a> {
         App::new("predict").about("Looks up delay predictions from the statistics for a specified event.")
@@ -90,11 +101,21 @@ impl<'a> Predictor<'a> {
     }
 
     pub fn new(main: &'a Main, args: &'a ArgMatches) -> FnResult<Predictor<'a>> {
+        Self::with_schedule(main, args, main.get_schedule()?)
+    }
+
+    /// Creates a predictor which uses the given schedule whenever the schedule 
+    /// for the requested date does not know the trip.
+    pub fn with_schedule(main: &'a Main, args: &'a ArgMatches, schedule: Arc<Gtfs>) -> FnResult<Predictor<'a>> {
+        let delay_statistics = main.get_delay_statistics()?;
+        let stations = StationGrouping::for_stop_curves(&schedule, delay_statistics.general.grouped_stations);
         Ok(Predictor {
             main,
             args,
-            schedule: main.get_schedule()?,
-            delay_statistics: main.get_delay_statistics()?,
+            schedule,
+            delay_statistics,
+            forecast_accuracy: None,
+            stations,
         })
     }
 
@@ -185,7 +206,8 @@ impl<'a> Predictor<'a> {
 
         // parse lookup parameters from input
         let ts = TimeSlot::from_datetime(date_time);
-        let trip = self.schedule.get_trip(trip_id)?;
+        let schedule = self.get_schedule_for_trip(trip_id, date_time);
+        let trip = schedule.get_trip(trip_id)?;
        
         let route_variant : u64 = u64::from_str(trip.route_variant.as_ref().unwrap()).unwrap(); 
         // should never panic because we already checked the validity of 
@@ -212,14 +234,16 @@ impl<'a> Predictor<'a> {
         //     }
         // }
 
-        // unwrap that, or try a default prediction if it failed:
+        // unwrap that, or try a stop prediction or a default prediction if it failed:
         specific_prediction.or_else(|_| {
+            self.predict_stop(trip, stop_sequence, ts, et)
+        }).or_else(|_| {
             // eprintln!("⚠️ No specific_prediction because: {}", e);
 
             // prepare some more lookup parameters
             let key = DefaultCurveKey {
-                route_type: self.schedule.get_route(route_id)?.route_type,
-                route_section: RouteSection::get_route_section_by_stop_sequence(&self.schedule, trip_id, stop_sequence)?,
+                route_type: schedule.get_route(route_id)?.route_type,
+                route_section: RouteSection::get_route_section_by_stop_sequence(&schedule, trip_id, stop_sequence)?,
                 time_slot: ts.clone(),
                 event_type: et
             };
@@ -238,6 +262,81 @@ impl<'a> Predictor<'a> {
         })
     }
 
+    /// Predicts the departure delay at the first stop of a trip of the given route, if the vehicle
+    /// arrives with `previous_delay` at the last stop of the previous trip of its block.
+    pub fn predict_layover(&self, route_id: &str, previous_delay: f32, date_time: DateTime<Local>) -> FnResult<CurveData> {
+        let ts = TimeSlot::from_datetime(date_time);
+        let layover_recovery = &self.delay_statistics.specific.get(route_id).or_error("No specific statistics for route_id")?.layover_recovery;
+        let (curve_set_data, precision_type) = match layover_recovery.get(ts) {
+            Some(data) => (data, PrecisionType::Specific),
+            None => (layover_recovery.get(&TimeSlot::DEFAULT).or_error("No layover recovery curves for route_id")?, PrecisionType::FallbackSpecific)
+        };
+        if curve_set_data.curve_set.curves.is_empty() {
+            bail!("Found layover recovery curve set, but it was empty.");
+        }
+        Ok(CurveData {
+            curve: curve_set_data.curve_set.curve_at_x_with_continuation(previous_delay),
+            precision_type,
+            sample_size: curve_set_data.sample_size,
+            smoothing: curve_set_data.smoothing
+        })
+    }
+
+    /// Mixes a predicted curve with the provider's own prognosis for the event, which was made `lead_time`
+    /// seconds before it. The prognosis is turned into a distribution with the errors that the provider's
+    /// prognoses had at similar lead times, and both distributions are weighted by the inverse of their
+    /// squared mean absolute errors. Returns None if the accuracy of the provider is not known well enough.
+    pub fn blend_with_provider(&self, curve_data: &CurveData, et: EventType, prognosis: f32, lead_time: i64) -> Option<CurveData> {
+        let row = self.forecast_accuracy.as_ref()?.get_row(et, lead_time)?;
+        if row.count < MIN_PROGNOSES_FOR_BLEND {
+            return None;
+        }
+        let curve_error = row.curve_mean_absolute_error?;
+        let provider_error = row.provider_mean_absolute_error;
+        if curve_error + provider_error <= 0.0 {
+            return None;
+        }
+        let provider_weight = curve_error.powi(2) / (curve_error.powi(2) + provider_error.powi(2));
+
+        let (xs, ys) = row.provider_errors.as_ref()?.get_values_as_vectors();
+        let provider_curve = IrregularDynamicCurve::new(xs.into_iter().zip(ys).map(|(x, y)| Tup { x: x + prognosis, y }).collect());
+        Some(CurveData {
+            curve: mix_curves(&provider_curve, &curve_data.curve, provider_weight),
+            precision_type: curve_data.precision_type.clone(),
+            sample_size: curve_data.sample_size,
+            smoothing: curve_data.smoothing
+        })
+    }
+
+    /// Returns the schedule that the predictor was created with, if it contains the trip.
+    /// Otherwise, falls back to the schedule that is valid on the date of `date_time`.
+    fn get_schedule_for_trip(&self, trip_id: &str, date_time: DateTime<Local>) -> Arc<Gtfs> {
+        if self.schedule.trips.contains_key(trip_id) {
+            return self.schedule.clone();
+        }
+        match self.main.get_schedule_for_date(date_time.naive_local().date()) {
+            Ok(schedule) if schedule.trips.contains_key(trip_id) => schedule,
+            _ => self.schedule.clone(),
+        }
+    }
+
+    // looks up a curve from the stop curves of the target stop's station and returns it
+    fn predict_stop(&self, trip: &Trip, stop_sequence: u16, ts: &TimeSlot, et: EventType) -> FnResult<PredictionResult> {
+        let stop = &trip.get_stop_time_by_sequence(stop_sequence)?.stop;
+        let stop_id = self.stations.station_id(&stop.id);
+        for time_slot in &[ts, &TimeSlot::DEFAULT] {
+            let key = StopCurveKey {
+                stop_id: stop_id.to_string(),
+                time_slot: (*time_slot).clone(),
+                event_type: et
+            };
+            if let Some(curve_data) = self.delay_statistics.general.stop_curves.get(&key) {
+                return Ok(PredictionResult::CurveData(curve_data.clone()));
+            }
+        }
+        bail!("No stop curve.");
+    }
+
     // looks up a curve from default curves and returns it
     fn predict_default(&self, key: &DefaultCurveKey) // rt: RouteType, rs: RouteSection, ts: &TimeSlot, et: EventType) 
             -> FnResult<PredictionResult> {
@@ -325,7 +424,8 @@ impl<'a> Predictor<'a> {
                         let curve_data = CurveData {
                             curve,
                             precision_type: if *ts == TimeSlot::DEFAULT { PrecisionType::FallbackSpecific } else { PrecisionType::Specific },
-                            sample_size: curve_set_data.sample_size
+                            sample_size: curve_set_data.sample_size,
+                            smoothing: curve_set_data.smoothing
                         };
                         return Ok(PredictionResult::CurveData(curve_data));
                     }
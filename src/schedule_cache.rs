@@ -0,0 +1,202 @@
+use chrono::NaiveDate;
+use gtfs_structures::Gtfs;
+use simple_error::bail;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{FnResult, OrError, Loadable, read_dir_simple, date_from_filename};
+
+/// How long the list of schedule files and the modification times of cached schedules
+/// are trusted before the file system is checked again.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A bounded cache for multiple GTFS schedules.
+///
+/// Entries are evicted in least-recently-used order as soon as either the number of
+/// entries or the estimated memory usage of all entries exceeds its limit. The most
+/// recently used schedule is never evicted, even if it exceeds the memory limit by itself.
+///
+/// The cache also keeps an index of the schedule files in a directory, so that the
+/// schedule which is valid on a given service date can be looked up.
+pub struct ScheduleCache {
+    /// cached schedules, ordered from least recently used to most recently used
+    entries: Vec<ScheduleCacheEntry>,
+    max_entries: usize,
+    max_memory: usize,
+    /// schedule file names with the date from which they are valid, sorted by date
+    index: Vec<(NaiveDate, String)>,
+    index_dir: Option<String>,
+    index_time: Option<Instant>,
+}
+
+struct ScheduleCacheEntry {
+    filename: String,
+    modification_time: SystemTime,
+    checked_time: Instant,
+    estimated_size: usize,
+    schedule: Arc<Gtfs>,
+}
+
+impl ScheduleCache {
+    /// Creates a new, empty schedule cache. `max_memory` is given in bytes.
+    pub fn new(max_entries: usize, max_memory: usize) -> ScheduleCache {
+        ScheduleCache {
+            entries: Vec::new(),
+            max_entries: max_entries.max(1),
+            max_memory,
+            index: Vec::new(),
+            index_dir: None,
+            index_time: None,
+        }
+    }
+
+    /// Returns the cached schedule, loading it if it is not cached yet or if the file changed.
+    /// The mutex is not held while the schedule is loaded, so that other threads can still use
+    /// the cached schedules in the meantime.
+    pub fn get_cached_simple(cache: &Mutex<Self>, filename: &str) -> FnResult<Arc<Gtfs>> {
+        if let Some(schedule) = cache.lock().unwrap().lookup(filename)? {
+            return Ok(schedule);
+        }
+
+        let (schedule, mod_time) = Self::load(filename)?;
+
+        let mut cache_lock = cache.lock().unwrap();
+        // another thread might have loaded the same schedule in the meantime
+        if let Some(schedule) = cache_lock.lookup(filename)? {
+            return Ok(schedule);
+        }
+        cache_lock.insert(filename, schedule.clone(), mod_time);
+        Ok(schedule)
+    }
+
+    // wrapper around filename_for_date so the mutex stuff does not have to be repeated
+    pub fn filename_for_date_simple(cache: &Mutex<Self>, schedule_dir: &str, date: NaiveDate) -> FnResult<String> {
+        let mut cache_lock = cache.lock().unwrap();
+        cache_lock.filename_for_date(schedule_dir, date)
+    }
+
+    /// Returns the cached schedule if it is cached and the file did not change since.
+    fn lookup(&mut self, filename: &str) -> FnResult<Option<Arc<Gtfs>>> {
+        if let Some(pos) = self.entries.iter().position(|e| e.filename == filename) {
+            let mut entry = self.entries.remove(pos);
+            let still_valid = if entry.checked_time.elapsed() < RECHECK_INTERVAL {
+                true
+            } else {
+                let mod_time = fs::metadata(filename)?.modified()?;
+                entry.checked_time = Instant::now();
+                mod_time == entry.modification_time
+            };
+            if still_valid {
+                let schedule = entry.schedule.clone();
+                self.entries.push(entry);
+                return Ok(Some(schedule));
+            }
+            // the file changed, so drop the outdated entry so that it is loaded again
+        }
+        Ok(None)
+    }
+
+    fn load(filename: &str) -> FnResult<(Arc<Gtfs>, SystemTime)> {
+        let mod_time = fs::metadata(filename)?.modified()?;
+        println!("Loading {}...", filename);
+        let now = Instant::now();
+        let schedule = Arc::new(Gtfs::load(filename)?);
+        println!("...loading {} took {} seconds.", filename, now.elapsed().as_secs());
+        Ok((schedule, mod_time))
+    }
+
+    fn insert(&mut self, filename: &str, schedule: Arc<Gtfs>, mod_time: SystemTime) {
+        self.entries.push(ScheduleCacheEntry {
+            filename: filename.to_string(),
+            modification_time: mod_time,
+            checked_time: Instant::now(),
+            estimated_size: estimate_schedule_size(&schedule),
+            schedule,
+        });
+        self.evict();
+    }
+
+    /// Returns the file name of the schedule which is valid on the given service date.
+    ///
+    /// Schedule file names are expected to contain the date from which they are valid.
+    /// A schedule is considered valid until the date of the next schedule file. If the
+    /// date is before the first known schedule, the oldest schedule is used.
+    pub fn filename_for_date(&mut self, schedule_dir: &str, date: NaiveDate) -> FnResult<String> {
+        self.refresh_index(schedule_dir)?;
+        let filename = match self.index.iter().rev().find(|(valid_from, _)| *valid_from <= date) {
+            Some((_, filename)) => filename,
+            None => &self.index.first().or_error("No schedule found in schedule directory.")?.1,
+        };
+        Ok(filename.clone())
+    }
+
+    /// Sum of the estimated sizes of all cached schedules, in bytes.
+    pub fn estimated_memory(&self) -> usize {
+        self.entries.iter().map(|e| e.estimated_size).sum()
+    }
+
+    fn refresh_index(&mut self, schedule_dir: &str) -> FnResult<()> {
+        let is_fresh = match (&self.index_dir, self.index_time) {
+            (Some(dir), Some(time)) => dir == schedule_dir && time.elapsed() < RECHECK_INTERVAL,
+            _ => false,
+        };
+        if is_fresh {
+            return Ok(());
+        }
+
+        let mut index = Vec::new();
+        for filename in read_dir_simple(schedule_dir)? {
+            match date_from_filename(&filename) {
+                Ok(date) => index.push((date.naive_local(), filename)),
+                Err(_) => eprintln!("Ignoring schedule file without date in its name: {}", filename),
+            }
+        }
+        if index.is_empty() {
+            bail!("No schedule with a date in its name found in {}.", schedule_dir);
+        }
+        // file names are already sorted, but they might have a prefix before the date:
+        index.sort();
+
+        self.index = index;
+        self.index_dir = Some(schedule_dir.to_string());
+        self.index_time = Some(Instant::now());
+        Ok(())
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > 1
+            && (self.entries.len() > self.max_entries || self.estimated_memory() > self.max_memory)
+        {
+            let entry = self.entries.remove(0);
+            println!(
+                "Evicting schedule {} (about {} MB) from cache.",
+                entry.filename,
+                entry.estimated_size / (1024 * 1024)
+            );
+        }
+    }
+}
+
+/// Gives a rough estimate of the heap memory used by a parsed schedule, in bytes.
+/// Only the large collections are taken into account.
+fn estimate_schedule_size(schedule: &Gtfs) -> usize {
+    const BYTES_PER_STOP_TIME: usize = 64;
+    const BYTES_PER_TRIP: usize = 256;
+    const BYTES_PER_STOP: usize = 256;
+    const BYTES_PER_ROUTE: usize = 256;
+    const BYTES_PER_SHAPE_POINT: usize = 64;
+    const BYTES_PER_CALENDAR_ENTRY: usize = 64;
+
+    let stop_times: usize = schedule.trips.values().map(|t| t.stop_times.len()).sum();
+    let shape_points: usize = schedule.shapes.values().map(|s| s.len()).sum();
+    let calendar_entries: usize =
+        schedule.calendar.len() + schedule.calendar_dates.values().map(|d| d.len()).sum::<usize>();
+
+    stop_times * BYTES_PER_STOP_TIME
+        + schedule.trips.len() * BYTES_PER_TRIP
+        + schedule.stops.len() * BYTES_PER_STOP
+        + schedule.routes.len() * BYTES_PER_ROUTE
+        + shape_points * BYTES_PER_SHAPE_POINT
+        + calendar_entries * BYTES_PER_CALENDAR_ENTRY
+}
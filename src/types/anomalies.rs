@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+
+use chrono::NaiveDate;
+use serde::{Serialize, Deserialize};
+
+use dystonse_curves::tree::{SerdeFormat, NodeData};
+
+use crate::FnResult;
+use super::DbItem;
+
+/// A vehicle (i.e. a trip on a certain day) with unusual delays.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FlaggedVehicle {
+    pub trip_id: String,
+    pub start_date: NaiveDate,
+    /// seconds since midnight of `start_date`
+    pub start_time: i64,
+}
+
+/// All vehicles of a route within one time slot on one day, whose delays deviate
+/// significantly from the days before.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Anomaly {
+    pub route_id: String,
+    pub route_short_name: String,
+    pub date: NaiveDate,
+    pub time_slot: String,
+    /// median over the vehicles of their median delay, in seconds
+    pub median_delay: f32,
+    /// 90th percentile over the vehicles of their median delay, in seconds
+    pub p90_delay: f32,
+    /// median and median absolute deviation of `median_delay` on the days of the history
+    pub history_median: f32,
+    pub history_mad: f32,
+    /// number of days within the history that had enough vehicles
+    pub history_day_count: usize,
+    /// modified z-score, i.e. the deviation from `history_median` in units of the standard
+    /// deviation, estimated robustly from `history_mad`
+    pub score: f32,
+    pub vehicles: Vec<FlaggedVehicle>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnomalyReport {
+    pub source: String,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub history_days: i64,
+    pub threshold: f32,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl AnomalyReport {
+    pub const NAME : &'static str = "anomalies";
+}
+
+/// Vehicles whose records shall be left out when curves are computed.
+pub struct AnomalyExclusions {
+    vehicles: HashSet<(String, NaiveDate, i64)>,
+}
+
+impl AnomalyExclusions {
+    pub fn none() -> Self {
+        AnomalyExclusions {
+            vehicles: HashSet::new(),
+        }
+    }
+
+    /// Reads the flagged vehicles from the anomaly report in `dir`.
+    pub fn load(dir: &str) -> FnResult<Self> {
+        let report = AnomalyReport::load_from_file(dir, AnomalyReport::NAME, &SerdeFormat::Json)?;
+        let vehicles : HashSet<(String, NaiveDate, i64)> = report.anomalies.iter()
+            .flat_map(|anomaly| anomaly.vehicles.iter())
+            .map(|v| (v.trip_id.clone(), v.start_date, v.start_time))
+            .collect();
+        println!("Leaving out the records of {} vehicles from {} anomalies.", vehicles.len(), report.anomalies.len());
+        Ok(AnomalyExclusions { vehicles })
+    }
+
+    pub fn is_excluded(&self, item: &DbItem) -> bool {
+        if self.vehicles.is_empty() {
+            return false;
+        }
+        match (item.trip_start_date, item.trip_start_time) {
+            (Some(date), Some(time)) => self.vehicles.contains(&(item.trip_id.clone(), date.naive_local(), time.num_seconds())),
+            _ => false
+        }
+    }
+}
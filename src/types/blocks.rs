@@ -0,0 +1,178 @@
+use chrono::NaiveDate;
+use gtfs_structures::{Gtfs, Trip};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Trips that share a `block_id` are run by the same vehicle, one after another.
+/// This index allows to look up the trips before and after a given trip on the same service day.
+pub struct BlockIndex {
+    /// trip ids for each block_id, sorted by the departure time at their first stop
+    trips_by_block: HashMap<String, Vec<String>>,
+    /// the trips of `trips_by_block` that run on a service day, for each block_id and service day that was looked up,
+    /// because finding out on which days a trip runs is much more expensive than looking it up
+    running_trips: Mutex<HashMap<(String, NaiveDate), Arc<Vec<String>>>>,
+}
+
+impl BlockIndex {
+    pub fn new(schedule: &Gtfs) -> Self {
+        let mut trips_by_block: HashMap<String, Vec<(u32, String)>> = HashMap::new();
+        for trip in schedule.trips.values() {
+            if let Some(block_id) = &trip.block_id {
+                if let Some(start_time) = trip.stop_times.first().and_then(|st| st.departure_time) {
+                    trips_by_block.entry(block_id.clone()).or_insert_with(Vec::new).push((start_time, trip.id.clone()));
+                }
+            }
+        }
+
+        BlockIndex {
+            trips_by_block: trips_by_block.into_iter().map(|(block_id, mut trips)| {
+                trips.sort();
+                (block_id, trips.into_iter().map(|(_time, trip_id)| trip_id).collect())
+            }).collect(),
+            running_trips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns all trips of the block of `trip` that run on the given service day, in order of their departure.
+    /// `schedule` must be the schedule from which the index was built.
+    pub fn get_block_trips<'a>(&self, schedule: &'a Gtfs, trip: &Trip, service_day: NaiveDate) -> Vec<&'a Trip> {
+        let block_id = match &trip.block_id {
+            Some(block_id) => block_id,
+            None => return Vec::new(),
+        };
+        let key = (block_id.clone(), service_day);
+        let cached = self.running_trips.lock().unwrap().get(&key).cloned();
+        let trip_ids = match cached {
+            Some(trip_ids) => trip_ids,
+            None => {
+                // computed without holding the lock, so that other threads can look up other blocks meanwhile
+                let trip_ids = match self.trips_by_block.get(block_id) {
+                    Some(trip_ids) => trip_ids,
+                    None => return Vec::new(),
+                };
+                let running : Vec<String> = trip_ids.iter()
+                    .filter(|trip_id| schedule.get_trip(trip_id).map_or(false, |t| schedule.trip_days(&t.service_id, service_day).contains(&0)))
+                    .cloned()
+                    .collect();
+                let running = Arc::new(running);
+                self.running_trips.lock().unwrap().insert(key, running.clone());
+                running
+            }
+        };
+        trip_ids.iter()
+            .filter_map(|trip_id| schedule.get_trip(trip_id).ok())
+            .collect()
+    }
+
+    /// Returns the trip that the vehicle runs before `trip` on the given service day.
+    pub fn get_previous_trip<'a>(&self, schedule: &'a Gtfs, trip: &Trip, service_day: NaiveDate) -> Option<&'a Trip> {
+        let block_trips = self.get_block_trips(schedule, trip, service_day);
+        let position = block_trips.iter().position(|t| t.id == trip.id)?;
+        if position > 0 {
+            Some(block_trips[position - 1])
+        } else {
+            None
+        }
+    }
+
+    /// Returns the trips that the vehicle runs after `trip` on the given service day, in order of their departure.
+    pub fn get_following_trips<'a>(&self, schedule: &'a Gtfs, trip: &Trip, service_day: NaiveDate) -> Vec<&'a Trip> {
+        let block_trips = self.get_block_trips(schedule, trip, service_day);
+        match block_trips.iter().position(|t| t.id == trip.id) {
+            Some(position) => block_trips[position + 1 ..].to_vec(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_structures::{CalendarDate, Exception, StopTime};
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(2020, 6, day)
+    }
+
+    fn trip(id: &str, block_id: Option<&str>, service_id: &str, start_time: u32) -> Trip {
+        Trip {
+            id: id.to_string(),
+            service_id: service_id.to_string(),
+            block_id: block_id.map(|b| b.to_string()),
+            stop_times: vec![StopTime { stop_sequence: 1, departure_time: Some(start_time), ..Default::default() }],
+            ..Default::default()
+        }
+    }
+
+    /// Block "b" has three trips, of which "t2" only runs on June 8th. All other trips run on June 8th and 9th.
+    fn schedule() -> Gtfs {
+        let mut schedule = Gtfs::default();
+        let trips = vec![
+            trip("t3", Some("b"), "daily", 10800),
+            trip("t1", Some("b"), "daily", 3600),
+            trip("t2", Some("b"), "monday", 7200),
+            trip("other_block", Some("c"), "daily", 5400),
+            trip("no_block", None, "daily", 5400),
+        ];
+        for trip in trips {
+            schedule.trips.insert(trip.id.clone(), trip);
+        }
+        for (service_id, days) in &[("daily", vec![8, 9]), ("monday", vec![8])] {
+            schedule.calendar_dates.insert(service_id.to_string(), days.iter().map(|day| CalendarDate {
+                service_id: service_id.to_string(),
+                date: date(*day),
+                exception_type: Exception::Added,
+            }).collect());
+        }
+        schedule
+    }
+
+    fn ids(trips: Vec<&Trip>) -> Vec<&str> {
+        trips.iter().map(|t| t.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_block_trips() {
+        let schedule = schedule();
+        let index = BlockIndex::new(&schedule);
+        let t1 = schedule.get_trip("t1").unwrap();
+        let t2 = schedule.get_trip("t2").unwrap();
+        let t3 = schedule.get_trip("t3").unwrap();
+
+        assert_eq!(ids(index.get_block_trips(&schedule, t3, date(8))), vec!["t1", "t2", "t3"]);
+        assert_eq!(index.get_previous_trip(&schedule, t1, date(8)).map(|t| t.id.as_str()), None);
+        assert_eq!(index.get_previous_trip(&schedule, t3, date(8)).map(|t| t.id.as_str()), Some("t2"));
+        assert_eq!(ids(index.get_following_trips(&schedule, t1, date(8))), vec!["t2", "t3"]);
+        assert_eq!(ids(index.get_following_trips(&schedule, t3, date(8))), Vec::<&str>::new());
+
+        // the second lookup of a block and day comes from the cache
+        assert_eq!(ids(index.get_following_trips(&schedule, t1, date(8))), vec!["t2", "t3"]);
+        assert_eq!(index.running_trips.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_trips_that_do_not_run() {
+        let schedule = schedule();
+        let index = BlockIndex::new(&schedule);
+        let t1 = schedule.get_trip("t1").unwrap();
+        let t2 = schedule.get_trip("t2").unwrap();
+        let t3 = schedule.get_trip("t3").unwrap();
+
+        // t2 doesn't run on the 9th, so t3 follows t1 directly
+        assert_eq!(ids(index.get_block_trips(&schedule, t1, date(9))), vec!["t1", "t3"]);
+        assert_eq!(index.get_previous_trip(&schedule, t3, date(9)).map(|t| t.id.as_str()), Some("t1"));
+        assert_eq!(ids(index.get_following_trips(&schedule, t1, date(9))), vec!["t3"]);
+        assert_eq!(index.get_previous_trip(&schedule, t2, date(9)).map(|t| t.id.as_str()), None);
+        assert_eq!(ids(index.get_following_trips(&schedule, t2, date(9))), Vec::<&str>::new());
+
+        // nothing runs on the 10th
+        assert_eq!(ids(index.get_block_trips(&schedule, t1, date(10))), Vec::<&str>::new());
+
+        // trips without a block, or alone in their block, have neither previous nor following trips
+        for trip_id in &["no_block", "other_block"] {
+            let trip = schedule.get_trip(trip_id).unwrap();
+            assert_eq!(index.get_previous_trip(&schedule, trip, date(8)).map(|t| t.id.as_str()), None);
+            assert_eq!(ids(index.get_following_trips(&schedule, trip, date(8))), Vec::<&str>::new());
+        }
+    }
+}
@@ -4,17 +4,38 @@ use crate::FnResult;
 
 use dystonse_curves::{
     irregular_dynamic::*,
+    tree::{TreeData, SerdeFormat, NodeData},
     CurveSet
 };
 
 use super::PrecisionType;
 
+/// How a curve was made from its samples.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SmoothingMethod {
+    /// the empirical distribution of the samples
+    Empirical,
+    /// kernel density estimation with a gaussian kernel and automatically chosen bandwidth
+    Kde,
+    /// a log-normal distribution that is shifted to start just below the smallest sample
+    LogNormal,
+}
+
+impl Default for SmoothingMethod {
+    // curves that were computed before the smoothing methods existed are empirical
+    fn default() -> Self {
+        SmoothingMethod::Empirical
+    }
+}
+
 // A curve with some metadata about its quality and origin:
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CurveData {
     pub curve: IrregularDynamicCurve<f32, f32>,
     pub precision_type: PrecisionType,
     pub sample_size: u32,
+    #[serde(default)]
+    pub smoothing: SmoothingMethod,
 }
 
 impl CurveData {
@@ -24,6 +45,7 @@ impl CurveData {
         }
 
         let mut sample_size: u32 = 0;
+        let smoothing = data[0].smoothing;
 
         let mut curves : Vec<&IrregularDynamicCurve<f32, f32>> = Vec::with_capacity(data.len());
         for curve_data in data {
@@ -37,7 +59,8 @@ impl CurveData {
         Ok(CurveData {
             curve,
             precision_type,
-            sample_size
+            sample_size,
+            smoothing
         })
     } 
 }
@@ -48,4 +71,54 @@ pub struct CurveSetData {
     pub curve_set: CurveSet<f32, IrregularDynamicCurve<f32,f32>>,
     pub precision_type: PrecisionType,
     pub sample_size: u32,
-}
\ No newline at end of file
+    #[serde(default)]
+    pub smoothing: SmoothingMethod,
+}
+
+/// The metadata of a `CurveSetData`, which is saved next to the curve set
+/// when the curve set is saved as a tree.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct CurveSetMetadata {
+    precision_type: PrecisionType,
+    sample_size: u32,
+    #[serde(default)]
+    smoothing: SmoothingMethod,
+}
+
+impl CurveSetData {
+    pub const NAME : &'static str = "CurveSetData";
+}
+
+impl TreeData for CurveSetData {
+    fn save_tree(&self, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> {
+        if leaves.contains(&Self::NAME) {
+            self.save_to_file(dir_name, own_name, format)?;
+        } else {
+            let sub_dir_name = format!("{}/{}", dir_name, own_name);
+            self.curve_set.save_tree(&sub_dir_name, "curve_set", format, leaves)?;
+            let metadata = CurveSetMetadata {
+                precision_type: self.precision_type.clone(),
+                sample_size: self.sample_size,
+                smoothing: self.smoothing,
+            };
+            metadata.save_to_file(&sub_dir_name, "metadata", format)?;
+        }
+        Ok(())
+    }
+
+    fn load_tree(dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self> {
+        if leaves.contains(&Self::NAME) {
+            Self::load_from_file(dir_name, own_name, format)
+        } else {
+            let sub_dir_name = format!("{}/{}", dir_name, own_name);
+            let curve_set = CurveSet::<f32, IrregularDynamicCurve<f32,f32>>::load_tree(&sub_dir_name, "curve_set", format, leaves)?;
+            let metadata = CurveSetMetadata::load_from_file(&sub_dir_name, "metadata", format)?;
+            Ok(CurveSetData {
+                curve_set,
+                precision_type: metadata.precision_type,
+                sample_size: metadata.sample_size,
+                smoothing: metadata.smoothing,
+            })
+        }
+    }
+}
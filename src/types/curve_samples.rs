@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use chrono::{Local, NaiveDate};
+use serde::{Serialize, Deserialize};
+
+use super::{CurveSetKey, EventPair, EventType, RouteSection, StopCurveKey, TimeSlot};
+
+/// Sums up the weights of the observed values.
+///
+/// Delays are whole seconds (and rounded to multiples of 12 for curve sets), so storing
+/// the weights instead of the individual values is lossless, but much more compact.
+/// Without recency weighting, each observation has the weight 1, so the weights are counts.
+/// Merging two histograms is equivalent to computing one histogram from both data sets.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SampleHistogram<K: Ord> {
+    counts: BTreeMap<K, f64>,
+    /// Sum of the squared weights of all observations, for the effective sample size.
+    /// Histograms that were stored before there were weights don't have it, as all their weights are 1.
+    #[serde(default)]
+    squared_weights: Option<f64>,
+}
+
+pub type DelayHistogram = SampleHistogram<i32>;
+
+/// Pairs of (delay at start, delay at end).
+pub type PairHistogram = SampleHistogram<(i32, i32)>;
+
+impl<K: Ord + Clone> SampleHistogram<K> {
+    pub fn new() -> Self {
+        SampleHistogram { counts: BTreeMap::new(), squared_weights: Some(0.0) }
+    }
+
+    pub fn add_weighted(&mut self, value: K, weight: f64) {
+        self.squared_weights = Some(self.sum_of_squared_weights() + weight * weight);
+        *self.counts.entry(value).or_insert(0.0) += weight;
+    }
+
+    pub fn merge(&mut self, other: Self) {
+        self.squared_weights = Some(self.sum_of_squared_weights() + other.sum_of_squared_weights());
+        for (value, weight) in other.counts {
+            *self.counts.entry(value).or_insert(0.0) += weight;
+        }
+    }
+
+    /// Multiplies all weights with `factor`, e.g. to let the observations age.
+    pub fn decay(&mut self, factor: f64) {
+        self.squared_weights = Some(self.sum_of_squared_weights() * factor * factor);
+        for weight in self.counts.values_mut() {
+            *weight *= factor;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Sum of the weights of all observed values.
+    pub fn total_weight(&self) -> f64 {
+        self.counts.values().sum()
+    }
+
+    fn sum_of_squared_weights(&self) -> f64 {
+        self.squared_weights.unwrap_or_else(|| self.total_weight())
+    }
+
+    /// Kish's effective sample size, which is the number of observations if all weights are equal.
+    pub fn effective_size(&self) -> f64 {
+        let squared_weights = self.sum_of_squared_weights();
+        if squared_weights > 0.0 {
+            self.total_weight().powi(2) / squared_weights
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns all observed values in ascending order, each with the sum of its weights.
+    pub fn weighted_values(&self) -> Vec<(K, f64)> {
+        self.counts.iter().map(|(value, weight)| (value.clone(), *weight)).collect()
+    }
+}
+
+/// Multiplies the weights of all histograms with `factor`.
+pub fn decay_histograms<K, V: Ord + Clone>(histograms: &mut HashMap<K, SampleHistogram<V>>, factor: f64) {
+    for histogram in histograms.values_mut() {
+        histogram.decay(factor);
+    }
+}
+
+/// Merges all histograms of `source` into the histograms with the same key in `target`.
+pub fn merge_histograms<K: Eq + Hash, V: Ord + Clone>(target: &mut HashMap<K, SampleHistogram<V>>, source: HashMap<K, SampleHistogram<V>>) {
+    for (key, histogram) in source {
+        target.entry(key).or_insert_with(SampleHistogram::new).merge(histogram);
+    }
+}
+
+/// Raw data from which the curves of a `RouteVariantData` are computed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteVariantSamples {
+    /// delays at each stop (by stop index), regardless of the time slot
+    pub general_delay: EventPair<HashMap<u32, DelayHistogram>>,
+    pub curve_sets: EventPair<HashMap<CurveSetKey, PairHistogram>>,
+}
+
+impl RouteVariantSamples {
+    pub fn new() -> Self {
+        RouteVariantSamples {
+            general_delay: EventPair { arrival: HashMap::new(), departure: HashMap::new() },
+            curve_sets: EventPair { arrival: HashMap::new(), departure: HashMap::new() },
+        }
+    }
+
+    pub fn merge(&mut self, other: Self) {
+        let RouteVariantSamples { mut general_delay, mut curve_sets } = other;
+        for et in &EventType::TYPES {
+            merge_histograms(&mut self.general_delay[**et], std::mem::take(&mut general_delay[**et]));
+            merge_histograms(&mut self.curve_sets[**et], std::mem::take(&mut curve_sets[**et]));
+        }
+    }
+
+    pub fn decay(&mut self, factor: f64) {
+        for et in &EventType::TYPES {
+            decay_histograms(&mut self.general_delay[**et], factor);
+            decay_histograms(&mut self.curve_sets[**et], factor);
+        }
+    }
+}
+
+/// Raw data from which the curves of a `RouteData` are computed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteSamples {
+    pub variants: HashMap<u64, RouteVariantSamples>,
+    pub layover_recovery: HashMap<TimeSlot, PairHistogram>,
+}
+
+impl RouteSamples {
+    pub fn new() -> Self {
+        RouteSamples {
+            variants: HashMap::new(),
+            layover_recovery: HashMap::new(),
+        }
+    }
+
+    pub fn merge(&mut self, other: Self) {
+        for (route_variant, variant_samples) in other.variants {
+            self.variants.entry(route_variant).or_insert_with(RouteVariantSamples::new).merge(variant_samples);
+        }
+        merge_histograms(&mut self.layover_recovery, other.layover_recovery);
+    }
+
+    pub fn decay(&mut self, factor: f64) {
+        for variant_samples in self.variants.values_mut() {
+            variant_samples.decay(factor);
+        }
+        decay_histograms(&mut self.layover_recovery, factor);
+    }
+}
+
+/// Key for the samples of the default curves.
+///
+/// Default curves are averaged over one curve per route variant, so unlike
+/// `DefaultCurveKey`, the samples are kept separately for each route variant.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct DefaultSampleKey {
+    pub route_id: String,
+    pub route_variant: String,
+    pub route_section: RouteSection,
+    pub time_slot: TimeSlot,
+    pub event_type: EventType
+}
+
+/// Delays at each station, from all routes serving it.
+pub type StopSamples = HashMap<StopCurveKey, DelayHistogram>;
+
+/// Everything that is needed to compute `DelayStatistics` without reading
+/// all records from the database again.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CurveSamples {
+    pub source: String,
+    /// Records with a trip_start_date up to and including this date have been added already.
+    pub high_water_mark: Option<NaiveDate>,
+    pub specific: HashMap<String, RouteSamples>,
+    pub general: HashMap<DefaultSampleKey, DelayHistogram>,
+    #[serde(default)]
+    pub stops: StopSamples,
+    /// half-life (in days) of the recency weighting of the samples, if any
+    #[serde(default)]
+    pub half_life: Option<f64>,
+}
+
+impl CurveSamples {
+    pub fn new(source: &str) -> Self {
+        CurveSamples {
+            source: source.to_string(),
+            high_water_mark: None,
+            specific: HashMap::new(),
+            general: HashMap::new(),
+            stops: HashMap::new(),
+            half_life: None,
+        }
+    }
+
+    pub fn merge_specific(&mut self, specific: HashMap<String, RouteSamples>) {
+        for (route_id, route_samples) in specific {
+            self.specific.entry(route_id).or_insert_with(RouteSamples::new).merge(route_samples);
+        }
+    }
+
+    pub fn merge_general(&mut self, general: HashMap<DefaultSampleKey, DelayHistogram>) {
+        merge_histograms(&mut self.general, general);
+    }
+
+    pub fn merge_stops(&mut self, stops: StopSamples) {
+        merge_histograms(&mut self.stops, stops);
+    }
+
+    /// Adds the samples of the records within `date_range`, which starts at the high water mark. The new samples are
+    /// weighted relative to the end of the date range, so the samples that were added before need to age accordingly.
+    pub fn merge_date_range(&mut self, date_range: &DateRange, specific: HashMap<String, RouteSamples>, general: HashMap<DefaultSampleKey, DelayHistogram>, stops: StopSamples) {
+        if let (Some(after), Some(until)) = (date_range.after, date_range.until) {
+            self.decay((until - after).num_days());
+        }
+        self.merge_specific(specific);
+        self.merge_general(general);
+        self.merge_stops(stops);
+        self.high_water_mark = date_range.until;
+    }
+
+    /// Lets all samples age by the given number of days, according to their half-life.
+    pub fn decay(&mut self, days: i64) {
+        let half_life = match self.half_life {
+            Some(half_life) => half_life,
+            None => return
+        };
+        let factor = 0.5f64.powf(days as f64 / half_life);
+        for route_samples in self.specific.values_mut() {
+            route_samples.decay(factor);
+        }
+        decay_histograms(&mut self.general, factor);
+        decay_histograms(&mut self.stops, factor);
+    }
+}
+
+/// Range of trip_start_dates of the records that shall be read from the database.
+#[derive(Debug, Clone, Copy)]
+pub struct DateRange {
+    /// exclusive lower bound
+    pub after: Option<NaiveDate>,
+    /// inclusive upper bound
+    pub until: Option<NaiveDate>,
+}
+
+impl DateRange {
+    pub const ALL : DateRange = DateRange { after: None, until: None };
+}
+
+/// Exponential time-decay of the weights of the samples (see `--half-life`),
+/// so that older records count less than recent ones.
+#[derive(Debug, Clone, Copy)]
+pub struct RecencyWeighting {
+    /// half-life in days, or None if all samples have the same weight
+    pub half_life: Option<f64>,
+    /// samples from this day get the weight 1
+    pub reference_date: NaiveDate,
+}
+
+impl RecencyWeighting {
+    /// Records up to the end of `date_range` are weighted relative to its end, or to today if it is open.
+    pub fn new(half_life: Option<f64>, date_range: &DateRange) -> Self {
+        RecencyWeighting {
+            half_life,
+            reference_date: date_range.until.unwrap_or_else(|| Local::today().naive_local()),
+        }
+    }
+
+    pub fn weight(&self, date: NaiveDate) -> f64 {
+        match self.half_life {
+            Some(half_life) => 0.5f64.powf((self.reference_date - date).num_days() as f64 / half_life),
+            None => 1.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effective_size() {
+        let mut histogram = DelayHistogram::new();
+        assert_eq!(histogram.effective_size(), 0.0);
+        for delay in &[0, 12, 12, 24, 60] {
+            histogram.add_weighted(*delay, 1.0);
+        }
+        // with equal weights, the effective size is the number of observations, regardless of duplicate values
+        assert_eq!(histogram.total_weight(), 5.0);
+        assert_eq!(histogram.effective_size(), 5.0);
+
+        // decaying all weights by the same factor doesn't change the effective size
+        histogram.decay(0.25);
+        assert_eq!(histogram.total_weight(), 1.25);
+        assert!((histogram.effective_size() - 5.0).abs() < 1e-9);
+
+        // observations with small weights count less
+        let mut other = DelayHistogram::new();
+        other.add_weighted(12, 1.0);
+        other.add_weighted(36, 0.5);
+        assert!((other.effective_size() - 1.8).abs() < 1e-9);
+        histogram.merge(other);
+        assert_eq!(histogram.weighted_values(), vec![(0, 0.25), (12, 1.5), (24, 0.25), (36, 0.5), (60, 0.25)]);
+        // (1.25 + 1.5)² / (5 * 0.25² + 1 + 0.5²)
+        assert!((histogram.effective_size() - 2.75f64.powi(2) / 1.5625).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_effective_size_of_unweighted_histogram() {
+        // stored before there were weights, so all weights were 1
+        let mut histogram : DelayHistogram = SampleHistogram { counts: BTreeMap::new(), squared_weights: None };
+        histogram.counts.insert(12, 2.0);
+        histogram.counts.insert(24, 1.0);
+        assert_eq!(histogram.effective_size(), 3.0);
+
+        histogram.add_weighted(36, 1.0);
+        assert_eq!(histogram.effective_size(), 4.0);
+    }
+}
@@ -16,7 +16,7 @@ pub struct DbItem {
     pub trip_id: String,
     pub stop_sequence: u16,
     pub stop_id: String,
-    pub route_variant: u64
+    pub route_variant: u64,
 }
 
 impl FromRow for DbItem {
@@ -1,27 +1,33 @@
 use std::collections::HashMap;
 
 use serde::{Serialize, Deserialize};
-use gtfs_structures::{RouteType};
-
-use simple_error::bail;
+use gtfs_structures::RouteType;
 
 use dystonse_curves::{
     tree::{TreeData, SerdeFormat, NodeData},
 };
 
-use crate::FnResult;
+use crate::{FnResult, OrError};
 
 use crate::types::{
     EventType,
     RouteSection,
     TimeSlot,
-    CurveData
+    CurveData,
+    tree_entries
 };
 
 /// a struct to hold a hash map of all the default curves
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DefaultCurves {
-    pub all_default_curves: HashMap<DefaultCurveKey, CurveData>
+    pub all_default_curves: HashMap<DefaultCurveKey, CurveData>,
+    /// curves for single stations, built from all routes serving them
+    #[serde(default)]
+    pub stop_curves: HashMap<StopCurveKey, CurveData>,
+    /// true if the stops of the stop curves were grouped into stations with `--group-stations`,
+    /// otherwise only stops with a parent station were grouped (see `StationGrouping::for_stop_curves`)
+    #[serde(default)]
+    pub grouped_stations: bool,
 }
 
 // Key type for the default curves hashmap, so we don't have to use a tuple:
@@ -33,12 +39,44 @@ pub struct DefaultCurveKey {
     pub event_type: EventType
 }
 
+// Key type for the stop curves hashmap:
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct StopCurveKey {
+    /// id of the station, as given by `StationGrouping::for_stop_curves`
+    pub stop_id: String,
+    pub time_slot: TimeSlot,
+    pub event_type: EventType
+}
+
 impl DefaultCurves {
     pub const NAME : &'static str = "DefaultCurves";
+
+    /// Name of the file in which the stop curves are saved within the tree.
+    const STOP_CURVES_NAME : &'static str = "stop_curves";
+
+    /// Name of the file in which `grouped_stations` is saved within the tree.
+    const GROUPED_STATIONS_NAME : &'static str = "grouped_stations";
+
+    /// All route types for which default curves are computed.
+    pub const ROUTE_TYPES : [RouteType; 11] = [
+        RouteType::Tramway,
+        RouteType::Subway,
+        RouteType::Rail,
+        RouteType::Bus,
+        RouteType::Ferry,
+        RouteType::CableCar,
+        RouteType::Gondola,
+        RouteType::Funicular,
+        RouteType::Coach,
+        RouteType::Air,
+        RouteType::Taxi
+    ];
  
     pub fn new() -> Self {
         return Self {
-            all_default_curves: HashMap::new()
+            all_default_curves: HashMap::new(),
+            stop_curves: HashMap::new(),
+            grouped_stations: false,
         };
     }
 }
@@ -46,18 +84,65 @@ impl DefaultCurves {
 impl TreeData for DefaultCurves {
     fn save_tree(&self, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> {
         if leaves.contains(&Self::NAME) {
-            self.save_to_file(dir_name, "statistics", format)?;
+            self.save_to_file(dir_name, own_name, format)?;
         } else {
             for (key, curve) in &self.all_default_curves {
-                let sub_dir_name = format!("{}/{}/{:?}/{:?}/{}", dir_name, own_name, key.route_type, key.route_section, key.time_slot);
+                let sub_dir_name = format!("{}/{}/{:?}/{:?}/{}", dir_name, own_name, key.route_type, key.route_section, key.time_slot.get_description());
                 let own_name = format!("route_{:?}", key.event_type);
                 curve.save_to_file(&sub_dir_name, &own_name, format)?;
             }
+            // there can be many thousands of stops, so they are not split into directories.
+            // A list is used instead of a map because Json does not allow structs as keys.
+            let stop_curves : Vec<(StopCurveKey, CurveData)> = self.stop_curves.iter().map(|(k, c)| (k.clone(), c.clone())).collect();
+            stop_curves.save_to_file(&format!("{}/{}", dir_name, own_name), Self::STOP_CURVES_NAME, format)?;
+            self.grouped_stations.save_to_file(&format!("{}/{}", dir_name, own_name), Self::GROUPED_STATIONS_NAME, format)?;
         }
         Ok(())
     }
 
-    fn load_tree(_dir_name: &str, _own_name: &str, _format: &SerdeFormat, _leaves: &Vec<&str>) -> FnResult<Self>{
-        bail!("Not yet implemented!");
+    fn load_tree(dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self>{
+        if leaves.contains(&Self::NAME) {
+            return Self::load_from_file(dir_name, own_name, format);
+        }
+
+        let mut dc = Self::new();
+        let own_dir_name = format!("{}/{}", dir_name, own_name);
+        for route_type_name in tree_entries(&own_dir_name)? {
+            if route_type_name == Self::STOP_CURVES_NAME {
+                let stop_curves = Vec::<(StopCurveKey, CurveData)>::load_from_file(&own_dir_name, Self::STOP_CURVES_NAME, format)?;
+                dc.stop_curves = stop_curves.into_iter().collect();
+                continue;
+            }
+            if route_type_name == Self::GROUPED_STATIONS_NAME {
+                dc.grouped_stations = bool::load_from_file(&own_dir_name, Self::GROUPED_STATIONS_NAME, format)?;
+                continue;
+            }
+            let route_type = Self::ROUTE_TYPES.iter().find(|rt| format!("{:?}", rt) == route_type_name)
+                .or_error(&format!("Unknown route type: {}", route_type_name))?;
+            let route_type_dir_name = format!("{}/{}", own_dir_name, route_type_name);
+            for route_section_name in tree_entries(&route_type_dir_name)? {
+                let route_section = RouteSection::SECTIONS.iter().find(|rs| format!("{:?}", rs) == route_section_name)
+                    .or_error(&format!("Unknown route section: {}", route_section_name))?;
+                let route_section_dir_name = format!("{}/{}", route_type_dir_name, route_section_name);
+                for time_slot_name in tree_entries(&route_section_dir_name)? {
+                    let time_slot = TimeSlot::from_description(&time_slot_name)
+                        .or_error(&format!("Unknown time slot: {}", time_slot_name))?;
+                    let time_slot_dir_name = format!("{}/{}", route_section_dir_name, time_slot_name);
+                    for curve_name in tree_entries(&time_slot_dir_name)? {
+                        let event_type = EventType::TYPES.iter().find(|et| format!("route_{:?}", et) == curve_name)
+                            .or_error(&format!("Unknown curve name: {}", curve_name))?;
+                        let key = DefaultCurveKey {
+                            route_type: *route_type,
+                            route_section: route_section.clone(),
+                            time_slot: time_slot.clone(),
+                            event_type: **event_type
+                        };
+                        let curve = CurveData::load_from_file(&time_slot_dir_name, &curve_name, format)?;
+                        dc.all_default_curves.insert(key, curve);
+                    }
+                }
+            }
+        }
+        Ok(dc)
     }
-}
\ No newline at end of file
+}
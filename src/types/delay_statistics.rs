@@ -1,17 +1,19 @@
 use std::collections::HashMap;
+use std::path::Path;
 use serde::{Serialize, Deserialize};
 
 use dystonse_curves::tree::{SerdeFormat, TreeData, NodeData};
 
 use crate::FnResult;
-use crate::types::{RouteData, DefaultCurves};
-
-use simple_error::bail;
+use crate::types::{RouteData, DefaultCurves, SegmentData, tree_entries};
 
 #[derive(Serialize, Deserialize)]
 pub struct DelayStatistics {
     pub specific: HashMap<String, RouteData>,
-    pub general: DefaultCurves
+    pub general: DefaultCurves,
+    /// run and dwell times, by route_id and route_variant, see `analyse segments`
+    #[serde(default)]
+    pub segments: HashMap<String, HashMap<u64, SegmentData>>
 }
 
 impl DelayStatistics {
@@ -21,7 +23,8 @@ impl DelayStatistics {
     pub fn new() -> Self {
         return Self {
             specific: HashMap::new(),
-            general: DefaultCurves::new()
+            general: DefaultCurves::new(),
+            segments: HashMap::new()
         };
     }
 }
@@ -39,12 +42,55 @@ impl TreeData for DelayStatistics {
                 let own_name = format!("route_{}", route_id);
                 route_data.save_tree(&sub_dir_name, &own_name, format, leaves)?;
             }
+
+            for (route_id, variants) in &self.segments {
+                let sub_dir_name = format!("{}/{}/segments/route_{}", dir_name, own_name, route_id);
+                for (route_variant_id, segment_data) in variants {
+                    let own_name = format!("route_variant_{}", route_variant_id);
+                    segment_data.save_tree(&sub_dir_name, &own_name, format, leaves)?;
+                }
+            }
         }
 
         Ok(())
     }
 
-    fn load_tree(_dir_name: &str, _own_name: &str, _format: &SerdeFormat, _leaves: &Vec<&str>) -> FnResult<Self>{
-        bail!("Not yet implemented!");
+    fn load_tree(dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self>{
+        if leaves.contains(&Self::NAME) {
+            return Self::load_from_file(dir_name, own_name, format);
+        }
+
+        let sub_dir_name = format!("{}/{}", dir_name, own_name);
+        let general = DefaultCurves::load_tree(&sub_dir_name, "general", format, leaves)?;
+
+        let mut specific = HashMap::new();
+        let sub_dir_name = format!("{}/{}/specific", dir_name, own_name);
+        if Path::new(&sub_dir_name).is_dir() {
+            for entry in tree_entries(&sub_dir_name)? {
+                let route_data = RouteData::load_tree(&sub_dir_name, &entry, format, leaves)?;
+                specific.insert(route_data.route_id.clone(), route_data);
+            }
+        }
+
+        let mut segments = HashMap::new();
+        let sub_dir_name = format!("{}/{}/segments", dir_name, own_name);
+        if Path::new(&sub_dir_name).is_dir() {
+            for route_entry in tree_entries(&sub_dir_name)? {
+                let route_id = route_entry["route_".len()..].to_string();
+                let route_dir_name = format!("{}/{}", sub_dir_name, route_entry);
+                let mut variants = HashMap::new();
+                for variant_entry in tree_entries(&route_dir_name)? {
+                    let route_variant_id : u64 = variant_entry["route_variant_".len()..].parse()?;
+                    variants.insert(route_variant_id, SegmentData::load_tree(&route_dir_name, &variant_entry, format, leaves)?);
+                }
+                segments.insert(route_id, variants);
+            }
+        }
+
+        Ok(Self {
+            specific,
+            general,
+            segments
+        })
     }
-}
\ No newline at end of file
+}
@@ -1,7 +1,6 @@
 use gtfs_structures::StopTime;
 use std::ops::{Index, IndexMut};
 use serde::{Serialize, Deserialize};
-use simple_error::bail;
 use crate::FnResult;
 use dystonse_curves::tree::{TreeData, LeafData, SerdeFormat, NodeData};
 
@@ -86,8 +85,16 @@ impl<T> TreeData for EventPair<T>
         Ok(())
     }
 
-    fn load_tree(_dir_name: &str, _own_name: &str, _format: &SerdeFormat, _leaves: &Vec<&str>) -> FnResult<Self> {
-        bail!("Nerv nicht.");
+    fn load_tree(dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self> {
+        if leaves.contains(&Self::NAME) {
+            Self::load_from_file(dir_name, own_name, format)
+        } else {
+            let sub_dir_name = format!("{}/{}", dir_name, own_name);
+            Ok(EventPair {
+                arrival: T::load_tree(&sub_dir_name, "arrival", format, leaves)?,
+                departure: T::load_tree(&sub_dir_name, "departure", format, leaves)?,
+            })
+        }
     }
 }
 
@@ -0,0 +1,58 @@
+use chrono::NaiveDate;
+use serde::{Serialize, Deserialize};
+
+use dystonse_curves::irregular_dynamic::IrregularDynamicCurve;
+use dystonse_curves::tree::{SerdeFormat, NodeData};
+
+use crate::FnResult;
+use super::EventType;
+
+/// How accurate the prognoses of the provider were for one event type and lead time bucket,
+/// compared with the final delay and with the curves made at the same time.
+/// All values that refer to delays are in seconds.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForecastAccuracyRow {
+    pub event_type: EventType,
+    /// lower bound of the lead time bucket, in minutes
+    pub min_lead_time: i32,
+    pub lead_time: String,
+    pub count: usize,
+    /// mean absolute difference between the prognosis and the final delay
+    pub provider_mean_absolute_error: f32,
+    /// mean difference between the prognosis and the final delay, positive if the prognoses were too late
+    pub provider_bias: f32,
+    /// share of prognoses within one minute of the final delay
+    pub provider_within_60s: f32,
+    /// number of prognoses for which a curve could be made, too
+    pub curve_count: usize,
+    /// mean absolute difference between the median of the curves and the final delay
+    pub curve_mean_absolute_error: Option<f32>,
+    /// distribution of the final delay minus the prognosis
+    pub provider_errors: Option<IrregularDynamicCurve<f32, f32>>,
+}
+
+/// Result of `analyse forecast-accuracy`, which is used by the importer to blend the
+/// provider's prognoses with the predicted curves (see `import --blend-provider`).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForecastAccuracy {
+    pub source: String,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub rows: Vec<ForecastAccuracyRow>,
+}
+
+impl ForecastAccuracy {
+    pub const NAME : &'static str = "forecast_accuracy";
+
+    /// Reads the forecast accuracy from `dir`.
+    pub fn load(dir: &str) -> FnResult<Self> {
+        ForecastAccuracy::load_from_file(dir, ForecastAccuracy::NAME, &SerdeFormat::Json)
+    }
+
+    /// Returns the row for a prognosis of the event type that was made `lead_time` seconds before the event.
+    pub fn get_row(&self, event_type: EventType, lead_time: i64) -> Option<&ForecastAccuracyRow> {
+        self.rows.iter()
+            .filter(|row| row.event_type == event_type && row.min_lead_time as i64 * 60 <= lead_time)
+            .max_by_key(|row| row.min_lead_time)
+    }
+}
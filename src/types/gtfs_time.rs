@@ -20,29 +20,39 @@ impl GtfsDateTime {
     }
 
     pub fn from_trip_descriptor(trip_descriptor: &TripDescriptor) -> FnResult<Self> {
+        let start_date = Self::parse_date(trip_descriptor.start_date.as_ref().or_error("No start_date")?)?;
+        let time = Self::parse_time(trip_descriptor.start_time.as_ref().or_error("No start_time")?)?;
+
+        Ok(Self{
+            service_day: start_date,
+            time,
+        })
+    }
+
+    /// Parses a date in the GTFS format YYYYMMDD.
+    pub fn parse_date(date_string: &str) -> FnResult<Date<Local>> {
+        let naive_start_date = NaiveDate::parse_from_str(date_string, "%Y%m%d")?;
+        Ok(Local.from_local_date(&naive_start_date).unwrap())
+    }
+
+    /// Parses a time in the GTFS format HH:MM:SS into seconds, where HH may exceed 23.
+    pub fn parse_time(time_string: &str) -> FnResult<i32> {
         lazy_static! {
             static ref FIND_TIME: Regex = Regex::new(r"(\d+):(\d+):(\d+)").unwrap(); // can't fail because our hard-coded regex is known to be ok
         }
 
-        let start_date_string: &String = trip_descriptor.start_date.as_ref().or_error("No start_date")?;
-        let naive_start_date = NaiveDate::parse_from_str(start_date_string, "%Y%m%d")?;
-        let start_date = Local.from_local_date(&naive_start_date).unwrap();
-
         let time_element_captures = FIND_TIME
-            .captures(trip_descriptor.start_time.as_ref().or_error("No start_time")?)
+            .captures(time_string)
             .or_error(&format!(
             "Trip descriptor does not contain a valid time (does not match format HH:MM:SS): {}",
-            trip_descriptor.start_time.as_ref().unwrap()
+            time_string
         ))?;
 
         let hours  : i32 = time_element_captures[1].parse().unwrap();
         let minutes: i32 = time_element_captures[2].parse().unwrap();
         let seconds: i32 = time_element_captures[3].parse().unwrap();
 
-        Ok(Self{
-            service_day: start_date,
-            time: hours * 3600 + minutes * 60 + seconds,
-        })
+        Ok(hours * 3600 + minutes * 60 + seconds)
     }
 
     /// Return the logical date, which may be different from the actual date of this 
@@ -0,0 +1,330 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use gtfs_structures::{Calendar, Exception, Gtfs};
+use once_cell::sync::OnceCell;
+use simple_error::bail;
+
+use crate::FnResult;
+
+/// Kind of day with respect to holidays, as far as it is relevant for the matching of time slots.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DayType {
+    Regular,
+    /// public holidays are treated like sundays
+    PublicHoliday,
+    /// workdays during school holidays get time slots of their own
+    SchoolHoliday,
+}
+
+/// Minimum number of services that regularly run on a weekday, so that
+/// the exceptions in calendar_dates are considered to be meaningful.
+const MIN_REGULAR_SERVICES: usize = 10;
+
+/// Public and school holidays of one source.
+///
+/// A process only ever handles one source, so the calendar is set once at startup
+/// via `HolidayCalendar::set_global` and then used by `TimeSlot::matches`. After that,
+/// it can't be changed anymore, so that it can be read without locking.
+#[derive(Debug, Clone)]
+pub struct HolidayCalendar {
+    public_holidays: HashSet<NaiveDate>,
+    school_holidays: HashSet<NaiveDate>,
+}
+
+static GLOBAL_CALENDAR: OnceCell<HolidayCalendar> = OnceCell::new();
+
+impl HolidayCalendar {
+    pub fn new() -> Self {
+        HolidayCalendar {
+            public_holidays: HashSet::new(),
+            school_holidays: HashSet::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_public_holidays(dates: &[NaiveDate]) -> Self {
+        let mut calendar = HolidayCalendar::new();
+        calendar.public_holidays.extend(dates);
+        calendar
+    }
+
+    /// Sets the calendar that is used for all time slot matching. This can only be done once.
+    pub fn set_global(calendar: HolidayCalendar) -> FnResult<()> {
+        if GLOBAL_CALENDAR.set(calendar).is_err() {
+            bail!("The holiday calendar was already set.");
+        }
+        Ok(())
+    }
+
+    /// Looks up the day type in the calendar that was set via `set_global`.
+    /// Before that, all days are regular days.
+    pub fn global_day_type(date: NaiveDate) -> DayType {
+        match GLOBAL_CALENDAR.get() {
+            Some(calendar) => calendar.day_type(date),
+            None => DayType::Regular,
+        }
+    }
+
+    pub fn day_type(&self, date: NaiveDate) -> DayType {
+        if self.public_holidays.contains(&date) {
+            DayType::PublicHoliday
+        } else if self.school_holidays.contains(&date) {
+            DayType::SchoolHoliday
+        } else {
+            DayType::Regular
+        }
+    }
+
+    pub fn public_holiday_count(&self) -> usize {
+        self.public_holidays.len()
+    }
+
+    pub fn school_holiday_count(&self) -> usize {
+        self.school_holidays.len()
+    }
+
+    pub fn add_public_holidays_from_file(&mut self, filename: &str) -> FnResult<()> {
+        let dates = read_dates_from_file(filename)?;
+        self.public_holidays.extend(dates);
+        Ok(())
+    }
+
+    pub fn add_school_holidays_from_file(&mut self, filename: &str) -> FnResult<()> {
+        let dates = read_dates_from_file(filename)?;
+        self.school_holidays.extend(dates);
+        Ok(())
+    }
+
+    /// Guesses public holidays from the exceptions in the schedule's calendar_dates:
+    /// A workday or saturday on which more than half of the services that would
+    /// regularly run on that weekday are removed is considered to be a public holiday.
+    ///
+    /// Returns the number of public holidays that were found.
+    pub fn add_public_holidays_from_schedule(&mut self, schedule: &Gtfs) -> usize {
+        let mut removed_per_date: HashMap<NaiveDate, usize> = HashMap::new();
+        for calendar_dates in schedule.calendar_dates.values() {
+            for calendar_date in calendar_dates {
+                match calendar_date.exception_type {
+                    Exception::Deleted => {},
+                    _ => continue,
+                }
+                if let Some(calendar) = schedule.calendar.get(&calendar_date.service_id) {
+                    if runs_regularly(calendar, calendar_date.date) {
+                        *removed_per_date.entry(calendar_date.date).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        let mut count = 0;
+        for (date, removed) in removed_per_date {
+            if date.weekday() == Weekday::Sun {
+                continue;
+            }
+            let regular = schedule.calendar.values().filter(|c| runs_regularly(c, date)).count();
+            if regular >= MIN_REGULAR_SERVICES && removed * 2 > regular && self.public_holidays.insert(date) {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+/// Checks if the service of the calendar runs on the given date, without looking at exceptions.
+fn runs_regularly(calendar: &Calendar, date: NaiveDate) -> bool {
+    if date < calendar.start_date || date > calendar.end_date {
+        return false;
+    }
+    match date.weekday() {
+        Weekday::Mon => calendar.monday,
+        Weekday::Tue => calendar.tuesday,
+        Weekday::Wed => calendar.wednesday,
+        Weekday::Thu => calendar.thursday,
+        Weekday::Fri => calendar.friday,
+        Weekday::Sat => calendar.saturday,
+        Weekday::Sun => calendar.sunday,
+    }
+}
+
+/// Reads all dates from an ICS or CSV file, depending on the file extension.
+fn read_dates_from_file(filename: &str) -> FnResult<Vec<NaiveDate>> {
+    let content = match fs::read_to_string(filename) {
+        Ok(content) => content,
+        Err(e) => bail!("Could not read holiday file {}: {}", filename, e),
+    };
+    if filename.to_lowercase().ends_with(".ics") {
+        parse_ics(&content)
+    } else {
+        parse_csv(&content)
+    }
+}
+
+/// Parses lines of the form `start_date[,end_date][,description]` with dates as YYYY-MM-DD.
+/// The end date is inclusive. Fields may also be separated by semicolons,
+/// empty lines and lines starting with # are ignored, as well as a header line.
+fn parse_csv(content: &str) -> FnResult<Vec<NaiveDate>> {
+    let mut dates = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(|c| c == ',' || c == ';').map(|f| f.trim()).collect();
+        let start = match NaiveDate::parse_from_str(fields[0], "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) if index == 0 => continue, // header
+            Err(e) => bail!("Invalid date '{}' in line {} of holiday file: {}", fields[0], index + 1, e),
+        };
+        let end = match fields.get(1).map(|f| NaiveDate::parse_from_str(f, "%Y-%m-%d")) {
+            Some(Ok(date)) => date,
+            _ => start, // no second field, or it is a description
+        };
+        add_range(&mut dates, start, end.succ());
+    }
+    Ok(dates)
+}
+
+/// Parses the all-day events of an iCalendar file. DTEND is exclusive, as defined by RFC 5545.
+fn parse_ics(content: &str) -> FnResult<Vec<NaiveDate>> {
+    let mut dates = Vec::new();
+    let mut start: Option<NaiveDate> = None;
+    let mut end: Option<NaiveDate> = None;
+    for line in content.lines() {
+        let line = line.trim_end();
+        // folded lines only continue descriptions and the like, which we don't need
+        if line.starts_with(' ') || line.starts_with('\t') {
+            continue;
+        }
+        let (name, value) = match line.find(':') {
+            Some(pos) => (&line[..pos], &line[pos + 1..]),
+            None => continue,
+        };
+        let name = name.split(';').next().unwrap().to_uppercase(); // split always yields at least one part
+        match (name.as_str(), value) {
+            ("BEGIN", "VEVENT") => {
+                start = None;
+                end = None;
+            },
+            ("DTSTART", _) => start = Some(parse_ics_date(value)?),
+            ("DTEND", _) => {
+                // an event that ends after midnight covers a part of its last day, which is counted, too
+                let date = parse_ics_date(value)?;
+                end = Some(if ends_after_midnight(value) { date.succ() } else { date });
+            },
+            ("END", "VEVENT") => {
+                match start {
+                    Some(s) => add_range(&mut dates, s, end.filter(|e| *e > s).unwrap_or(s.succ())),
+                    None => bail!("Found event without DTSTART in holiday file."),
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(dates)
+}
+
+fn parse_ics_date(value: &str) -> FnResult<NaiveDate> {
+    if value.len() < 8 {
+        bail!("Invalid date '{}' in holiday file.", value);
+    }
+    Ok(NaiveDate::parse_from_str(&value[..8], "%Y%m%d")?)
+}
+
+/// Checks if the value is a date-time (like `20200501T180000Z`) with a time other than midnight.
+fn ends_after_midnight(value: &str) -> bool {
+    match value.find('T') {
+        Some(pos) => value[pos + 1..].chars().take_while(|c| c.is_ascii_digit()).any(|c| c != '0'),
+        None => false,
+    }
+}
+
+/// Adds all dates from `start` (inclusive) to `end` (exclusive).
+fn add_range(dates: &mut Vec<NaiveDate>, start: NaiveDate, end: NaiveDate) {
+    let mut date = start;
+    while date < end {
+        dates.push(date);
+        date = date.succ();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(2020, month, day)
+    }
+
+    #[test]
+    fn test_parse_csv() -> FnResult<()> {
+        let content = "start,end,description\n\
+            # comment\n\
+            \n\
+            2020-05-01,Labour Day\n\
+            2020-05-21\n\
+            2020-07-30;2020-08-02;Summer holidays\n";
+        assert_eq!(parse_csv(content)?, vec![date(5, 1), date(5, 21), date(7, 30), date(7, 31), date(8, 1), date(8, 2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_csv_with_invalid_date() {
+        assert!(parse_csv("2020-05-01\n2020-13-01\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_ics() -> FnResult<()> {
+        let content = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART;VALUE=DATE:20200501\r\n\
+            DTEND;VALUE=DATE:20200502\r\n\
+            SUMMARY:Labour Day\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART;VALUE=DATE:20200521\r\n\
+            SUMMARY:Ascension Day,\r\n  without DTEND\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART;VALUE=DATE:20200730\r\n\
+            DTEND;VALUE=DATE:20200802\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        assert_eq!(parse_ics(content)?, vec![date(5, 1), date(5, 21), date(7, 30), date(7, 31), date(8, 1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ics_with_date_times() -> FnResult<()> {
+        let content = "BEGIN:VEVENT\n\
+            DTSTART:20201003T080000Z\n\
+            DTEND:20201003T180000Z\n\
+            END:VEVENT\n\
+            BEGIN:VEVENT\n\
+            DTSTART:20201224T000000\n\
+            DTEND:20201226T000000\n\
+            END:VEVENT\n\
+            BEGIN:VEVENT\n\
+            DTSTART:20201231T120000\n\
+            DTEND:20210101T020000\n\
+            END:VEVENT\n";
+        assert_eq!(parse_ics(content)?, vec![
+            date(10, 3),
+            date(12, 24), date(12, 25),
+            date(12, 31), NaiveDate::from_ymd(2021, 1, 1),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_day_type() {
+        let mut calendar = HolidayCalendar::new();
+        calendar.public_holidays.insert(date(5, 1));
+        calendar.school_holidays.insert(date(5, 1));
+        calendar.school_holidays.insert(date(7, 30));
+        assert_eq!(calendar.day_type(date(5, 1)), DayType::PublicHoliday);
+        assert_eq!(calendar.day_type(date(7, 30)), DayType::SchoolHoliday);
+        assert_eq!(calendar.day_type(date(7, 29)), DayType::Regular);
+    }
+}
@@ -1,30 +1,46 @@
+mod anomalies;
+mod blocks;
+mod curve_samples;
 mod db_item;
 mod default_curves;
 mod delay_statistics;
 mod event_type;
+mod forecast_accuracy;
+mod holidays;
 mod prediction_result;
 mod route_data;
 mod route_sections;
 mod route_variant_data;
+mod segment_data;
+mod station_grouping;
 mod time_slots;
 mod curve_data;
 mod gtfs_time;
 
+pub use anomalies::{Anomaly, AnomalyReport, AnomalyExclusions, FlaggedVehicle};
+pub use blocks::BlockIndex;
+pub use curve_samples::{CurveSamples, RouteSamples, RouteVariantSamples, DefaultSampleKey, DelayHistogram, PairHistogram, StopSamples, DateRange, RecencyWeighting, merge_histograms};
 pub use db_item::DbItem;
 pub use default_curves::DefaultCurves;
-pub use default_curves::DefaultCurveKey;
+pub use default_curves::{DefaultCurveKey, StopCurveKey};
 pub use delay_statistics::DelayStatistics;
 pub use event_type::{EventType, EventPair, GetByEventType};
+pub use forecast_accuracy::{ForecastAccuracy, ForecastAccuracyRow};
+pub use holidays::{HolidayCalendar, DayType};
 pub use prediction_result::PredictionResult;
 pub use route_data::RouteData;
 pub use route_sections::RouteSection;
 pub use route_variant_data::{RouteVariantData, CurveSetKey};
+pub use segment_data::{SegmentData, SegmentKey, SegmentCurveData};
+pub use station_grouping::StationGrouping;
 pub use time_slots::TimeSlot;
-pub use curve_data::{CurveData, CurveSetData};
+pub use curve_data::{CurveData, CurveSetData, SmoothingMethod};
 pub use gtfs_time::GtfsDateTime;
 
 use serde::{Serialize, Deserialize};
 
+use crate::FnResult;
+
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct PredictionBasis {
     pub stop_sequence: u16,
@@ -37,6 +53,7 @@ pub enum OriginType {
     Unknown,
     Realtime,
     Schedule,
+    Block,      // realtime data of a previous trip that is run by the same vehicle
 }
 
 impl OriginType {
@@ -45,6 +62,7 @@ impl OriginType {
             Self::Unknown => 0,
             Self::Realtime => 1,
             Self::Schedule => 2,
+            Self::Block => 3,
         }
     }
 
@@ -53,6 +71,7 @@ impl OriginType {
         match num {
             1 => Self::Realtime,
             2 => Self::Schedule,
+            3 => Self::Block,
             _ => Self::Unknown
         }
     }
@@ -68,7 +87,8 @@ pub enum PrecisionType {
     SemiSpecific,      // depends on recorded data for this specific stop, but without current realtime data
     General,           // depends on RouteType, TimeSlot, RouteSection
     FallbackGeneral,   // depends on RouteType
-    SuperGeneral       // average of everything
+    SuperGeneral,      // average of everything
+    StopSpecific,      // depends on recorded data of all routes at this stop (or station), separated by TimeSlot
 }
 
 impl PrecisionType {
@@ -81,6 +101,7 @@ impl PrecisionType {
             Self::General => 4,
             Self::FallbackGeneral => 5,
             Self::SuperGeneral => 6,
+            Self::StopSpecific => 7,
         }
     }
 
@@ -93,11 +114,28 @@ impl PrecisionType {
             4 => Self::General,
             5 => Self::FallbackGeneral,
             6 => Self::SuperGeneral,
+            7 => Self::StopSpecific,
             _ => Self::Unknown 
         }
     }
 }
 
+/// Lists the names of all entries in a directory that was written by `save_tree`.
+/// File extensions are removed, so that sub trees and leaves have the same names.
+fn tree_entries(dir_name: &str) -> FnResult<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir_name)? {
+        let path = entry?.path();
+        let name = if path.is_dir() { path.file_name() } else { path.file_stem() };
+        if let Some(name) = name {
+            names.push(name.to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
 #[derive(Hash, PartialEq, Eq, Clone, Debug)]
 pub struct VehicleIdentifier {
     pub trip_id: String,
@@ -109,8 +147,12 @@ pub struct VehicleIdentifier {
 mod tests {
 
     use crate::FnResult;
-    use super::DelayStatistics;
-    use dystonse_curves::tree::{NodeData, SerdeFormat};
+    use super::*;
+    use gtfs_structures::RouteType;
+    use std::collections::HashMap;
+    use dystonse_curves::irregular_dynamic::{IrregularDynamicCurve, Tup};
+    use dystonse_curves::curve_set::CurveSet;
+    use dystonse_curves::tree::{NodeData, TreeData, SerdeFormat};
 
     #[test]
     fn test_load_save() -> FnResult<()> {
@@ -122,4 +164,166 @@ mod tests {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn make_curve_data(offset: f32) -> CurveData {
+        CurveData {
+            curve: IrregularDynamicCurve::new(vec![
+                Tup { x: offset - 60.0, y: 0.0 },
+                Tup { x: offset, y: 0.4 },
+                Tup { x: offset + 120.0, y: 1.0 },
+            ]),
+            precision_type: PrecisionType::SemiSpecific,
+            sample_size: 42,
+            smoothing: SmoothingMethod::Kde,
+        }
+    }
+
+    fn make_curve_set_data() -> CurveSetData {
+        let mut curve_set = CurveSet::<f32, IrregularDynamicCurve<f32, f32>>::new();
+        curve_set.add_curve(0.0, make_curve_data(0.0).curve);
+        curve_set.add_curve(60.0, make_curve_data(90.0).curve);
+        CurveSetData {
+            curve_set,
+            precision_type: PrecisionType::Specific,
+            sample_size: 23,
+            smoothing: SmoothingMethod::LogNormal,
+        }
+    }
+
+    fn make_delay_statistics() -> DelayStatistics {
+        let mut variant_data = RouteVariantData::new();
+        variant_data.stop_ids = vec![String::from("stop_a"), String::from("stop_b"), String::from("stop_c")];
+        for et in &EventType::TYPES {
+            variant_data.general_delay[**et].insert(0, make_curve_data(10.0));
+            variant_data.general_delay[**et].insert(2, make_curve_data(20.0));
+            for ts in &[&TimeSlot::WORKDAY_MORNING, &TimeSlot::DEFAULT] {
+                let key = CurveSetKey { start_stop_index: 0, end_stop_index: 2, time_slot: (**ts).clone() };
+                variant_data.curve_sets[**et].insert(key, make_curve_set_data());
+            }
+        }
+
+        let mut route_data = RouteData::new("route:1");
+        route_data.variants.insert(17, variant_data.clone());
+        route_data.variants.insert(4711, variant_data);
+        route_data.layover_recovery.insert(TimeSlot::SUNDAY_DAY.clone(), make_curve_set_data());
+
+        let mut statistics = DelayStatistics::new();
+        statistics.specific.insert(route_data.route_id.clone(), route_data);
+        statistics.specific.insert(String::from("route:2"), RouteData::new("route:2"));
+        for et in &EventType::TYPES {
+            for rs in &RouteSection::SECTIONS {
+                let key = DefaultCurveKey {
+                    route_type: RouteType::Bus,
+                    route_section: rs.clone(),
+                    time_slot: TimeSlot::WORKDAY_EVENING.clone(),
+                    event_type: **et
+                };
+                statistics.general.all_default_curves.insert(key, make_curve_data(30.0));
+            }
+            let key = StopCurveKey { stop_id: String::from("station:1"), time_slot: TimeSlot::SATURDAY_DAY.clone(), event_type: **et };
+            statistics.general.stop_curves.insert(key, make_curve_data(40.0));
+        }
+        statistics.general.grouped_stations = true;
+
+        let mut segment_data = SegmentData::new();
+        segment_data.stop_ids = vec![String::from("stop_a"), String::from("stop_b")];
+        for ts in &[&TimeSlot::SCHOOL_HOLIDAY_DAY, &TimeSlot::DEFAULT] {
+            let segment_curve_data = SegmentCurveData { curve_data: make_curve_data(300.0), scheduled_duration: 240.0, within_schedule: 0.4 };
+            segment_data.run_times.insert(SegmentKey { stop_index: 0, time_slot: (**ts).clone() }, segment_curve_data.clone());
+            segment_data.dwell_times.insert(SegmentKey { stop_index: 1, time_slot: (**ts).clone() }, segment_curve_data);
+        }
+        let mut segment_variants = HashMap::new();
+        segment_variants.insert(17, segment_data);
+        statistics.segments.insert(String::from("route:1"), segment_variants);
+        statistics
+    }
+
+    // None of the types implement PartialEq, so we compare their debug representations,
+    // but only below the level of hash maps, because their order is not deterministic.
+    fn assert_same_statistics(a: &DelayStatistics, b: &DelayStatistics) {
+        let same = |x: &dyn std::fmt::Debug, y: &dyn std::fmt::Debug| assert_eq!(format!("{:?}", x), format!("{:?}", y));
+
+        assert_eq!(a.general.all_default_curves.len(), b.general.all_default_curves.len());
+        for (key, curve_data) in &a.general.all_default_curves {
+            same(curve_data, &b.general.all_default_curves[key]);
+        }
+        assert_eq!(a.general.grouped_stations, b.general.grouped_stations);
+        assert_eq!(a.general.stop_curves.len(), b.general.stop_curves.len());
+        for (key, curve_data) in &a.general.stop_curves {
+            same(curve_data, &b.general.stop_curves[key]);
+        }
+
+        assert_eq!(a.segments.len(), b.segments.len());
+        for (route_id, variants_a) in &a.segments {
+            let variants_b = &b.segments[route_id];
+            assert_eq!(variants_a.len(), variants_b.len());
+            for (route_variant, segments_a) in variants_a {
+                let segments_b = &variants_b[route_variant];
+                assert_eq!(segments_a.stop_ids, segments_b.stop_ids);
+                assert_eq!(segments_a.run_times.len(), segments_b.run_times.len());
+                for (key, data) in &segments_a.run_times {
+                    same(data, &segments_b.run_times[key]);
+                }
+                assert_eq!(segments_a.dwell_times.len(), segments_b.dwell_times.len());
+                for (key, data) in &segments_a.dwell_times {
+                    same(data, &segments_b.dwell_times[key]);
+                }
+            }
+        }
+
+        assert_eq!(a.specific.len(), b.specific.len());
+        for (route_id, route_a) in &a.specific {
+            let route_b = &b.specific[route_id];
+            assert_eq!(route_a.route_id, route_b.route_id);
+            assert_eq!(route_a.layover_recovery.len(), route_b.layover_recovery.len());
+            for (time_slot, curve_set_data) in &route_a.layover_recovery {
+                same(curve_set_data, &route_b.layover_recovery[time_slot]);
+            }
+            assert_eq!(route_a.variants.len(), route_b.variants.len());
+            for (route_variant, variant_a) in &route_a.variants {
+                let variant_b = &route_b.variants[route_variant];
+                assert_eq!(variant_a.stop_ids, variant_b.stop_ids);
+                for et in &EventType::TYPES {
+                    assert_eq!(variant_a.general_delay[**et].len(), variant_b.general_delay[**et].len());
+                    for (stop_index, curve_data) in &variant_a.general_delay[**et] {
+                        same(curve_data, &variant_b.general_delay[**et][stop_index]);
+                    }
+                    assert_eq!(variant_a.curve_sets[**et].len(), variant_b.curve_sets[**et].len());
+                    for (key, curve_set_data) in &variant_a.curve_sets[**et] {
+                        same(curve_set_data, &variant_b.curve_sets[**et][key]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn round_trip(format: &SerdeFormat, leaves: Vec<&str>, test_name: &str) -> FnResult<()> {
+        let dir_name = std::env::temp_dir().join(format!("dystonse_round_trip_{}_{}", std::process::id(), test_name));
+        let dir_name = dir_name.to_str().unwrap();
+
+        let statistics = make_delay_statistics();
+        statistics.save_tree(dir_name, "statistics", format, &leaves)?;
+        let loaded = DelayStatistics::load_tree(dir_name, "statistics", format, &leaves);
+        std::fs::remove_dir_all(dir_name)?;
+
+        assert_same_statistics(&statistics, &loaded?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_full_tree() -> FnResult<()> {
+        round_trip(&SerdeFormat::MessagePack, vec!(), "full_tree_msgpack")?;
+        round_trip(&SerdeFormat::Json, vec!(), "full_tree_json")
+    }
+
+    #[test]
+    fn test_round_trip_with_leaves() -> FnResult<()> {
+        round_trip(&SerdeFormat::MessagePack, vec!(CurveSetData::NAME), "curve_set_data")?;
+        round_trip(&SerdeFormat::MessagePack, vec!(RouteVariantData::NAME), "route_variant_data")?;
+        round_trip(&SerdeFormat::MessagePack, vec!(RouteData::NAME), "route_data")?;
+        round_trip(&SerdeFormat::MessagePack, vec!(DefaultCurves::NAME), "default_curves")?;
+        round_trip(&SerdeFormat::MessagePack, vec!(RouteData::NAME, DefaultCurves::NAME), "route_data_and_default_curves")?;
+        round_trip(&SerdeFormat::MessagePack, vec!(SegmentData::NAME), "segment_data")?;
+        round_trip(&SerdeFormat::MessagePack, vec!(DelayStatistics::NAME), "delay_statistics")
+    }
+}
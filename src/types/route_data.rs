@@ -1,19 +1,22 @@
 use std::collections::HashMap;
+use std::path::Path;
 
 use mysql::*;
 use serde::{Serialize, Deserialize};
 
 use dystonse_curves::tree::{SerdeFormat, TreeData, NodeData};
 
-use crate::{FnResult};
-use super::RouteVariantData;
-
-use simple_error::bail;
+use crate::{FnResult, OrError};
+use super::{RouteVariantData, TimeSlot, CurveSetData, tree_entries};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RouteData {
     pub route_id: String,
-    pub variants: HashMap<u64, RouteVariantData>
+    pub variants: HashMap<u64, RouteVariantData>,
+    /// How much of the arrival delay of the previous trip in the vehicle's block 
+    /// remains at the departure from the first stop of a trip of this route.
+    #[serde(default)]
+    pub layover_recovery: HashMap<TimeSlot, CurveSetData>,
 }
 
 impl RouteData {
@@ -22,7 +25,8 @@ impl RouteData {
     pub fn new(route_id: &str) -> Self {
         return Self {
             route_id: String::from(route_id),
-            variants: HashMap::new()
+            variants: HashMap::new(),
+            layover_recovery: HashMap::new(),
         };
     }
 }
@@ -33,16 +37,46 @@ impl TreeData for RouteData {
             self.save_to_file(dir_name, own_name, format)?;
         } else {
             let sub_dir_name = format!("{}/{}", dir_name, own_name);
+            self.route_id.save_to_file(&sub_dir_name, "route_id", format)?;
             for (route_variant_id, variant_data) in &self.variants {
                 let own_name = format!("route_variant_{}", route_variant_id);
                 variant_data.save_tree(&sub_dir_name, &own_name, format, leaves)?;
             }
+            let layover_dir_name = format!("{}/layover_recovery", sub_dir_name);
+            for (time_slot, curve_set_data) in &self.layover_recovery {
+                curve_set_data.save_tree(&layover_dir_name, time_slot.get_description(), format, leaves)?;
+            }
         }
 
         Ok(())
     }
 
-    fn load_tree(_dir_name: &str, _own_name: &str, _format: &SerdeFormat, _leaves: &Vec<&str>) -> FnResult<Self>{
-        bail!("Not yet implemented!");
+    fn load_tree(dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self>{
+        if leaves.contains(&Self::NAME) {
+            return Self::load_from_file(dir_name, own_name, format);
+        }
+
+        let sub_dir_name = format!("{}/{}", dir_name, own_name);
+        let route_id = String::load_from_file(&sub_dir_name, "route_id", format)?;
+        let mut data = Self::new(&route_id);
+
+        for entry in tree_entries(&sub_dir_name)? {
+            if entry.starts_with("route_variant_") {
+                let route_variant_id = &entry["route_variant_".len()..];
+                let variant_data = RouteVariantData::load_tree(&sub_dir_name, &entry, format, leaves)?;
+                data.variants.insert(route_variant_id.parse()?, variant_data);
+            }
+        }
+
+        let layover_dir_name = format!("{}/layover_recovery", sub_dir_name);
+        if Path::new(&layover_dir_name).is_dir() {
+            for entry in tree_entries(&layover_dir_name)? {
+                let time_slot = TimeSlot::from_description(&entry).or_error(&format!("Unknown time slot: {}", entry))?;
+                let curve_set_data = CurveSetData::load_tree(&layover_dir_name, &entry, format, leaves)?;
+                data.layover_recovery.insert(time_slot.clone(), curve_set_data);
+            }
+        }
+
+        Ok(data)
     }
-}
\ No newline at end of file
+}
@@ -11,6 +11,12 @@ pub enum RouteSection {
 }
 
 impl RouteSection {
+    pub const SECTIONS : [RouteSection; 3] = [
+        RouteSection::Beginning,
+        RouteSection::Middle,
+        RouteSection::End
+    ];
+
     pub fn get_route_section_by_stop_sequence(schedule: &Gtfs, trip_id: &str, stop_sequence: u16) -> FnResult<RouteSection> {
         // check if trip_id is valid for the given schedule
         // and get the right trip object
@@ -1,11 +1,12 @@
 use std::collections::HashMap;
+use std::path::Path;
 
 use serde::{Serialize, Deserialize};
 
 use dystonse_curves::tree::{SerdeFormat, TreeData, NodeData};
 
 use crate::{FnResult};
-use super::{TimeSlot, CurveSetData, CurveData, EventPair, EventType};
+use super::{TimeSlot, CurveSetData, CurveData, EventPair, EventType, tree_entries};
 
 use simple_error::bail;
 
@@ -28,32 +29,70 @@ impl TreeData for RouteVariantData {
         if leaves.contains(&Self::NAME) {
             self.save_to_file(dir_name, own_name, format)?;
         } else {
-            self.stop_ids.save_to_file(dir_name, "stop_ids", format)?;
-            self.general_delay.save_to_file(dir_name, "general_delay", format)?;
+            let sub_dir_name = format!("{}/{}", dir_name, own_name);
+            self.stop_ids.save_to_file(&sub_dir_name, "stop_ids", format)?;
+            self.general_delay.save_to_file(&sub_dir_name, "general_delay", format)?;
             for et in &EventType::TYPES {
                 for (key, curve_set_data) in &self.curve_sets[**et] {
-                    let sub_dir_name = format!("{}/{}/{}/{:?}", dir_name, own_name, key.time_slot.description, et);
+                    let curve_set_dir_name = format!("{}/{}/{:?}", sub_dir_name, key.time_slot.get_description(), et);
                     let own_name = format!("from_{}_to_{}", key.start_stop_index, key.end_stop_index);
-                    curve_set_data.curve_set.save_tree(&sub_dir_name, &own_name, format, leaves)?;
-                    //TODO: this ignores the CurveSetData's meta data, but we don't use it anyway, so we can fix this later.
+                    curve_set_data.save_tree(&curve_set_dir_name, &own_name, format, leaves)?;
                 }
             }
-
         }
 
         Ok(())
     }
 
+    fn load_tree(dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self>{
+        if leaves.contains(&Self::NAME) {
+            return Self::load_from_file(dir_name, own_name, format);
+        }
+
+        let sub_dir_name = format!("{}/{}", dir_name, own_name);
+        let mut data = Self::new();
+        data.stop_ids = Vec::<String>::load_from_file(&sub_dir_name, "stop_ids", format)?;
+        data.general_delay = EventPair::<HashMap<u32, CurveData>>::load_from_file(&sub_dir_name, "general_delay", format)?;
 
-    //TODO: implement this :D
-    fn load_tree(_dir_name: &str, _own_name: &str, _format: &SerdeFormat, _leaves: &Vec<&str>) -> FnResult<Self>{
-        bail!("Not yet implemented!");
+        for entry in tree_entries(&sub_dir_name)? {
+            // all other entries are named after the time slot of the curve sets within
+            let time_slot = match TimeSlot::from_description(&entry) {
+                Some(time_slot) => time_slot,
+                None => continue
+            };
+            for et in &EventType::TYPES {
+                let curve_set_dir_name = format!("{}/{}/{:?}", sub_dir_name, entry, et);
+                if !Path::new(&curve_set_dir_name).is_dir() {
+                    continue;
+                }
+                for curve_set_name in tree_entries(&curve_set_dir_name)? {
+                    let key = Self::parse_curve_set_name(&curve_set_name, time_slot)?;
+                    let curve_set_data = CurveSetData::load_tree(&curve_set_dir_name, &curve_set_name, format, leaves)?;
+                    data.curve_sets[**et].insert(key, curve_set_data);
+                }
+            }
+        }
+
+        Ok(data)
     }
 }
 
 impl RouteVariantData {
     pub const NAME : &'static str = "RouteVariantData";
 
+    /// Parses names like "from_3_to_7", as written by save_tree.
+    fn parse_curve_set_name(name: &str, time_slot: &TimeSlot) -> FnResult<CurveSetKey> {
+        let parts: Vec<&str> = name.split('_').collect();
+        if parts.len() != 4 || parts[0] != "from" || parts[2] != "to" {
+            bail!("Invalid curve set name: {}", name);
+        }
+        Ok(CurveSetKey {
+            start_stop_index: parts[1].parse()?,
+            end_stop_index: parts[3].parse()?,
+            time_slot: time_slot.clone()
+        })
+    }
+
     pub fn new() -> Self {
         return Self {
             stop_ids: Vec::new(),
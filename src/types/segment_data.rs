@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+use simple_error::bail;
+
+use dystonse_curves::tree::{SerdeFormat, TreeData, NodeData};
+
+use crate::FnResult;
+use super::{TimeSlot, CurveData, tree_entries};
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Hash, Debug, Clone)]
+pub struct SegmentKey {
+    /// for run times, the index of the stop at which the segment begins
+    pub stop_index: u32,
+    pub time_slot: TimeSlot
+}
+
+/// Distribution of the actual duration of a run or a dwell, compared with the schedule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SegmentCurveData {
+    /// actual durations in seconds
+    pub curve_data: CurveData,
+    /// scheduled duration in seconds, averaged over all observed vehicles
+    pub scheduled_duration: f32,
+    /// share of the observed vehicles that needed at most the scheduled duration
+    pub within_schedule: f32,
+}
+
+/// Run times between consecutive stops and dwell times at the stops of one route variant.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SegmentData {
+    pub stop_ids: Vec<String>,
+    /// from the departure at the stop with the key's index to the arrival at the next stop
+    pub run_times: HashMap<SegmentKey, SegmentCurveData>,
+    /// from the arrival to the departure at the stop with the key's index
+    pub dwell_times: HashMap<SegmentKey, SegmentCurveData>,
+}
+
+impl SegmentData {
+    pub const NAME : &'static str = "SegmentData";
+
+    pub fn new() -> Self {
+        return Self {
+            stop_ids: Vec::new(),
+            run_times: HashMap::new(),
+            dwell_times: HashMap::new(),
+        };
+    }
+
+    fn save_map(map: &HashMap<SegmentKey, SegmentCurveData>, dir_name: &str, prefix: &str, format: &SerdeFormat) -> FnResult<()> {
+        for (key, segment_curve_data) in map {
+            let time_slot_dir_name = format!("{}/{}", dir_name, key.time_slot.get_description());
+            segment_curve_data.save_to_file(&time_slot_dir_name, &format!("{}_{}", prefix, key.stop_index), format)?;
+        }
+        Ok(())
+    }
+
+    fn load_map(dir_name: &str, prefix: &str, format: &SerdeFormat) -> FnResult<HashMap<SegmentKey, SegmentCurveData>> {
+        let mut map = HashMap::new();
+        if !Path::new(dir_name).is_dir() {
+            return Ok(map);
+        }
+        let prefix = format!("{}_", prefix);
+        for time_slot_name in tree_entries(dir_name)? {
+            let time_slot = match TimeSlot::from_description(&time_slot_name) {
+                Some(time_slot) => time_slot,
+                None => bail!("Unknown time slot: {}", time_slot_name)
+            };
+            let time_slot_dir_name = format!("{}/{}", dir_name, time_slot_name);
+            for name in tree_entries(&time_slot_dir_name)? {
+                if !name.starts_with(&prefix) {
+                    bail!("Invalid segment name: {}", name);
+                }
+                let key = SegmentKey {
+                    stop_index: name[prefix.len()..].parse()?,
+                    time_slot: time_slot.clone()
+                };
+                map.insert(key, SegmentCurveData::load_from_file(&time_slot_dir_name, &name, format)?);
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl TreeData for SegmentData {
+    fn save_tree(&self, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> {
+        if leaves.contains(&Self::NAME) {
+            self.save_to_file(dir_name, own_name, format)?;
+        } else {
+            let sub_dir_name = format!("{}/{}", dir_name, own_name);
+            self.stop_ids.save_to_file(&sub_dir_name, "stop_ids", format)?;
+            Self::save_map(&self.run_times, &format!("{}/run_times", sub_dir_name), "from", format)?;
+            Self::save_map(&self.dwell_times, &format!("{}/dwell_times", sub_dir_name), "at", format)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_tree(dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self>{
+        if leaves.contains(&Self::NAME) {
+            return Self::load_from_file(dir_name, own_name, format);
+        }
+
+        let sub_dir_name = format!("{}/{}", dir_name, own_name);
+        Ok(Self {
+            stop_ids: Vec::<String>::load_from_file(&sub_dir_name, "stop_ids", format)?,
+            run_times: Self::load_map(&format!("{}/run_times", sub_dir_name), "from", format)?,
+            dwell_times: Self::load_map(&format!("{}/dwell_times", sub_dir_name), "at", format)?,
+        })
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+
+use geo::prelude::*;
+use geo::{point, Point};
+use gtfs_structures::{Gtfs, Stop};
+
+/// Stops with the same name are only considered to be the same station
+/// if they are at most this far apart (in meters).
+const MAX_DISTANCE : f64 = 300.0;
+
+/// Groups stops that are actually the same place, e.g. several platforms of a station,
+/// so that they can be treated as one station.
+///
+/// Stops with a `parent_station` belong to the station of their parent. Stops without
+/// a parent station are grouped with other stops of the same name that are close by.
+pub struct StationGrouping {
+    /// id of the station for each stop id
+    station_ids: HashMap<String, String>,
+}
+
+impl StationGrouping {
+    pub fn new(schedule: &Gtfs) -> Self {
+        let mut stop_ids : Vec<&String> = schedule.stops.keys().collect();
+        stop_ids.sort();
+        let index_of : HashMap<&String, usize> = stop_ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        let mut sets = DisjointSets::new(stop_ids.len());
+
+        let mut parent_ids = Vec::new();
+        let mut stops_by_name : HashMap<&str, Vec<(usize, &Stop)>> = HashMap::new();
+        for (i, stop_id) in stop_ids.iter().enumerate() {
+            let stop : &Stop = &schedule.stops[*stop_id];
+            match &stop.parent_station {
+                Some(parent_station) if !parent_station.is_empty() => {
+                    if let Some(parent_index) = index_of.get(parent_station) {
+                        sets.union(i, *parent_index);
+                        parent_ids.push(*parent_index);
+                    }
+                },
+                _ => stops_by_name.entry(stop.name.as_str()).or_insert_with(Vec::new).push((i, stop))
+            }
+        }
+
+        for stops in stops_by_name.values() {
+            for (a, (i_a, stop_a)) in stops.iter().enumerate() {
+                for (i_b, stop_b) in stops.iter().skip(a + 1) {
+                    if let (Some(location_a), Some(location_b)) = (location(stop_a), location(stop_b)) {
+                        if location_a.haversine_distance(&location_b) <= MAX_DISTANCE {
+                            sets.union(*i_a, *i_b);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Each station is named after one of its stops: a parent station if there is one,
+        // otherwise the stop with the smallest id. As the stop ids are sorted, this is the
+        // first matching stop of each set.
+        let mut representatives : HashMap<usize, usize> = HashMap::new();
+        parent_ids.sort();
+        for i in parent_ids.into_iter().chain(0..stop_ids.len()) {
+            representatives.entry(sets.find(i)).or_insert(i);
+        }
+
+        let station_ids = stop_ids.iter().enumerate().map(|(i, stop_id)| {
+            let representative = representatives[&sets.find(i)];
+            ((*stop_id).clone(), stop_ids[representative].clone())
+        }).collect();
+
+        StationGrouping { station_ids }
+    }
+
+    /// Returns a grouping in which only the stops with a `parent_station` are grouped, with the station of their parent.
+    pub fn by_parent_station(schedule: &Gtfs) -> Self {
+        let station_ids = schedule.stops.values().filter_map(|stop| match &stop.parent_station {
+            Some(parent_station) if !parent_station.is_empty() => Some((stop.id.clone(), parent_station.clone())),
+            _ => None
+        }).collect();
+        StationGrouping { station_ids }
+    }
+
+    /// Returns the grouping by which the stop curves are built and looked up: all stops of a
+    /// station (e.g. its platforms) share their curves, see `DefaultCurves::grouped_stations`.
+    pub fn for_stop_curves(schedule: &Gtfs, grouped_stations: bool) -> Self {
+        if grouped_stations {
+            Self::new(schedule)
+        } else {
+            Self::by_parent_station(schedule)
+        }
+    }
+
+    /// Returns a grouping in which each stop is a station of its own.
+    pub fn none() -> Self {
+        StationGrouping {
+            station_ids: HashMap::new(),
+        }
+    }
+
+    /// Returns the id of the station of the stop, or the stop id itself if the stop is unknown.
+    pub fn station_id<'a>(&'a self, stop_id: &'a str) -> &'a str {
+        match self.station_ids.get(stop_id) {
+            Some(station_id) => station_id.as_str(),
+            None => stop_id
+        }
+    }
+
+    pub fn same_station(&self, stop_id_a: &str, stop_id_b: &str) -> bool {
+        stop_id_a == stop_id_b || self.station_id(stop_id_a) == self.station_id(stop_id_b)
+    }
+}
+
+fn location(stop: &Stop) -> Option<Point<f64>> {
+    Some(point!(x: stop.longitude?, y: stop.latitude?))
+}
+
+/// Union-find data structure over the numbers `0..len`.
+struct DisjointSets {
+    parents: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> Self {
+        DisjointSets { parents: (0..len).collect() }
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parents[root] != root {
+            root = self.parents[root];
+        }
+        // path compression
+        let mut current = i;
+        while self.parents[current] != root {
+            let next = self.parents[current];
+            self.parents[current] = root;
+            current = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let root_a = self.find(a);
+        let root_b = self.find(b);
+        if root_a != root_b {
+            self.parents[root_b] = root_a;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn stop(id: &str, name: &str, parent_station: Option<&str>, latitude: f64) -> Stop {
+        Stop {
+            id: id.to_string(),
+            name: name.to_string(),
+            parent_station: parent_station.map(|p| p.to_string()),
+            latitude: Some(latitude),
+            longitude: Some(13.0),
+            ..Default::default()
+        }
+    }
+
+    fn grouping(stops: Vec<Stop>) -> StationGrouping {
+        let mut schedule = Gtfs::default();
+        for stop in stops {
+            schedule.stops.insert(stop.id.clone(), Arc::new(stop));
+        }
+        StationGrouping::new(&schedule)
+    }
+
+    #[test]
+    fn test_parent_stations() {
+        // 0.01° of latitude are about 1.1 km
+        let stations = grouping(vec![
+            stop("p", "Hauptbahnhof", None, 52.0),
+            stop("a1", "Hauptbahnhof Gleis 1", Some("p"), 52.0),
+            stop("a2", "Hauptbahnhof Gleis 2", Some("p"), 52.01),
+            stop("b", "Hauptbahnhof", Some("unknown"), 52.0),
+        ]);
+        assert_eq!(stations.station_id("a1"), "p");
+        assert_eq!(stations.station_id("a2"), "p");
+        assert_eq!(stations.station_id("p"), "p");
+        assert!(stations.same_station("a1", "a2"));
+        // a stop with an unknown parent is a station of its own
+        assert_eq!(stations.station_id("b"), "b");
+        assert!(!stations.same_station("b", "p"));
+    }
+
+    #[test]
+    fn test_stops_with_the_same_name() {
+        // 0.0009° of latitude are about 100 m
+        let stations = grouping(vec![
+            stop("x2", "Markt", None, 52.0),
+            stop("x1", "Markt", None, 52.0009),
+            stop("x3", "Markt", None, 52.003),
+            stop("y", "Markt", None, 52.01),
+            stop("z", "Rathaus", None, 52.0),
+        ]);
+        assert_eq!(stations.station_id("x1"), "x1");
+        assert_eq!(stations.station_id("x2"), "x1");
+        // x3 is 330 m from x2, but only 230 m from x1
+        assert_eq!(stations.station_id("x3"), "x1");
+        assert_eq!(stations.station_id("y"), "y");
+        assert_eq!(stations.station_id("z"), "z");
+        assert!(!stations.same_station("x2", "z"));
+        assert_eq!(stations.station_id("unknown"), "unknown");
+        assert!(StationGrouping::none().same_station("x1", "x1"));
+        assert!(!StationGrouping::none().same_station("x1", "x2"));
+    }
+
+    #[test]
+    fn test_grouping_by_parent_station() {
+        let stops = vec![
+            stop("p", "Hauptbahnhof", None, 52.0),
+            stop("a1", "Hauptbahnhof Gleis 1", Some("p"), 52.0),
+            stop("b", "Hauptbahnhof", Some("unknown"), 52.0),
+            stop("x1", "Markt", None, 52.0),
+            stop("x2", "Markt", None, 52.0),
+        ];
+        let mut schedule = Gtfs::default();
+        for stop in stops {
+            schedule.stops.insert(stop.id.clone(), Arc::new(stop));
+        }
+
+        let stations = StationGrouping::for_stop_curves(&schedule, false);
+        assert_eq!(stations.station_id("a1"), "p");
+        // unlike with `new`, the unknown parent is used as well, and stops of the same name are not grouped
+        assert_eq!(stations.station_id("b"), "unknown");
+        assert_eq!(stations.station_id("x2"), "x2");
+
+        let stations = StationGrouping::for_stop_curves(&schedule, true);
+        assert_eq!(stations.station_id("a1"), "p");
+        assert_eq!(stations.station_id("b"), "b");
+        assert_eq!(stations.station_id("x2"), "x1");
+    }
+
+    #[test]
+    fn test_disjoint_sets() {
+        let mut sets = DisjointSets::new(6);
+        sets.union(0, 1);
+        sets.union(2, 3);
+        sets.union(3, 1);
+        sets.union(4, 4);
+        for i in 1..4 {
+            assert_eq!(sets.find(i), sets.find(0));
+        }
+        assert_ne!(sets.find(4), sets.find(0));
+        assert_ne!(sets.find(5), sets.find(4));
+        assert_eq!(sets.find(5), 5);
+    }
+}
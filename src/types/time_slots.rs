@@ -1,8 +1,8 @@
-use chrono::{Weekday, Datelike, Timelike, DateTime, Local};
+use chrono::{Weekday, Datelike, Timelike, DateTime, Local, NaiveDate};
 use serde::{Serialize, Deserialize};
 use gtfs_structures::Trip;
 use crate::types::{
-    EventType, DbItem
+    EventType, DbItem, HolidayCalendar, DayType
 };
 use std::fmt::{Display, Formatter};
 use std::cmp::Ordering;
@@ -10,7 +10,8 @@ use std::hash::{Hash, Hasher};
 
 /// Time slots are specific ranges in time that occur repeatedly. 
 /// Any DateTime should be able to be mapped to exactly one TimeSlot constant.
-/// TimeSlots are defined by: id, description, weekday and hour criteria
+/// TimeSlots are defined by: id, description, weekday and hour criteria, and whether they apply during school holidays.
+/// Public holidays count as sundays, see `HolidayCalendar`.
 
 #[derive(Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct TimeSlot {
@@ -21,6 +22,23 @@ pub struct TimeSlot {
     pub max_weekday: Weekday,
     pub min_hour: u32, //including
     pub max_hour: u32, //excluding
+    #[serde(default)]
+    pub school_holidays: SchoolHolidays,
+}
+
+/// Whether a TimeSlot applies on workdays during school holidays.
+/// Public holidays are always treated like sundays, see `HolidayCalendar`.
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum SchoolHolidays {
+    Include,
+    Exclude,
+    Only,
+}
+
+impl Default for SchoolHolidays {
+    fn default() -> Self {
+        SchoolHolidays::Include
+    }
 }
 
 impl TimeSlot {
@@ -31,6 +49,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 4,
         max_hour: 6,
+        school_holidays: SchoolHolidays::Exclude,
     };
     pub const WORKDAY_MORNING_RUSH : TimeSlot = TimeSlot {
         id: 2, 
@@ -39,6 +58,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 6,
         max_hour: 8,
+        school_holidays: SchoolHolidays::Exclude,
     };
     pub const WORKDAY_LATE_MORNING : TimeSlot = TimeSlot {
         id: 3, 
@@ -47,6 +67,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 8,
         max_hour: 12,
+        school_holidays: SchoolHolidays::Exclude,
     };
     pub const WORKDAY_NOON_RUSH : TimeSlot = TimeSlot {
         id: 4, 
@@ -55,6 +76,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 12,
         max_hour: 14,
+        school_holidays: SchoolHolidays::Exclude,
     };
     pub const WORKDAY_AFTERNOON : TimeSlot = TimeSlot {
         id: 5, 
@@ -63,6 +85,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 14,
         max_hour: 16,
+        school_holidays: SchoolHolidays::Exclude,
     };
     pub const WORKDAY_AFTERNOON_RUSH : TimeSlot = TimeSlot {
         id: 6, 
@@ -71,6 +94,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 16,
         max_hour: 18,
+        school_holidays: SchoolHolidays::Exclude,
     };
     pub const WORKDAY_EVENING : TimeSlot = TimeSlot {
         id: 7, 
@@ -79,6 +103,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 18,
         max_hour: 20,
+        school_holidays: SchoolHolidays::Exclude,
     };
     pub const SATURDAY_DAY : TimeSlot = TimeSlot {
         id: 8, 
@@ -87,6 +112,7 @@ impl TimeSlot {
         max_weekday: Weekday::Sat,
         min_hour: 4,
         max_hour: 20,
+        school_holidays: SchoolHolidays::Include,
     };
     pub const SUNDAY_DAY : TimeSlot = TimeSlot {
         id: 9, 
@@ -95,6 +121,7 @@ impl TimeSlot {
         max_weekday: Weekday::Sun,
         min_hour: 4,
         max_hour: 20,
+        school_holidays: SchoolHolidays::Include,
     };
     pub const NIGHT_BEFORE_WORKDAY : TimeSlot = TimeSlot {
         id: 10, 
@@ -103,6 +130,7 @@ impl TimeSlot {
         max_weekday: Weekday::Thu,
         min_hour: 20,
         max_hour: 4,
+        school_holidays: SchoolHolidays::Include,
     };
     pub const NIGHT_BEFORE_WEEKEND_DAY : TimeSlot = TimeSlot {
         id: 11, 
@@ -111,6 +139,7 @@ impl TimeSlot {
         max_weekday: Weekday::Sat,
         min_hour: 20,
         max_hour: 4,
+        school_holidays: SchoolHolidays::Include,
     };
 
     pub const DEFAULT : TimeSlot = TimeSlot {
@@ -120,9 +149,20 @@ impl TimeSlot {
         max_weekday: Weekday::Sun,
         min_hour: 0,
         max_hour: 24,
+        school_holidays: SchoolHolidays::Include,
     };
 
-    pub const TIME_SLOTS : [&'static TimeSlot; 11] = [
+    pub const SCHOOL_HOLIDAY_DAY : TimeSlot = TimeSlot {
+        id: 13,
+        description: "Workdays during school holidays from 4 to 20h",
+        min_weekday: Weekday::Mon,
+        max_weekday: Weekday::Fri,
+        min_hour: 4,
+        max_hour: 20,
+        school_holidays: SchoolHolidays::Only,
+    };
+
+    pub const TIME_SLOTS : [&'static TimeSlot; 12] = [
         &Self::WORKDAY_MORNING, 
         &Self::WORKDAY_MORNING_RUSH, 
         &Self::WORKDAY_LATE_MORNING,
@@ -133,10 +173,11 @@ impl TimeSlot {
         &Self::SATURDAY_DAY,
         &Self::SUNDAY_DAY,
         &Self::NIGHT_BEFORE_WORKDAY,
-        &Self::NIGHT_BEFORE_WEEKEND_DAY
+        &Self::NIGHT_BEFORE_WEEKEND_DAY,
+        &Self::SCHOOL_HOLIDAY_DAY
         ];
 
-    pub const TIME_SLOTS_WITH_DEFAULT : [&'static TimeSlot; 12] = [
+    pub const TIME_SLOTS_WITH_DEFAULT : [&'static TimeSlot; 13] = [
         &Self::WORKDAY_MORNING, 
         &Self::WORKDAY_MORNING_RUSH, 
         &Self::WORKDAY_LATE_MORNING,
@@ -148,10 +189,30 @@ impl TimeSlot {
         &Self::SUNDAY_DAY,
         &Self::NIGHT_BEFORE_WORKDAY,
         &Self::NIGHT_BEFORE_WEEKEND_DAY,
+        &Self::SCHOOL_HOLIDAY_DAY,
         &Self::DEFAULT
         ];
 
 
+    /// find the TimeSlot constant with the given id
+    pub fn from_id(id: u8) -> Option<&'static TimeSlot> {
+        Self::TIME_SLOTS_WITH_DEFAULT.iter().find(|ts| ts.id == id).map(|ts| *ts)
+    }
+
+    /// find the TimeSlot constant with the given description
+    pub fn from_description(description: &str) -> Option<&'static TimeSlot> {
+        Self::TIME_SLOTS_WITH_DEFAULT.iter().find(|ts| ts.description == description).map(|ts| *ts)
+    }
+
+    /// The description is not serialized, so deserialized TimeSlots have an empty one.
+    /// This looks it up from the constant with the same id instead.
+    pub fn get_description(&self) -> &'static str {
+        match Self::from_id(self.id) {
+            Some(ts) => ts.description,
+            None => self.description
+        }
+    }
+
     /// find the matching TimeSlot for a given DateTime
     pub fn from_datetime(dt: DateTime<Local>) -> &'static TimeSlot {
         
@@ -166,20 +227,44 @@ impl TimeSlot {
 
     /// check if a given DateTime fits inside the TimeSlot
     pub fn matches(&self, dt: DateTime<Local>) -> bool {
+        self.matches_with_calendar(dt, HolidayCalendar::global_day_type)
+    }
+
+    /// check if a given DateTime fits inside the TimeSlot, with the day types from `day_type_of`
+    fn matches_with_calendar(&self, dt: DateTime<Local>, day_type_of: impl Fn(NaiveDate) -> DayType) -> bool {
         
         let mut day = false;
         let mut hour = false;
 
+        // public holidays are treated like sundays. Evenings belong to the night before the next day,
+        // so the evening before a public holiday is treated like a saturday evening instead.
+        let date = dt.date().naive_local();
+        let day_type = day_type_of(date);
+        let weekday = if dt.hour() >= Self::NIGHT_BEFORE_WORKDAY.min_hour {
+            if day_type_of(date.succ()) == DayType::PublicHoliday { Weekday::Sat } else { dt.weekday() }
+        } else if day_type == DayType::PublicHoliday {
+            Weekday::Sun
+        } else {
+            dt.weekday()
+        };
+
+        let school_holiday = day_type == DayType::SchoolHoliday;
+        match self.school_holidays {
+            SchoolHolidays::Exclude if school_holiday => return false,
+            SchoolHolidays::Only if !school_holiday => return false,
+            _ => {}
+        }
+
         // simple case for days:
-        if dt.weekday().num_days_from_monday() >= self.min_weekday.num_days_from_monday() 
-            && dt.weekday().num_days_from_monday() <= self.max_weekday.num_days_from_monday()
+        if weekday.num_days_from_monday() >= self.min_weekday.num_days_from_monday() 
+            && weekday.num_days_from_monday() <= self.max_weekday.num_days_from_monday()
             {
                 day = true;
             }
         // complex case for days:
         else if self.min_weekday.num_days_from_monday() > self.max_weekday.num_days_from_monday() 
-            && (dt.weekday().num_days_from_monday() >= self.min_weekday.num_days_from_monday() 
-                || dt.weekday().num_days_from_monday() <= self.max_weekday.num_days_from_monday())
+            && (weekday.num_days_from_monday() >= self.min_weekday.num_days_from_monday() 
+                || weekday.num_days_from_monday() <= self.max_weekday.num_days_from_monday())
             {
                 day = true;
             }
@@ -242,4 +327,35 @@ impl PartialEq for TimeSlot {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn slot_at(calendar: &HolidayCalendar, month: u32, day: u32, hour: u32) -> &'static TimeSlot {
+        let dt = Local.ymd(2020, month, day).and_hms(hour, 30, 0);
+        TimeSlot::TIME_SLOTS.iter()
+            .find(|ts| ts.matches_with_calendar(dt, |date| calendar.day_type(date)))
+            .map(|ts| *ts)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_public_holidays() {
+        // Friday, May 1st 2020 and Thursday, May 21st 2020 are public holidays
+        let calendar = HolidayCalendar::with_public_holidays(&[NaiveDate::from_ymd(2020, 5, 1), NaiveDate::from_ymd(2020, 5, 21)]);
+
+        assert_eq!(slot_at(&calendar, 5, 1, 10).id, TimeSlot::SUNDAY_DAY.id);
+        assert_eq!(slot_at(&calendar, 5, 21, 10).id, TimeSlot::SUNDAY_DAY.id);
+        assert_eq!(slot_at(&calendar, 5, 22, 10).id, TimeSlot::WORKDAY_LATE_MORNING.id);
+
+        // the nights before the holidays
+        assert_eq!(slot_at(&calendar, 4, 30, 22).id, TimeSlot::NIGHT_BEFORE_WEEKEND_DAY.id);
+        assert_eq!(slot_at(&calendar, 5, 20, 22).id, TimeSlot::NIGHT_BEFORE_WEEKEND_DAY.id);
+        // the nights after the holidays, and a regular one
+        assert_eq!(slot_at(&calendar, 5, 21, 22).id, TimeSlot::NIGHT_BEFORE_WORKDAY.id);
+        assert_eq!(slot_at(&calendar, 5, 1, 22).id, TimeSlot::NIGHT_BEFORE_WEEKEND_DAY.id);
+        assert_eq!(slot_at(&calendar, 5, 19, 22).id, TimeSlot::NIGHT_BEFORE_WORKDAY.id);
+    }
+}
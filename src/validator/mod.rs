@@ -0,0 +1,310 @@
+use clap::{App, Arg, ArgMatches};
+use gtfs_structures::Gtfs;
+use std::collections::BTreeMap;
+use std::fs::{DirBuilder, File};
+use std::io::prelude::*;
+
+use crate::importer::Importer;
+use crate::{Main, FnResult, Loadable, read_dir_simple};
+
+/// Number of example ids that are listed in the report for each kind of issue.
+const MAX_EXAMPLES: usize = 10;
+
+/// Checks static GTFS schedules for problems that would cause failed predictions
+/// or panics later on, and writes a human-readable report.
+pub struct Validator<'a> {
+    main: &'a Main,
+    args: &'a ArgMatches,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    /// The schedule can't be used reliably.
+    Error,
+    /// Some features (like the monitor) will not work completely with this schedule.
+    Warning,
+}
+
+/// Collects the issues of one schedule, grouped by their description.
+struct ValidationResult {
+    filename: String,
+    issues: BTreeMap<(Severity, &'static str), Vec<String>>,
+}
+
+impl ValidationResult {
+    fn new(filename: &str) -> Self {
+        ValidationResult {
+            filename: filename.to_string(),
+            issues: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, severity: Severity, description: &'static str, id: &str) {
+        self.issues.entry((severity, description)).or_insert_with(Vec::new).push(id.to_string());
+    }
+
+    fn has_errors(&self) -> bool {
+        self.issues.keys().any(|(severity, _)| *severity == Severity::Error)
+    }
+
+    fn write_report(&self, w: &mut dyn Write) -> FnResult<()> {
+        writeln!(w, "Schedule {}:", self.filename)?;
+        if self.issues.is_empty() {
+            writeln!(w, "  No issues found.")?;
+        }
+        for ((severity, description), ids) in &self.issues {
+            let examples: Vec<&str> = ids.iter().take(MAX_EXAMPLES).map(|id| id.as_str()).collect();
+            writeln!(
+                w,
+                "  {:?}: {} ({} times), e.g. {}{}",
+                severity,
+                description,
+                ids.len(),
+                examples.join(", "),
+                if ids.len() > MAX_EXAMPLES { ", …" } else { "" }
+            )?;
+        }
+        writeln!(w)?;
+        Ok(())
+    }
+}
+
+impl<'a> Validator<'a> {
+    pub fn get_subcommand() -> App<'a> {
+        App::new("schedule").about("Works with the static GTFS schedules. The database is not used, but the connection options are still required.")
+            .subcommand(App::new("validate")
+                .about("Checks GTFS schedules for problems that would cause failed predictions later on, and writes a report.")
+                .arg(Arg::new("schedules")
+                    .index(1)
+                    .multiple(true)
+                    .value_name("SCHEDULE")
+                    .about("The schedules to check. If omitted, all schedules in the 'schedule' subdirectory of --dir are checked.")
+                ).arg(Arg::new("report")
+                    .long("report")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .about("File name of the report. Defaults to 'schedule_validation.txt' within --dir.")
+                ).arg(Arg::new("move-failed")
+                    .long("move-failed")
+                    .about("If provided, schedules with errors (not just warnings) will be moved to the 'failed' subdirectory of --dir.")
+                )
+            )
+    }
+
+    pub fn new(main: &'a Main, args: &'a ArgMatches) -> Validator<'a> {
+        Validator {
+            main,
+            args,
+        }
+    }
+
+    /// Runs the actions that are selected via the command line args
+    pub fn run(&self) -> FnResult<()> {
+        match self.args.clone().subcommand() {
+            ("validate", Some(sub_args)) => self.run_validate(sub_args),
+            _ => panic!("Invalid arguments."),
+        }
+    }
+
+    fn run_validate(&self, args: &ArgMatches) -> FnResult<()> {
+        let schedule_filenames: Vec<String> = match args.values_of("schedules") {
+            Some(values) => values.map(|s| s.to_string()).collect(),
+            None => read_dir_simple(&format!("{}/schedule", self.main.dir))?,
+        };
+        let report_filename = match args.value_of("report") {
+            Some(filename) => filename.to_string(),
+            None => format!("{}/schedule_validation.txt", self.main.dir),
+        };
+        let fail_dir = format!("{}/failed", self.main.dir);
+
+        let mut report = File::create(&report_filename)?;
+        let mut failed_count = 0;
+
+        for filename in &schedule_filenames {
+            if self.main.verbose {
+                println!("Validating {}…", filename);
+            }
+            let result = match Gtfs::load(filename) {
+                Ok(schedule) => Self::validate_schedule(filename, &schedule),
+                Err(e) => {
+                    let mut result = ValidationResult::new(filename);
+                    result.add(Severity::Error, "Schedule could not be parsed", &format!("{}", e));
+                    result
+                }
+            };
+            result.write_report(&mut report)?;
+
+            if result.has_errors() {
+                failed_count += 1;
+                if args.is_present("move-failed") {
+                    DirBuilder::new().recursive(true).create(&fail_dir)?;
+                    Importer::move_file_to_dir(filename, &fail_dir)?;
+                    println!("Moved schedule {} with errors to {}.", filename, fail_dir);
+                }
+            }
+        }
+
+        println!(
+            "Validated {} schedules, {} of them with errors. Report written to {}.",
+            schedule_filenames.len(),
+            failed_count,
+            report_filename
+        );
+        Ok(())
+    }
+
+    /// Checks all properties of a schedule that this tool depends on.
+    fn validate_schedule(filename: &str, schedule: &Gtfs) -> ValidationResult {
+        let mut result = ValidationResult::new(filename);
+
+        for (trip_id, trip) in &schedule.trips {
+            if schedule.get_route(&trip.route_id).is_err() {
+                result.add(Severity::Error, "Trip refers to an unknown route", trip_id);
+            }
+            if trip.route_variant.is_none() {
+                result.add(Severity::Error, "Trip has no route_variant", trip_id);
+            } else if trip.route_variant.as_ref().unwrap().parse::<u64>().is_err() {
+                result.add(Severity::Error, "Trip has a route_variant that is not a number", trip_id);
+            }
+            if trip.trip_headsign.is_none() {
+                result.add(Severity::Warning, "Trip has no trip_headsign", trip_id);
+            }
+
+            if trip.stop_times.is_empty() {
+                result.add(Severity::Error, "Trip has no stop_times", trip_id);
+                continue;
+            }
+            // the first stop needs no arrival time and the last stop no departure time
+            let last = trip.stop_times.len() - 1;
+            if trip.stop_times[..last].iter().any(|st| st.departure_time.is_none()) {
+                result.add(Severity::Error, "Trip has stop_times without departure time", trip_id);
+            }
+            if trip.stop_times[1..].iter().any(|st| st.arrival_time.is_none()) {
+                result.add(Severity::Error, "Trip has stop_times without arrival time", trip_id);
+            }
+            // gtfs-structures sorts the stop_times by stop_sequence, so only duplicates can be found here
+            if trip.stop_times.windows(2).any(|pair| pair[0].stop_sequence == pair[1].stop_sequence) {
+                result.add(Severity::Error, "Trip has duplicate stop_sequences", trip_id);
+            }
+        }
+
+        for (stop_id, stop) in &schedule.stops {
+            if stop.latitude.is_none() || stop.longitude.is_none() {
+                result.add(Severity::Warning, "Stop has no coordinates", stop_id);
+            }
+        }
+
+        if schedule.agencies.len() > 1 {
+            for (route_id, route) in &schedule.routes {
+                if route.agency_id.is_none() {
+                    result.add(Severity::Warning, "Route has no agency_id, but there are several agencies", route_id);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_structures::{Route, Stop, StopTime, Trip};
+    use std::sync::Arc;
+
+    fn stop_time(stop_sequence: u16, arrival_time: Option<u32>, departure_time: Option<u32>) -> StopTime {
+        StopTime {
+            stop_sequence,
+            arrival_time,
+            departure_time,
+            ..Default::default()
+        }
+    }
+
+    fn trip(id: &str, stop_times: Vec<StopTime>) -> Trip {
+        Trip {
+            id: id.to_string(),
+            route_id: String::from("route"),
+            route_variant: Some(String::from("1")),
+            trip_headsign: Some(String::from("Hauptbahnhof")),
+            stop_times,
+            ..Default::default()
+        }
+    }
+
+    fn schedule(trips: Vec<Trip>) -> Gtfs {
+        let mut schedule = Gtfs::default();
+        schedule.routes.insert(String::from("route"), Route { id: String::from("route"), ..Default::default() });
+        for trip in trips {
+            schedule.trips.insert(trip.id.clone(), trip);
+        }
+        schedule
+    }
+
+    fn issues(schedule: &Gtfs) -> Vec<(Severity, &'static str, Vec<String>)> {
+        Validator::validate_schedule("test", schedule).issues.into_iter()
+            .map(|((severity, description), mut ids)| {
+                ids.sort();
+                (severity, description, ids)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_trip() {
+        let schedule = schedule(vec![
+            trip("valid", vec![stop_time(1, None, Some(100)), stop_time(2, Some(200), Some(210)), stop_time(5, Some(300), None)]),
+        ]);
+        let result = Validator::validate_schedule("test", &schedule);
+        assert!(result.issues.is_empty());
+        assert!(!result.has_errors());
+    }
+
+    #[test]
+    fn test_trip_errors() {
+        let mut unknown_route = trip("unknown_route", vec![stop_time(1, None, Some(100))]);
+        unknown_route.route_id = String::from("other");
+        let mut no_variant = trip("no_variant", vec![stop_time(1, None, Some(100))]);
+        no_variant.route_variant = None;
+        let mut text_variant = trip("text_variant", vec![stop_time(1, None, Some(100))]);
+        text_variant.route_variant = Some(String::from("A"));
+        let schedule = schedule(vec![
+            unknown_route,
+            no_variant,
+            text_variant,
+            trip("no_stop_times", vec![]),
+            trip("no_departure", vec![stop_time(1, Some(100), None), stop_time(2, Some(200), None)]),
+            trip("no_later_departure", vec![stop_time(1, None, Some(100)), stop_time(2, Some(200), None), stop_time(3, Some(300), None)]),
+            trip("no_arrival", vec![stop_time(1, None, Some(100)), stop_time(2, None, Some(200)), stop_time(3, Some(300), None)]),
+            trip("no_time", vec![stop_time(1, None, Some(100)), stop_time(2, None, None)]),
+            trip("duplicate", vec![stop_time(1, None, Some(100)), stop_time(1, Some(200), None)]),
+        ]);
+
+        let result = Validator::validate_schedule("test", &schedule);
+        assert!(result.has_errors());
+        assert_eq!(issues(&schedule), vec![
+            (Severity::Error, "Trip has a route_variant that is not a number", vec![String::from("text_variant")]),
+            (Severity::Error, "Trip has duplicate stop_sequences", vec![String::from("duplicate")]),
+            (Severity::Error, "Trip has no route_variant", vec![String::from("no_variant")]),
+            (Severity::Error, "Trip has no stop_times", vec![String::from("no_stop_times")]),
+            (Severity::Error, "Trip has stop_times without arrival time", vec![String::from("no_arrival"), String::from("no_time")]),
+            (Severity::Error, "Trip has stop_times without departure time", vec![String::from("no_departure"), String::from("no_later_departure")]),
+            (Severity::Error, "Trip refers to an unknown route", vec![String::from("unknown_route")]),
+        ]);
+    }
+
+    #[test]
+    fn test_warnings() {
+        let mut no_headsign = trip("no_headsign", vec![stop_time(1, None, Some(100))]);
+        no_headsign.trip_headsign = None;
+        let mut schedule = schedule(vec![no_headsign]);
+        schedule.stops.insert(String::from("nowhere"), Arc::new(Stop { id: String::from("nowhere"), ..Default::default() }));
+
+        let result = Validator::validate_schedule("test", &schedule);
+        assert!(!result.has_errors());
+        assert_eq!(issues(&schedule), vec![
+            (Severity::Warning, "Stop has no coordinates", vec![String::from("nowhere")]),
+            (Severity::Warning, "Trip has no trip_headsign", vec![String::from("no_headsign")]),
+        ]);
+    }
+}